@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+/// How many lines each generated source has, the size we want to stay well under a second for
+const LINES: usize = 100_000;
+
+/// Unrolled straight-line code, as generated by a compiler or macro expansion. Nothing refers to
+/// an address since a program this size wouldn't fit in memory
+fn unrolled_code() -> String {
+    let mut source = String::from("alias counter V3\nalias step 0x01\n");
+    for i in 0..LINES / 4 {
+        source += &format!("LD V{:X}, {:#04X} ; load\n", i % 16, i % 256);
+        source += "ADD counter, step\n";
+        source += &format!("SE V{:X}, V{:X}\n", i % 16, (i + 1) % 16);
+        source += "DRW V0, V1, 0x5\n";
+    }
+    source
+}
+
+/// A data table made of sprite blocks, each with a label
+fn data_table() -> String {
+    let mut source = String::new();
+    for i in 0..LINES / 17 {
+        source += &format!("sprite table_{i}\n");
+        for row in 0..15 {
+            source += &format!("0b{:08b}\n", (i + row) % 256);
+        }
+        source += "endsprite\n";
+    }
+    source
+}
+
+fn bench_assemble(c: &mut Criterion) {
+    let mut group = c.benchmark_group("assemble");
+    for (name, source) in [
+        ("unrolled_code", unrolled_code()),
+        ("data_table", data_table()),
+    ] {
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| ch8asm::assemble(black_box(&source)).expect("benchmark source assembles"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_assemble);
+criterion_main!(benches);
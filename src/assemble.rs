@@ -1,8 +1,21 @@
-use std::cmp::Ordering;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use phf::phf_map;
 use thiserror::Error;
+pub mod expr;
+pub mod ir;
 pub mod parse;
+use ir::{Arg, Data, Instruction, Op};
 use parse::{AsmArgParseError, AsmArgument};
 
+use super::symbols::SymbolTable;
+use super::tokenize::Line;
+
+/// The address at which programs are loaded and begin execution
+pub const PROGRAM_START: u16 = 0x200;
+/// The size of addressable memory in bytes
+pub const MEMORY_SIZE: usize = 0x1000;
+
 /// An error that occured while parsing the assembly string
 #[derive(Debug, Error)]
 pub enum AssembleError {
@@ -22,575 +35,438 @@ pub enum AssembleError {
     ),
 }
 
-/// For a line of assembly, emit its machine code
-pub fn assemble_instruction(inst: &str) -> Result<u16, AssembleError> {
-    let tokens = inst
-        .split_whitespace()
-        .map(|t| t.trim_end_matches(',')) // commas are optional
-        .collect::<Vec<&str>>();
-
-    match *tokens
-        .first()
-        .expect("Attempt to parse empty string as instruction")
-    {
-        // TODO: check for too many args on cls and ret
-        "CLS" => Ok(0x00E0),
-        "RET" => Ok(0x00EE),
-
-        "JP" | "jp" | "jP" | "Jp" => assemble_jp(&tokens),
-        "LD" | "ld" | "lD" | "Ld" => assemble_ld(&tokens),
-
-        "SYS" | "sYs" | "Sys" | "syS" | "SYs" | "sYS" | "SyS" | "sys" => assemble_sys(&tokens),
-        "CALL" | "call" => assemble_call(&tokens),
-        "SE" | "sE" | "Se" | "se" => assemble_se(&tokens),
-        "SNE" | "snE" | "sNe" | "Sne" | "SNe" | "SnE" | "sNE" | "sne" => assemble_sne(&tokens),
-        "ADD" | "adD" | "aDd" | "Add" | "ADd" | "AdD" | "aDD" | "add" => assemble_add(&tokens),
-
-        "OR" | "or" | "oR" | "Or" => assemble_or(&tokens),
-        "AND" | "anD" | "aNd" | "And" | "ANd" | "AnD" | "aND" | "and" => assemble_and(&tokens),
-        "XOR" | "xoR" | "xOr" | "Xor" | "XOr" | "XoR" | "xOR" | "xor" => assemble_xor(&tokens),
-
-        "SUB" | "suB" | "sUb" | "Sub" | "SUb" | "SuB" | "sUB" | "sub" => assemble_sub(&tokens),
-        "SUBN" | "subn" => assemble_subn(&tokens),
-
-        "SHR" | "shR" | "sHr" | "Shr" | "SHr" | "ShR" | "sHR" | "shr" => assemble_shr(&tokens),
-        "SHL" | "shL" | "sHl" | "Shl" | "SHl" | "ShL" | "sHL" | "shl" => assemble_shl(&tokens),
-
-        "RND" | "rnD" | "rNd" | "Rnd" | "RNd" | "RnD" | "rND" | "rnd" => assemble_rnd(&tokens),
-        "DRW" | "drW" | "dRw" | "Drw" | "DRw" | "DrW" | "dRW" | "drw" => assemble_drw(&tokens),
-
-        "SKP" | "skP" | "sKp" | "Skp" | "SKp" | "SkP" | "sKP" | "skp" => assemble_skp(&tokens),
-        "SKNP" | "sknp" => assemble_sknp(&tokens),
-
-        other => {
-            if other.starts_with("0x") && tokens.len() == 1 {
-                Ok(parse::parse_raw(&tokens)?)
-            } else {
-                Err(AssembleError::UnknownOp(inst.to_string()))
-            }
+impl AssembleError {
+    #[cfg(feature = "std")]
+    /// A short name for the kind of error that stays the same as the message is reworded, for tools that act on
+    /// particular errors. Arguments that can't be parsed are the kind of the parsing error
+    pub fn code(&self) -> &'static str {
+        match self {
+            AssembleError::UnknownOp(_) => "unknown-op",
+            AssembleError::MissingArgs(_) => "missing-args",
+            AssembleError::ExtraArgs(_) => "extra-args",
+            AssembleError::InvalidArg(_) => "invalid-arg",
+            AssembleError::BadParse(error) => error.code(),
         }
     }
 }
 
-/// Given the tokens of a jp instrutction, return its machine code or an error
-fn assemble_jp(tokens: &[&str]) -> Result<u16, AssembleError> {
-    let args = parse::parse_asm_args(&tokens[1..])?;
-    match args.len() {
-        // JP addr - 1nnn
-        1 => {
-            let addr = parse::parse_valid_addr(&args[0])?;
-            Ok(0x1000 + addr)
-        }
-
-        // JP V0, addr - Bnnn
-        2 => match args[0] {
-            AsmArgument::Register(0u8) => {
-                let addr = parse::parse_valid_addr(&args[1])?;
-                Ok(0xB000 + addr)
-            }
-            _ => Err(AssembleError::InvalidArg(tokens.join(" "))),
-        },
-
-        0 => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        _ => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-    }
+/// Scratch space reused from one instruction to the next, so encoding a long program doesn't
+/// allocate for every line
+#[derive(Default)]
+pub struct Buffers {
+    args: Vec<AsmArgument>,
 }
 
-/// Given the tokens of a LD instruction, return its machine code or an error
-fn assemble_ld(tokens: &[&str]) -> Result<u16, AssembleError> {
-    // handle errors for bad number of args
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-
-            match (&args[0], &args[1]) {
-                // LD Vx, Vy - 8xy0
-                (AsmArgument::Register(vx), AsmArgument::Register(vy)) => {
-                    let mut out = 0x8000;
-                    let vx = *vx as u16;
-                    let vy = *vy as u16;
-                    out += vx << 8;
-                    out += vy << 4;
-                    Ok(out)
-                }
-
-                // LD Vx, byte - 6xkk
-                (AsmArgument::Register(vx), AsmArgument::Numeric(_)) => {
-                    let mut out = 0x6000;
-                    let vx = *vx as u16;
-                    let byte = parse::parse_valid_byte(&args[1])? as u16;
-                    out += vx << 8;
-                    out += byte;
-                    Ok(out)
-                }
-
-                // LD I, addr - Annn
-                (AsmArgument::IPointer, AsmArgument::Numeric(_)) => {
-                    let mut out = 0xA000;
-                    let addr = parse::parse_valid_addr(&args[1])?;
-                    out += addr;
-                    Ok(out)
+/// For an instruction, emit its machine code, resolving any symbols it uses along the way
+pub fn assemble_instruction(
+    inst: &Instruction,
+    symbols: &SymbolTable,
+    buffers: &mut Buffers,
+) -> Result<u16, AssembleError> {
+    match inst.op {
+        Op::Forms(forms) => encode(forms, inst, symbols, &mut buffers.args),
+        Op::Raw(raw) => Ok(raw),
+        // only an alias can still make this an operation
+        Op::Name(name) => {
+            let mnemonic = symbols.substitute(name);
+            match lookup_mnemonic(mnemonic) {
+                Some(forms) => encode(forms, inst, symbols, &mut buffers.args),
+                None if mnemonic.starts_with("0x") && inst.args.is_empty() => {
+                    Ok(parse::parse_raw(&[mnemonic])?)
                 }
-
-                // LD Vx, DT - Fx07
-                (AsmArgument::Register(vx), AsmArgument::DelayTimer) => {
-                    let mut out = 0xF007;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD Vx, K - Fx0A
-                (AsmArgument::Register(vx), AsmArgument::AnyKey) => {
-                    let mut out = 0xF00A;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD DT, Vx - Fx15
-                (AsmArgument::DelayTimer, AsmArgument::Register(vx)) => {
-                    let mut out = 0xF015;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD ST, Vx - Fx18
-                (AsmArgument::SoundTimer, AsmArgument::Register(vx)) => {
-                    let mut out = 0xF018;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD F, Vx - Fx29
-                (AsmArgument::Sprite, AsmArgument::Register(vx)) => {
-                    let mut out = 0xF029;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD B, Vx - Fx33
-                (AsmArgument::Bcd, AsmArgument::Register(vx)) => {
-                    let mut out = 0xF033;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD [I], Vx - Fx55
-                (AsmArgument::IRange, AsmArgument::Register(vx)) => {
-                    let mut out = 0xF055;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                // LD Vx, [I] - Fx65
-                (AsmArgument::Register(vx), AsmArgument::IRange) => {
-                    let mut out = 0xF065;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
-
-                (_, _) => Err(AssembleError::InvalidArg(tokens.join(" "))),
+                None => Err(AssembleError::UnknownOp(inst.line.text.to_string())),
             }
         }
     }
 }
 
-/// Given the tokens of a SYS instruction, return its machine code or an error
-// SYS addr - 0nnn
-fn assemble_sys(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&2) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let AsmArgument::Numeric(_) = args[0] {
-                let addr = parse::parse_valid_addr(&args[0])?;
-                #[allow(clippy::identity_op)] // Leaving the opcode here makes the code clearer
-                Ok(0x0000 + addr)
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
-    }
+/// The (1-indexed) column of the part of an instruction an error is about: the argument it names, or the start of the
+/// instruction when it's about the instruction as a whole
+pub fn column(inst: &Line, symbols: &SymbolTable, error: &AssembleError) -> usize {
+    let start = inst.tokens.first().map_or(1, |token| token.column);
+    let AssembleError::BadParse(error) = error else {
+        return start;
+    };
+    inst.tokens
+        .iter()
+        .skip(1)
+        .find(|token| {
+            let arg = symbols.substitute(token.text);
+            let value = match symbols.value_of(arg) {
+                Some(value) => i32::try_from(value).ok(),
+                None if expr::is_expression(arg) => evaluate_signed(arg, symbols).ok(),
+                None => match parse::parse_asm_arg(arg) {
+                    Ok(AsmArgument::Numeric(value)) => Some(value.into()),
+                    _ => None,
+                },
+            };
+            error.is_about(arg, value)
+        })
+        .map_or(start, |token| token.column)
 }
 
-/// Given the tokens of a CALL instruction, return its machine code or an error
-// CALL addr - 2nnn
-fn assemble_call(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&2) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let AsmArgument::Numeric(_) = args[0] {
-                let addr = parse::parse_valid_addr(&args[0])?;
-                Ok(0x2000 + addr)
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
+/// Which byte of a 16 bit data word comes first in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// High byte first, the way instructions are stored
+    Big,
+    /// Low byte first
+    Little,
+}
+
+/// For a line of word data, emit each value as two bytes in the given order, resolving any symbols it uses along the
+/// way
+pub fn assemble_words(
+    data: &Data,
+    order: ByteOrder,
+    symbols: &SymbolTable,
+    buffers: &mut Buffers,
+    rom: &mut Vec<u8>,
+) -> Result<(), AssembleError> {
+    for arg in resolve_args(&data.args, symbols, &mut buffers.args)? {
+        if !matches!(arg, AsmArgument::Numeric(_) | AsmArgument::Negative(_)) {
+            return Err(AssembleError::InvalidArg(data.line.text.to_string()));
         }
+        let value = parse::parse_valid_word(arg)?;
+        rom.extend(match order {
+            ByteOrder::Big => value.to_be_bytes(),
+            ByteOrder::Little => value.to_le_bytes(),
+        });
     }
+    Ok(())
 }
 
-/// Given the tokens of a SE instruction, return its machine code or an error
-fn assemble_se(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-
-            match (&args[0], &args[1]) {
-                // SE Vx, byte - 3xkk
-                (AsmArgument::Register(vx), AsmArgument::Numeric(_)) => {
-                    let mut out = 0x3000;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    out += parse::parse_valid_byte(&args[1])? as u16;
-                    Ok(out)
-                }
-                // SE Vx, Vy - 5xy0
-                (AsmArgument::Register(vx), AsmArgument::Register(vy)) => {
-                    let mut out = 0x5000;
-                    let vx = *vx as u16;
-                    let vy = *vy as u16;
-                    out += vx << 8;
-                    out += vy << 4;
-                    Ok(out)
-                }
-
-                (_, _) => Err(AssembleError::InvalidArg(tokens.join(" "))),
-            }
+/// For a line of byte data, emit each value as a byte, resolving any symbols it uses along the way
+pub fn assemble_bytes(
+    data: &Data,
+    symbols: &SymbolTable,
+    buffers: &mut Buffers,
+    rom: &mut Vec<u8>,
+) -> Result<(), AssembleError> {
+    for arg in resolve_args(&data.args, symbols, &mut buffers.args)? {
+        if !matches!(arg, AsmArgument::Numeric(_) | AsmArgument::Negative(_)) {
+            return Err(AssembleError::InvalidArg(data.line.text.to_string()));
         }
+        rom.push(parse::parse_valid_byte(arg)?);
     }
+    Ok(())
 }
 
-/// Given the tokens of a SNE instruction, return its machine code or an error
-fn assemble_sne(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-
-            match (&args[0], &args[1]) {
-                // SNE Vx, byte - 4xkk
-                (AsmArgument::Register(vx), AsmArgument::Numeric(_)) => {
-                    let mut out = 0x4000;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    out += parse::parse_valid_byte(&args[1])? as u16;
-                    Ok(out)
-                }
-                // SNE Vx, Vy - 9xy0
-                (AsmArgument::Register(vx), AsmArgument::Register(vy)) => {
-                    let mut out = 0x9000;
-                    let vx = *vx as u16;
-                    let vy = *vy as u16;
-                    out += vx << 8;
-                    out += vy << 4;
-                    Ok(out)
-                }
+/// The kind of argument an operand takes, and where it goes in the opcode
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    /// Any register, in the second nibble
+    Vx,
+    /// Any register, in the third nibble
+    Vy,
+    /// Only V0, which isn't encoded
+    V0,
+    /// A 12 bit address, in the last three nibbles
+    Addr,
+    /// A byte, in the last two nibbles
+    Byte,
+    /// A nibble, in the last one
+    Nibble,
+    I,
+    IRange,
+    K,
+    Dt,
+    St,
+    F,
+    B,
+}
 
-                (_, _) => Err(AssembleError::InvalidArg(tokens.join(" "))),
-            }
-        }
+use Operand::*;
+
+impl Operand {
+    /// Whether an argument is the kind this operand takes, before checking that a number is in range
+    fn accepts(self, arg: &AsmArgument) -> bool {
+        matches!(
+            (self, arg),
+            (Vx | Vy, AsmArgument::Register(_))
+                | (V0, AsmArgument::Register(0))
+                | (
+                    Addr | Byte | Nibble,
+                    AsmArgument::Numeric(_) | AsmArgument::Negative(_)
+                )
+                | (I, AsmArgument::IPointer)
+                | (IRange, AsmArgument::IRange)
+                | (K, AsmArgument::AnyKey)
+                | (Dt, AsmArgument::DelayTimer)
+                | (St, AsmArgument::SoundTimer)
+                | (F, AsmArgument::Sprite)
+                | (B, AsmArgument::Bcd)
+        )
+    }
+
+    /// The bits an argument this operand accepts adds to the opcode
+    fn encode(self, arg: &AsmArgument) -> Result<u16, AsmArgParseError> {
+        Ok(match (self, arg) {
+            (Vx, AsmArgument::Register(vx)) => (*vx as u16) << 8,
+            (Vy, AsmArgument::Register(vy)) => (*vy as u16) << 4,
+            (Addr, _) => parse::parse_valid_addr(arg)?,
+            (Byte, _) => parse::parse_valid_byte(arg)? as u16,
+            (Nibble, _) => parse::parse_valid_nibble(arg)? as u16,
+            _ => 0,
+        })
     }
 }
 
-/// Given the tokens of a ADD instruction, return its machine code or an error
-fn assemble_add(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-
-            match (&args[0], &args[1]) {
-                // ADD Vx, byte - 7xkk
-                (AsmArgument::Register(vx), AsmArgument::Numeric(_)) => {
-                    let mut out = 0x7000;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    out += parse::parse_valid_byte(&args[1])? as u16;
-                    Ok(out)
-                }
-                // ADD Vx, Vy - 8xy4
-                (AsmArgument::Register(vx), AsmArgument::Register(vy)) => {
-                    let mut out = 0x8004;
-                    let vx = *vx as u16;
-                    let vy = *vy as u16;
-                    out += vx << 8;
-                    out += vy << 4;
-                    Ok(out)
-                }
-                // ADD I, Vx - Fx1E
-                (AsmArgument::IPointer, AsmArgument::Register(vx)) => {
-                    let mut out = 0xF01E;
-                    let vx = *vx as u16;
-                    out += vx << 8;
-                    Ok(out)
-                }
+/// One form of an operation: the operands it takes, and its opcode with each of their fields left as zero
+#[derive(Debug)]
+struct Form(&'static [Operand], u16);
+
+/// The longest mnemonic in the table, so lookups can normalize case on the stack
+const MAX_MNEMONIC_LEN: usize = 4;
+
+/// Every form of every operation by its uppercase mnemonic, hashed perfectly at compile time
+static INSTRUCTIONS: phf::Map<&'static str, &'static [Form]> = phf_map! {
+    "CLS" => &[Form(&[], 0x00E0)],
+    "RET" => &[Form(&[], 0x00EE)],
+    "SYS" => &[Form(&[Addr], 0x0000)],
+    "JP" => &[Form(&[Addr], 0x1000), Form(&[V0, Addr], 0xB000)],
+    "CALL" => &[Form(&[Addr], 0x2000)],
+    "SE" => &[Form(&[Vx, Byte], 0x3000), Form(&[Vx, Vy], 0x5000)],
+    "SNE" => &[Form(&[Vx, Byte], 0x4000), Form(&[Vx, Vy], 0x9000)],
+    "LD" => &[
+        Form(&[Vx, Byte], 0x6000),
+        Form(&[Vx, Vy], 0x8000),
+        Form(&[I, Addr], 0xA000),
+        Form(&[Vx, Dt], 0xF007),
+        Form(&[Vx, K], 0xF00A),
+        Form(&[Dt, Vx], 0xF015),
+        Form(&[St, Vx], 0xF018),
+        Form(&[F, Vx], 0xF029),
+        Form(&[B, Vx], 0xF033),
+        Form(&[IRange, Vx], 0xF055),
+        Form(&[Vx, IRange], 0xF065),
+    ],
+    "ADD" => &[
+        Form(&[Vx, Byte], 0x7000),
+        Form(&[Vx, Vy], 0x8004),
+        Form(&[I, Vx], 0xF01E),
+    ],
+    "OR" => &[Form(&[Vx, Vy], 0x8001)],
+    "AND" => &[Form(&[Vx, Vy], 0x8002)],
+    "XOR" => &[Form(&[Vx, Vy], 0x8003)],
+    "SUB" => &[Form(&[Vx, Vy], 0x8005)],
+    // the second register of a shift is optional
+    "SHR" => &[Form(&[Vx], 0x8006), Form(&[Vx, Vy], 0x8006)],
+    "SUBN" => &[Form(&[Vx, Vy], 0x8007)],
+    "SHL" => &[Form(&[Vx], 0x800E), Form(&[Vx, Vy], 0x800E)],
+    "RND" => &[Form(&[Vx, Byte], 0xC000)],
+    "DRW" => &[Form(&[Vx, Vy, Nibble], 0xD000)],
+    "SKP" => &[Form(&[Vx], 0xE09E)],
+    "SKNP" => &[Form(&[Vx], 0xE0A1)],
+};
+
+/// Whether a token is the mnemonic of an operation, in any case
+pub fn is_mnemonic(token: &str) -> bool {
+    lookup_mnemonic(token).is_some()
+}
 
-                (_, _) => Err(AssembleError::InvalidArg(tokens.join(" "))),
-            }
-        }
-    }
+/// Every mnemonic, in uppercase
+pub fn mnemonics() -> impl Iterator<Item = &'static str> {
+    INSTRUCTIONS.keys().copied()
 }
 
-/// Given the tokens of a OR instruction, return its machine code or an error
-// OR Vx, Vy - 8xy1
-fn assemble_or(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x8001 + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
-    }
+/// Find the forms of an operation by its mnemonic in any case, without allocating
+fn lookup_mnemonic(mnemonic: &str) -> Option<&'static [Form]> {
+    let mut buf = [0u8; MAX_MNEMONIC_LEN];
+    let normalized = buf.get_mut(..mnemonic.len())?;
+    normalized.copy_from_slice(mnemonic.as_bytes());
+    normalized.make_ascii_uppercase();
+    INSTRUCTIONS
+        .get(core::str::from_utf8(normalized).ok()?)
+        .copied()
 }
 
-/// Given the tokens of a AND instruction, return its machine code or an error
-// OR Vx, Vy - 8xy2
-fn assemble_and(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x8002 + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+/// Given an instruction and the forms of its operation, return the machine code of the form its arguments match, or an
+/// error
+fn encode(
+    forms: &[Form],
+    inst: &Instruction,
+    symbols: &SymbolTable,
+    args: &mut Vec<AsmArgument>,
+) -> Result<u16, AssembleError> {
+    // the number of arguments is checked before any of their symbols are resolved
+    let count = inst.args.len();
+    if forms.iter().all(|Form(operands, _)| count < operands.len()) {
+        return Err(AssembleError::MissingArgs(written(&inst.line, symbols)));
+    }
+    if forms.iter().all(|Form(operands, _)| count > operands.len()) {
+        return Err(AssembleError::ExtraArgs(written(&inst.line, symbols)));
     }
-}
 
-/// Given the tokens of a XOR instruction, return its machine code or an error
-// OR Vx, Vy - 8xy3
-fn assemble_xor(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x8003 + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+    let args = resolve_args(&inst.args, symbols, args)?;
+    let Some(Form(operands, opcode)) = forms.iter().find(|Form(operands, _)| {
+        operands.len() == args.len()
+            && operands
+                .iter()
+                .zip(args)
+                .all(|(operand, arg)| operand.accepts(arg))
+    }) else {
+        return Err(AssembleError::InvalidArg(written(&inst.line, symbols)));
+    };
+    let mut out = *opcode;
+    for (operand, arg) in operands.iter().zip(args) {
+        out += operand.encode(arg)?;
     }
+    Ok(out)
 }
 
-/// Given the tokens of a SUB instruction, return its machine code or an error
-// SUB Vx, Vy - 8xy5
-fn assemble_sub(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x8005 + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
-    }
+/// An instruction as it reads once aliases are substituted, for errors about it as a whole
+fn written(line: &Line, symbols: &SymbolTable) -> String {
+    let tokens: Vec<&str> = line
+        .tokens
+        .iter()
+        .map(|t| symbols.substitute(t.text))
+        .collect();
+    tokens.join(" ")
 }
 
-/// Given the tokens of a XOR instruction, return its machine code or an error
-// SUBN Vx, Vy - 8xy7
-fn assemble_subn(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x8007 + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
+/// Resolve the typed arguments of an operation, looking up the labels, memory offsets, and constants the names among
+/// them stand for
+fn resolve_args<'b>(
+    typed: &[Arg],
+    symbols: &SymbolTable,
+    args: &'b mut Vec<AsmArgument>,
+) -> Result<&'b [AsmArgument], AsmArgParseError> {
+    args.clear();
+    for arg in typed {
+        args.push(match *arg {
+            Arg::Fixed(arg) => arg,
+            Arg::Expression(expression) => evaluate_number(expression, symbols)?,
+            Arg::Name(name) => {
+                // an alias can stand for anything an argument can be
+                let name = symbols.substitute(name);
+                match symbols.value_of(name) {
+                    Some(value) => AsmArgument::Numeric(
+                        u16::try_from(value).map_err(|_| invalid_word(&value))?,
+                    ),
+                    None if expr::is_expression(name) => evaluate_number(name, symbols)?,
+                    None => parse::parse_asm_arg(name)?,
+                }
             }
-        }
+        });
     }
+    Ok(args)
 }
 
-/// Given the tokens of a SHR instruction, return its machine code or an error
-// SHR Vx {, Vy} - 8xy6
-fn assemble_shr(tokens: &[&str]) -> Result<u16, AssembleError> {
-    let args = parse::parse_asm_args(&tokens[1..])?;
-
-    match args.len() {
-        // the second arg is optional
-        1 => {
-            if let AsmArgument::Register(vx) = &args[0] {
-                let vx = *vx as u16;
-                Ok(0x8006 + (vx << 8))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+/// Nothing takes more than 16 bits, so a value that doesn't fit is invalid whatever it's for
+fn invalid_word(value: &dyn ToString) -> AsmArgParseError {
+    AsmArgParseError::InvalidWord(value.to_string())
+}
 
-        2 => {
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x8006 + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
+/// Evaluate an expression argument. A negative number is only valid for some operands, which are left to decide
+fn evaluate_number(
+    expression: &str,
+    symbols: &SymbolTable,
+) -> Result<AsmArgument, AsmArgParseError> {
+    Ok(match evaluate_signed(expression, symbols)? {
+        value @ 0.. => {
+            AsmArgument::Numeric(u16::try_from(value).map_err(|_| invalid_word(&value))?)
         }
-
-        0 => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        _ => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-    }
+        value => AsmArgument::Negative(i16::try_from(value).map_err(|_| invalid_word(&value))?),
+    })
 }
 
-/// Given the tokens of a SHL instruction, return its machine code or an error
-// SHL Vx {, Vy} - 8xyE
-fn assemble_shl(tokens: &[&str]) -> Result<u16, AssembleError> {
-    let args = parse::parse_asm_args(&tokens[1..])?;
-
-    match args.len() {
-        // the second arg is optional
-        1 => {
-            if let AsmArgument::Register(vx) = &args[0] {
-                let vx = *vx as u16;
-                Ok(0x800E + (vx << 8))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+/// Evaluate an expression, looking up the names in it as labels, constants, memory offsets, or aliases of any of them
+pub fn evaluate(expression: &str, symbols: &SymbolTable) -> Result<u16, AsmArgParseError> {
+    expr::evaluate(expression, |name| resolve(name, symbols))
+}
 
-        2 => {
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                Ok(0x800E + (vx << 8) + (vy << 4))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+/// Evaluate an expression the same way, but let it come to a negative number
+pub fn evaluate_signed(expression: &str, symbols: &SymbolTable) -> Result<i32, AsmArgParseError> {
+    expr::evaluate_signed(expression, |name| resolve(name, symbols))
+}
 
-        0 => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        _ => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-    }
+/// The value of a name in an expression
+fn resolve(name: &str, symbols: &SymbolTable) -> Option<usize> {
+    let name = symbols.substitute(name);
+    symbols
+        .value_of(name)
+        .or_else(|| match parse::parse_asm_arg(name) {
+            Ok(AsmArgument::Numeric(value)) => Some(value as usize),
+            _ => None,
+        })
 }
 
-/// Given the tokens of a RND instruction, return its machine code or an error
-// RND Vx, byte - Cxkk
-fn assemble_rnd(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&3) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Numeric(_)) = (&args[0], &args[1]) {
-                let vx = *vx as u16;
-                let byte = parse::parse_valid_byte(&args[1])? as u16;
-                Ok(0xC000 + (vx << 8) + byte)
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
+#[cfg(all(test, feature = "invariants"))]
+mod tests {
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::super::invariants;
+    use super::super::tokenize;
+    use super::*;
+
+    /// Every way an operand can be written, along with the bits it adds to the opcode
+    fn operand_space(operand: Operand) -> Vec<(String, u16)> {
+        let fixed = |text: &str| vec![(String::from(text), 0)];
+        match operand {
+            Vx => (0..=0xF).map(|x| (format!("V{x:X}"), x << 8)).collect(),
+            Vy => (0..=0xF).map(|y| (format!("V{y:X}"), y << 4)).collect(),
+            V0 => fixed("V0"),
+            Addr => (0..=0xFFF)
+                .map(|addr| (format!("{addr:#X}"), addr))
+                .collect(),
+            Byte => (0..=0xFF)
+                .map(|byte| (format!("{byte:#X}"), byte))
+                .collect(),
+            Nibble => (0..=0xF)
+                .map(|nibble| (format!("{nibble:#X}"), nibble))
+                .collect(),
+            I => fixed("I"),
+            IRange => fixed("[I]"),
+            K => fixed("K"),
+            Dt => fixed("DT"),
+            St => fixed("ST"),
+            F => fixed("F"),
+            B => fixed("B"),
         }
     }
-}
 
-/// Given the tokens of a DRW instruction, return its machine code or an error
-// DRW Vx, Vy, nibble - Dxyn
-fn assemble_drw(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&4) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let (AsmArgument::Register(vx), AsmArgument::Register(vy), AsmArgument::Numeric(_)) =
-                (&args[0], &args[1], &args[2])
-            {
-                let vx = *vx as u16;
-                let vy = *vy as u16;
-                let nibble = parse::parse_valid_nibble(&args[2])? as u16;
-                Ok(0xD000 + (vx << 8) + (vy << 4) + nibble)
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+    #[test]
+    fn decode_encode() {
+        invariants::check_decode_encode().unwrap();
     }
-}
 
-/// Given the tokens of a SKP instruction, return its machine code or an error
-// SKP Vx - Ex9E
-fn assemble_skp(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&2) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let AsmArgument::Register(vx) = &args[0] {
-                let vx = *vx as u16;
-                Ok(0xE09E + (vx << 8))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
-            }
-        }
+    #[test]
+    fn encode_decode() {
+        invariants::check_encode_decode().unwrap();
     }
-}
 
-/// Given the tokens of a SKNP instruction, return its machine code or an error
-// SKNP Vx - ExA1
-fn assemble_sknp(tokens: &[&str]) -> Result<u16, AssembleError> {
-    match tokens.len().cmp(&2) {
-        Ordering::Less => Err(AssembleError::MissingArgs(tokens.join(" "))),
-        Ordering::Greater => Err(AssembleError::ExtraArgs(tokens.join(" "))),
-
-        Ordering::Equal => {
-            let args = parse::parse_asm_args(&tokens[1..])?;
-            if let AsmArgument::Register(vx) = &args[0] {
-                let vx = *vx as u16;
-                Ok(0xE0A1 + (vx << 8))
-            } else {
-                Err(AssembleError::InvalidArg(tokens.join(" ")))
+    #[test]
+    fn every_form_round_trips() {
+        for (mnemonic, forms) in INSTRUCTIONS.entries() {
+            for Form(operands, opcode) in forms.iter() {
+                // every combination of every operand, starting from just the mnemonic
+                let mut instances = vec![(String::from(*mnemonic), *opcode)];
+                for (i, &operand) in operands.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    instances = instances
+                        .iter()
+                        .flat_map(|(text, op)| {
+                            operand_space(operand).into_iter().map(move |(arg, bits)| {
+                                (format!("{text}{separator}{arg}"), op | bits)
+                            })
+                        })
+                        .collect();
+                }
+                for (text, expected) in instances {
+                    let line = Instruction::new(tokenize::tokenize_line(&text));
+                    let op = assemble_instruction(
+                        &line,
+                        &SymbolTable::default(),
+                        &mut Buffers::default(),
+                    )
+                    .unwrap_or_else(|error| panic!("`{text}` failed to assemble: {error}"));
+                    assert_eq!(op, expected, "`{text}`");
+                    invariants::check_opcode(op).unwrap();
+                }
             }
         }
     }
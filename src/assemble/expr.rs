@@ -0,0 +1,291 @@
+use super::parse::{self, AsmArgParseError, AsmArgument};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Characters that only show up in an argument when it's an arithmetic expression
+const OPERATOR_CHARS: &str = "+-*/%&|^~()<>=!";
+
+/// Operators written with two characters, which are split off before any single character one
+const TWO_CHAR_OPERATORS: [&str; 8] = ["<<", ">>", "<=", ">=", "==", "!=", "&&", "||"];
+
+/// A piece of an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Piece<'a> {
+    /// A number or the name of a symbol
+    Value(&'a str),
+    Operator(&'a str),
+}
+
+/// Whether an argument is an arithmetic expression like `table + 5` rather than a single number or name
+pub fn is_expression(arg: &str) -> bool {
+    arg.contains(|c| OPERATOR_CHARS.contains(c))
+}
+
+/// Every name an expression refers to
+pub fn names(expression: &str) -> impl Iterator<Item = &str> {
+    split(expression)
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Value(value)
+                if !value.starts_with(|c: char| c.is_ascii_digit())
+                    && !matches!(parse::parse_asm_arg(value), Ok(AsmArgument::Numeric(_))) =>
+            {
+                Some(value)
+            }
+            _ => None,
+        })
+}
+
+/// Evaluate an expression of numbers and names, with the value of each name looked up as it's needed. Operators are
+/// C's, with the same precedence: unary `-`, `~`, and `!`, then `* / %`, `+ -`, `<< >>`, `< <= > >=`, `== !=`, `&`,
+/// `^`, `|`, `&&`, and `||`. Comparisons and logical operators come to 1 or 0. The result has to fit in 16 bits, but anything in between can be
+/// negative or bigger
+pub fn evaluate(
+    expression: &str,
+    resolve: impl Fn(&str) -> Option<usize>,
+) -> Result<u16, AsmArgParseError> {
+    let value = evaluate_signed(expression, resolve)?;
+    u16::try_from(value).map_err(|_| invalid(expression, doesnt_fit(value.into())))
+}
+
+/// Evaluate an expression the same way, but let it come to a negative number down to -32768, for operands that take
+/// one as two's complement
+pub fn evaluate_signed(
+    expression: &str,
+    resolve: impl Fn(&str) -> Option<usize>,
+) -> Result<i32, AsmArgParseError> {
+    let mut evaluator = Evaluator {
+        pieces: split(expression),
+        next: 0,
+        resolve: &resolve,
+    };
+    let value = evaluator
+        .binary(0)
+        .map_err(|reason| invalid(expression, reason))?;
+    if let Some(piece) = evaluator.pieces.get(evaluator.next) {
+        return Err(invalid(
+            expression,
+            format!("unexpected {}", describe(*piece)),
+        ));
+    }
+    match value {
+        -0x8000..=0xFFFF => Ok(value as i32),
+        _ => Err(invalid(expression, doesnt_fit(value))),
+    }
+}
+
+/// The error for an expression that can't be evaluated
+fn invalid(expression: &str, reason: String) -> AsmArgParseError {
+    AsmArgParseError::InvalidExpression {
+        expression: expression.to_string(),
+        reason,
+    }
+}
+
+/// Why an expression that comes to a number too big or small to use is invalid
+fn doesnt_fit(value: i64) -> String {
+    format!("it comes to {value}, which doesn't fit in 16 bits")
+}
+
+/// Split an expression into numbers, names, and operators
+fn split(expression: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = expression.trim_start();
+    let value_len = |rest: &str| {
+        rest.find(|c: char| c.is_whitespace() || OPERATOR_CHARS.contains(c))
+            .unwrap_or(rest.len())
+    };
+    while let Some(c) = rest.chars().next() {
+        // a `%` where a value is expected starts a binary number, like `%1010`, rather than taking a remainder
+        let binary =
+            c == '%' && !matches!(pieces.last(), Some(Piece::Value(_) | Piece::Operator(")")));
+        let len = if binary {
+            1 + value_len(&rest[1..])
+        } else if TWO_CHAR_OPERATORS.iter().any(|op| rest.starts_with(op)) {
+            2
+        } else if OPERATOR_CHARS.contains(c) {
+            1
+        } else {
+            value_len(rest)
+        };
+        let (piece, after) = rest.split_at(len);
+        pieces.push(if OPERATOR_CHARS.contains(c) && !binary {
+            Piece::Operator(piece)
+        } else {
+            Piece::Value(piece)
+        });
+        rest = after.trim_start();
+    }
+    pieces
+}
+
+/// How tightly a binary operator binds, or None if it isn't one
+fn precedence(operator: &str) -> Option<u8> {
+    match operator {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        _ => None,
+    }
+}
+
+/// A piece of an expression as it's described in an error
+fn describe(piece: Piece) -> String {
+    match piece {
+        Piece::Value(value) | Piece::Operator(value) => format!("`{value}`"),
+    }
+}
+
+/// Works through the pieces of an expression by precedence climbing
+struct Evaluator<'a, 'r> {
+    pieces: Vec<Piece<'a>>,
+    next: usize,
+    resolve: &'r dyn Fn(&str) -> Option<usize>,
+}
+
+impl Evaluator<'_, '_> {
+    /// Evaluate binary operators binding more tightly than a precedence, from left to right
+    fn binary(&mut self, min_precedence: u8) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        while let Some(&Piece::Operator(operator)) = self.pieces.get(self.next) {
+            let Some(precedence) = precedence(operator).filter(|&p| p > min_precedence) else {
+                break;
+            };
+            self.next += 1;
+            let rhs = self.binary(precedence)?;
+            value = match operator {
+                "+" => value.checked_add(rhs),
+                "-" => value.checked_sub(rhs),
+                "*" => value.checked_mul(rhs),
+                "/" => value.checked_div(rhs),
+                "%" => value.checked_rem(rhs),
+                "<<" => u32::try_from(rhs)
+                    .ok()
+                    .and_then(|rhs| value.checked_shl(rhs)),
+                ">>" => u32::try_from(rhs)
+                    .ok()
+                    .and_then(|rhs| value.checked_shr(rhs)),
+                "&" => Some(value & rhs),
+                "^" => Some(value ^ rhs),
+                "==" => Some((value == rhs) as i64),
+                "!=" => Some((value != rhs) as i64),
+                "<" => Some((value < rhs) as i64),
+                "<=" => Some((value <= rhs) as i64),
+                ">" => Some((value > rhs) as i64),
+                ">=" => Some((value >= rhs) as i64),
+                "&&" => Some((value != 0 && rhs != 0) as i64),
+                "||" => Some((value != 0 || rhs != 0) as i64),
+                _ => Some(value | rhs),
+            }
+            .ok_or_else(|| format!("`{operator}` overflows or divides by zero"))?;
+        }
+        Ok(value)
+    }
+
+    /// Evaluate a number, name, parenthesized expression, or unary operator and what it applies to
+    fn unary(&mut self) -> Result<i64, String> {
+        let piece = self
+            .pieces
+            .get(self.next)
+            .copied()
+            .ok_or("it ends where a value was expected")?;
+        self.next += 1;
+        match piece {
+            Piece::Operator("-") => Ok(-self.unary()?),
+            Piece::Operator("~") => Ok(!self.unary()?),
+            Piece::Operator("!") => Ok((self.unary()? == 0) as i64),
+            Piece::Operator("+") => self.unary(),
+            Piece::Operator("(") => {
+                let value = self.binary(0)?;
+                match self.pieces.get(self.next) {
+                    Some(Piece::Operator(")")) => {
+                        self.next += 1;
+                        Ok(value)
+                    }
+                    _ => Err("a `(` is never closed".to_string()),
+                }
+            }
+            Piece::Operator(_) => Err(format!("unexpected {}", describe(piece))),
+            Piece::Value(value) => match parse::parse_asm_arg(value) {
+                Ok(AsmArgument::Numeric(number)) => Ok(number as i64),
+                _ => (self.resolve)(value)
+                    .map(|value| value as i64)
+                    .ok_or_else(|| format!("`{value}` isn't a number or defined symbol")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Looks up `table` at 0x300 and `WIDTH` as 64, and nothing else
+    fn resolve(name: &str) -> Option<usize> {
+        match name {
+            "table" => Some(0x300),
+            "WIDTH" => Some(64),
+            _ => None,
+        }
+    }
+
+    /// The value of an expression, or the reason it's invalid
+    fn value(expression: &str) -> Result<i32, String> {
+        evaluate_signed(expression, resolve).map_err(|error| match error {
+            AsmArgParseError::InvalidExpression { reason, .. } => reason,
+            other => other.to_string(),
+        })
+    }
+
+    #[test]
+    fn precedence_is_cs() {
+        assert_eq!(value("1 + 2 * 3"), Ok(7));
+        assert_eq!(value("(1 + 2) * 3"), Ok(9));
+        assert_eq!(value("10 - 4 - 3"), Ok(3));
+        assert_eq!(value("1 << 2 + 1"), Ok(8));
+        assert_eq!(value("6 & 3 | 8"), Ok(10));
+        assert_eq!(value("1 + 1 == 2 && 3 > 2"), Ok(1));
+        assert_eq!(value("-2 * -3"), Ok(6));
+        assert_eq!(value("~0 & 0xFF"), Ok(0xFF));
+        assert_eq!(value("!0 + !5"), Ok(1));
+    }
+
+    #[test]
+    fn names_and_literals() {
+        assert_eq!(value("table + 5"), Ok(0x305));
+        assert_eq!(value("(WIDTH / 2) - 1"), Ok(31));
+        assert_eq!(value("$10 + %11 + 0o7 % 4"), Ok(22));
+        assert_eq!(
+            names("table + WIDTH * 0x2 - %10").collect::<Vec<_>>(),
+            ["table", "WIDTH"]
+        );
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(value("missing + 1").unwrap_err().contains("`missing`"));
+        assert!(value("(1 + 2").unwrap_err().contains("never closed"));
+        assert!(value("1 +").unwrap_err().contains("ends where a value"));
+        assert!(value("1 2").unwrap_err().contains("unexpected `2`"));
+        assert!(value("1 / 0").unwrap_err().contains("divides by zero"));
+    }
+
+    #[test]
+    fn results_fit_in_16_bits() {
+        assert_eq!(value("0xFFFF"), Ok(0xFFFF));
+        assert_eq!(value("-0x8000"), Ok(-0x8000));
+        assert!(value("0xFFFF + 1").unwrap_err().contains("65536"));
+        assert!(value("-0x8000 - 1").is_err());
+        // only operands that take negatives get them
+        assert!(evaluate("1 - 2", resolve).is_err());
+        assert_eq!(evaluate("WIDTH * 4 - 0x100", resolve).ok(), Some(0));
+    }
+}
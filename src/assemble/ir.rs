@@ -0,0 +1,134 @@
+use alloc::vec::Vec;
+
+use super::expr;
+use super::parse::{self, AsmArgument};
+use super::{lookup_mnemonic, Form};
+use crate::tokenize::Line;
+
+/// An argument as the first pass types it. Registers, keywords, and numbers mean the same thing wherever they appear,
+/// so they're parsed once; names stay symbolic until the second pass knows what they stand for
+#[derive(Debug, Clone, Copy)]
+pub enum Arg<'a> {
+    /// A register, keyword, or number
+    Fixed(AsmArgument),
+    /// A label, memory offset, constant, or alias of any of them, or a token that doesn't parse, which is reported
+    /// once it's known not to be a name
+    Name(&'a str),
+    /// An arithmetic expression over numbers and names
+    Expression(&'a str),
+}
+
+impl<'a> Arg<'a> {
+    /// Type a token
+    pub fn new(token: &'a str) -> Arg<'a> {
+        if expr::is_expression(token) {
+            return Arg::Expression(token);
+        }
+        match parse::parse_asm_arg(token) {
+            Ok(arg) => Arg::Fixed(arg),
+            Err(_) => Arg::Name(token),
+        }
+    }
+}
+
+/// The operation of an instruction
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Op<'a> {
+    /// An operation from the table, with every form it takes
+    Forms(&'static [Form]),
+    /// A raw opcode written as a single hex number
+    Raw(u16),
+    /// Anything else, which may be an alias of an operation
+    Name(&'a str),
+}
+
+/// A line of source to be assembled as an instruction, with its operation looked up and its arguments typed
+#[derive(Debug, Clone)]
+pub struct Instruction<'a> {
+    /// The tokens it was typed from, for anything that shows it as it's written
+    pub line: Line<'a>,
+    pub(super) op: Op<'a>,
+    pub(super) args: Vec<Arg<'a>>,
+}
+
+impl<'a> Instruction<'a> {
+    /// Type a line of source. Nothing is checked yet, since what a name stands for decides whether it's valid
+    pub fn new(line: Line<'a>) -> Instruction<'a> {
+        let head = line
+            .head()
+            .expect("Attempt to parse empty string as instruction");
+        let op = match lookup_mnemonic(head) {
+            Some(forms) => Op::Forms(forms),
+            None => match parse::parse_raw(&[head]) {
+                Ok(raw) if line.tokens.len() == 1 => Op::Raw(raw),
+                _ => Op::Name(head),
+            },
+        };
+        let args = line.tokens[1..].iter().map(|t| Arg::new(t.text)).collect();
+        Instruction { line, op, args }
+    }
+}
+
+/// A line of `db` or `dw` data, with its values typed
+#[derive(Debug, Clone)]
+pub struct Data<'a> {
+    /// The tokens it was typed from, directive first
+    pub line: Line<'a>,
+    pub(super) args: Vec<Arg<'a>>,
+}
+
+impl<'a> Data<'a> {
+    /// Type a line of data
+    pub fn new(line: Line<'a>) -> Data<'a> {
+        let args = line.tokens[1..].iter().map(|t| Arg::new(t.text)).collect();
+        Data { line, args }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{assemble_instruction, Buffers};
+    use super::*;
+    use crate::symbols::SymbolTable;
+    use crate::tokenize;
+
+    #[test]
+    fn arguments_are_typed_before_symbols_are_known() {
+        let inst = Instruction::new(tokenize::tokenize_line("LD V1, table + 2"));
+        assert!(matches!(inst.op, Op::Forms(_)));
+        assert!(matches!(
+            inst.args[..],
+            [
+                Arg::Fixed(AsmArgument::Register(1)),
+                Arg::Expression("table + 2")
+            ]
+        ));
+
+        let inst = Instruction::new(tokenize::tokenize_line("draw x, 0x10"));
+        assert!(matches!(inst.op, Op::Name("draw")));
+        assert!(matches!(
+            inst.args[..],
+            [Arg::Name("x"), Arg::Fixed(AsmArgument::Numeric(0x10))]
+        ));
+        assert!(matches!(
+            Instruction::new(tokenize::tokenize_line("0x00E0")).op,
+            Op::Raw(0x00E0)
+        ));
+    }
+
+    #[test]
+    fn names_are_resolved_when_encoded() {
+        let mut symbols = SymbolTable::default();
+        symbols.define_alias("draw", "DRW");
+        symbols.define_alias("x", "V3");
+        symbols.define_label("target", 0x204);
+        let encoded = |text: &str| {
+            let inst = Instruction::new(tokenize::tokenize_line(text));
+            assemble_instruction(&inst, &symbols, &mut Buffers::default()).ok()
+        };
+        assert_eq!(encoded("draw x, V1, 5"), Some(0xD315));
+        assert_eq!(encoded("JP target"), Some(0x1204));
+        assert_eq!(encoded("LD I, target - 4"), Some(0xA200));
+        assert_eq!(encoded("LD x, nothing"), None);
+    }
+}
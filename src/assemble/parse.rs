@@ -1,11 +1,17 @@
-use std::num::ParseIntError;
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+use core::num::ParseIntError;
 use thiserror::Error;
 
 /// An enum representing a possible argument passed to an operation in the assembly code
 /// It's up to assemble.rs to make sure that the arguments make sense for any given operation
 /// It's also up to assemble.rs to figure out a numeric arg represents and if it's valid
+#[derive(Debug, Clone, Copy)]
 pub enum AsmArgument {
     Numeric(u16), //the largest possible numeric arg is a 12 bit address and all numeric args are unsigned
+    /// An expression that came to a negative number, which only byte and word operands take, as two's complement
+    Negative(i16),
     Register(u8),
     AnyKey,
     IPointer,
@@ -28,8 +34,12 @@ pub enum AsmArgParseError {
     InvalidByte(String),
     #[error("attempted use of invalid nibble: {0}")]
     InvalidNibble(String),
+    #[error("attempted use of invalid word: {0}")]
+    InvalidWord(String),
     #[error("attempted use of invalid raw: {0}")]
     InvalidRaw(String),
+    #[error("invalid expression `{expression}`: {reason}")]
+    InvalidExpression { expression: String, reason: String },
     #[error("{0}")]
     NotANumber(
         #[source]
@@ -38,6 +48,41 @@ pub enum AsmArgParseError {
     ),
 }
 
+impl AsmArgParseError {
+    #[cfg(feature = "std")]
+    /// A short name for the kind of error, see [`AssembleError::code`](super::AssembleError::code)
+    pub fn code(&self) -> &'static str {
+        match self {
+            AsmArgParseError::InvalidRegister(_) => "invalid-register",
+            AsmArgParseError::InvalidAddress(_) => "invalid-address",
+            AsmArgParseError::InvalidByte(_) => "invalid-byte",
+            AsmArgParseError::InvalidNibble(_) => "invalid-nibble",
+            AsmArgParseError::InvalidWord(_) => "invalid-word",
+            AsmArgParseError::InvalidRaw(_) => "invalid-raw",
+            AsmArgParseError::InvalidExpression { .. } => "invalid-expression",
+            AsmArgParseError::NotANumber(_) => "not-a-number",
+        }
+    }
+
+    /// Whether this is about an argument, given as it reads after aliases are substituted along with the value it
+    /// stands for, if it's a number or symbol
+    pub fn is_about(&self, arg: &str, value: Option<i32>) -> bool {
+        match self {
+            AsmArgParseError::InvalidRegister(invalid) => invalid == arg,
+            AsmArgParseError::NotANumber(error) => error.arg == arg,
+            // these are only given the value that was out of range, written in decimal
+            AsmArgParseError::InvalidAddress(invalid)
+            | AsmArgParseError::InvalidByte(invalid)
+            | AsmArgParseError::InvalidNibble(invalid)
+            | AsmArgParseError::InvalidWord(invalid) => {
+                value.is_some_and(|value| value.to_string() == *invalid)
+            }
+            AsmArgParseError::InvalidExpression { expression, .. } => expression == arg,
+            AsmArgParseError::InvalidRaw(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("encountered ParseIntError {source} while parsing `{arg}`")]
 pub struct NumberParsingError {
@@ -46,6 +91,7 @@ pub struct NumberParsingError {
     arg: String,
 }
 
+#[cfg(feature = "std")]
 /// Given a collection of string slices, return parsed AsmArgument enums or error if one or more is invalid
 pub fn parse_asm_args(args: &[&str]) -> Result<Vec<AsmArgument>, AsmArgParseError> {
     let mut out = Vec::with_capacity(args.len());
@@ -59,7 +105,7 @@ pub fn parse_asm_args(args: &[&str]) -> Result<Vec<AsmArgument>, AsmArgParseErro
 }
 
 /// Given a string slice, parse it into an AsmArgument if possible, otherwise error
-fn parse_asm_arg(arg: &str) -> Result<AsmArgument, AsmArgParseError> {
+pub fn parse_asm_arg(arg: &str) -> Result<AsmArgument, AsmArgParseError> {
     match arg {
         "K" | "k" => Ok(AsmArgument::AnyKey),
         "I" | "i" => Ok(AsmArgument::IPointer),
@@ -88,29 +134,33 @@ fn parse_numeric_asm_arg(arg: &str) -> Result<AsmArgument, AsmArgParseError> {
             }
         }
 
-    // other numeric arg in hex
-    } else if let Some(hex_num) = arg.strip_prefix("0x") {
-        match u16::from_str_radix(hex_num, 16) {
-            Ok(hex) => Ok(AsmArgument::Numeric(hex)),
-            Err(e) => Err(AsmArgParseError::from(NumberParsingError {
-                source: e,
-                arg: arg.to_string(),
-            })),
-        }
-
-    // other numeric arg in binary
-    } else if let Some(bin_num) = arg.strip_prefix("0b") {
-        match u16::from_str_radix(bin_num, 2) {
-            Ok(bin) => Ok(AsmArgument::Numeric(bin)),
-            Err(e) => Err(AsmArgParseError::from(NumberParsingError {
-                source: e,
-                arg: arg.to_string(),
-            })),
-        }
-
-    // other numeric arg in decimal
+    // other numeric arg, in the base its prefix gives
     } else {
-        match arg.parse::<u16>() {
+        let (digits, radix) = if let Some(hex_num) = ["0x", "#$", "$"]
+            .iter()
+            .find_map(|prefix| arg.strip_prefix(prefix))
+        {
+            (hex_num, 16)
+        } else if let Some(bin_num) = ["0b", "%"]
+            .iter()
+            .find_map(|prefix| arg.strip_prefix(prefix))
+        {
+            (bin_num, 2)
+        } else if let Some(oct_num) = arg.strip_prefix("0o") {
+            (oct_num, 8)
+        } else {
+            (arg, 10)
+        };
+
+        // underscores can separate digits, like `0b1010_1010`, but can't come first
+        let without_underscores: String;
+        let digits = if digits.contains('_') && !digits.starts_with('_') {
+            without_underscores = digits.replace('_', "");
+            &without_underscores
+        } else {
+            digits
+        };
+        match u16::from_str_radix(digits, radix) {
             Ok(num) => Ok(AsmArgument::Numeric(num)),
             Err(e) => Err(AsmArgParseError::from(NumberParsingError {
                 source: e,
@@ -128,12 +178,15 @@ pub fn parse_valid_addr(arg: &AsmArgument) -> Result<u16, AsmArgParseError> {
         } else {
             Err(AsmArgParseError::InvalidAddress(addr.to_string()))
         }
+    } else if let AsmArgument::Negative(addr) = *arg {
+        Err(AsmArgParseError::InvalidAddress(addr.to_string()))
     } else {
         panic!("parse_valid_addr called with invalid AsmArgument variant. If this happens a lot, consider using the type state pattern.");
     }
 }
 
-/// Given an AsmArgument numeric variant, ensure that it represents a valid byte and pass back the value
+/// Given an AsmArgument numeric variant, ensure that it represents a valid byte and pass back the value. Negative
+/// bytes down to -128 are passed back as two's complement
 pub fn parse_valid_byte(arg: &AsmArgument) -> Result<u8, AsmArgParseError> {
     if let AsmArgument::Numeric(byte) = *arg {
         if byte <= 0xFF {
@@ -141,6 +194,11 @@ pub fn parse_valid_byte(arg: &AsmArgument) -> Result<u8, AsmArgParseError> {
         } else {
             Err(AsmArgParseError::InvalidByte(byte.to_string()))
         }
+    } else if let AsmArgument::Negative(byte) = *arg {
+        match i8::try_from(byte) {
+            Ok(byte) => Ok(byte as u8),
+            Err(_) => Err(AsmArgParseError::InvalidByte(byte.to_string())),
+        }
     } else {
         panic!("parse_valid_byte called with invalid AsmArgument variant. If this happens a lot, consider using the type state pattern.");
     }
@@ -154,11 +212,23 @@ pub fn parse_valid_nibble(arg: &AsmArgument) -> Result<u8, AsmArgParseError> {
         } else {
             Err(AsmArgParseError::InvalidNibble(nibble.to_string()))
         }
+    } else if let AsmArgument::Negative(nibble) = *arg {
+        Err(AsmArgParseError::InvalidNibble(nibble.to_string()))
     } else {
         panic!("parse_valid_nibble called with invalid AsmArgument variant. If this happens a lot, consider using the type state pattern.");
     }
 }
 
+/// Given an AsmArgument numeric variant, pass back the value as a 16 bit word. Negative words are passed back as two's
+/// complement, and anything else is an error
+pub fn parse_valid_word(arg: &AsmArgument) -> Result<u16, AsmArgParseError> {
+    match *arg {
+        AsmArgument::Numeric(word) => Ok(word),
+        AsmArgument::Negative(word) => Ok(word as u16),
+        _ => panic!("parse_valid_word called with invalid AsmArgument variant. If this happens a lot, consider using the type state pattern."),
+    }
+}
+
 /// Given a slice of string tokens, either convert from hex u16 or error
 pub fn parse_raw(tokens: &[&str]) -> Result<u16, AsmArgParseError> {
     if tokens.len() != 1 || !tokens[0].starts_with("0x") {
@@ -174,3 +244,104 @@ pub fn parse_raw(tokens: &[&str]) -> Result<u16, AsmArgParseError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of a numeric argument, or None if it isn't one
+    fn number(arg: &str) -> Option<u16> {
+        match parse_asm_arg(arg) {
+            Ok(AsmArgument::Numeric(num)) => Some(num),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn numbers_in_every_base() {
+        assert_eq!(number("42"), Some(42));
+        assert_eq!(number("0x2A"), Some(42));
+        assert_eq!(number("$2a"), Some(42));
+        assert_eq!(number("#$2A"), Some(42));
+        assert_eq!(number("0b101010"), Some(42));
+        assert_eq!(number("%101010"), Some(42));
+        assert_eq!(number("0o52"), Some(42));
+    }
+
+    #[test]
+    fn underscores_separate_digits() {
+        assert_eq!(number("0b0010_1010"), Some(42));
+        assert_eq!(number("1_000"), Some(1000));
+        assert_eq!(number("0x_2A"), None);
+        assert_eq!(number("_42"), None);
+    }
+
+    #[test]
+    fn not_numbers() {
+        assert_eq!(number("0x"), None);
+        assert_eq!(number("0b102"), None);
+        assert_eq!(number("0o8"), None);
+        assert_eq!(number("0x10000"), None);
+        assert!(matches!(
+            parse_asm_arg("label"),
+            Err(AsmArgParseError::NotANumber(_))
+        ));
+    }
+
+    #[test]
+    fn registers_and_keywords() {
+        assert!(matches!(parse_asm_arg("VA"), Ok(AsmArgument::Register(10))));
+        assert!(matches!(parse_asm_arg("v0"), Ok(AsmArgument::Register(0))));
+        assert!(matches!(
+            parse_asm_arg("V10"),
+            Err(AsmArgParseError::InvalidRegister(_))
+        ));
+        assert!(matches!(parse_asm_arg("[i]"), Ok(AsmArgument::IRange)));
+        assert!(matches!(parse_asm_arg("dT"), Ok(AsmArgument::DelayTimer)));
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            parse_valid_addr(&AsmArgument::Numeric(0xFFF)).ok(),
+            Some(0xFFF)
+        );
+        assert!(parse_valid_addr(&AsmArgument::Numeric(0x1000)).is_err());
+        assert!(parse_valid_addr(&AsmArgument::Negative(-1)).is_err());
+        assert_eq!(
+            parse_valid_byte(&AsmArgument::Numeric(0xFF)).ok(),
+            Some(0xFF)
+        );
+        assert!(parse_valid_byte(&AsmArgument::Numeric(0x100)).is_err());
+        assert_eq!(
+            parse_valid_nibble(&AsmArgument::Numeric(0xF)).ok(),
+            Some(0xF)
+        );
+        assert!(parse_valid_nibble(&AsmArgument::Numeric(0x10)).is_err());
+    }
+
+    #[test]
+    fn negatives_are_twos_complement() {
+        assert_eq!(
+            parse_valid_byte(&AsmArgument::Negative(-1)).ok(),
+            Some(0xFF)
+        );
+        assert_eq!(
+            parse_valid_byte(&AsmArgument::Negative(-128)).ok(),
+            Some(0x80)
+        );
+        assert!(parse_valid_byte(&AsmArgument::Negative(-129)).is_err());
+        assert_eq!(
+            parse_valid_word(&AsmArgument::Negative(-2)).ok(),
+            Some(0xFFFE)
+        );
+        assert!(parse_valid_nibble(&AsmArgument::Negative(-1)).is_err());
+    }
+
+    #[test]
+    fn raw_words() {
+        assert_eq!(parse_raw(&["0x00E0"]).ok(), Some(0x00E0));
+        assert!(parse_raw(&["00E0"]).is_err());
+        assert!(parse_raw(&["0x00", "E0"]).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+/// An error encountered while loading an image to turn into a sprite
+#[derive(Debug, Error)]
+pub enum BitmapError {
+    #[error("failed to read image")]
+    Io(
+        #[from]
+        #[source]
+        io::Error,
+    ),
+    #[error("unsupported image format `{0}`; use .png, .bmp, or .pbm")]
+    UnsupportedFormat(String),
+    #[error("malformed image: {0}")]
+    Malformed(&'static str),
+    #[cfg(not(feature = "images"))]
+    #[error("png sprites require ch8asm to be built with the `images` feature")]
+    PngUnavailable,
+    #[cfg(feature = "images")]
+    #[error("failed to decode png")]
+    Png(
+        #[from]
+        #[source]
+        png::DecodingError,
+    ),
+}
+
+/// A monochrome image, where lit pixels are the ones that would be drawn
+#[derive(Debug)]
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    /// Row by row, left to right
+    pixels: Vec<bool>,
+}
+
+impl Bitmap {
+    /// Whether the pixel at the given position is lit
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Cut out a rectangle of the image, or None if it doesn't fit inside the image
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Option<Bitmap> {
+        if x.checked_add(width)? > self.width || y.checked_add(height)? > self.height {
+            return None;
+        }
+        let pixels = (y..y + height)
+            .flat_map(|row| (x..x + width).map(move |col| self.get(col, row)))
+            .collect();
+        Some(Bitmap {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+/// Load an image, picking the format from the file extension
+pub fn load(path: &Path) -> Result<Bitmap, BitmapError> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => read_png(path),
+        "bmp" => read_bmp(&fs::read(path)?),
+        "pbm" => read_pbm(&fs::read(path)?),
+        other => Err(BitmapError::UnsupportedFormat(other.to_string())),
+    }
+}
+
+/// Whether a colour is bright enough to count as a lit pixel
+fn is_lit(r: u8, g: u8, b: u8) -> bool {
+    (r as u32 + g as u32 + b as u32) / 3 >= 0x80
+}
+
+/// Decode an uncompressed bmp with 1, 4, 8, 24, or 32 bits per pixel, thresholding it so bright
+/// pixels are lit
+fn read_bmp(data: &[u8]) -> Result<Bitmap, BitmapError> {
+    let malformed = BitmapError::Malformed;
+    let u16_at = |at: usize| -> Result<u16, BitmapError> {
+        let bytes = data
+            .get(at..at + 2)
+            .ok_or(malformed("truncated bmp header"))?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let u32_at = |at: usize| -> Result<u32, BitmapError> {
+        let bytes = data
+            .get(at..at + 4)
+            .ok_or(malformed("truncated bmp header"))?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    if data.get(..2) != Some(b"BM") {
+        return Err(malformed("missing bmp signature"));
+    }
+    let pixel_offset = u32_at(10)? as usize;
+    let header_size = u32_at(14)? as usize;
+    let width = u32_at(18)? as i32;
+    let height = u32_at(22)? as i32;
+    let bits = u16_at(28)? as usize;
+    // bitfields are allowed for 32 bit images as long as they're the usual order, which we assume
+    match (u32_at(30)?, bits) {
+        (0, _) | (3, 32) => (),
+        _ => return Err(malformed("compressed bmps aren't supported")),
+    }
+    if width < 0 || !matches!(bits, 1 | 4 | 8 | 24 | 32) {
+        return Err(malformed("unsupported bmp layout"));
+    }
+
+    // rows are stored bottom up unless the height is negative
+    let (width, height, top_down) = (width as usize, height.unsigned_abs() as usize, height < 0);
+    let palette = data.get(14 + header_size..pixel_offset).unwrap_or_default();
+    let row_size = (width * bits).div_ceil(32) * 4;
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let stored = if top_down { y } else { height - 1 - y };
+        let start = pixel_offset + stored * row_size;
+        let row = data
+            .get(start..start + row_size)
+            .ok_or(malformed("truncated bmp pixel data"))?;
+        for x in 0..width {
+            let lit = match bits {
+                24 | 32 => {
+                    let px = &row[x * bits / 8..];
+                    is_lit(px[2], px[1], px[0])
+                }
+                _ => {
+                    // palette indices are packed from the most significant bit
+                    let bit = x * bits;
+                    let index = (row[bit / 8] >> (8 - bits - bit % 8)) & ((1 << bits) - 1) as u8;
+                    let entry = palette
+                        .get(index as usize * 4..index as usize * 4 + 3)
+                        .ok_or(malformed("bmp palette index out of range"))?;
+                    is_lit(entry[2], entry[1], entry[0])
+                }
+            };
+            pixels.push(lit);
+        }
+    }
+
+    Ok(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Decode a plain (P1) or raw (P4) pbm, where a 1 is a lit pixel, like the screen dumps
+fn read_pbm(data: &[u8]) -> Result<Bitmap, BitmapError> {
+    let malformed = BitmapError::Malformed;
+
+    // the header is whitespace separated, and comments run from # to the end of the line
+    let mut at = 0;
+    let mut header = || -> Result<&[u8], BitmapError> {
+        loop {
+            match data.get(at) {
+                Some(b'#') => {
+                    while data.get(at).is_some_and(|&c| c != b'\n') {
+                        at += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => at += 1,
+                Some(_) => break,
+                None => return Err(malformed("truncated pbm header")),
+            }
+        }
+        let start = at;
+        while data.get(at).is_some_and(|c| !c.is_ascii_whitespace()) {
+            at += 1;
+        }
+        Ok(&data[start..at])
+    };
+
+    let magic = header()?;
+    let mut dimension = || -> Result<usize, BitmapError> {
+        std::str::from_utf8(header()?)
+            .ok()
+            .and_then(|d| d.parse().ok())
+            .ok_or(malformed("invalid pbm dimensions"))
+    };
+    let width = dimension()?;
+    let height = dimension()?;
+
+    let pixels = match magic {
+        b"P1" => {
+            // after the header, pixels are 0s and 1s, optionally separated by whitespace
+            let body = &data[at..];
+            let pixels = body
+                .iter()
+                .filter(|c| !c.is_ascii_whitespace())
+                .take(width * height)
+                .map(|&c| match c {
+                    b'0' => Ok(false),
+                    b'1' => Ok(true),
+                    _ => Err(malformed("invalid pixel in pbm")),
+                })
+                .collect::<Result<Vec<bool>, BitmapError>>()?;
+            if pixels.len() < width * height {
+                return Err(malformed("truncated pbm pixel data"));
+            }
+            pixels
+        }
+        b"P4" => {
+            // a single whitespace character separates the header from packed rows of bits
+            let body = data.get(at + 1..).unwrap_or_default();
+            let row_size = width.div_ceil(8);
+            if body.len() < row_size * height {
+                return Err(malformed("truncated pbm pixel data"));
+            }
+            (0..height)
+                .flat_map(|y| (0..width).map(move |x| (y, x)))
+                .map(|(y, x)| body[y * row_size + x / 8] & (0x80 >> (x % 8)) != 0)
+                .collect()
+        }
+        _ => return Err(malformed("missing pbm signature")),
+    };
+
+    Ok(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Decode a png, thresholding it so bright, opaque pixels are lit, like the screen dumps
+#[cfg(feature = "images")]
+fn read_png(path: &Path) -> Result<Bitmap, BitmapError> {
+    let mut decoder = png::Decoder::new(io::BufReader::new(fs::File::open(path)?));
+    // always get 8 bit samples, whatever the image was saved as
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+
+    let channels = info.color_type.samples();
+    let pixels = buf[..info.buffer_size()]
+        .chunks(channels)
+        .map(|px| {
+            let (lit, alpha) = match *px {
+                [luma] => (is_lit(luma, luma, luma), 0xFF),
+                [luma, alpha] => (is_lit(luma, luma, luma), alpha),
+                [r, g, b] => (is_lit(r, g, b), 0xFF),
+                [r, g, b, alpha] => (is_lit(r, g, b), alpha),
+                _ => (false, 0),
+            };
+            lit && alpha >= 0x80
+        })
+        .collect();
+
+    Ok(Bitmap {
+        width: info.width as usize,
+        height: info.height as usize,
+        pixels,
+    })
+}
+
+#[cfg(not(feature = "images"))]
+fn read_png(path: &Path) -> Result<Bitmap, BitmapError> {
+    // still make sure the file is there, so a typo isn't reported as a missing feature
+    fs::metadata(path)?;
+    Err(BitmapError::PngUnavailable)
+}
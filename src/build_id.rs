@@ -0,0 +1,29 @@
+/// How many bytes a build id takes up in the rom
+pub const SIZE: usize = 8;
+
+/// 64 bit FNV-1a, picked over the standard library's hasher because its output is guaranteed never to change between
+/// releases, so the same source always gets the same id
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+/// A short identifier for a build, made by hashing the version of the assembler, the source, and anything else that
+/// changes the rom, like the options it was built with
+pub fn compute(source: &str, settings: &[String]) -> [u8; SIZE] {
+    let mut hash = FNV_OFFSET;
+    let inputs = [env!("CARGO_PKG_VERSION"), source]
+        .into_iter()
+        .chain(settings.iter().map(String::as_str));
+    for input in inputs {
+        // a separator after each input keeps `ab` + `c` from hashing the same as `a` + `bc`
+        for &byte in input.as_bytes().iter().chain(&[0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash.to_be_bytes()
+}
+
+/// The build id as hex, the way it's reported to the user
+pub fn to_hex(id: &[u8; SIZE]) -> String {
+    id.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use super::input;
+use super::tokenize::{self, Line};
+use super::{plural, RunError};
+
+/// What the code before the first label is called in the graph
+const START: &str = "<start>";
+
+/// How the call graph is written out
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    Text,
+    Dot,
+}
+
+/// A label that starts a routine, along with every routine it calls
+struct Routine<'a> {
+    name: &'a str,
+    /// The (1-indexed) line the label is declared on, or None if it isn't declared in the source
+    line: Option<usize>,
+    /// Each routine called and how many places it's called from
+    calls: Vec<(&'a str, usize)>,
+}
+
+/// Which routines call which. A routine starts at the first label or at any label that's called, and runs until the
+/// next one, so labels that are only jumped to (like loops) count as part of the routine they're in
+struct CallGraph<'a> {
+    routines: Vec<Routine<'a>>,
+    /// The index of each routine's callees in `routines`
+    edges: Vec<Vec<usize>>,
+}
+
+/// Write out the graph of which routines in a source file call which, along with the most used routines and any
+/// recursion, which quickly overflows the small chip8 stack
+pub fn run(input: &Path, format: GraphFormat) -> Result<(), RunError> {
+    let source = input::read_source(input)?;
+    let graph = CallGraph::build(&source);
+    let mut out = io::stdout().lock();
+    match format {
+        GraphFormat::Text => graph.write_text(&mut out)?,
+        GraphFormat::Dot => graph.write_dot(&mut out)?,
+    }
+    Ok(())
+}
+
+impl<'a> CallGraph<'a> {
+    fn build(source: &'a str) -> CallGraph<'a> {
+        let lines: Vec<Line> = source.lines().map(tokenize::tokenize_line).collect();
+        // a routine can be called through an alias, so resolve those first
+        let aliases: HashMap<&str, &str> = lines
+            .iter()
+            .filter_map(|line| match line.tokens.as_slice() {
+                [head, name, value] if head.text == "alias" => Some((name.text, value.text)),
+                _ => None,
+            })
+            .collect();
+        let call_target = |line: &Line<'a>| match line.tokens.as_slice() {
+            [op, target] if op.text.eq_ignore_ascii_case("CALL") => {
+                Some(*aliases.get(target.text).unwrap_or(&target.text))
+            }
+            _ => None,
+        };
+        let called: HashSet<&str> = lines.iter().filter_map(call_target).collect();
+
+        let mut routines: Vec<Routine> = Vec::new();
+        for (number, line) in lines.iter().enumerate() {
+            if let [label] = line.tokens.as_slice() {
+                let name = label.text.trim_end_matches(':');
+                if line.text.ends_with(':') && (routines.is_empty() || called.contains(name)) {
+                    routines.push(Routine {
+                        name,
+                        line: Some(number + 1),
+                        calls: Vec::new(),
+                    });
+                }
+            } else if let Some(target) = call_target(line) {
+                if routines.is_empty() {
+                    routines.push(Routine {
+                        name: START,
+                        line: None,
+                        calls: Vec::new(),
+                    });
+                }
+                let calls = &mut routines
+                    .last_mut()
+                    .expect("just made sure there's one")
+                    .calls;
+                match calls.iter_mut().find(|(callee, _)| *callee == target) {
+                    Some((_, sites)) => *sites += 1,
+                    None => calls.push((target, 1)),
+                }
+            }
+        }
+
+        // calls to addresses or labels the source doesn't declare still get a node
+        let mut index: HashMap<&str, usize> = routines
+            .iter()
+            .enumerate()
+            .map(|(i, routine)| (routine.name, i))
+            .collect();
+        let callees: Vec<&str> = routines
+            .iter()
+            .flat_map(|routine| routine.calls.iter().map(|&(callee, _)| callee))
+            .collect();
+        for callee in callees {
+            if !index.contains_key(callee) {
+                index.insert(callee, routines.len());
+                routines.push(Routine {
+                    name: callee,
+                    line: None,
+                    calls: Vec::new(),
+                });
+            }
+        }
+
+        let edges = routines
+            .iter()
+            .map(|routine| {
+                routine
+                    .calls
+                    .iter()
+                    .map(|(callee, _)| index[callee])
+                    .collect()
+            })
+            .collect();
+        CallGraph { routines, edges }
+    }
+
+    /// How many places call each routine, and from how many different routines
+    fn usage(&self) -> Vec<(usize, usize)> {
+        let mut usage = vec![(0, 0); self.routines.len()];
+        for (routine, edges) in self.routines.iter().zip(&self.edges) {
+            for (&callee, &(_, sites)) in edges.iter().zip(&routine.calls) {
+                usage[callee].0 += sites;
+                usage[callee].1 += 1;
+            }
+        }
+        usage
+    }
+
+    /// Groups of routines that can end up calling themselves, each in source order
+    fn recursion(&self) -> Vec<Vec<usize>> {
+        let mut tarjan = Tarjan {
+            edges: &self.edges,
+            index: vec![None; self.routines.len()],
+            low: vec![0; self.routines.len()],
+            stack: Vec::new(),
+            on_stack: vec![false; self.routines.len()],
+            next: 0,
+            components: Vec::new(),
+        };
+        for routine in 0..self.routines.len() {
+            if tarjan.index[routine].is_none() {
+                tarjan.visit(routine);
+            }
+        }
+
+        let mut recursive: Vec<Vec<usize>> = tarjan
+            .components
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1 || self.edges[component[0]].contains(&component[0])
+            })
+            .map(|mut component| {
+                component.sort_unstable();
+                component
+            })
+            .collect();
+        recursive.sort_unstable();
+        recursive
+    }
+
+    fn write_text(&self, out: &mut impl Write) -> io::Result<()> {
+        let usage = self.usage();
+        for (routine, &(sites, callers)) in self.routines.iter().zip(&usage) {
+            write!(out, "{}", routine.name)?;
+            match (routine.line, sites) {
+                (None, _) if routine.name == START => writeln!(out, " (before the first label)")?,
+                (None, _) => writeln!(out, " (not declared)")?,
+                (Some(line), 0) => writeln!(out, " (line {line})")?,
+                (Some(line), _) => writeln!(
+                    out,
+                    " (line {line}, {} from {})",
+                    plural(sites, "call"),
+                    plural(callers, "routine")
+                )?,
+            }
+            for (callee, sites) in &routine.calls {
+                match sites {
+                    1 => writeln!(out, "    -> {callee}")?,
+                    _ => writeln!(out, "    -> {callee} x{sites}")?,
+                }
+            }
+        }
+
+        let mut most_used: Vec<usize> = (0..self.routines.len())
+            .filter(|&routine| usage[routine].0 > 0)
+            .collect();
+        // stable, so ties stay in source order
+        most_used.sort_by_key(|&routine| std::cmp::Reverse(usage[routine]));
+        if !most_used.is_empty() {
+            writeln!(out, "\nmost used:")?;
+            for routine in most_used {
+                let (sites, callers) = usage[routine];
+                writeln!(
+                    out,
+                    "    {}: {} from {}",
+                    self.routines[routine].name,
+                    plural(sites, "call"),
+                    plural(callers, "routine")
+                )?;
+            }
+        }
+
+        let recursion = self.recursion();
+        if !recursion.is_empty() {
+            writeln!(out, "\nrecursion:")?;
+            for component in recursion {
+                let names: Vec<&str> = component
+                    .iter()
+                    .map(|&routine| self.routines[routine].name)
+                    .collect();
+                match names.as_slice() {
+                    [name] => writeln!(out, "    {name} calls itself")?,
+                    names => writeln!(out, "    {} call each other", names.join(", "))?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_dot(&self, out: &mut impl Write) -> io::Result<()> {
+        // which cycle each recursive routine is part of
+        let cycle: HashMap<usize, usize> = self
+            .recursion()
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, component)| component.into_iter().map(move |routine| (routine, i)))
+            .collect();
+        writeln!(out, "digraph calls {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        for (i, routine) in self.routines.iter().enumerate() {
+            let mut attributes = Vec::new();
+            if routine.line.is_none() && routine.name != START {
+                attributes.push("style=dashed");
+            }
+            if cycle.contains_key(&i) {
+                attributes.push("color=red");
+            }
+            writeln!(
+                out,
+                "    \"{}\" [{}];",
+                escape(routine.name),
+                attributes.join(", ")
+            )?;
+        }
+        for ((routine, edges), caller) in self.routines.iter().zip(&self.edges).zip(0..) {
+            for (&callee, &(_, sites)) in edges.iter().zip(&routine.calls) {
+                let mut attributes = Vec::new();
+                let label = format!("label=\"{sites}\"");
+                if sites > 1 {
+                    attributes.push(label.as_str());
+                }
+                // an edge between two routines in the same cycle is part of the recursion
+                if cycle.contains_key(&caller) && cycle.get(&caller) == cycle.get(&callee) {
+                    attributes.push("color=red");
+                }
+                writeln!(
+                    out,
+                    "    \"{}\" -> \"{}\" [{}];",
+                    escape(routine.name),
+                    escape(self.routines[callee].name),
+                    attributes.join(", ")
+                )?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+/// Tarjan's strongly connected components algorithm, which finds every cycle in one pass over the graph
+struct Tarjan<'g> {
+    edges: &'g [Vec<usize>],
+    /// The order each routine was first visited in
+    index: Vec<Option<usize>>,
+    /// The earliest visited routine reachable from each routine
+    low: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    next: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, routine: usize) {
+        self.index[routine] = Some(self.next);
+        self.low[routine] = self.next;
+        self.next += 1;
+        self.stack.push(routine);
+        self.on_stack[routine] = true;
+
+        let edges = self.edges;
+        for &callee in &edges[routine] {
+            match self.index[callee] {
+                None => {
+                    self.visit(callee);
+                    self.low[routine] = self.low[routine].min(self.low[callee]);
+                }
+                Some(index) if self.on_stack[callee] => {
+                    self.low[routine] = self.low[routine].min(index)
+                }
+                Some(_) => (),
+            }
+        }
+
+        // a routine nothing earlier is reachable from is the root of a component
+        if Some(self.low[routine]) == self.index[routine] {
+            let mut component = Vec::new();
+            loop {
+                let member = self.stack.pop().expect("the root is still on the stack");
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == routine {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+/// Make a name safe to put in a quoted DOT id
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,578 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::assemble::parse::{self, AsmArgument};
+use super::emulator::{AccessKind, Chip8, CYCLES_PER_FRAME};
+use super::screen;
+use super::sourcemap::Mapping;
+use super::transport::{self, read_message, TransportError};
+use super::Program;
+
+const FRAME: Duration = Duration::from_micros(16_667);
+/// There's only ever one thread of execution on a chip8
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+
+/// Serve the debug adapter protocol over stdin/stdout until the client disconnects
+pub fn serve() -> Result<(), TransportError> {
+    let requests = spawn_reader();
+    let mut session = Session {
+        out: io::stdout(),
+        seq: 1,
+        debuggee: None,
+        running: false,
+        stepped_from_console: false,
+    };
+
+    loop {
+        // only block on the client while the program isn't running
+        let message = if session.running {
+            match requests.try_recv() {
+                Ok(message) => Some(message?),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match requests.recv() {
+                Ok(message) => Some(message?),
+                Err(_) => return Ok(()),
+            }
+        };
+
+        if let Some(request) = message {
+            if !session.handle(&request)? {
+                return Ok(());
+            }
+        }
+
+        if session.running {
+            session.run_frame()?;
+        }
+    }
+}
+
+/// Read messages from stdin on their own thread so that a running program can be interrupted
+fn spawn_reader() -> Receiver<Result<Value, TransportError>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = BufReader::new(io::stdin());
+        loop {
+            let message = read_message(&mut stdin);
+            let done = !matches!(message, Ok(Some(_)));
+            if let Some(message) = message.transpose() {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Parse an inclusive range of addresses written as `START..END`, or a single address
+fn parse_range(range: &str) -> Result<(u16, u16), String> {
+    let (start, end) = range.split_once("..").unwrap_or((range, range));
+    let bounds = parse::parse_asm_args(&[start, end]).map_err(|e| e.to_string())?;
+    match bounds[..] {
+        [AsmArgument::Numeric(start), AsmArgument::Numeric(end)] if start <= end => {
+            Ok((start, end))
+        }
+        _ => Err(format!("invalid address range: {range}")),
+    }
+}
+
+/// The program being debugged
+struct Debuggee {
+    chip8: Chip8,
+    source: PathBuf,
+    program: Program,
+    /// The addresses to stop at, by the file they were set in
+    breakpoints: HashMap<PathBuf, HashSet<u16>>,
+    /// Inclusive ranges of memory that pause execution when read or written
+    watchpoints: Vec<(u16, u16)>,
+    stop_on_entry: bool,
+    /// Where to stop when stepping over or out of a call, along with the stack depth to stop at
+    step_target: Option<(u16, usize)>,
+    /// Whether execution is picking up from where it stopped, so a breakpoint there doesn't stop it again straight away
+    resuming: bool,
+}
+
+impl Debuggee {
+    /// The file and line in it that a mapping came from. Anything included from a file is in that file, while the
+    /// rest, including libraries and the output of external directives, is on its line of the program itself
+    fn location(&self, mapping: &Mapping) -> (PathBuf, usize) {
+        match &mapping.origin {
+            Some(origin) if Path::new(&*origin.file).is_file() => {
+                (PathBuf::from(&*origin.file), origin.line)
+            }
+            _ => (self.source.clone(), mapping.line),
+        }
+    }
+
+    /// The file and line the instruction at an address came from
+    fn location_of(&self, addr: u16) -> Option<(PathBuf, usize)> {
+        let addr = addr as usize;
+        self.program
+            .mappings
+            .iter()
+            .find(|mapping| mapping.addr <= addr && addr < mapping.addr + mapping.size)
+            .map(|mapping| self.location(mapping))
+    }
+
+    /// Every address of the first line of a file on or after the given one that has instructions, which is more than
+    /// one address when the line was repeated, along with that line
+    fn addrs_of(&self, file: &Path, line: usize) -> Option<(HashSet<u16>, usize)> {
+        let placed: Vec<(u16, usize)> = self
+            .program
+            .mappings
+            .iter()
+            .filter(|mapping| mapping.code)
+            .filter_map(|mapping| {
+                let (path, l) = self.location(mapping);
+                (l >= line && same_file(&path, file)).then_some((mapping.addr as u16, l))
+            })
+            .collect();
+        let nearest = placed.iter().map(|&(_, l)| l).min()?;
+        let addrs = placed
+            .iter()
+            .filter(|&&(_, l)| l == nearest)
+            .map(|&(addr, _)| addr)
+            .collect();
+        Some((addrs, nearest))
+    }
+}
+
+/// Whether two paths are the same file, however they're written
+fn same_file(a: &Path, b: &Path) -> bool {
+    a == b
+        || matches!(
+            (a.canonicalize(), b.canonicalize()),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+struct Session {
+    out: io::Stdout,
+    seq: u64,
+    debuggee: Option<Debuggee>,
+    running: bool,
+    /// Whether a console command moved execution, so the client needs to refresh its view
+    stepped_from_console: bool,
+}
+
+impl Session {
+    /// Handle a request from the client, returning whether the session should continue
+    fn handle(&mut self, request: &Value) -> Result<bool, TransportError> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+
+        let body = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "chip8" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [{
+                    "name": "Registers",
+                    "variablesReference": REGISTERS_REFERENCE,
+                    "expensive": false,
+                }]
+            })),
+            "variables" => self.variables(args),
+            "evaluate" => self.evaluate(args),
+            "continue" => {
+                self.resume();
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => self.step_over(),
+            "stepIn" => self.step_in(),
+            "stepOut" => self.step_out(),
+            "pause" => Ok(Value::Null),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null))?;
+                return Ok(false);
+            }
+            other => Err(format!("unsupported request: {other}")),
+        };
+        let succeeded = body.is_ok();
+        self.respond(request, body)?;
+
+        // some requests need events sent after their response
+        match command {
+            "launch" if succeeded => self.send_event("initialized", Value::Null)?,
+            "configurationDone" => match &self.debuggee {
+                Some(debuggee) if debuggee.stop_on_entry => self.stop("entry", None)?,
+                Some(_) => self.running = true,
+                None => (),
+            },
+            "pause" => self.stop("pause", None)?,
+            "stepIn" | "stepOut" | "next" if !self.running => self.stop("step", None)?,
+            "evaluate" if self.stepped_from_console => {
+                self.stepped_from_console = false;
+                self.stop("step", None)?;
+            }
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    /// Assemble the program to debug and load it into the emulator
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let source = PathBuf::from(
+            args["program"]
+                .as_str()
+                .ok_or("launch requires a `program` to debug")?,
+        );
+        let text = fs::read_to_string(&source).map_err(|e| format!("{}: {e}", source.display()))?;
+        let program = super::assemble_program(&text, &super::options_for(&source))
+            .map_err(|e| e.to_string())?;
+        let chip8 = Chip8::new(&program.rom).map_err(|e| e.to_string())?;
+        // show warnings in the debug console, since stderr isn't usually visible
+        for warning in &program.warnings {
+            let output = format!("WARNING: line {}: {warning}\n", warning.line());
+            self.send_event("output", json!({ "category": "console", "output": output }))
+                .map_err(|e| e.to_string())?;
+        }
+
+        self.debuggee = Some(Debuggee {
+            chip8,
+            source,
+            program,
+            breakpoints: HashMap::new(),
+            watchpoints: Vec::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            step_target: None,
+            resuming: false,
+        });
+        Ok(Value::Null)
+    }
+
+    /// Replace the breakpoints in a file with ones at the instructions nearest to the requested lines
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        let file = args["source"]["path"]
+            .as_str()
+            .map_or_else(|| debuggee.source.clone(), PathBuf::from);
+
+        let mut breakpoints = HashSet::new();
+        let mut verified = Vec::new();
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = requested["line"].as_u64().unwrap_or_default() as usize;
+            match debuggee.addrs_of(&file, line) {
+                Some((addrs, actual_line)) => {
+                    breakpoints.extend(addrs);
+                    verified.push(json!({ "verified": true, "line": actual_line }));
+                }
+                None => verified.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no instruction at or after this line",
+                })),
+            }
+        }
+        debuggee.breakpoints.insert(file, breakpoints);
+
+        Ok(json!({ "breakpoints": verified }))
+    }
+
+    /// Report the current instruction and the call sites of each frame on the stack
+    fn stack_trace(&self) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_ref()
+            .ok_or("no program has been launched")?;
+
+        // return addresses point just after the CALL that pushed them
+        let frames = std::iter::once(debuggee.chip8.pc)
+            .chain(debuggee.chip8.stack.iter().rev().map(|ret| ret - 2))
+            .enumerate()
+            .map(|(id, addr)| {
+                let (file, line) = debuggee
+                    .location_of(addr)
+                    .unwrap_or_else(|| (debuggee.source.clone(), 0));
+                json!({
+                    "id": id,
+                    "name": format!("{addr:#05X}"),
+                    "source": {
+                        "name": file.file_name().map(|n| n.to_string_lossy()),
+                        "path": file.to_string_lossy(),
+                    },
+                    "line": line,
+                    "column": 0,
+                    "instructionPointerReference": format!("{addr:#05X}"),
+                })
+            })
+            .collect::<Vec<Value>>();
+
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    /// Report the values of the registers
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_ref()
+            .ok_or("no program has been launched")?;
+        if args["variablesReference"].as_u64() != Some(REGISTERS_REFERENCE) {
+            return Ok(json!({ "variables": [] }));
+        }
+
+        let chip8 = &debuggee.chip8;
+        let mut variables = chip8
+            .v
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("V{i:X}"), format!("{v:#04X}")))
+            .collect::<Vec<(String, String)>>();
+        variables.push((String::from("I"), format!("{:#05X}", chip8.i)));
+        variables.push((String::from("PC"), format!("{:#05X}", chip8.pc)));
+        variables.push((String::from("SP"), chip8.stack.len().to_string()));
+        variables.push((String::from("DT"), format!("{:#04X}", chip8.delay_timer)));
+        variables.push((String::from("ST"), format!("{:#04X}", chip8.sound_timer)));
+
+        let variables = variables
+            .into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value, "variablesReference": 0 }))
+            .collect::<Vec<Value>>();
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Run a debugger command typed into the client's console
+    /// `watch START..END` pauses whenever the inclusive range of memory is read or written,
+    /// `unwatch START..END` removes such a watchpoint, and `unwatch` on its own removes them all,
+    /// `frame` advances exactly one 60Hz tick worth of instructions and decrements the timers,
+    /// `dump FILE` writes the display to a .pbm or .png image
+    fn evaluate(&mut self, args: &Value) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        let expression = args["expression"].as_str().unwrap_or_default();
+        let tokens = expression.split_whitespace().collect::<Vec<&str>>();
+
+        let result = match tokens[..] {
+            ["watch"] | ["watches"] => {
+                if debuggee.watchpoints.is_empty() {
+                    String::from("no watchpoints set")
+                } else {
+                    debuggee
+                        .watchpoints
+                        .iter()
+                        .map(|(start, end)| format!("{start:#05X}..{end:#05X}"))
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                }
+            }
+            ["watch", range] => {
+                let range = parse_range(range)?;
+                if !debuggee.watchpoints.contains(&range) {
+                    debuggee.watchpoints.push(range);
+                }
+                format!("watching {:#05X}..{:#05X}", range.0, range.1)
+            }
+            ["unwatch"] => {
+                debuggee.watchpoints.clear();
+                String::from("removed all watchpoints")
+            }
+            ["unwatch", range] => {
+                let range = parse_range(range)?;
+                debuggee.watchpoints.retain(|&w| w != range);
+                format!("removed watchpoint {:#05X}..{:#05X}", range.0, range.1)
+            }
+            ["frame"] => {
+                for _ in 0..CYCLES_PER_FRAME {
+                    debuggee.chip8.step().map_err(|e| e.to_string())?;
+                }
+                debuggee.chip8.tick_timers();
+                self.stepped_from_console = true;
+                format!("advanced one frame to {:#05X}", debuggee.chip8.pc)
+            }
+            ["dump", path] => {
+                screen::dump(&debuggee.chip8.display, Path::new(path))
+                    .map_err(|e| e.to_string())?;
+                format!("wrote display to {path}")
+            }
+            _ => return Err(format!("unknown debugger command: {expression}")),
+        };
+
+        Ok(json!({ "result": result, "variablesReference": 0 }))
+    }
+
+    /// Execute a single instruction
+    fn step_in(&mut self) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        debuggee.chip8.step().map_err(|e| e.to_string())?;
+        Ok(Value::Null)
+    }
+
+    /// Execute a single instruction, running through the whole subroutine if it's a CALL
+    fn step_over(&mut self) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        let pc = debuggee.chip8.pc;
+        let is_call = debuggee.chip8.opcode_at(pc).map_err(|e| e.to_string())? & 0xF000 == 0x2000;
+
+        if is_call {
+            debuggee.step_target = Some((pc + 2, debuggee.chip8.stack.len()));
+            self.resume();
+            Ok(Value::Null)
+        } else {
+            self.step_in()
+        }
+    }
+
+    /// Run until the current subroutine returns
+    fn step_out(&mut self) -> Result<Value, String> {
+        let debuggee = self
+            .debuggee
+            .as_mut()
+            .ok_or("no program has been launched")?;
+        match debuggee.chip8.stack.last() {
+            Some(&ret) => {
+                debuggee.step_target = Some((ret, debuggee.chip8.stack.len() - 1));
+                self.resume();
+                Ok(Value::Null)
+            }
+            // there's nothing to return out of, so this is just a step
+            None => self.step_in(),
+        }
+    }
+
+    /// Carry on running from where execution stopped
+    fn resume(&mut self) {
+        if let Some(debuggee) = self.debuggee.as_mut() {
+            debuggee.resuming = true;
+        }
+        self.running = true;
+    }
+
+    /// Run a frame's worth of instructions, stopping early at breakpoints, step targets, and errors
+    fn run_frame(&mut self) -> Result<(), TransportError> {
+        let start = Instant::now();
+        let Some(debuggee) = self.debuggee.as_mut() else {
+            self.running = false;
+            return Ok(());
+        };
+
+        for _ in 0..CYCLES_PER_FRAME {
+            // a breakpoint stops before its instruction runs, apart from the one execution resumed from
+            let pc = debuggee.chip8.pc;
+            let resuming = std::mem::take(&mut debuggee.resuming);
+            if !resuming
+                && debuggee
+                    .breakpoints
+                    .values()
+                    .any(|breakpoints| breakpoints.contains(&pc))
+            {
+                debuggee.step_target = None;
+                return self.stop("breakpoint", None);
+            }
+
+            if let Err(e) = debuggee.chip8.step() {
+                return self.stop("exception", Some(e.to_string()));
+            }
+
+            let pc = debuggee.chip8.pc;
+            if let Some((target, depth)) = debuggee.step_target {
+                if pc == target && debuggee.chip8.stack.len() == depth {
+                    debuggee.step_target = None;
+                    return self.stop("step", None);
+                }
+            }
+            if let Some(access) = debuggee.chip8.last_access() {
+                if debuggee
+                    .watchpoints
+                    .iter()
+                    .any(|&(start, end)| access.overlaps(start, end))
+                {
+                    let verb = match access.kind {
+                        AccessKind::Read => "read",
+                        AccessKind::Write => "wrote",
+                    };
+                    let text = format!(
+                        "instruction at {:#05X} {verb} {:#05X}..{:#05X}",
+                        access.pc,
+                        access.start,
+                        access.start + access.len - 1
+                    );
+                    debuggee.step_target = None;
+                    return self.stop("data breakpoint", Some(text));
+                }
+            }
+        }
+        debuggee.chip8.tick_timers();
+
+        // keep the program running at its real speed
+        if let Some(remaining) = FRAME.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+        Ok(())
+    }
+
+    /// Stop execution and let the client know why
+    fn stop(&mut self, reason: &str, text: Option<String>) -> Result<(), TransportError> {
+        self.running = false;
+        self.send_event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+                "text": text,
+            }),
+        )
+    }
+
+    fn respond(
+        &mut self,
+        request: &Value,
+        body: Result<Value, String>,
+    ) -> Result<(), TransportError> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = Value::from(message),
+        }
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> Result<(), TransportError> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    /// Write a message to the client framed by a Content-Length header
+    fn send(&mut self, mut message: Value) -> Result<(), TransportError> {
+        message["seq"] = Value::from(self.seq);
+        self.seq += 1;
+
+        transport::write_message(&mut self.out.lock(), &message)
+    }
+}
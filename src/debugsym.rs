@@ -0,0 +1,46 @@
+use std::io::{self, BufWriter, Write};
+
+use super::Program;
+
+/// Write a symbol file for debuggers: the address of every label as `0200 main`, and every run of sprites and data
+/// as `0230 .data:0008`, the length in hex, so a disassembler knows not to read it as code. This is the `.sym` layout
+/// debuggers have read since no$gmb, without the bank, since a CHIP-8 doesn't have any
+pub fn write(program: &Program, out: impl Write) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    writeln!(
+        out,
+        "; debug symbols written by ch8asm {}",
+        env!("CARGO_PKG_VERSION")
+    )?;
+
+    let mut symbols: Vec<(usize, String)> = program
+        .labels
+        .iter()
+        .map(|(name, &addr)| (addr, name.clone()))
+        .collect();
+    // blocks of data right after each other are one region as far as a debugger cares
+    let mut regions: Vec<(usize, usize)> = Vec::new();
+    for mapping in program.mappings.iter().filter(|mapping| !mapping.code) {
+        let (addr, size) = (mapping.addr, mapping.size);
+        match regions.last_mut() {
+            Some((start, len)) if *start + *len == addr => *len += size,
+            _ => regions.push((addr, size)),
+        }
+    }
+    symbols.extend(
+        regions
+            .into_iter()
+            .filter(|&(_, len)| len > 0)
+            .map(|(addr, len)| (addr, format!(".data:{len:04X}"))),
+    );
+    // a label comes before the data it names
+    symbols.sort_by(|a, b| {
+        a.0.cmp(&b.0)
+            .then(a.1.starts_with('.').cmp(&b.1.starts_with('.')))
+            .then(a.1.cmp(&b.1))
+    });
+    for (addr, name) in symbols {
+        writeln!(out, "{addr:04X} {name}")?;
+    }
+    out.flush()
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde_json::json;
+
+use super::fixes::Fix;
+
+/// How errors and warnings are written to stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// The message under a header naming its kind, followed by the line of the source it's about with the part that's
+    /// wrong underlined
+    #[default]
+    Human,
+    /// A line of text for each, like `ERROR: line 3: ...`
+    Short,
+    /// A JSON object on a line of its own for each, for editors and CI to parse
+    Json,
+}
+
+/// Whether diagnostics are colored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Color {
+    /// When stderr is a terminal and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    fn enabled(self) -> bool {
+        match self {
+            Color::Auto => io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
+/// The ANSI escape codes for what's colored
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Something about the other diagnostics, like how many errors weren't shown
+    Note,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    /// The escape code of the color it's shown in
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+            Severity::Note => "\x1b[1;32m",
+        }
+    }
+}
+
+/// An error or warning, along with where in the source it was found if it was found in the source
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What's wrong, without where
+    pub message: String,
+    /// The file it was found in when it isn't the source itself, like an included file
+    pub file: Option<String>,
+    /// The (1-indexed) line
+    pub line: Option<usize>,
+    /// The (1-indexed) column, for the errors that know it
+    pub column: Option<usize>,
+    /// The kind of error or warning, like `unknown-op` or `padded-sprite`
+    pub code: &'static str,
+    /// A change to the line that would fix it, when there's an obvious one
+    pub fix: Option<Fix>,
+}
+
+/// Writes diagnostics to stderr in the format that was asked for
+#[derive(Debug, Default)]
+pub struct Reporter {
+    format: ErrorFormat,
+    color: bool,
+    /// The source the diagnostics are about, or None if it was read from stdin
+    file: Option<PathBuf>,
+}
+
+impl Reporter {
+    pub fn new(format: ErrorFormat, color: Color, file: Option<PathBuf>) -> Reporter {
+        Reporter {
+            format,
+            color: color.enabled(),
+            file,
+        }
+    }
+
+    /// The text of the source the diagnostics are about, or None if it was read from stdin or can't be read anymore
+    pub fn source(&self) -> Option<String> {
+        fs::read_to_string(self.file.as_ref()?).ok()
+    }
+
+    /// The file a diagnostic was found in, or None if it was found in source read from stdin
+    fn file_of<'a>(&'a self, diagnostic: &'a Diagnostic) -> Option<&'a Path> {
+        diagnostic
+            .file
+            .as_deref()
+            .map(Path::new)
+            .or(self.file.as_deref())
+    }
+
+    pub fn report(&self, diagnostic: &Diagnostic) {
+        match self.format {
+            ErrorFormat::Human => eprint!("{}", self.render(diagnostic)),
+            ErrorFormat::Short => {
+                let prefix = diagnostic.severity.name().to_uppercase();
+                let message = &diagnostic.message;
+                let file = match &diagnostic.file {
+                    Some(file) => format!("{file} "),
+                    None => String::new(),
+                };
+                match (diagnostic.line, diagnostic.column) {
+                    (Some(line), Some(column)) => {
+                        eprintln!("{prefix}: {file}line {line}, column {column}: {message}")
+                    }
+                    (Some(line), None) => eprintln!("{prefix}: {file}line {line}: {message}"),
+                    _ => eprintln!("{prefix}: {message}"),
+                }
+            }
+            ErrorFormat::Json => {
+                let object = json!({
+                    "severity": diagnostic.severity.name(),
+                    "message": diagnostic.message,
+                    "file": self.file_of(diagnostic).map(|file| file.display().to_string()),
+                    "line": diagnostic.line,
+                    "column": diagnostic.column,
+                    "code": diagnostic.code,
+                    "fix": diagnostic.fix.as_ref().map(Fix::to_json),
+                });
+                eprintln!("{object}");
+            }
+        }
+    }
+
+    /// A diagnostic the way rustc shows them: a header with its kind, where it was found, and the line of the source
+    /// with the part that's wrong underlined, read back from the file. Source read from stdin can't be shown
+    fn render(&self, diagnostic: &Diagnostic) -> String {
+        let paint = |style: &str, text: &str| match self.color {
+            true => format!("{style}{text}{RESET}"),
+            false => text.to_string(),
+        };
+        let severity = diagnostic.severity;
+        let mut rendered = format!(
+            "{}{}\n",
+            paint(
+                severity.color(),
+                &format!("{}[{}]", severity.name(), diagnostic.code)
+            ),
+            paint(BOLD, &format!(": {}", diagnostic.message)),
+        );
+        let Some(line) = diagnostic.line else {
+            return rendered;
+        };
+
+        let path = self.file_of(diagnostic);
+        let file = match path {
+            Some(file) => file.display().to_string(),
+            None => "<stdin>".to_string(),
+        };
+        let location = match diagnostic.column {
+            Some(column) => format!("{file}:{line}:{column}"),
+            None => format!("{file}:{line}"),
+        };
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        rendered.push_str(&format!("{gutter}{} {location}\n", paint(BLUE, "-->")));
+
+        let source = path.and_then(|file| fs::read_to_string(file).ok());
+        let Some(text) = source
+            .as_deref()
+            .and_then(|source| source.lines().nth(line - 1))
+        else {
+            return rendered;
+        };
+        let bar = paint(BLUE, "|");
+        rendered.push_str(&format!("{gutter} {bar}\n"));
+        rendered.push_str(&format!("{} {bar} {text}\n", paint(BLUE, &number)));
+        if let Some((start, len)) = underlined(text, diagnostic.column) {
+            // keep tabs as tabs so the carets line up under them however wide they're shown
+            let indent: String = text[..start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let carets = paint(severity.color(), &"^".repeat(len));
+            rendered.push_str(&format!("{gutter} {bar} {indent}{carets}\n"));
+        }
+        if let Some(fix) = &diagnostic.fix {
+            rendered.push_str(&format!(
+                "{gutter} {} {}: replace it with `{}`\n",
+                paint(BLUE, "="),
+                paint(BOLD, "help"),
+                fix.replacement
+            ));
+        }
+        rendered
+    }
+
+    pub fn report_all(&self, diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics {
+            self.report(diagnostic);
+        }
+    }
+}
+
+/// The byte offset and length in characters of what's underlined on a line: the token at a (1-indexed) byte column, or
+/// the whole line when there's no column. None if there's nothing there to underline
+fn underlined(text: &str, column: Option<usize>) -> Option<(usize, usize)> {
+    let (start, rest) = match column {
+        Some(column) => {
+            let rest = text.get(column - 1..)?;
+            let token = rest.split(|c: char| c.is_whitespace() || c == ',').next()?;
+            (column - 1, token)
+        }
+        None => {
+            let rest = text.trim();
+            (text.len() - text.trim_start().len(), rest)
+        }
+    };
+    let len = rest.chars().count();
+    (len > 0).then_some((start, len))
+}
@@ -0,0 +1,65 @@
+/// For an opcode, emit a line of assembly that assembles back into it
+/// Opcodes that don't correspond to any instruction are emitted as raws
+pub fn disassemble_instruction(op: u16) -> String {
+    // split the opcode into the nibbles and fields the instructions are defined in terms of
+    let nnn = op & 0x0FFF;
+    let kk = op & 0x00FF;
+    let n = op & 0x000F;
+    let x = (op & 0x0F00) >> 8;
+    let y = (op & 0x00F0) >> 4;
+
+    match (op & 0xF000) >> 12 {
+        0x0 => match op {
+            0x00E0 => String::from("CLS"),
+            0x00EE => String::from("RET"),
+            _ => format!("SYS {nnn:#X}"),
+        },
+        0x1 => format!("JP {nnn:#X}"),
+        0x2 => format!("CALL {nnn:#X}"),
+        0x3 => format!("SE V{x:X}, {kk:#X}"),
+        0x4 => format!("SNE V{x:X}, {kk:#X}"),
+        0x5 if n == 0x0 => format!("SE V{x:X}, V{y:X}"),
+        0x6 => format!("LD V{x:X}, {kk:#X}"),
+        0x7 => format!("ADD V{x:X}, {kk:#X}"),
+        0x8 => match n {
+            0x0 => format!("LD V{x:X}, V{y:X}"),
+            0x1 => format!("OR V{x:X}, V{y:X}"),
+            0x2 => format!("AND V{x:X}, V{y:X}"),
+            0x3 => format!("XOR V{x:X}, V{y:X}"),
+            0x4 => format!("ADD V{x:X}, V{y:X}"),
+            0x5 => format!("SUB V{x:X}, V{y:X}"),
+            0x6 => format!("SHR V{x:X}, V{y:X}"),
+            0x7 => format!("SUBN V{x:X}, V{y:X}"),
+            0xE => format!("SHL V{x:X}, V{y:X}"),
+            _ => format_raw(op),
+        },
+        0x9 if n == 0x0 => format!("SNE V{x:X}, V{y:X}"),
+        0xA => format!("LD I, {nnn:#X}"),
+        0xB => format!("JP V0, {nnn:#X}"),
+        0xC => format!("RND V{x:X}, {kk:#X}"),
+        0xD => format!("DRW V{x:X}, V{y:X}, {n:#X}"),
+        0xE => match kk {
+            0x9E => format!("SKP V{x:X}"),
+            0xA1 => format!("SKNP V{x:X}"),
+            _ => format_raw(op),
+        },
+        0xF => match kk {
+            0x07 => format!("LD V{x:X}, DT"),
+            0x0A => format!("LD V{x:X}, K"),
+            0x15 => format!("LD DT, V{x:X}"),
+            0x18 => format!("LD ST, V{x:X}"),
+            0x1E => format!("ADD I, V{x:X}"),
+            0x29 => format!("LD F, V{x:X}"),
+            0x33 => format!("LD B, V{x:X}"),
+            0x55 => format!("LD [I], V{x:X}"),
+            0x65 => format!("LD V{x:X}, [I]"),
+            _ => format_raw(op),
+        },
+        _ => format_raw(op),
+    }
+}
+
+/// Format an opcode as a raw so the assembler emits it unchanged
+fn format_raw(op: u16) -> String {
+    format!("{op:#06X}")
+}
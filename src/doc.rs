@@ -0,0 +1,188 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use super::input;
+use super::outline::{self, Declaration};
+use super::RunError;
+
+/// The comment marker for documentation, as opposed to a regular `;` comment
+const DOC_COMMENT: &str = ";;;";
+
+/// The annotation listing the registers a routine clobbers, as `; @clobbers V0-V2` or `;;; clobbers: V0, V1, V2`
+pub const CLOBBERS: &str = "clobbers";
+
+/// The annotation listing the registers a routine takes its arguments in, as `; @args V0` or `;;; args: V0`
+pub const ARGS: &str = "args";
+
+/// How the reference is written out
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+/// The documentation of a single declaration
+struct Entry<'a> {
+    declaration: Declaration<'a>,
+    /// Paragraphs of description, split on blank doc comment lines
+    paragraphs: Vec<String>,
+    args: Option<&'a str>,
+    clobbers: Option<&'a str>,
+}
+
+/// Extract the `;;;` doc comments above the declarations in a source file into a reference of its routines, sprites,
+/// and other symbols
+pub fn run(input: &Path, format: DocFormat) -> Result<(), RunError> {
+    let source = input::read_source(input)?;
+    let lines: Vec<&str> = source.lines().collect();
+    let entries: Vec<Entry> = outline::declarations(&source)
+        .into_iter()
+        .filter_map(|declaration| document(&lines, declaration))
+        .collect();
+
+    let title = input
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let mut out = io::stdout().lock();
+    match format {
+        DocFormat::Markdown => write_markdown(&mut out, &title, &entries)?,
+        DocFormat::Html => write_html(&mut out, &title, &entries)?,
+    }
+    Ok(())
+}
+
+/// The value of an annotation like `clobbers` in the comments directly above a (zero-indexed) line, if there is one
+pub fn annotation<'a>(lines: &[&'a str], line: usize, key: &str) -> Option<&'a str> {
+    comments_above(lines, line)
+        .into_iter()
+        .find_map(|comment| annotation_of(comment, key))
+}
+
+/// The run of comment lines directly above a (zero-indexed) line, in order
+fn comments_above<'a>(lines: &[&'a str], line: usize) -> Vec<&'a str> {
+    let mut comments: Vec<&str> = lines[..line]
+        .iter()
+        .map(|line| line.trim())
+        .rev()
+        .take_while(|line| line.starts_with(';'))
+        .collect();
+    comments.reverse();
+    comments
+}
+
+/// The value of an annotation on a comment line, if that's what the line is. `@KEY` works in any comment, while
+/// `KEY:` only counts in doc comments, so it doesn't catch regular comments that happen to start the same way
+fn annotation_of<'a>(comment: &'a str, key: &str) -> Option<&'a str> {
+    let text = comment.trim_start_matches(';').trim();
+    let rest = match text.strip_prefix('@') {
+        Some(tagged) => tagged
+            .strip_prefix(key)
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))?,
+        None if comment.starts_with(DOC_COMMENT) => text
+            .get(..key.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(key))
+            .and_then(|_| text[key.len()..].strip_prefix(':'))?,
+        None => return None,
+    };
+    Some(rest.trim())
+}
+
+/// Gather the doc comment and annotations directly above a declaration, or None if it doesn't have either
+fn document<'a>(lines: &[&'a str], declaration: Declaration<'a>) -> Option<Entry<'a>> {
+    let comments = comments_above(lines, declaration.line);
+
+    let mut paragraphs = vec![String::new()];
+    let (mut args, mut clobbers) = (None, None);
+    let mut documented = false;
+    for comment in comments {
+        if let Some(registers) = annotation_of(comment, ARGS) {
+            args = Some(registers);
+        } else if let Some(registers) = annotation_of(comment, CLOBBERS) {
+            clobbers = Some(registers);
+        } else if let Some(line) = comment.strip_prefix(DOC_COMMENT).map(str::trim) {
+            documented = true;
+            if line.is_empty() {
+                paragraphs.push(String::new());
+            } else {
+                let paragraph = paragraphs.last_mut().expect("there's always a paragraph");
+                if !paragraph.is_empty() {
+                    paragraph.push(' ');
+                }
+                paragraph.push_str(line);
+            }
+        }
+    }
+    if !documented && args.is_none() && clobbers.is_none() {
+        return None;
+    }
+    paragraphs.retain(|paragraph| !paragraph.is_empty());
+
+    Some(Entry {
+        declaration,
+        paragraphs,
+        args,
+        clobbers,
+    })
+}
+
+fn write_markdown(out: &mut impl Write, title: &str, entries: &[Entry]) -> io::Result<()> {
+    writeln!(out, "# {title}")?;
+    for entry in entries {
+        let Declaration {
+            name, kind, line, ..
+        } = entry.declaration;
+        writeln!(out, "\n## `{name}`\n\n*{kind}, line {}*", line + 1)?;
+        for paragraph in &entry.paragraphs {
+            writeln!(out, "\n{paragraph}")?;
+        }
+        if let Some(args) = entry.args {
+            writeln!(out, "\n**Arguments:** {args}")?;
+        }
+        if let Some(clobbers) = entry.clobbers {
+            writeln!(out, "\n**Clobbers:** {clobbers}")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_html(out: &mut impl Write, title: &str, entries: &[Entry]) -> io::Result<()> {
+    let title = escape(title);
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(
+        out,
+        "<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>"
+    )?;
+    writeln!(out, "<h1>{title}</h1>")?;
+    for entry in entries {
+        let Declaration {
+            name, kind, line, ..
+        } = entry.declaration;
+        let name = escape(name);
+        writeln!(out, "<h2 id=\"{name}\"><code>{name}</code></h2>")?;
+        writeln!(out, "<p><em>{kind}, line {}</em></p>", line + 1)?;
+        for paragraph in &entry.paragraphs {
+            writeln!(out, "<p>{}</p>", escape(paragraph))?;
+        }
+        if let Some(args) = entry.args {
+            writeln!(out, "<p><strong>Arguments:</strong> {}</p>", escape(args))?;
+        }
+        if let Some(clobbers) = entry.clobbers {
+            writeln!(
+                out,
+                "<p><strong>Clobbers:</strong> {}</p>",
+                escape(clobbers)
+            )?;
+        }
+    }
+    writeln!(out, "</body>\n</html>")
+}
+
+/// Make text safe to put in html
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,432 @@
+use thiserror::Error;
+
+pub use super::assemble::{MEMORY_SIZE, PROGRAM_START};
+
+/// How many instructions to execute per 60Hz frame
+pub const CYCLES_PER_FRAME: usize = 11;
+/// The width of the display in pixels
+pub const DISPLAY_WIDTH: usize = 64;
+/// The height of the display in pixels
+pub const DISPLAY_HEIGHT: usize = 32;
+
+const STACK_DEPTH: usize = 16;
+/// The address of the built in hex digit font, each glyph being 5 bytes tall
+const FONT_START: u16 = 0x050;
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// An error that stops the emulated program from continuing
+#[derive(Debug, Error)]
+pub enum EmulatorError {
+    #[error("rom of {0} bytes does not fit in memory")]
+    RomTooLarge(usize),
+    #[error("unknown opcode {op:#06X} at {pc:#05X}")]
+    UnknownOpcode { op: u16, pc: u16 },
+    #[error("stack overflow from CALL at {0:#05X}")]
+    StackOverflow(u16),
+    #[error("RET with an empty stack at {0:#05X}")]
+    StackUnderflow(u16),
+    #[error("attempted to access memory out of bounds at {0:#05X}")]
+    OutOfBounds(u16),
+}
+
+/// Whether an instruction read from or wrote to memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A range of memory accessed by an instruction, not counting the fetch of the instruction itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub start: u16,
+    pub len: u16,
+    /// The address of the instruction that made the access
+    pub pc: u16,
+}
+
+impl MemoryAccess {
+    /// Check whether the access touches any of the addresses from start to end inclusive
+    pub fn overlaps(&self, start: u16, end: u16) -> bool {
+        self.len > 0 && self.start <= end && start <= self.start + (self.len - 1)
+    }
+}
+
+/// A headless chip8 interpreter. Presentation (drawing the display, reading the keypad, pacing
+/// the clock) is left to whoever is driving it
+pub struct Chip8 {
+    pub memory: [u8; MEMORY_SIZE],
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub display: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    pub keys: [bool; 16],
+    last_access: Option<MemoryAccess>,
+    rng_state: u32,
+}
+
+impl Chip8 {
+    /// Create an interpreter with the rom loaded at the start of program memory
+    pub fn new(rom: &[u8]) -> Result<Chip8, EmulatorError> {
+        let start = PROGRAM_START as usize;
+        if rom.len() > MEMORY_SIZE - start {
+            return Err(EmulatorError::RomTooLarge(rom.len()));
+        }
+
+        let mut memory = [0; MEMORY_SIZE];
+        memory[FONT_START as usize..FONT_START as usize + FONT.len()].copy_from_slice(&FONT);
+        memory[start..start + rom.len()].copy_from_slice(rom);
+
+        Ok(Chip8 {
+            memory,
+            v: [0; 16],
+            i: 0,
+            pc: PROGRAM_START,
+            stack: Vec::with_capacity(STACK_DEPTH),
+            delay_timer: 0,
+            sound_timer: 0,
+            display: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            keys: [false; 16],
+            last_access: None,
+            // any nonzero seed will do for xorshift
+            rng_state: 0x2545_F491,
+        })
+    }
+
+    /// Read the opcode stored at the given address
+    pub fn opcode_at(&self, addr: u16) -> Result<u16, EmulatorError> {
+        let addr = addr as usize;
+        if addr + 1 >= MEMORY_SIZE {
+            return Err(EmulatorError::OutOfBounds(addr as u16));
+        }
+        Ok(u16::from_be_bytes([
+            self.memory[addr],
+            self.memory[addr + 1],
+        ]))
+    }
+
+    /// Decrement the timers, which should happen at 60Hz
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// The memory accessed by the most recently executed instruction, if any
+    pub fn last_access(&self) -> Option<MemoryAccess> {
+        self.last_access
+    }
+
+    /// Fetch, decode, and execute a single instruction
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        let pc = self.pc;
+        self.last_access = None;
+        let op = self.opcode_at(pc)?;
+        self.pc += 2;
+
+        let nnn = op & 0x0FFF;
+        let kk = (op & 0x00FF) as u8;
+        let n = (op & 0x000F) as u8;
+        let x = ((op & 0x0F00) >> 8) as usize;
+        let y = ((op & 0x00F0) >> 4) as usize;
+
+        match (op & 0xF000) >> 12 {
+            0x0 => match op {
+                0x00E0 => self.display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+                0x00EE => self.pc = self.stack.pop().ok_or(EmulatorError::StackUnderflow(pc))?,
+                // machine code routines don't exist on an interpreter
+                _ => (),
+            },
+            0x1 => self.pc = nnn,
+            0x2 => {
+                if self.stack.len() == STACK_DEPTH {
+                    return Err(EmulatorError::StackOverflow(pc));
+                }
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            }
+            0x3 => self.skip_if(self.v[x] == kk),
+            0x4 => self.skip_if(self.v[x] != kk),
+            0x5 if n == 0 => self.skip_if(self.v[x] == self.v[y]),
+            0x6 => self.v[x] = kk,
+            0x7 => self.v[x] = self.v[x].wrapping_add(kk),
+            0x8 => match n {
+                0x0 => self.v[x] = self.v[y],
+                0x1 => self.v[x] |= self.v[y],
+                0x2 => self.v[x] &= self.v[y],
+                0x3 => self.v[x] ^= self.v[y],
+                0x4 => {
+                    let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
+                    self.v[x] = sum;
+                    self.v[0xF] = carry as u8;
+                }
+                0x5 => {
+                    let (diff, borrow) = self.v[x].overflowing_sub(self.v[y]);
+                    self.v[x] = diff;
+                    self.v[0xF] = !borrow as u8;
+                }
+                // shifts act on Vx in place, which is what `SHR Vx` without a Vy reads as
+                0x6 => {
+                    let out = self.v[x] & 0x1;
+                    self.v[x] >>= 1;
+                    self.v[0xF] = out;
+                }
+                0x7 => {
+                    let (diff, borrow) = self.v[y].overflowing_sub(self.v[x]);
+                    self.v[x] = diff;
+                    self.v[0xF] = !borrow as u8;
+                }
+                0xE => {
+                    let out = self.v[x] >> 7;
+                    self.v[x] <<= 1;
+                    self.v[0xF] = out;
+                }
+                _ => return Err(EmulatorError::UnknownOpcode { op, pc }),
+            },
+            0x9 if n == 0 => self.skip_if(self.v[x] != self.v[y]),
+            0xA => self.i = nnn,
+            0xB => self.pc = nnn + self.v[0] as u16,
+            0xC => self.v[x] = self.next_random() & kk,
+            0xD => {
+                self.draw(x, y, n)?;
+                self.record_access(AccessKind::Read, n as usize, pc);
+            }
+            0xE => match kk {
+                0x9E => self.skip_if(self.keys[(self.v[x] & 0xF) as usize]),
+                0xA1 => self.skip_if(!self.keys[(self.v[x] & 0xF) as usize]),
+                _ => return Err(EmulatorError::UnknownOpcode { op, pc }),
+            },
+            0xF => match kk {
+                0x07 => self.v[x] = self.delay_timer,
+                0x0A => match self.keys.iter().position(|&k| k) {
+                    Some(key) => self.v[x] = key as u8,
+                    // keep executing this instruction until a key is pressed
+                    None => self.pc = pc,
+                },
+                0x15 => self.delay_timer = self.v[x],
+                0x18 => self.sound_timer = self.v[x],
+                0x1E => self.i = self.i.wrapping_add(self.v[x] as u16),
+                0x29 => self.i = FONT_START + (self.v[x] & 0xF) as u16 * 5,
+                0x33 => {
+                    let addr = self.checked_range(self.i, 3)?;
+                    self.record_access(AccessKind::Write, 3, pc);
+                    self.memory[addr] = self.v[x] / 100;
+                    self.memory[addr + 1] = self.v[x] / 10 % 10;
+                    self.memory[addr + 2] = self.v[x] % 10;
+                }
+                0x55 => {
+                    let addr = self.checked_range(self.i, x + 1)?;
+                    self.record_access(AccessKind::Write, x + 1, pc);
+                    self.memory[addr..=addr + x].copy_from_slice(&self.v[..=x]);
+                }
+                0x65 => {
+                    let addr = self.checked_range(self.i, x + 1)?;
+                    self.record_access(AccessKind::Read, x + 1, pc);
+                    self.v[..=x].copy_from_slice(&self.memory[addr..=addr + x]);
+                }
+                _ => return Err(EmulatorError::UnknownOpcode { op, pc }),
+            },
+            _ => return Err(EmulatorError::UnknownOpcode { op, pc }),
+        }
+
+        Ok(())
+    }
+
+    /// Skip the next instruction if the condition holds
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.pc += 2;
+        }
+    }
+
+    /// Remember that the current instruction accessed len bytes starting at I
+    fn record_access(&mut self, kind: AccessKind, len: usize, pc: u16) {
+        self.last_access = Some(MemoryAccess {
+            kind,
+            start: self.i,
+            len: len as u16,
+            pc,
+        });
+    }
+
+    /// Make sure a range of memory starting at addr is in bounds, returning addr as an index
+    fn checked_range(&self, addr: u16, len: usize) -> Result<usize, EmulatorError> {
+        if addr as usize + len > MEMORY_SIZE {
+            Err(EmulatorError::OutOfBounds(addr))
+        } else {
+            Ok(addr as usize)
+        }
+    }
+
+    /// Draw an n byte sprite from I at (Vx, Vy), setting VF on collision
+    fn draw(&mut self, x: usize, y: usize, n: u8) -> Result<(), EmulatorError> {
+        let addr = self.checked_range(self.i, n as usize)?;
+        // the starting position wraps but the sprite itself is clipped at the edges
+        let x0 = self.v[x] as usize % DISPLAY_WIDTH;
+        let y0 = self.v[y] as usize % DISPLAY_HEIGHT;
+        self.v[0xF] = 0;
+
+        for (row, byte) in self.memory[addr..addr + n as usize].iter().enumerate() {
+            let py = y0 + row;
+            if py >= DISPLAY_HEIGHT {
+                break;
+            }
+            for col in 0..8 {
+                let px = x0 + col;
+                if px >= DISPLAY_WIDTH {
+                    break;
+                }
+                if byte & (0x80 >> col) != 0 {
+                    if self.display[py][px] {
+                        self.v[0xF] = 1;
+                    }
+                    self.display[py][px] ^= true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Generate a pseudorandom byte with xorshift
+    fn next_random(&mut self) -> u8 {
+        let mut state = self.rng_state;
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        self.rng_state = state;
+        (state >> 24) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An interpreter that has executed the given number of instructions of a rom made of opcodes
+    fn run(opcodes: &[u16], steps: usize) -> Chip8 {
+        let rom: Vec<u8> = opcodes.iter().flat_map(|op| op.to_be_bytes()).collect();
+        let mut chip8 = Chip8::new(&rom).unwrap();
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn arithmetic_sets_vf() {
+        // LD V0, 0xFF; LD V1, 2; ADD V0, V1
+        let chip8 = run(&[0x60FF, 0x6102, 0x8014], 3);
+        assert_eq!((chip8.v[0], chip8.v[0xF]), (1, 1));
+        // LD V0, 1; LD V1, 2; SUB V0, V1
+        let chip8 = run(&[0x6001, 0x6102, 0x8015], 3);
+        assert_eq!((chip8.v[0], chip8.v[0xF]), (0xFF, 0));
+        // LD V0, 0x81; SHL V0
+        let chip8 = run(&[0x6081, 0x800E], 2);
+        assert_eq!((chip8.v[0], chip8.v[0xF]), (0x02, 1));
+        // ADD Vx, byte wraps without touching VF
+        let chip8 = run(&[0x60FF, 0x7002], 2);
+        assert_eq!((chip8.v[0], chip8.v[0xF]), (1, 0));
+    }
+
+    #[test]
+    fn skips_calls_and_returns() {
+        // SE V0, 0 skips the LD V1, 1
+        let chip8 = run(&[0x3000, 0x6101, 0x6202], 2);
+        assert_eq!((chip8.v[1], chip8.v[2]), (0, 2));
+        // CALL 0x206; LD V0, 1; JP 0x204; LD V1, 1; RET
+        let chip8 = run(&[0x2206, 0x6001, 0x1204, 0x6101, 0x00EE], 4);
+        assert_eq!((chip8.v[0], chip8.v[1]), (1, 1));
+        assert!(chip8.stack.is_empty());
+    }
+
+    #[test]
+    fn stack_errors() {
+        let mut chip8 = run(&[0x00EE], 0);
+        assert!(matches!(
+            chip8.step(),
+            Err(EmulatorError::StackUnderflow(PROGRAM_START))
+        ));
+        // CALL itself until the stack is full
+        let mut chip8 = run(&[0x2200], STACK_DEPTH);
+        assert!(matches!(
+            chip8.step(),
+            Err(EmulatorError::StackOverflow(PROGRAM_START))
+        ));
+    }
+
+    #[test]
+    fn memory_accesses() {
+        // LD V0, 123; LD I, 0x300; LD B, V0
+        let chip8 = run(&[0x607B, 0xA300, 0xF033], 3);
+        assert_eq!(chip8.memory[0x300..0x303], [1, 2, 3]);
+        let access = chip8.last_access().unwrap();
+        assert_eq!(
+            (access.kind, access.start, access.len),
+            (AccessKind::Write, 0x300, 3)
+        );
+        assert!(access.overlaps(0x302, 0x310));
+        assert!(!access.overlaps(0x303, 0x310));
+        // LD I, 0xFFF; LD [I], V1
+        let mut chip8 = run(&[0xAFFF, 0xF155], 1);
+        assert!(matches!(
+            chip8.step(),
+            Err(EmulatorError::OutOfBounds(0xFFF))
+        ));
+    }
+
+    #[test]
+    fn drawing_collides() {
+        // LD V0, 0; LD F, V0; DRW V0, V0, 5 twice
+        let chip8 = run(&[0x6000, 0xF029, 0xD005], 3);
+        assert!(chip8.display[0][..4].iter().all(|&pixel| pixel));
+        assert_eq!(chip8.v[0xF], 0);
+        let chip8 = run(&[0x6000, 0xF029, 0xD005, 0xD005], 4);
+        assert!(chip8.display.iter().flatten().all(|&pixel| !pixel));
+        assert_eq!(chip8.v[0xF], 1);
+    }
+
+    #[test]
+    fn waits_for_a_key() {
+        // LD V0, K
+        let mut chip8 = run(&[0xF00A], 1);
+        assert_eq!(chip8.pc, PROGRAM_START);
+        chip8.keys[7] = true;
+        chip8.step().unwrap();
+        assert_eq!((chip8.pc, chip8.v[0]), (PROGRAM_START + 2, 7));
+    }
+
+    #[test]
+    fn unknown_opcodes_and_large_roms() {
+        let mut chip8 = run(&[0x5001], 0);
+        assert!(matches!(
+            chip8.step(),
+            Err(EmulatorError::UnknownOpcode { op: 0x5001, .. })
+        ));
+        let rom = [0; MEMORY_SIZE];
+        assert!(matches!(
+            Chip8::new(&rom),
+            Err(EmulatorError::RomTooLarge(MEMORY_SIZE))
+        ));
+    }
+}
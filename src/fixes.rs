@@ -0,0 +1,106 @@
+use serde_json::{json, Value};
+
+use super::assemble::parse::{self, AsmArgParseError, AsmArgument};
+use super::assemble::{self, AssembleError};
+use super::outline;
+use super::preprocess::Located;
+use super::tokenize;
+
+/// How many edits away a misspelled name can be from a real one and still be suggested
+const MAX_DISTANCE: usize = 2;
+
+/// A replacement for part of the line a diagnostic is on that would fix it, for editors to offer as a quick fix
+#[derive(Debug)]
+pub struct Fix {
+    /// The zero-indexed range of characters on the line to replace
+    pub start: usize,
+    pub end: usize,
+    pub replacement: String,
+}
+
+impl Fix {
+    pub fn to_json(&self) -> Value {
+        json!({ "start": self.start, "end": self.end, "replacement": self.replacement })
+    }
+}
+
+/// A fix for an error, when there's an obvious one: a misspelled mnemonic or symbol, or a byte too big to fit that
+/// was most likely meant to be masked down to its low byte. The text is that of the file the error was found in,
+/// which is the included file it came from if it did
+pub fn suggest(text: &str, located: &Located<AssembleError>) -> Option<Fix> {
+    let number = located
+        .origin
+        .as_ref()
+        .map_or(located.line, |origin| origin.line);
+    let line = tokenize::tokenize_line(text.lines().nth(number.checked_sub(1)?)?);
+    let fix = |token: &tokenize::Token, replacement: String| Fix {
+        start: token.column - 1,
+        end: token.column - 1 + token.text.len(),
+        replacement,
+    };
+
+    match &located.error {
+        AssembleError::UnknownOp(_) => {
+            let head = line.tokens.first()?;
+            let closest = closest(&head.text.to_ascii_uppercase(), assemble::mnemonics())?;
+            // keep to the case the rest of the source is written in
+            if head.text.chars().any(|c| c.is_ascii_lowercase()) {
+                Some(fix(head, closest.to_ascii_lowercase()))
+            } else {
+                Some(fix(head, closest.to_string()))
+            }
+        }
+        AssembleError::BadParse(AsmArgParseError::InvalidByte(value)) => {
+            let value: usize = value.parse().ok()?;
+            let token = line.tokens[1..].iter().find(|token| {
+                matches!(parse::parse_asm_arg(token.text), Ok(AsmArgument::Numeric(n)) if n as usize == value)
+            })?;
+            Some(fix(token, format!("{:#04X}", value & 0xFF)))
+        }
+        // a misspelled name starting with a V is taken for a register
+        AssembleError::BadParse(
+            AsmArgParseError::NotANumber(_) | AsmArgParseError::InvalidRegister(_),
+        ) => {
+            let declarations = outline::declarations(text);
+            let names = || declarations.iter().map(|declaration| declaration.name);
+            // the argument that isn't a register, number, or declared symbol is the one that's misspelled
+            let token = line.tokens[1..].iter().find(|token| {
+                parse::parse_asm_arg(token.text).is_err()
+                    && !token.text.starts_with('#')
+                    && !names().any(|name| name == token.text)
+            })?;
+            Some(fix(token, closest(token.text, names())?.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// The candidate closest to a word, if any is close enough to be a likely misspelling of it
+fn closest<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .filter(|&candidate| candidate != word)
+        // typos rarely get the first letter wrong, so among equally close names prefer ones starting the same way
+        .map(|candidate| {
+            let first_differs = word.chars().next() != candidate.chars().next();
+            (distance(word, candidate), first_differs, candidate)
+        })
+        .filter(|&(distance, ..)| distance <= MAX_DISTANCE)
+        .min()
+        .map(|(.., candidate)| candidate)
+}
+
+/// The Levenshtein distance between two words: how many characters have to be inserted, removed, or swapped for
+/// another to turn one into the other
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use super::assemble;
+use super::preprocess;
+use super::tokenize;
+use super::RunError;
+
+/// How far instructions under a label are indented
+const INDENT: &str = "  ";
+
+/// A line of formatted source, before trailing comments are lined up
+struct Formatted<'a> {
+    code: String,
+    comment: Option<&'a str>,
+    /// Whether the comment follows code, rather than being on a line of its own
+    trailing: bool,
+}
+
+/// Format source the canonical way: mnemonics in uppercase, instructions indented under the label they follow,
+/// arguments separated by a comma and a space, and the comments after code in each paragraph lined up. Everything else,
+/// like directives, sprite rows, blank lines, and the text of comments, is kept as it's written, apart from whitespace
+/// at the ends of lines
+pub fn format_source(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut under_label = false;
+    for source in text.lines() {
+        let (code, comment) = match tokenize::comment_start(source) {
+            Some(i) => (&source[..i], Some(source[i..].trim_end())),
+            None => (source, None),
+        };
+        let line = tokenize::tokenize_line(code);
+        let indent = if under_label { INDENT } else { "" };
+        let code = match line.tokens.split_first() {
+            // comments on lines of their own keep to the left edge if they started there
+            None if source.starts_with(char::is_whitespace) && comment.is_some() => {
+                indent.to_string()
+            }
+            None => String::new(),
+            Some((label, [])) if label.text.ends_with(':') => {
+                under_label = true;
+                label.text.to_string()
+            }
+            Some((mnemonic, args))
+                if assemble::is_mnemonic(mnemonic.text)
+                    || preprocess::is_pseudo_op(mnemonic.text) =>
+            {
+                let mut code = format!("{indent}{}", mnemonic.text.to_uppercase());
+                if !args.is_empty() {
+                    let args: Vec<&str> = args.iter().map(|arg| arg.text).collect();
+                    code.push(' ');
+                    code.push_str(&args.join(", "));
+                }
+                code
+            }
+            Some(_) => code.trim_end().to_string(),
+        };
+        lines.push(Formatted {
+            code,
+            comment,
+            trailing: !line.tokens.is_empty(),
+        });
+    }
+    while lines
+        .last()
+        .is_some_and(|line| line.code.is_empty() && line.comment.is_none())
+    {
+        lines.pop();
+    }
+
+    let mut formatted = String::with_capacity(text.len());
+    // paragraphs are separated by blank lines
+    let paragraphs = lines.split(|line| line.code.is_empty() && line.comment.is_none());
+    for (i, paragraph) in paragraphs.enumerate() {
+        if i > 0 {
+            formatted.push('\n');
+        }
+        let column = paragraph
+            .iter()
+            .filter(|line| line.trailing && line.comment.is_some())
+            .map(|line| line.code.chars().count() + 1)
+            .max()
+            .unwrap_or_default();
+        for line in paragraph {
+            formatted.push_str(&line.code);
+            if let Some(comment) = line.comment {
+                if line.trailing {
+                    let width = line.code.chars().count();
+                    formatted.push_str(&" ".repeat(column - width));
+                }
+                formatted.push_str(comment);
+            }
+            formatted.push('\n');
+        }
+    }
+    formatted
+}
+
+/// Format each file in place, or stdin to stdout if there aren't any. With `check`, nothing is written, and every
+/// file that isn't formatted already is listed in the error
+pub fn run(inputs: &[PathBuf], check: bool) -> Result<(), RunError> {
+    if inputs.is_empty() {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        let formatted = format_source(&text);
+        if check {
+            return match formatted == text {
+                true => Ok(()),
+                false => Err(RunError::Unformatted(vec![PathBuf::from("<stdin>")])),
+            };
+        }
+        return Ok(io::stdout().write_all(formatted.as_bytes())?);
+    }
+
+    let mut unformatted = Vec::new();
+    for input in inputs {
+        let text = fs::read_to_string(input)?;
+        let formatted = format_source(&text);
+        if formatted == text {
+            continue;
+        }
+        match check {
+            true => unformatted.push(input.clone()),
+            false => fs::write(input, formatted)?,
+        }
+    }
+    match unformatted.is_empty() {
+        true => Ok(()),
+        false => Err(RunError::Unformatted(unformatted)),
+    }
+}
@@ -0,0 +1,51 @@
+use std::borrow::Cow;
+
+use clap::ValueEnum;
+
+/// How many bytes of the rom go in each Intel HEX data record
+const IHEX_RECORD_SIZE: usize = 16;
+
+/// How the rom is written out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// The raw bytes, which is what emulators load
+    #[default]
+    Bin,
+    /// Intel HEX records, for flash tools and hardware builds
+    Ihex,
+}
+
+impl Format {
+    /// The rom written out in this format, given the address it's loaded at
+    pub fn encode(self, rom: &[u8], base: usize) -> Cow<'_, [u8]> {
+        match self {
+            Format::Bin => Cow::Borrowed(rom),
+            Format::Ihex => Cow::Owned(ihex(rom, base).into_bytes()),
+        }
+    }
+}
+
+/// Intel HEX data records for the rom, placed from the address it's loaded at, followed by the end of file record.
+/// Every address fits in 16 bits, so no extended address records are needed
+fn ihex(rom: &[u8], base: usize) -> String {
+    let mut hex = String::new();
+    for (i, chunk) in rom.chunks(IHEX_RECORD_SIZE).enumerate() {
+        let addr = (base + i * IHEX_RECORD_SIZE) as u16;
+        record(&mut hex, addr, 0x00, chunk);
+    }
+    record(&mut hex, 0, 0x01, &[]);
+    hex
+}
+
+/// Write one record: its length, address, type, and data, then a checksum that makes all of its bytes sum to 0
+fn record(hex: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let [high, low] = addr.to_be_bytes();
+    let header = [data.len() as u8, high, low, kind];
+    let sum = header
+        .iter()
+        .chain(data)
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    hex.push(':');
+    hex.extend(header.iter().chain(data).map(|byte| format!("{byte:02X}")));
+    hex.push_str(&format!("{:02X}\n", sum.wrapping_neg()));
+}
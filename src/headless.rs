@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use super::diagnostic::Reporter;
+use super::emulator::{Chip8, CYCLES_PER_FRAME};
+use super::input;
+use super::screen;
+use super::RunError;
+
+/// Why a headless run came to an end
+enum StopReason {
+    ReachedLabel(String),
+    FrameLimit,
+}
+
+/// Assemble a program and run it in the emulator without a display until it reaches a label or
+/// runs for a number of frames, whichever comes first, then print the state of the machine and
+/// optionally dump the display to an image. When watching, every file the source included is put in `included` once
+/// it's assembled
+pub fn run(
+    input: &Path,
+    run_until: Option<&str>,
+    frames: Option<u32>,
+    dump_screen: Option<&Path>,
+    reporter: &Reporter,
+    included: Option<&mut Vec<PathBuf>>,
+) -> Result<(), RunError> {
+    let source = input::read_source(input)?;
+    let program = super::assemble_program(&source, &super::options_for(input))?;
+    if let Some(included) = included {
+        included.clone_from(&program.included);
+    }
+    program.print_warnings(reporter);
+
+    let until = match run_until {
+        Some(label) => match program.labels.get(label) {
+            Some(&addr) => Some(addr as u16),
+            None => return Err(RunError::UnknownLabel(label.to_string())),
+        },
+        None => None,
+    };
+
+    let mut chip8 = Chip8::new(&program.rom)?;
+
+    let mut frame = 0;
+    let reason = 'run: loop {
+        if Some(frame) == frames {
+            break StopReason::FrameLimit;
+        }
+        for _ in 0..CYCLES_PER_FRAME {
+            if Some(chip8.pc) == until {
+                break 'run StopReason::ReachedLabel(run_until.unwrap_or_default().to_string());
+            }
+            chip8.step()?;
+        }
+        chip8.tick_timers();
+        frame += 1;
+    };
+
+    match reason {
+        StopReason::ReachedLabel(label) => print!("reached `{label}`"),
+        StopReason::FrameLimit => print!("stopped"),
+    }
+    print!(" at {:#05X}", chip8.pc);
+    if let Some(line) = program.line_of(chip8.pc) {
+        print!(" (line {line})");
+    }
+    println!(" after {frame} frames");
+    print_state(&chip8);
+
+    if let Some(path) = dump_screen {
+        screen::dump(&chip8.display, path)?;
+    }
+
+    Ok(())
+}
+
+/// Print the registers, timers, and stack of the machine
+pub fn print_state(chip8: &Chip8) {
+    for (offset, registers) in chip8.v.chunks(8).enumerate() {
+        let registers = registers
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("V{:X}={v:#04X}", offset * 8 + i))
+            .collect::<Vec<String>>();
+        println!("{}", registers.join(" "));
+    }
+    println!(
+        "I={:#05X} DT={:#04X} ST={:#04X} SP={}",
+        chip8.i,
+        chip8.delay_timer,
+        chip8.sound_timer,
+        chip8.stack.len()
+    );
+}
@@ -0,0 +1,47 @@
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+/// The text of a source file, either read into memory or, with the `mmap` feature, mapped
+/// straight from disk so huge generated sources aren't held in memory twice
+pub enum SourceText {
+    Owned(String),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Deref for SourceText {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            SourceText::Owned(text) => text,
+            // SAFETY: the mapping is checked to be valid utf-8 when it's created in read_source
+            #[cfg(feature = "mmap")]
+            SourceText::Mapped(map) => unsafe { std::str::from_utf8_unchecked(map) },
+        }
+    }
+}
+
+/// Read a source file into memory
+#[cfg(not(feature = "mmap"))]
+pub fn read_source(path: &Path) -> io::Result<SourceText> {
+    Ok(SourceText::Owned(fs::read_to_string(path)?))
+}
+
+/// Map a source file into memory. Empty files can't be mapped on every platform, so they're read
+/// the usual way instead
+#[cfg(feature = "mmap")]
+pub fn read_source(path: &Path) -> io::Result<SourceText> {
+    let file = fs::File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(SourceText::Owned(String::new()));
+    }
+
+    // SAFETY: the file could be changed underneath us while mapped, which is no different than the
+    // user editing it halfway through a read_to_string, so we accept the same risk
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    std::str::from_utf8(&map).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(SourceText::Mapped(map))
+}
@@ -0,0 +1,120 @@
+use thiserror::Error;
+
+use super::assemble::ir::Instruction;
+use super::assemble::{self, AssembleError, Buffers};
+use super::disassemble;
+use super::symbols::SymbolTable;
+use super::tokenize;
+
+/// A violation of the round-trip invariant between the assembler and the disassembler
+#[derive(Debug, Error)]
+pub enum InvariantError {
+    #[error("{op:#06X} disassembled to `{text}`, which failed to assemble: {source}")]
+    Unassemblable {
+        op: u16,
+        text: String,
+        #[source]
+        source: AssembleError,
+    },
+    #[error("{op:#06X} disassembled to `{text}`, which reassembled to {reassembled:#06X}")]
+    Mismatch {
+        op: u16,
+        text: String,
+        reassembled: u16,
+    },
+    #[error("`{text}` failed to assemble: {source}")]
+    InvalidTemplate {
+        text: String,
+        #[source]
+        source: AssembleError,
+    },
+}
+
+/// Decode every representable opcode and ensure that it re-encodes to itself
+pub fn check_decode_encode() -> Result<(), InvariantError> {
+    for op in 0..=u16::MAX {
+        check_opcode(op)?;
+    }
+    Ok(())
+}
+
+/// Assemble every instruction the assembler accepts in every operand combination and ensure that
+/// decoding the result re-encodes to the same opcode
+pub fn check_encode_decode() -> Result<(), InvariantError> {
+    for text in assembler_output_space() {
+        let line = Instruction::new(tokenize::tokenize_line(&text));
+        let op =
+            assemble::assemble_instruction(&line, &SymbolTable::default(), &mut Buffers::default())
+                .map_err(|source| InvariantError::InvalidTemplate {
+                    text: text.clone(),
+                    source,
+                })?;
+        check_opcode(op)?;
+    }
+    Ok(())
+}
+
+/// Ensure that a single opcode survives a trip through the disassembler and back
+pub fn check_opcode(op: u16) -> Result<(), InvariantError> {
+    let text = disassemble::disassemble_instruction(op);
+    let line = Instruction::new(tokenize::tokenize_line(&text));
+    match assemble::assemble_instruction(&line, &SymbolTable::default(), &mut Buffers::default()) {
+        Ok(reassembled) if reassembled == op => Ok(()),
+        Ok(reassembled) => Err(InvariantError::Mismatch {
+            op,
+            text,
+            reassembled,
+        }),
+        Err(source) => Err(InvariantError::Unassemblable { op, text, source }),
+    }
+}
+
+/// Build the text of every instruction form the assembler accepts, with every possible operand
+fn assembler_output_space() -> Vec<String> {
+    let mut out = vec![String::from("CLS"), String::from("RET")];
+
+    for addr in 0..=0xFFF {
+        out.push(format!("SYS {addr:#X}"));
+        out.push(format!("JP {addr:#X}"));
+        out.push(format!("JP V0, {addr:#X}"));
+        out.push(format!("CALL {addr:#X}"));
+        out.push(format!("LD I, {addr:#X}"));
+    }
+
+    for x in 0..=0xF {
+        for byte in 0..=0xFF {
+            for op in ["SE", "SNE", "LD", "ADD", "RND"] {
+                out.push(format!("{op} V{x:X}, {byte:#X}"));
+            }
+        }
+
+        for y in 0..=0xF {
+            for op in [
+                "SE", "SNE", "LD", "OR", "AND", "XOR", "ADD", "SUB", "SUBN", "SHR", "SHL",
+            ] {
+                out.push(format!("{op} V{x:X}, V{y:X}"));
+            }
+            for nibble in 0..=0xF {
+                out.push(format!("DRW V{x:X}, V{y:X}, {nibble:#X}"));
+            }
+        }
+
+        // the optional second register of the shifts
+        out.push(format!("SHR V{x:X}"));
+        out.push(format!("SHL V{x:X}"));
+
+        out.push(format!("SKP V{x:X}"));
+        out.push(format!("SKNP V{x:X}"));
+        out.push(format!("LD V{x:X}, DT"));
+        out.push(format!("LD V{x:X}, K"));
+        out.push(format!("LD DT, V{x:X}"));
+        out.push(format!("LD ST, V{x:X}"));
+        out.push(format!("ADD I, V{x:X}"));
+        out.push(format!("LD F, V{x:X}"));
+        out.push(format!("LD B, V{x:X}"));
+        out.push(format!("LD [I], V{x:X}"));
+        out.push(format!("LD V{x:X}, [I]"));
+    }
+
+    out
+}
@@ -1,47 +1,402 @@
+//! An assembler for the CHIP-8. Without the default `std` feature, only [`assemble_snippet`] is built, which needs
+//! nothing more than `alloc`, so programs can be assembled on the devices that run them
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
 use std::fs;
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
+#[cfg(feature = "std")]
+use std::io::{self, BufWriter, Read, Write};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::process::Command;
 
-use clap::Parser;
+#[cfg(feature = "std")]
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "std")]
 use thiserror::Error;
 
 mod preprocess;
-use preprocess::PreprocessingError;
+use preprocess::{InstructionText, Located};
+#[cfg(feature = "std")]
+pub use preprocess::{Options, Target};
+#[cfg(feature = "std")]
+use preprocess::{PreprocessingError, PreprocessingWarning};
 mod assemble;
+#[cfg(feature = "std")]
+mod bitmap;
+#[cfg(feature = "std")]
+mod build_id;
+#[cfg(feature = "std")]
+mod callgraph;
+mod snippet;
+pub use snippet::{assemble_snippet, SnippetError};
+mod symbols;
+mod tokenize;
+#[cfg(feature = "std")]
+mod transport;
 use assemble::AssembleError;
+#[cfg(feature = "std")]
+use transport::TransportError;
+#[cfg(feature = "std")]
+mod dap;
+#[cfg(feature = "std")]
+mod debugsym;
+#[cfg(feature = "std")]
+mod diagnostic;
+#[cfg(feature = "std")]
+pub use diagnostic::{Diagnostic, Reporter, Severity};
+#[cfg(feature = "std")]
+mod disassemble;
+#[cfg(feature = "std")]
+mod doc;
+#[cfg(feature = "std")]
+mod emulator;
+#[cfg(feature = "std")]
+mod fixes;
+#[cfg(feature = "std")]
+mod fmt;
+#[cfg(feature = "std")]
+mod format;
+#[cfg(feature = "std")]
+use emulator::EmulatorError;
+#[cfg(feature = "std")]
+mod headless;
+#[cfg(feature = "std")]
+mod input;
+#[cfg(feature = "std")]
+mod lint;
+#[cfg(feature = "std")]
+mod listing;
+#[cfg(feature = "std")]
+mod lsp;
+#[cfg(feature = "std")]
+mod mapfile;
+#[cfg(feature = "std")]
+mod object;
+#[cfg(feature = "std")]
+use object::ObjectError;
+#[cfg(feature = "std")]
+mod optimize;
+#[cfg(feature = "std")]
+mod outline;
+#[cfg(feature = "std")]
+mod plugins;
+#[cfg(feature = "std")]
+use plugins::PluginsError;
+#[cfg(feature = "std")]
+mod project;
+#[cfg(feature = "std")]
+use project::ProjectError;
+#[cfg(feature = "std")]
+mod screen;
+#[cfg(feature = "std")]
+mod size;
+#[cfg(feature = "std")]
+mod sourcemap;
+#[cfg(feature = "std")]
+mod stages;
+#[cfg(feature = "std")]
+mod symfile;
+#[cfg(feature = "std")]
+mod terminal;
+#[cfg(feature = "std")]
+mod watch;
+#[cfg(feature = "std")]
+use screen::ScreenDumpError;
+#[cfg(feature = "std")]
+use symfile::SymbolFileError;
+/// Exhaustive round-trip checks between the assembler and the disassembler, for validating dialects
+#[cfg(feature = "invariants")]
+pub mod invariants;
 
 #[derive(Parser)]
 #[command(name = "ch8asmcodechange")]
 #[command(author = "Daniel Gysi <danielgysi@protonmail.com")]
 #[command(about = "Basic assembler for the chip8 architecture")]
 #[command(version, long_about=None)]
+#[cfg(feature = "std")]
 struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>,
     /// The file from which to read the assembly instrucions to be assembled. If none is provided, stdin is used instead.
     #[arg(short, long)]
     input: Option<PathBuf>,
     /// The file into which the assembled bytes will be written. If none is provided, stdout is used instead.
     #[arg(short, long)]
     output: Option<PathBuf>,
+    /// After a successful build, run this emulator command on the output file. Any `{}` in the command is replaced with the output path, otherwise the path is appended as the last argument. Requires an output file.
+    #[arg(long, value_name = "COMMAND")]
+    run_with: Option<String>,
+    /// Place only one copy of sprites with identical bytes, and report how many bytes it saved
+    #[arg(long)]
+    dedup_sprites: bool,
+    /// Print every sprite as pixel art next to its bytes, so the art can be reviewed without running it
+    #[arg(long)]
+    preview_sprites: bool,
+    /// What to write to the output: the assembled rom, the declarations, token classifications, and diagnostics of the source as JSON for editor plugins, or the address, source line, and text of every instruction after a stage of assembly, for seeing how aliases, sprites, and labels were turned into code
+    #[arg(long, value_enum, default_value_t = Emit::Rom)]
+    emit: Emit,
+    /// Shrink the rom by removing unreachable instructions or rewriting short runs of instructions into fewer, and report how many bytes it saved
+    #[arg(long, value_enum, value_name = "OPTIMIZATION")]
+    optimize: Vec<optimize::Optimization>,
+    /// Leave out one of the peephole optimization's rewrite rules
+    #[arg(long, value_enum, value_name = "RULE")]
+    disable_rule: Vec<optimize::Rule>,
+    /// Pad the rom out to this many bytes
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pad_to: Option<usize>,
+    /// What --pad-to pads with: `byte B`, `pattern B ...` repeated from the end of the program, or `random SEED`. Defaults to 0x00 bytes
+    #[arg(long, value_name = "FILL", value_parser = parse_fill, requires = "pad_to")]
+    pad_fill: Option<preprocess::Fill>,
+    /// Write an 8 byte id, hashed from the source, assembler version, and options, over the bytes at this label or address, so a rom can be traced back to what it was built from. Reserve the space with something like an 8 byte data block
+    #[arg(long, value_name = "LOCATION")]
+    build_id: Option<String>,
+    /// Write a relocatable object file instead of a rom, leaving labels from other files to be resolved by `link`
+    #[arg(short = 'c', long, conflicts_with_all = ["run_with", "emit", "optimize", "pad_to", "build_id", "export_symbols", "listing", "map", "debug_symbols", "source_map"])]
+    compile: bool,
+    /// Write the address of every label to this file, so another build can refer to them with --import-symbols
+    #[arg(long, value_name = "FILE")]
+    export_symbols: Option<PathBuf>,
+    /// Write a listing to this file: every line of the source, comments and all, next to the address and bytes it
+    /// assembled to
+    #[arg(long, value_name = "FILE")]
+    listing: Option<PathBuf>,
+    /// Write a map to this file: the address of every label and sprite, and the value of every constant and alias
+    #[arg(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+    /// Write a symbol file for emulators and debuggers to this file, like `game.sym`: the address of every label and
+    /// where each run of sprites and data sits, so it isn't disassembled as code
+    #[arg(long, value_name = "FILE")]
+    debug_symbols: Option<PathBuf>,
+    /// Write a JSON source map to this file, giving the file and line every address of the rom came from, for
+    /// debuggers to step through the source
+    #[arg(long, value_name = "FILE")]
+    source_map: Option<PathBuf>,
+    /// Define the labels in a file written by --export-symbols, so an overlay or patch can call into a separately
+    /// assembled rom by name
+    #[arg(long, value_name = "FILE")]
+    import_symbols: Vec<PathBuf>,
+    /// The system to build for, which picks the code assembled from `.if TARGET == ...` blocks
+    #[arg(long, value_enum, default_value_t = preprocess::Target::Chip8)]
+    target: preprocess::Target,
+    /// Look for libraries included with `include <NAME>` in this directory, before the bundled standard library of
+    /// `std/print`, `std/delay`, `std/keypad`, and `std/random`
+    #[arg(short = 'I', long = "include-path", value_name = "DIR")]
+    include_paths: Vec<PathBuf>,
+    /// Declare a constant before the source is assembled, as if by `const NAME VALUE`, so builds can switch `ifdef`
+    /// blocks or set values without editing the source. VALUE is given in decimal or in hex with a leading 0x, and is
+    /// 1 if it's left out
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]", value_parser = parse_define)]
+    defines: Vec<(String, usize)>,
+    /// Report a kind of warning, like `-W unused-label`, leave one out with `no-`, like `-Wno-padded-sprite`, or turn
+    /// every kind on with `-Wall`. Later flags win over earlier ones
+    #[arg(short = 'W', value_name = "WARNING", value_parser = preprocess::WarningFlag::parse)]
+    warning_flags: Vec<preprocess::WarningFlag>,
+    /// Exit with status 2 if the build had any warnings, after writing the output as usual, so scripts can tell a clean
+    /// build from one with warnings
+    #[arg(long)]
+    warnings_as_status: bool,
+    /// Show at most this many errors, or all of them with 0. Every instruction is still checked
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    max_errors: usize,
+    /// How errors and warnings are written to stderr: with the line of the source they're about, as a line of text
+    /// each, or as a JSON object per line with the message, severity, file, line, column, and a code naming the kind
+    /// of error, for editors and CI
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = diagnostic::ErrorFormat::Human)]
+    error_format: diagnostic::ErrorFormat,
+    /// Whether errors and warnings are colored
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = diagnostic::Color::Auto)]
+    color: diagnostic::Color,
+    /// How the rom is written: raw bytes, or Intel HEX records placed at the address the rom is loaded at
+    #[arg(long, value_enum, default_value_t = format::Format::Bin, conflicts_with = "compile")]
+    format: format::Format,
+    /// The address the rom is loaded at, for platforms that don't load it at 0x200, given in decimal or in hex with a
+    /// leading 0x. Labels and the room left in memory are worked out from it
+    #[arg(long, value_name = "ADDR", value_parser = parse_base_addr, default_value = "0x200", conflicts_with = "compile")]
+    base_addr: usize,
+    /// Assemble the source and report any errors and warnings without writing a rom, for checking source on save or in
+    /// CI
+    #[arg(long, conflicts_with_all = ["output", "run_with", "compile", "emit", "export_symbols", "listing", "map", "debug_symbols", "source_map", "watch"])]
+    check: bool,
+    /// Rebuild whenever the source or a file it includes changes, until interrupted. The output file is replaced all
+    /// at once, so an emulator reloading it never reads half a rom
+    #[arg(long, requires_all = ["input", "output"], conflicts_with = "run_with")]
+    watch: bool,
+    /// After each successful rebuild with --watch, tell a running emulator: `signal:PID[:SIGNAL]` sends it a signal,
+    /// USR1 by default, while `http://HOST[:PORT]/PATH` POSTs and `ws://HOST[:PORT]/PATH` sends the path of the rom
+    #[arg(long, value_name = "TARGET", value_parser = watch::Notify::parse, requires = "watch")]
+    notify: Vec<watch::Notify>,
+}
+
+/// What gets written to the output
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[cfg(feature = "std")]
+enum Emit {
+    Rom,
+    SymbolsJson,
+    /// Every span of the source classified for highlighting, as JSON
+    Tokens,
+    /// The instruction list as the preprocessor leaves it
+    Preprocessed,
+    /// The instruction list with aliases and virtual registers substituted
+    AfterAliases,
+    /// The instruction list with every symbol swapped for its value and expressions evaluated, as it's encoded
+    AfterLabels,
+}
+
+#[cfg(feature = "std")]
+impl Emit {
+    /// The stage of the pipeline this dumps the instruction list at, if it does
+    fn stage(self) -> Option<stages::Stage> {
+        match self {
+            Emit::Rom | Emit::SymbolsJson | Emit::Tokens => None,
+            Emit::Preprocessed => Some(stages::Stage::Preprocessed),
+            Emit::AfterAliases => Some(stages::Stage::AfterAliases),
+            Emit::AfterLabels => Some(stages::Stage::AfterLabels),
+        }
+    }
+}
+
+/// Alternative ways of running ch8asm other than assembling a single file
+#[derive(Subcommand)]
+#[cfg(feature = "std")]
+enum Mode {
+    /// Serve the Debug Adapter Protocol over stdio to debug programs in the built in emulator
+    Dap,
+    /// Serve the Language Server Protocol over stdio for diagnostics, go to definition, hover, and completion in editors
+    Lsp,
+    /// Format source files in place: uppercase mnemonics, instructions indented under labels, `, ` between arguments,
+    /// and trailing comments lined up. Formats stdin to stdout when no files are given
+    Fmt {
+        /// The files to format
+        inputs: Vec<PathBuf>,
+        /// Don't write anything, but fail listing every file that isn't formatted, for CI
+        #[arg(long)]
+        check: bool,
+    },
+    /// Write a Markdown or HTML reference of the labels and other symbols documented with `;;;` comments above them
+    Doc {
+        /// The file containing the assembly instructions to document
+        input: PathBuf,
+        #[arg(long, value_enum, default_value_t = doc::DocFormat::Markdown)]
+        format: doc::DocFormat,
+    },
+    /// Show which routines call which, which are used the most, and any recursion, as text or a Graphviz DOT graph
+    CallGraph {
+        /// The file containing the assembly instructions to graph
+        input: PathBuf,
+        #[arg(long, value_enum, default_value_t = callgraph::GraphFormat::Text)]
+        format: callgraph::GraphFormat,
+    },
+    /// Build every source listed in the ch8asm.toml in the current directory into a rom under its `out` directory
+    Build {
+        /// Build with the settings of this [profile.NAME] table laid over the [build] table
+        #[arg(long, value_name = "NAME", conflicts_with = "release")]
+        profile: Option<String>,
+        /// Build with the release profile, short for --profile release
+        #[arg(long)]
+        release: bool,
+    },
+    /// Show how many bytes each label, sprite, data block, and variable takes up, biggest first
+    Size {
+        /// The file containing the assembly instructions to measure
+        input: PathBuf,
+        /// The system to build for, which picks the code assembled from `.if TARGET == ...` blocks
+        #[arg(long, value_enum, default_value_t = preprocess::Target::Chip8)]
+        target: preprocess::Target,
+    },
+    /// Assemble a program and run it in the built in emulator, then print the machine state. Without --run-until or
+    /// --frames it runs in the terminal, drawing the display and reading the keypad from 1234/qwer/asdf/zxcv, until
+    /// Escape or Ctrl-C is pressed; with them it runs headless until one is reached
+    #[command(group(clap::ArgGroup::new("limit").multiple(true)))]
+    Run {
+        /// The file containing the assembly instructions to run
+        input: PathBuf,
+        /// Stop when execution reaches this label
+        #[arg(long, value_name = "LABEL", group = "limit")]
+        run_until: Option<String>,
+        /// Stop after this many 60Hz frames
+        #[arg(long, value_name = "N", group = "limit")]
+        frames: Option<u32>,
+        /// Once stopped, write the display to this image (.pbm, or .png with the `images` feature)
+        #[arg(long, value_name = "FILE")]
+        dump_screen: Option<PathBuf>,
+        /// Reassemble and run the program again whenever the source or a file it includes changes, until interrupted
+        #[arg(long, requires = "limit")]
+        watch: bool,
+    },
+    /// Combine object files made with -c into a rom, placing them in the order given and resolving the labels they
+    /// use from each other
+    Link {
+        /// The object files to link
+        #[arg(required = true)]
+        objects: Vec<PathBuf>,
+        /// The file into which the linked rom will be written. If none is provided, stdout is used instead.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 /// An enum to represent the user's choice regarding output of assembled bytes
+#[cfg(feature = "std")]
 enum OutputConfig {
     Stdout,
     File(PathBuf),
 }
 
 /// An enum to represent the user's choice regarding input of assembly instructions
+#[cfg(feature = "std")]
 enum InputConfig {
     Stdin,
     File(PathBuf),
 }
 
 /// Represent the collection of choices made for how the assembler should be run
+#[cfg(feature = "std")]
 pub struct Config {
+    mode: Option<Mode>,
     input_config: InputConfig,
     output_config: OutputConfig,
+    run_with: Option<String>,
+    dedup_sprites: bool,
+    preview_sprites: bool,
+    emit: Emit,
+    optimizations: Vec<optimize::Optimization>,
+    disabled_rules: Vec<optimize::Rule>,
+    pad_to: Option<usize>,
+    pad_fill: preprocess::Fill,
+    build_id: Option<String>,
+    compile: bool,
+    export_symbols: Option<PathBuf>,
+    listing: Option<PathBuf>,
+    map: Option<PathBuf>,
+    debug_symbols: Option<PathBuf>,
+    source_map: Option<PathBuf>,
+    import_symbols: Vec<PathBuf>,
+    target: preprocess::Target,
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, usize)>,
+    warning_flags: Vec<preprocess::WarningFlag>,
+    warnings_as_status: bool,
+    max_errors: usize,
+    error_format: diagnostic::ErrorFormat,
+    color: diagnostic::Color,
+    format: format::Format,
+    base_addr: usize,
+    check: bool,
+    watch: bool,
+    notify: Vec<watch::Notify>,
 }
 
+#[cfg(feature = "std")]
 impl Config {
     pub fn make() -> Config {
         let args = Args::parse();
@@ -54,15 +409,74 @@ impl Config {
             None => OutputConfig::Stdout,
         };
         Config {
+            mode: args.mode,
             input_config,
             output_config,
+            run_with: args.run_with,
+            dedup_sprites: args.dedup_sprites,
+            preview_sprites: args.preview_sprites,
+            emit: args.emit,
+            optimizations: args.optimize,
+            disabled_rules: args.disable_rule,
+            pad_to: args.pad_to,
+            pad_fill: args.pad_fill.unwrap_or_default(),
+            build_id: args.build_id,
+            compile: args.compile,
+            export_symbols: args.export_symbols,
+            listing: args.listing,
+            map: args.map,
+            debug_symbols: args.debug_symbols,
+            source_map: args.source_map,
+            import_symbols: args.import_symbols,
+            target: args.target,
+            include_paths: args.include_paths,
+            defines: args.defines,
+            warning_flags: args.warning_flags,
+            warnings_as_status: args.warnings_as_status,
+            max_errors: args.max_errors,
+            error_format: args.error_format,
+            color: args.color,
+            format: args.format,
+            base_addr: args.base_addr,
+            check: args.check,
+            watch: args.watch,
+            notify: args.notify,
         }
     }
+
+    /// What writes the errors and warnings of this run, naming the source file they're about if there is one
+    pub fn reporter(&self) -> Reporter {
+        let file = match (&self.mode, &self.input_config) {
+            (
+                Some(
+                    Mode::Doc { input, .. }
+                    | Mode::CallGraph { input, .. }
+                    | Mode::Size { input, .. }
+                    | Mode::Run { input, .. },
+                ),
+                _,
+            )
+            | (None, InputConfig::File(input)) => Some(input.clone()),
+            _ => None,
+        };
+        Reporter::new(self.error_format, self.color, file)
+    }
+}
+
+/// Why source couldn't be assembled by [`assemble`] or [`assemble_with`]
+#[derive(Error, Debug)]
+#[error("line {line}: {message}")]
+#[cfg(feature = "std")]
+pub struct AsmError {
+    /// The (1-indexed) line of the source the error was found on
+    pub line: usize,
+    pub message: String,
 }
 
 /// The error that gets returned to the caller from our run function
 /// This should only be used to convey a message to the user
 #[derive(Error, Debug)]
+#[cfg(feature = "std")]
 pub enum RunError {
     #[error("encounterd an issue while attempting to read or write file; does thie file exist? do you have permission? is it open in another process?")]
     IoError(
@@ -70,54 +484,1174 @@ pub enum RunError {
         #[source]
         io::Error,
     ),
+    #[error("--run-with requires an output file to pass to the emulator")]
+    RunWithoutOutput,
+    #[error("--run-with needs a rom to run, so it can only be used with --emit rom")]
+    RunWithoutRom,
+    #[error("--run-with was given an empty command")]
+    EmptyRunCommand,
+    #[error("failed to launch emulator `{0}`: {1}")]
+    EmulatorLaunch(String, #[source] io::Error),
+    #[error("emulator `{0}` exited with {1}")]
+    EmulatorFailed(String, std::process::ExitStatus),
+    /// Preprocessing carries on past errors in lines that only declare something, so all of those can be fixed at
+    /// once too. Shown on its own, only the first is written out, see [`RunError::to_diagnostics`]
+    #[error("{}{}", errors[0], more_errors(errors.len() - 1 + omitted))]
+    Preprocessing {
+        errors: Vec<Located<PreprocessingError>>,
+        /// How many more errors were found than --max-errors allows to be shown
+        omitted: usize,
+    },
+    /// Every instruction is encoded even after one fails, so all of their errors can be fixed at once. Shown on its
+    /// own, only the first is written out, see [`RunError::to_diagnostics`]
+    #[error("{}{}", errors[0], more_errors(errors.len() - 1 + omitted))]
+    Assemble {
+        errors: Vec<Located<AssembleError>>,
+        /// How many more errors were found than --max-errors allows to be shown
+        omitted: usize,
+    },
     #[error("{0}")]
-    Preprocessing(
+    Transport(
+        #[from]
+        #[source]
+        TransportError,
+    ),
+    #[error("emulated program crashed: {0}")]
+    Emulator(
         #[from]
         #[source]
-        PreprocessingError,
+        EmulatorError,
     ),
+    #[error("no such label: {0}")]
+    UnknownLabel(String),
+    #[error(
+        "--build-id location `{}` doesn't have room for the {} byte build id inside the rom",
+        .0,
+        build_id::SIZE
+    )]
+    BuildIdOutsideRom(String),
+    #[error("--pad-to needs a size between the rom's {rom} bytes and the {max} bytes of memory programs get, not {size}")]
+    InvalidPadTo { size: usize, rom: usize, max: usize },
     #[error("{0}")]
-    Assemble(
+    Object(
         #[from]
         #[source]
-        AssembleError,
+        ObjectError,
     ),
+    #[error("{0}")]
+    SymbolFile(
+        #[from]
+        #[source]
+        SymbolFileError,
+    ),
+    #[error("the build had {} and --warnings-as-status was given", plural(*.0, "warning"))]
+    Warnings(usize),
+    #[error("{0}")]
+    Plugins(
+        #[from]
+        #[source]
+        PluginsError,
+    ),
+    #[error("{0}")]
+    Project(
+        #[from]
+        #[source]
+        ProjectError,
+    ),
+    #[error("{0}")]
+    ScreenDump(
+        #[from]
+        #[source]
+        ScreenDumpError,
+    ),
+    #[error("not formatted, so `ch8asm fmt` would change: {}", list_paths(.0))]
+    Unformatted(Vec<PathBuf>),
+}
+
+#[cfg(feature = "std")]
+impl RunError {
+    /// The status to exit with: 2 for a build that only failed because of --warnings-as-status, 1 for anything else
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::Warnings(_) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Every error to show the user, along with where it was found and a fix if there's an obvious one. Fixes are
+    /// worked out from the text of the source, if it's given, or of the included file an error was found in
+    pub fn to_diagnostics(&self, source: Option<&str>) -> Vec<Diagnostic> {
+        match self {
+            RunError::Preprocessing { errors, omitted } => with_omitted(
+                errors
+                    .iter()
+                    .map(|located| located_diagnostic(located, located.error.code(), None))
+                    .collect(),
+                *omitted,
+            ),
+            RunError::Assemble { errors, omitted } => with_omitted(
+                errors
+                    .iter()
+                    .map(|located| {
+                        let fix = match &located.origin {
+                            Some(origin) => fs::read_to_string(&*origin.file)
+                                .ok()
+                                .and_then(|text| fixes::suggest(&text, located)),
+                            None => source.and_then(|text| fixes::suggest(text, located)),
+                        };
+                        located_diagnostic(located, located.error.code(), fix)
+                    })
+                    .collect(),
+                *omitted,
+            ),
+            _ => vec![Diagnostic {
+                severity: Severity::Error,
+                message: self.to_string(),
+                file: None,
+                line: None,
+                column: None,
+                code: self.code(),
+                fix: None,
+            }],
+        }
+    }
+
+    /// A short name for the kind of error, the same as the kind of the source error it wraps if it's from assembling
+    pub fn code(&self) -> &'static str {
+        match self {
+            RunError::IoError(_) => "io",
+            RunError::RunWithoutOutput => "run-without-output",
+            RunError::RunWithoutRom => "run-without-rom",
+            RunError::EmptyRunCommand => "empty-run-command",
+            RunError::EmulatorLaunch(..) => "emulator-launch",
+            RunError::EmulatorFailed(..) => "emulator-failed",
+            RunError::Preprocessing { errors, .. } => errors[0].error.code(),
+            RunError::Assemble { errors, .. } => errors[0].error.code(),
+            RunError::Transport(_) => "transport",
+            RunError::Emulator(_) => "emulator-crashed",
+            RunError::UnknownLabel(_) => "unknown-label",
+            RunError::BuildIdOutsideRom(_) => "build-id-outside-rom",
+            RunError::InvalidPadTo { .. } => "invalid-pad-to",
+            RunError::Object(_) => "object",
+            RunError::SymbolFile(_) => "symbol-file",
+            RunError::Warnings(_) => "warnings",
+            RunError::Plugins(_) => "plugins",
+            RunError::Project(_) => "project",
+            RunError::ScreenDump(_) => "screen-dump",
+            RunError::Unformatted(_) => "unformatted",
+        }
+    }
+
+    /// Show no more than a number of preprocessing or assembly errors, or every one of them if the number is 0
+    fn capped(mut self, max: usize) -> RunError {
+        match &mut self {
+            RunError::Preprocessing { errors, omitted } => cap(errors, omitted, max),
+            RunError::Assemble { errors, omitted } => cap(errors, omitted, max),
+            _ => {}
+        }
+        self
+    }
+
+    /// The (1-indexed) line of the source the error was found on, or 1 if it wasn't found in the source, along with
+    /// the error without the line. Of several errors, this is the first
+    fn located(&self) -> (usize, String) {
+        match self {
+            RunError::Preprocessing { errors, .. } => (errors[0].line, errors[0].message()),
+            RunError::Assemble { errors, .. } => (errors[0].line, errors[0].message()),
+            _ => (1, self.to_string()),
+        }
+    }
+
+    /// Every error with the (1-indexed) line it was found on, the error without the line, and a fix if there's an
+    /// obvious one, for editors
+    fn diagnostics(&self, text: &str) -> Vec<(usize, String, Option<fixes::Fix>)> {
+        match self {
+            RunError::Assemble { errors, .. } => errors
+                .iter()
+                .map(|located| {
+                    // a fix for an error in an included file would be to that file rather than this one
+                    let fix = located
+                        .origin
+                        .is_none()
+                        .then(|| fixes::suggest(text, located))
+                        .flatten();
+                    (located.line, located.message(), fix)
+                })
+                .collect(),
+            RunError::Preprocessing { errors, .. } => errors
+                .iter()
+                .map(|located| (located.line, located.message(), None))
+                .collect(),
+            _ => {
+                let (line, message) = self.located();
+                vec![(line, message, None)]
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Vec<Located<PreprocessingError>>> for RunError {
+    fn from(errors: Vec<Located<PreprocessingError>>) -> RunError {
+        RunError::Preprocessing { errors, omitted: 0 }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Vec<Located<AssembleError>>> for RunError {
+    fn from(errors: Vec<Located<AssembleError>>) -> RunError {
+        RunError::Assemble { errors, omitted: 0 }
+    }
+}
+
+/// Drop every error past a number of them, counting them as omitted, unless the number is 0
+#[cfg(feature = "std")]
+fn cap<E>(errors: &mut Vec<E>, omitted: &mut usize, max: usize) {
+    if max > 0 && errors.len() > max {
+        *omitted += errors.len() - max;
+        errors.truncate(max);
+    }
+}
+
+/// A diagnostic for an error found in the source or a file it includes, pointing at where in that file it is
+#[cfg(feature = "std")]
+fn located_diagnostic<E: core::error::Error>(
+    located: &Located<E>,
+    code: &'static str,
+    fix: Option<fixes::Fix>,
+) -> Diagnostic {
+    let (file, line) = match &located.origin {
+        Some(origin) => (Some(origin.file.to_string()), origin.line),
+        None => (None, located.line),
+    };
+    Diagnostic {
+        severity: Severity::Error,
+        message: located.error.to_string(),
+        file,
+        line: Some(line),
+        column: located.column,
+        code,
+        fix,
+    }
+}
+
+/// Diagnostics for errors, followed by a note saying how many more weren't shown if any were left out
+#[cfg(feature = "std")]
+fn with_omitted(mut diagnostics: Vec<Diagnostic>, omitted: usize) -> Vec<Diagnostic> {
+    if omitted > 0 {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Note,
+            message: format!(
+                "{} not shown, raise --max-errors to see them",
+                plural(omitted, "more error")
+            ),
+            file: None,
+            line: None,
+            column: None,
+            code: "omitted-errors",
+            fix: None,
+        });
+    }
+    diagnostics
+}
+
+/// How many errors there were past the first, like ` (and 2 more errors)`, or nothing if there weren't any
+#[cfg(feature = "std")]
+fn more_errors(count: usize) -> String {
+    match count {
+        0 => String::new(),
+        _ => format!(" (and {})", plural(count, "more error")),
+    }
+}
+
+/// Paths separated by commas, like `a.asm, b.asm`
+#[cfg(feature = "std")]
+fn list_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    paths.join(", ")
+}
+
+/// An assembled program along with the source line each of its instructions came from
+#[cfg(feature = "std")]
+struct Program {
+    rom: Vec<u8>,
+    /// The address the rom is loaded at
+    base: usize,
+    /// How many bytes of the rom were assembled, leaving out any padding after them
+    size: usize,
+    /// The address each instruction starts at and the source line it came from, in address order
+    lines: Vec<(usize, usize)>,
+    /// Where everything in the rom came from, in address order
+    mappings: Vec<sourcemap::Mapping>,
+    labels: HashMap<String, usize>,
+    /// The value of every constant, whether declared with `const` or generated, like a sprite's height
+    constants: HashMap<String, usize>,
+    /// Every alias and what it stands for
+    aliases: Vec<(String, String)>,
+    /// How many bytes of sprites were left out by deduplication
+    sprite_bytes_saved: usize,
+    sprites: Vec<preprocess::PlacedSprite>,
+    warnings: Vec<PreprocessingWarning>,
+    /// Every file the source included
+    included: Vec<PathBuf>,
+}
+
+#[cfg(feature = "std")]
+impl Program {
+    /// The source line an address was assembled from, if it was
+    fn line_of(&self, addr: u16) -> Option<usize> {
+        let addr = addr as usize;
+        if addr >= self.base + self.rom.len() {
+            return None;
+        }
+        // the instruction containing an address is the last one starting at or before it
+        let index = self.lines.partition_point(|&(start, _)| start <= addr);
+        Some(self.lines.get(index.checked_sub(1)?)?.1)
+    }
+
+    /// Let the user know about anything suspicious found while assembling
+    fn print_warnings(&self, reporter: &Reporter) {
+        for warning in &self.warnings {
+            reporter.report(&Diagnostic {
+                severity: Severity::Warning,
+                message: warning.to_string(),
+                file: None,
+                line: Some(warning.line()),
+                column: None,
+                code: warning.kind(),
+                fix: None,
+            });
+        }
+    }
+
+    /// Sum up the warnings by kind, like `3 warnings (2 padded-sprite, 1 clobbered-register), 0 errors`. Anything
+    /// that isn't a warning stops the build, so a finished build never has errors
+    fn print_summary(&self) {
+        let mut kinds: Vec<(&str, usize)> = Vec::new();
+        for warning in &self.warnings {
+            match kinds.iter_mut().find(|(kind, _)| *kind == warning.kind()) {
+                Some((_, count)) => *count += 1,
+                None => kinds.push((warning.kind(), 1)),
+            }
+        }
+        // most common first, ties in the order they first came up
+        kinds.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        let breakdown: Vec<String> = kinds
+            .iter()
+            .map(|(kind, count)| format!("{count} {kind}"))
+            .collect();
+        if breakdown.is_empty() {
+            eprintln!("0 warnings, 0 errors");
+        } else {
+            eprintln!(
+                "{} ({}), 0 errors",
+                plural(self.warnings.len(), "warning"),
+                breakdown.join(", ")
+            );
+        }
+    }
+
+    /// Write out every sprite as pixel art next to its bytes
+    fn write_sprite_previews(&self, mut out: impl Write) -> io::Result<()> {
+        for placed in &self.sprites {
+            writeln!(out, "{} at {:#05X}", placed.name, placed.addr)?;
+            for (plane, rows) in placed.sprite.planes().enumerate() {
+                if placed.sprite.planes > 1 {
+                    writeln!(out, "  plane {}", plane + 1)?;
+                }
+                for &row in rows {
+                    writeln!(out, "  {}", placed.sprite.preview(row))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pad the rom out to a size
+    fn pad_to(&mut self, size: usize, fill: &preprocess::Fill) -> Result<(), RunError> {
+        let max = emulator::MEMORY_SIZE - self.base;
+        if size < self.rom.len() || size > max {
+            return Err(RunError::InvalidPadTo {
+                size,
+                rom: self.rom.len(),
+                max,
+            });
+        }
+        let padding = fill.bytes(size - self.rom.len());
+        self.rom.extend(padding);
+        Ok(())
+    }
+
+    /// Overwrite the bytes at a label or address with a build id
+    fn embed_build_id(&mut self, location: &str, id: &[u8]) -> Result<(), RunError> {
+        let addr = match assemble::parse::parse_asm_arg(location) {
+            Ok(assemble::parse::AsmArgument::Numeric(addr)) => addr as usize,
+            _ => *self
+                .labels
+                .get(location)
+                .ok_or_else(|| RunError::UnknownLabel(location.to_string()))?,
+        };
+        let bytes = addr
+            .checked_sub(self.base)
+            .and_then(|offset| self.rom.get_mut(offset..offset + id.len()))
+            .ok_or_else(|| RunError::BuildIdOutsideRom(location.to_string()))?;
+        bytes.copy_from_slice(id);
+        Ok(())
+    }
+
+    /// The rom as it's written out in a format
+    fn encoded(&self, format: format::Format) -> std::borrow::Cow<'_, [u8]> {
+        format.encode(&self.rom, self.base)
+    }
+
+    /// Write the rom out in a format through a buffer
+    fn write_rom(&self, out: impl Write, format: format::Format) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+        out.write_all(&self.encoded(format))?;
+        out.flush()
+    }
+}
+
+/// Assemble source into a rom in two passes: the first places every instruction and collects
+/// symbols, then the second encodes each instruction, resolving symbols as it goes
+#[cfg(feature = "std")]
+fn assemble_program(input_data: &str, options: &preprocess::Options) -> Result<Program, RunError> {
+    // the program copies out everything it keeps, so generated text is freed once it's assembled
+    let arena = preprocess::Arena::default();
+    let preprocess::Preprocessed {
+        instructions,
+        symbols,
+        size,
+        sprite_bytes_saved,
+        sprites,
+        mut warnings,
+        included,
+        ..
+    } = preprocess::preprocess(input_data, options, &arena)?;
+
+    let rom = encode_instructions(&instructions, &symbols, size)?;
+    let lines = instructions.iter().map(|i| (i.addr(), i.line())).collect();
+    let mappings = instructions
+        .iter()
+        .map(|i| sourcemap::Mapping {
+            addr: i.addr(),
+            size: i.text().size(),
+            code: matches!(
+                i.text(),
+                InstructionText::Source(_) | InstructionText::Code(_)
+            ),
+            line: i.line(),
+            origin: i.origin().cloned(),
+        })
+        .collect();
+
+    // symbols borrow from the source, so keep owned copies of what we need around
+    let labels = symbols
+        .labels()
+        .map(|(label, addr)| (label.to_string(), addr))
+        .collect();
+    warnings.extend(
+        lint::control_flow(&instructions, &rom, options.base, &labels)
+            .into_iter()
+            .filter(|warning| options.warnings.contains(warning.kind())),
+    );
+    warnings.sort_by_key(PreprocessingWarning::line);
+    let constants = symbols
+        .constants()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    let aliases = symbols
+        .aliases()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    Ok(Program {
+        size: rom.len(),
+        rom,
+        base: options.base,
+        lines,
+        mappings,
+        labels,
+        constants,
+        aliases,
+        sprite_bytes_saved,
+        sprites,
+        warnings,
+        included,
+    })
+}
+
+/// Assemble source into the bytes of a rom, looking up any files it refers to relative to the
+/// working directory
+#[cfg(feature = "std")]
+pub fn assemble(input_data: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_with(input_data, &Options::default())
+}
+
+/// Assemble source into the bytes of a rom with a choice of where files are looked up, the
+/// target, and so on
+#[cfg(feature = "std")]
+pub fn assemble_with(input_data: &str, options: &Options) -> Result<Vec<u8>, AsmError> {
+    let program = assemble_program(input_data, options).map_err(|error| {
+        let (line, message) = error.located();
+        AsmError { line, message }
+    })?;
+    Ok(program.rom)
+}
+
+/// Why [`assemble_from`] or [`assemble_from_with`] failed
+#[derive(Error, Debug)]
+#[cfg(feature = "std")]
+pub enum StreamError {
+    #[error("couldn't read the source or write the rom: {0}")]
+    Io(
+        #[from]
+        #[source]
+        io::Error,
+    ),
+    #[error("{0}")]
+    Asm(
+        #[from]
+        #[source]
+        AsmError,
+    ),
+}
+
+/// Assemble source read from a reader, like stdin or a socket, writing the rom through a buffer to a writer, so callers
+/// don't have to hold the source or the rom themselves. Labels can be used before they're declared, so the whole
+/// source is still read before anything is written
+#[cfg(feature = "std")]
+pub fn assemble_from(reader: impl Read, writer: impl Write) -> Result<(), StreamError> {
+    assemble_from_with(reader, writer, &Options::default())
+}
+
+/// [`assemble_from`] with a choice of where files are looked up, the target, and so on
+#[cfg(feature = "std")]
+pub fn assemble_from_with(
+    mut reader: impl Read,
+    writer: impl Write,
+    options: &Options,
+) -> Result<(), StreamError> {
+    let mut source = String::new();
+    reader.read_to_string(&mut source)?;
+    let program = assemble_program(&source, options).map_err(|error| {
+        let (line, message) = error.located();
+        AsmError { line, message }
+    })?;
+    program.write_rom(writer, format::Format::Bin)?;
+    Ok(())
+}
+
+/// Encode a single instruction onto the end of the rom
+fn encode_instruction<'a>(
+    instruction: &preprocess::PreprocessedInstruction<'a>,
+    symbols: &symbols::SymbolTable<'a>,
+    buffers: &mut assemble::Buffers,
+    rom: &mut Vec<u8>,
+) -> Result<(), Located<AssembleError>> {
+    let start = rom.len();
+    let located = |line, error| Located {
+        line: instruction.line(),
+        column: Some(assemble::column(line, symbols, &error)),
+        origin: instruction.origin().cloned(),
+        error,
+    };
+    match instruction.text() {
+        InstructionText::Source(inst) => {
+            let word = assemble::assemble_instruction(inst, symbols, buffers)
+                .map_err(|error| located(&inst.line, error))?;
+            rom.extend(word.to_be_bytes());
+        }
+        InstructionText::Data(bytes) | InstructionText::Code(bytes) => rom.extend_from_slice(bytes),
+        InstructionText::Words(data, order) => {
+            assemble::assemble_words(data, *order, symbols, buffers, rom)
+                .map_err(|error| located(&data.line, error))?
+        }
+        InstructionText::Bytes(data) => assemble::assemble_bytes(data, symbols, buffers, rom)
+            .map_err(|error| located(&data.line, error))?,
+    }
+    // everything after an instruction was placed assuming it's exactly this big
+    debug_assert_eq!(rom.len() - start, instruction.text().size());
+    Ok(())
+}
+
+/// Encode instructions into a rom of the given size, carrying on past any that fail so every
+/// error is found in one go
+#[cfg(all(feature = "std", not(feature = "parallel")))]
+fn encode_instructions(
+    instructions: &[preprocess::PreprocessedInstruction],
+    symbols: &symbols::SymbolTable,
+    size: usize,
+) -> Result<Vec<u8>, Vec<Located<AssembleError>>> {
+    let mut rom = Vec::with_capacity(size);
+    let mut buffers = assemble::Buffers::default();
+    let mut errors = Vec::new();
+    for instruction in instructions {
+        if let Err(error) = encode_instruction(instruction, symbols, &mut buffers, &mut rom) {
+            errors.push(error);
+        }
+    }
+    if errors.is_empty() {
+        Ok(rom)
+    } else {
+        Err(errors)
+    }
+}
+
+/// How many instructions each thread encodes at a time when encoding in parallel
+#[cfg(feature = "parallel")]
+const ENCODE_CHUNK_SIZE: usize = 4096;
+
+/// Encode instructions into a rom of the given size across every core. Each chunk collects its
+/// own errors and chunks are combined in order, so errors are reported in source order, like the
+/// sequential path
+#[cfg(feature = "parallel")]
+fn encode_instructions(
+    instructions: &[preprocess::PreprocessedInstruction],
+    symbols: &symbols::SymbolTable,
+    size: usize,
+) -> Result<Vec<u8>, Vec<Located<AssembleError>>> {
+    use rayon::prelude::*;
+
+    let chunks = instructions
+        .par_chunks(ENCODE_CHUNK_SIZE)
+        .map(|chunk| {
+            let mut rom = Vec::new();
+            let mut buffers = assemble::Buffers::default();
+            let mut errors = Vec::new();
+            for instruction in chunk {
+                if let Err(error) = encode_instruction(instruction, symbols, &mut buffers, &mut rom)
+                {
+                    errors.push(error);
+                }
+            }
+            (rom, errors)
+        })
+        .collect::<Vec<(Vec<u8>, Vec<Located<AssembleError>>)>>();
+
+    let mut rom = Vec::with_capacity(size);
+    let mut errors = Vec::new();
+    for (chunk, chunk_errors) in chunks {
+        rom.extend(chunk);
+        errors.extend(chunk_errors);
+    }
+    if errors.is_empty() {
+        Ok(rom)
+    } else {
+        Err(errors)
+    }
 }
 
 /// Run the assembler
-pub fn run(config: Config) -> Result<(), RunError> {
-    // read our input
-    let input_data = match config.input_config {
+#[cfg(feature = "std")]
+pub fn run(mut config: Config) -> Result<(), RunError> {
+    let reporter = config.reporter();
+    match config.mode.take() {
+        Some(Mode::Dap) => return Ok(dap::serve()?),
+        Some(Mode::Lsp) => return Ok(lsp::serve()?),
+        Some(Mode::Run {
+            input,
+            run_until,
+            frames,
+            dump_screen,
+            watch: true,
+        }) => {
+            return watch::watch(&input, &options_for(&input), &[], |included| {
+                report(
+                    headless::run(
+                        &input,
+                        run_until.as_deref(),
+                        frames,
+                        dump_screen.as_deref(),
+                        &reporter,
+                        Some(included),
+                    ),
+                    &reporter,
+                )
+            })
+        }
+        Some(Mode::Run {
+            input,
+            run_until,
+            frames,
+            dump_screen,
+            watch: false,
+        }) if run_until.is_none() && frames.is_none() => {
+            return terminal::run(&input, dump_screen.as_deref(), &reporter)
+        }
+        Some(Mode::Run {
+            input,
+            run_until,
+            frames,
+            dump_screen,
+            watch: false,
+        }) => {
+            return headless::run(
+                &input,
+                run_until.as_deref(),
+                frames,
+                dump_screen.as_deref(),
+                &reporter,
+                None,
+            )
+        }
+        Some(Mode::Doc { input, format }) => return doc::run(&input, format),
+        Some(Mode::CallGraph { input, format }) => return callgraph::run(&input, format),
+        Some(Mode::Size { input, target }) => return size::run(&input, target),
+        Some(Mode::Build { profile, release }) => {
+            let profile = profile.as_deref().or(release.then_some("release"));
+            return project::build(profile);
+        }
+        Some(Mode::Link { objects, output }) => return object::link(&objects, output.as_deref()),
+        Some(Mode::Fmt { inputs, check }) => return fmt::run(&inputs, check),
+        None => (),
+    }
+
+    // clap makes sure there's an input and output file to watch with
+    if let (true, InputConfig::File(input), OutputConfig::File(output)) =
+        (config.watch, &config.input_config, &config.output_config)
+    {
+        let options =
+            build_options(&config, source_dir(input)).unwrap_or_else(|_| options_for(input));
+        return watch::watch(
+            input,
+            &options,
+            &config.import_symbols,
+            |included| match build(&config, &reporter, Some(included)) {
+                Ok(()) => {
+                    for notify in &config.notify {
+                        if let Err(e) = notify.send(output) {
+                            eprintln!("WARNING: couldn't notify {notify}: {e}");
+                        }
+                    }
+                }
+                Err(err) => report(Err(err), &reporter),
+            },
+        );
+    }
+    build(&config, &reporter, None)
+}
+
+/// Print the error a rebuild in watch mode failed with, since watching carries on regardless
+#[cfg(feature = "std")]
+fn report(result: Result<(), RunError>, reporter: &Reporter) {
+    if let Err(err) = result {
+        reporter.report_all(&err.to_diagnostics(reporter.source().as_deref()));
+    }
+}
+
+/// The preprocessing options the arguments ask for, with files looked up in a directory
+#[cfg(feature = "std")]
+fn build_options(config: &Config, dir: PathBuf) -> Result<preprocess::Options, RunError> {
+    let mut imports = Vec::new();
+    for path in &config.import_symbols {
+        imports.extend(symfile::read(path)?);
+    }
+    imports.extend(config.defines.iter().cloned());
+    Ok(preprocess::Options {
+        directives: plugins::load(&dir)?,
+        dir,
+        dedup_sprites: config.dedup_sprites,
+        imports,
+        target: config.target,
+        base: config.base_addr,
+        warnings: preprocess::Warnings::from_flags(&config.warning_flags),
+        include_paths: config.include_paths.clone(),
+    })
+}
+
+/// Assemble the input the way the arguments ask and write it to the output. When watching, every file the source
+/// included is put in `included` once it's assembled, so they can be watched too
+#[cfg(feature = "std")]
+fn build(
+    config: &Config,
+    reporter: &Reporter,
+    included: Option<&mut Vec<PathBuf>>,
+) -> Result<(), RunError> {
+    // read our input, remembering where to look for any files it refers to
+    let (input_data, dir) = match &config.input_config {
         InputConfig::Stdin => {
             let mut buf = String::new();
             io::stdin().read_to_string(&mut buf)?;
-            buf
+            (input::SourceText::Owned(buf), PathBuf::new())
         }
-        InputConfig::File(f) => fs::read_to_string(f)?,
+        InputConfig::File(f) => (input::read_source(f)?, source_dir(f)),
     };
+    let options = build_options(config, dir)?;
+    // editor plugins want the outline of broken source too, so errors go in the export instead of stopping it
+    if matches!(config.emit, Emit::SymbolsJson | Emit::Tokens) {
+        if config.run_with.is_some() {
+            return Err(RunError::RunWithoutRom);
+        }
+        let export = match config.emit {
+            Emit::Tokens => outline::tokens(&input_data),
+            _ => outline::export(&input_data, &assemble_program(&input_data, &options)),
+        };
+        let mut json = serde_json::to_string_pretty(&export).map_err(io::Error::from)?;
+        json.push('\n');
+        match &config.output_config {
+            OutputConfig::File(f) => fs::write(f, json)?,
+            OutputConfig::Stdout => io::stdout().lock().write_all(json.as_bytes())?,
+        };
+        return Ok(());
+    }
 
-    // process input into vec of instruction strings
-    let instructions = preprocess::preprocess(&input_data)?;
+    if let Some(stage) = config.emit.stage() {
+        if config.run_with.is_some() {
+            return Err(RunError::RunWithoutRom);
+        }
+        match &config.output_config {
+            OutputConfig::File(f) => stages::write(
+                &input_data,
+                &options,
+                stage,
+                BufWriter::new(fs::File::create(f)?),
+            )?,
+            OutputConfig::Stdout => stages::write(
+                &input_data,
+                &options,
+                stage,
+                BufWriter::new(io::stdout().lock()),
+            )?,
+        };
+        return Ok(());
+    }
 
-    // assemble instructions into individual opcodes
-    // we need a for loop here in order to return a specific error
-    let mut opcodes: Vec<u16> = Vec::with_capacity(instructions.len());
+    if config.compile {
+        let object = object::compile(&input_data, &options)?;
+        let mut json = serde_json::to_string(&object.to_json()).map_err(io::Error::from)?;
+        json.push('\n');
+        match &config.output_config {
+            OutputConfig::File(f) => fs::write(f, json)?,
+            OutputConfig::Stdout => io::stdout().lock().write_all(json.as_bytes())?,
+        };
+        return Ok(());
+    }
 
-    for instruction in &instructions {
-        opcodes.push(assemble::assemble_instruction(instruction)?);
+    let mut program =
+        assemble_program(&input_data, &options).map_err(|err| err.capped(config.max_errors))?;
+    if let Some(included) = included {
+        included.clone_from(&program.included);
+    }
+    if !config.optimizations.is_empty() {
+        program = optimize::optimize(
+            &input_data,
+            program,
+            &options,
+            &config.optimizations,
+            &config.disabled_rules,
+        )?;
+    }
+    if let Some(size) = config.pad_to {
+        program.pad_to(size, &config.pad_fill)?;
+    }
+    if let Some(location) = &config.build_id {
+        let settings: Vec<String> = config
+            .optimizations
+            .iter()
+            .filter_map(|optimization| optimization.to_possible_value())
+            .chain(
+                config
+                    .disabled_rules
+                    .iter()
+                    .filter_map(|rule| rule.to_possible_value()),
+            )
+            .map(|value| value.get_name().to_string())
+            .chain(config.dedup_sprites.then(|| "dedup-sprites".to_string()))
+            // ids from before targets existed stay the same when building for plain chip8
+            .chain(
+                config
+                    .target
+                    .to_possible_value()
+                    .filter(|_| config.target != preprocess::Target::Chip8)
+                    .map(|value| format!("target {}", value.get_name())),
+            )
+            .collect();
+        let id = build_id::compute(&input_data, &settings);
+        program.embed_build_id(location, &id)?;
+        eprintln!("build id: {}", build_id::to_hex(&id));
+    }
+    if let Some(path) = &config.export_symbols {
+        let labels: Vec<(&str, usize)> = program
+            .labels
+            .iter()
+            .map(|(label, &addr)| (label.as_str(), addr))
+            .collect();
+        symfile::write(path, &labels)?;
+    }
+    if let Some(path) = &config.listing {
+        listing::write(&input_data, &program, fs::File::create(path)?)?;
+    }
+    if let Some(path) = &config.map {
+        mapfile::write(&program, fs::File::create(path)?)?;
+    }
+    if let Some(path) = &config.debug_symbols {
+        debugsym::write(&program, fs::File::create(path)?)?;
+    }
+    if let Some(path) = &config.source_map {
+        let source = match &config.input_config {
+            InputConfig::File(input) => input.display().to_string(),
+            InputConfig::Stdin => "<stdin>".to_string(),
+        };
+        sourcemap::write(&program, &source, fs::File::create(path)?)?;
+    }
+    program.print_warnings(reporter);
+    // the summary only repeats the warnings, so it's left out of output meant to be parsed
+    if config.error_format != diagnostic::ErrorFormat::Json {
+        program.print_summary();
+    }
+    // the rom might be going to stdout, so keep the previews out of its way
+    if config.preview_sprites {
+        program.write_sprite_previews(io::stderr().lock())?;
+    }
+    if config.dedup_sprites {
+        eprintln!(
+            "sprite deduplication saved {} bytes",
+            program.sprite_bytes_saved
+        );
     }
 
-    // convert opcodes into byte array in order to write rom
-    let out_bytes = opcodes
-        .into_iter()
-        .flat_map(|op| op.to_be_bytes())
-        .collect::<Vec<u8>>();
+    // check this before writing so we don't dump a rom to the terminal for nothing
+    if config.run_with.is_some() && matches!(config.output_config, OutputConfig::Stdout) {
+        return Err(RunError::RunWithoutOutput);
+    }
 
-    // write to output
-    match config.output_config {
-        OutputConfig::File(f) => fs::write(f, out_bytes)?,
-        OutputConfig::Stdout => io::stdout().write_all(&out_bytes)?,
+    // write to output, unless this is only a check
+    match &config.output_config {
+        _ if config.check => (),
+        // an emulator might reload the rom as soon as it changes, so it's swapped in whole
+        OutputConfig::File(f) if config.watch => {
+            watch::replace(f, &program.encoded(config.format))?
+        }
+        OutputConfig::File(f) => program.write_rom(fs::File::create(f)?, config.format)?,
+        OutputConfig::Stdout => program.write_rom(io::stdout().lock(), config.format)?,
     };
 
+    // hand the rom off to the user's emulator of choice
+    if let (Some(command), OutputConfig::File(f)) = (&config.run_with, &config.output_config) {
+        run_emulator(command, f)?;
+    }
+
+    if config.warnings_as_status && !program.warnings.is_empty() {
+        return Err(RunError::Warnings(program.warnings.len()));
+    }
     Ok(())
 }
+
+/// A count of something, like "1 call" or "2 calls"
+#[cfg(feature = "std")]
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {noun}"),
+        _ => format!("{count} {noun}s"),
+    }
+}
+
+/// The directory files referred to by a source file are looked up in
+#[cfg(feature = "std")]
+fn source_dir(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// The default preprocessing options for assembling a source file
+#[cfg(feature = "std")]
+fn options_for(path: &Path) -> preprocess::Options {
+    preprocess::Options {
+        dir: source_dir(path),
+        ..Default::default()
+    }
+}
+
+/// Parse a load address the same way as a size, making sure there's memory at it
+#[cfg(feature = "std")]
+fn parse_base_addr(text: &str) -> Result<usize, String> {
+    match parse_size(text)? {
+        addr if addr < emulator::MEMORY_SIZE => Ok(addr),
+        addr => Err(format!(
+            "{addr:#X} is past the end of the {:#X} bytes of memory",
+            emulator::MEMORY_SIZE
+        )),
+    }
+}
+
+/// Parse a constant given on the command line as `NAME` or `NAME=VALUE`
+#[cfg(feature = "std")]
+fn parse_define(text: &str) -> Result<(String, usize), String> {
+    let (name, value) = match text.split_once('=') {
+        Some((name, value)) => (name, parse_size(value)?),
+        None => (text, 1),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("`{name}` can't be the name of a constant"));
+    }
+    Ok((name.to_string(), value))
+}
+
+/// Parse a size given in decimal or in hex with a leading 0x
+#[cfg(feature = "std")]
+fn parse_size(text: &str) -> Result<usize, String> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Parse a fill like `pattern 0xDE 0xAD` given as a single argument
+#[cfg(feature = "std")]
+fn parse_fill(text: &str) -> Result<preprocess::Fill, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    preprocess::Fill::parse(&words)
+        .ok_or_else(|| "expected `byte B`, `pattern B ...`, or `random SEED`".to_string())
+}
+
+/// Run the user's emulator command on the assembled rom and wait for it to exit
+#[cfg(feature = "std")]
+fn run_emulator(command: &str, rom: &Path) -> Result<(), RunError> {
+    let rom = rom.to_string_lossy();
+    let mut substituted = false;
+    // we don't go through a shell, so arguments are simply split on whitespace
+    let mut tokens = command
+        .split_whitespace()
+        .map(|token| {
+            if token.contains("{}") {
+                substituted = true;
+                token.replace("{}", &rom)
+            } else {
+                token.to_string()
+            }
+        })
+        .collect::<Vec<String>>();
+    if !substituted {
+        tokens.push(rom.to_string());
+    }
+
+    let (program, args) = tokens.split_first().ok_or(RunError::EmptyRunCommand)?;
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| RunError::EmulatorLaunch(command.to_string(), e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(RunError::EmulatorFailed(command.to_string(), status))
+    }
+}
+
+/// A fresh directory holding files for a test to read, named after the test so tests running at the same time each
+/// get their own
+#[cfg(all(test, feature = "std"))]
+fn test_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ch8asm-{test}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (name, text) in files {
+        fs::write(dir.join(name), text).unwrap();
+    }
+    dir
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// The diagnostics for a source that fails to assemble
+    fn diagnostics_of(source: &str, dir: PathBuf) -> Vec<Diagnostic> {
+        let options = Options {
+            dir,
+            ..Options::default()
+        };
+        match assemble_program(source, &options) {
+            Ok(_) => panic!("`{source}` assembled"),
+            Err(error) => error.to_diagnostics(Some(source)),
+        }
+    }
+
+    #[test]
+    fn fixes_are_suggested() {
+        let diagnostics = diagnostics_of("JP strat\nstart:\nRET", PathBuf::new());
+        let diagnostic = &diagnostics[0];
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(1), Some(4)));
+        assert_eq!(diagnostic.file, None);
+        let fix = diagnostic.fix.as_ref().unwrap();
+        assert_eq!((fix.start, fix.end, &*fix.replacement), (3, 8, "start"));
+    }
+
+    #[test]
+    fn registers_keywords_and_numbers_are_not_names() {
+        for source in [
+            "b:\nJP b",
+            "alias VA target",
+            "const DT 5",
+            "sprite K\n0xFF\nendsprite",
+        ] {
+            let diagnostics = diagnostics_of(source, PathBuf::new());
+            assert!(
+                diagnostics[0].code.starts_with("reserved-"),
+                "`{source}`: {}",
+                diagnostics[0].code
+            );
+        }
+    }
+
+    #[test]
+    fn namespaces_keep_included_names_apart() {
+        let dir = test_files(
+            "namespaces",
+            &[
+                ("sfx.s", "play:\nJP done\ndone:\nRET"),
+                ("gfx.s", "play:\nCLS\nJP done\ndone:\nRET"),
+            ],
+        );
+        let options = Options {
+            dir: dir.clone(),
+            ..Options::default()
+        };
+        let source = "CALL sfx.play\nCALL gfx.play\nHALT\ninclude \"sfx.s\" as sfx\ninclude \"gfx.s\" as gfx";
+        assert_eq!(
+            assemble_program(source, &options).unwrap().rom,
+            [
+                0x22, 0x06, 0x22, 0x0A, 0x12, 0x04, 0x12, 0x08, 0x00, 0xEE, 0x00, 0xE0, 0x12, 0x0E,
+                0x00, 0xEE
+            ]
+        );
+        // without a namespace they clash
+        let source = "HALT\ninclude \"sfx.s\"\ninclude \"gfx.s\"";
+        assert!(assemble_program(source, &options).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors_point_into_included_files() {
+        let dir = test_files(
+            "errors_point_into_included_files",
+            &[
+                ("sfx.s", "CLS\nLD V0, 0x1FF\ninclude \"deep.s\""),
+                ("deep.s", "const X 1\nconst X 2"),
+            ],
+        );
+        let diagnostics = diagnostics_of("CLS\ninclude \"sfx.s\"", dir.clone());
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.code, "reused-constant");
+        assert_eq!(
+            diagnostic.file,
+            Some(dir.join("deep.s").display().to_string())
+        );
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(2), None));
+
+        fs::write(dir.join("deep.s"), "").unwrap();
+        let diagnostics = diagnostics_of("CLS\ninclude \"sfx.s\"", dir.clone());
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.code, "invalid-byte");
+        assert_eq!(
+            diagnostic.file,
+            Some(dir.join("sfx.s").display().to_string())
+        );
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(2), Some(8)));
+        assert_eq!(diagnostic.fix.as_ref().unwrap().replacement, "0xFF");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
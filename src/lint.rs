@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::optimize::is_skip;
+use super::preprocess::{InstructionText, PreprocessedInstruction, PreprocessingWarning};
+
+/// How execution got to an address
+#[derive(Clone, Copy)]
+enum Arrival {
+    /// By running off the end of the instruction on a line, or being skipped past it
+    Fell(usize),
+    /// By a jump or call on a line
+    Jumped(usize),
+}
+
+/// What following execution from one address turned up
+#[derive(Default)]
+struct Walk {
+    /// The line of every `ret` reached
+    returns: Vec<usize>,
+    /// Every routine called, by address, and the line of each call
+    calls: Vec<(usize, usize)>,
+}
+
+/// Where each instruction and block of data was placed, to follow execution through
+struct Flow<'p> {
+    rom: &'p [u8],
+    base: usize,
+    /// The line each instruction came from, by address
+    code: HashMap<usize, usize>,
+    /// Where each block of data ends, by where it starts
+    data: BTreeMap<usize, usize>,
+    /// The lines already warned about running into data, so a line reached along several paths is only warned about
+    /// once
+    warned: HashSet<usize>,
+    warnings: Vec<PreprocessingWarning>,
+}
+
+/// Follow execution from the start of the program and into every routine it calls, warning about the mistakes that
+/// quietly corrupt a program instead of failing to assemble: a `ret` reached without a call, a routine that never
+/// returns, and execution running or jumping into sprites and data. Like the optimizer, a computed jump is taken to
+/// land somewhere in the run of jumps at its base address
+pub fn control_flow(
+    instructions: &[PreprocessedInstruction],
+    rom: &[u8],
+    base: usize,
+    labels: &HashMap<String, usize>,
+) -> Vec<PreprocessingWarning> {
+    let mut flow = Flow {
+        rom,
+        base,
+        code: HashMap::new(),
+        data: BTreeMap::new(),
+        warned: HashSet::new(),
+        warnings: Vec::new(),
+    };
+    for instruction in instructions {
+        let (addr, line) = (instruction.addr(), instruction.line());
+        match instruction.text() {
+            InstructionText::Source(_) => {
+                flow.code.insert(addr, line);
+            }
+            InstructionText::Code(bytes) => {
+                flow.code.extend(
+                    (addr..addr + bytes.len())
+                        .step_by(2)
+                        .map(|addr| (addr, line)),
+                );
+            }
+            text => {
+                flow.data.insert(addr, addr + text.size());
+            }
+        }
+    }
+
+    let start = flow.walk(base, None);
+    for line in start.returns {
+        flow.warnings
+            .push(PreprocessingWarning::ReturnWithoutCall { line });
+    }
+
+    // routines call routines, so keep going until every one that's called has been followed
+    let mut pending = start.calls;
+    let mut followed = HashSet::new();
+    while let Some((routine, line)) = pending.pop() {
+        if !followed.insert(routine) {
+            continue;
+        }
+        let walk = flow.walk(routine, Some(Arrival::Jumped(line)));
+        // a call to something that isn't code has already been warned about
+        if walk.returns.is_empty() && flow.code.contains_key(&routine) {
+            let name = labels
+                .iter()
+                .filter(|&(_, &addr)| addr == routine)
+                .map(|(label, _)| label.clone())
+                .min()
+                .unwrap_or_else(|| format!("{routine:#05X}"));
+            flow.warnings
+                .push(PreprocessingWarning::RoutineNeverReturns { name, line });
+        }
+        pending.extend(walk.calls);
+    }
+    flow.warnings
+}
+
+impl Flow<'_> {
+    /// The instruction at an address, if one was placed there
+    fn opcode_at(&self, addr: usize) -> Option<u16> {
+        let offset = addr.checked_sub(self.base)?;
+        Some(u16::from_be_bytes([
+            *self.rom.get(offset)?,
+            *self.rom.get(offset + 1)?,
+        ]))
+    }
+
+    /// Follow execution from an address until it returns, stops, or leaves the program, taking every call to return
+    /// to the instruction after it
+    fn walk(&mut self, entry: usize, arrival: Option<Arrival>) -> Walk {
+        let mut walk = Walk::default();
+        let mut reached = HashSet::new();
+        let mut pending = vec![(entry, arrival)];
+        while let Some((addr, arrival)) = pending.pop() {
+            let in_data = self
+                .data
+                .range(..=addr)
+                .next_back()
+                .is_some_and(|(_, &end)| addr < end);
+            if in_data {
+                self.entered_data(addr, arrival);
+                continue;
+            }
+            // anything else outside the program's instructions, like a routine in the interpreter, isn't followed
+            let (Some(&line), Some(op)) = (self.code.get(&addr), self.opcode_at(addr)) else {
+                continue;
+            };
+            if !reached.insert(addr) {
+                continue;
+            }
+
+            let next = addr + 2;
+            let nnn = (op & 0x0FFF) as usize;
+            let fell = Some(Arrival::Fell(line));
+            let jumped = Some(Arrival::Jumped(line));
+            match op >> 12 {
+                _ if op == 0x00EE => walk.returns.push(line),
+                // exit
+                _ if op == 0x00FD => (),
+                0x1 => pending.push((nnn, jumped)),
+                0x2 => {
+                    walk.calls.push((nnn, line));
+                    pending.push((next, fell));
+                }
+                0xB => {
+                    let mut entry = nnn;
+                    pending.push((entry, jumped));
+                    while self
+                        .opcode_at(entry)
+                        .is_some_and(|op| op & 0xF000 == 0x1000)
+                    {
+                        pending.push((entry, jumped));
+                        entry += 2;
+                    }
+                }
+                _ if is_skip(op) => pending.extend([(next, fell), (next + 2, fell)]),
+                _ => pending.push((next, fell)),
+            }
+        }
+        walk
+    }
+
+    /// Warn about execution arriving in data
+    fn entered_data(&mut self, addr: usize, arrival: Option<Arrival>) {
+        let warning = match arrival {
+            Some(Arrival::Fell(line)) => PreprocessingWarning::FallsIntoData { addr, line },
+            Some(Arrival::Jumped(line)) => PreprocessingWarning::JumpsIntoData { addr, line },
+            // a program that starts with data isn't meant to be run from the start
+            None => return,
+        };
+        if self.warned.insert(warning.line()) {
+            self.warnings.push(warning);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::{self, BufWriter, Write};
+
+use super::disassemble;
+use super::preprocess;
+use super::tokenize;
+use super::Program;
+
+/// How many bytes are shown on each line of the listing, with the rest carried onto lines of their own
+const BYTES_PER_LINE: usize = 4;
+
+/// Write every line of the source next to the address and bytes it assembled to. Lines that don't assemble to
+/// anything, like comments and labels, are written with the address and bytes left blank, and lines that assemble to
+/// several places, like the lines of a repeated block, get a line for each. Pseudo-instructions are followed by the
+/// instruction they were expanded to, and any padding after the program is listed on its own at the end
+pub fn write(source: &str, program: &Program, out: impl Write) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    let end = program.base + program.size;
+    // where each instruction's bytes run to is where the next one starts
+    let mut placed: Vec<(usize, usize, usize)> = program
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(i, &(addr, line))| {
+            let next = program.lines.get(i + 1).map_or(end, |&(next, _)| next);
+            (line, addr, next)
+        })
+        .filter(|&(_, addr, next)| next > addr)
+        .collect();
+    placed.sort_by_key(|&(line, addr, _)| (line, addr));
+
+    let mut placed = placed.into_iter().peekable();
+    for (number, text) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut written = false;
+        let pseudo = tokenize::tokenize_line(text)
+            .head()
+            .is_some_and(preprocess::is_pseudo_op);
+        while let Some((_, start, end)) = placed.next_if(|&(line, ..)| line == number) {
+            let bytes = &program.rom[start - program.base..end - program.base];
+            for (i, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02X}")).collect();
+                let addr = start + i * BYTES_PER_LINE;
+                // the source goes on the first line of its bytes only
+                let text = if written { "" } else { text };
+                let expansion = match chunk {
+                    &[high, low] if pseudo => {
+                        let op = u16::from_be_bytes([high, low]);
+                        format!("  => {}", disassemble::disassemble_instruction(op))
+                    }
+                    _ => String::new(),
+                };
+                let line = format!(
+                    "{addr:#05X}  {:<11}  {number:>5}  {text}{expansion}",
+                    hex.join(" ")
+                );
+                writeln!(out, "{}", line.trim_end())?;
+                written = true;
+            }
+        }
+        if !written {
+            let line = format!("{:5}  {:11}  {number:>5}  {text}", "", "");
+            writeln!(out, "{}", line.trim_end())?;
+        }
+    }
+
+    // padding doesn't come from any line of the source
+    let padding = &program.rom[program.size..];
+    for (i, chunk) in padding.chunks(BYTES_PER_LINE).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02X}")).collect();
+        let addr = end + i * BYTES_PER_LINE;
+        writeln!(out, "{addr:#05X}  {}", hex.join(" "))?;
+    }
+    out.flush()
+}
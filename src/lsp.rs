@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+
+use super::outline;
+use super::tokenize;
+use super::transport::{self, read_message, TransportError};
+use super::Program;
+
+/// What each mnemonic does, shown when hovering over one
+const MNEMONIC_DOCS: [(&str, &str); 26] = [
+    ("CLS", "`CLS` - 00E0: clear the display"),
+    ("RET", "`RET` - 00EE: return from a subroutine"),
+    ("SYS", "`SYS addr` - 0nnn: jump to a machine code routine, ignored by modern interpreters"),
+    ("JP", "`JP addr` - 1nnn: jump to addr\n\n`JP V0, addr` - Bnnn: jump to addr + V0"),
+    ("CALL", "`CALL addr` - 2nnn: call the subroutine at addr"),
+    ("SE", "`SE Vx, byte` - 3xkk: skip the next instruction if Vx = byte\n\n`SE Vx, Vy` - 5xy0: skip the next instruction if Vx = Vy"),
+    ("SNE", "`SNE Vx, byte` - 4xkk: skip the next instruction if Vx != byte\n\n`SNE Vx, Vy` - 9xy0: skip the next instruction if Vx != Vy"),
+    ("LD", "`LD Vx, byte` - 6xkk: set Vx to byte\n\n`LD Vx, Vy` - 8xy0: set Vx to Vy\n\n`LD I, addr` - Annn: set I to addr\n\n`LD Vx, DT` - Fx07: set Vx to the delay timer\n\n`LD Vx, K` - Fx0A: wait for a key press and store it in Vx\n\n`LD DT, Vx` - Fx15: set the delay timer to Vx\n\n`LD ST, Vx` - Fx18: set the sound timer to Vx\n\n`LD F, Vx` - Fx29: point I at the font glyph for the digit in Vx\n\n`LD B, Vx` - Fx33: store the decimal digits of Vx at I, I+1, and I+2\n\n`LD [I], Vx` - Fx55: store V0 through Vx starting at I\n\n`LD Vx, [I]` - Fx65: load V0 through Vx starting at I"),
+    ("ADD", "`ADD Vx, byte` - 7xkk: add byte to Vx\n\n`ADD Vx, Vy` - 8xy4: add Vy to Vx, setting VF on carry\n\n`ADD I, Vx` - Fx1E: add Vx to I"),
+    ("OR", "`OR Vx, Vy` - 8xy1: set Vx to Vx OR Vy"),
+    ("AND", "`AND Vx, Vy` - 8xy2: set Vx to Vx AND Vy"),
+    ("XOR", "`XOR Vx, Vy` - 8xy3: set Vx to Vx XOR Vy"),
+    ("SUB", "`SUB Vx, Vy` - 8xy5: subtract Vy from Vx, setting VF when there's no borrow"),
+    ("SHR", "`SHR Vx` - 8xy6: shift Vx right by one, setting VF to the bit shifted out"),
+    ("SUBN", "`SUBN Vx, Vy` - 8xy7: set Vx to Vy - Vx, setting VF when there's no borrow"),
+    ("SHL", "`SHL Vx` - 8xyE: shift Vx left by one, setting VF to the bit shifted out"),
+    ("RND", "`RND Vx, byte` - Cxkk: set Vx to a random byte AND byte"),
+    ("DRW", "`DRW Vx, Vy, nibble` - Dxyn: draw the n byte sprite at I at (Vx, Vy), setting VF on collision"),
+    ("SKP", "`SKP Vx` - Ex9E: skip the next instruction if the key in Vx is pressed"),
+    ("SKNP", "`SKNP Vx` - ExA1: skip the next instruction if the key in Vx isn't pressed"),
+    ("NOP", "`NOP` - pseudo-instruction for `LD V0, V0`: do nothing"),
+    ("HALT", "`HALT` - pseudo-instruction for `JP` to itself: stop here forever"),
+    ("MOV", "`MOV Vx, Vy` - pseudo-instruction for `LD Vx, Vy`: set Vx to Vy"),
+    ("CLR", "`CLR Vx` - pseudo-instruction for `LD Vx, 0`: set Vx to 0"),
+    ("INC", "`INC Vx` - pseudo-instruction for `ADD Vx, 1`: add 1 to Vx, leaving VF alone"),
+    ("DEC", "`DEC Vx` - pseudo-instruction for `ADD Vx, 0xFF`: subtract 1 from Vx, leaving VF alone"),
+];
+
+/// LSP's CompletionItemKind for keywords and variables
+const KEYWORD_KIND: u64 = 14;
+const VARIABLE_KIND: u64 = 6;
+
+/// LSP's SymbolKind for each kind of declaration in the outline, with anything not listed shown as a variable
+const SYMBOL_KINDS: [(&str, u64); 5] = [
+    ("label", 12),
+    ("constant", 14),
+    ("struct", 23),
+    ("sprite", 18),
+    ("spritesheet", 18),
+];
+const VARIABLE_SYMBOL_KIND: u64 = 13;
+
+/// The semantic token types in the legend sent to the client, and the type each class of token from the outline is
+/// shown as, by its index in the legend
+const TOKEN_TYPES: [&str; 6] = [
+    "keyword", "macro", "variable", "number", "function", "comment",
+];
+const TOKEN_CLASSES: [(&str, u64); 9] = [
+    ("mnemonic", 0),
+    ("keyword", 0),
+    ("directive", 1),
+    ("register", 2),
+    ("symbol", 2),
+    ("literal", 3),
+    ("label-def", 4),
+    ("label-ref", 4),
+    ("comment", 5),
+];
+/// The bit of the `declaration` modifier, the only one in the legend
+const DECLARATION_MODIFIER: u64 = 1;
+
+/// LSP's DiagnosticSeverity for errors and warnings
+const ERROR_SEVERITY: u64 = 1;
+const WARNING_SEVERITY: u64 = 2;
+
+/// Serve the language server protocol over stdin/stdout until the client exits
+pub fn serve() -> Result<(), TransportError> {
+    let mut stdin = BufReader::new(io::stdin());
+    let mut server = Server {
+        out: io::stdout(),
+        documents: HashMap::new(),
+    };
+    while let Some(message) = read_message(&mut stdin)? {
+        if !server.handle(&message)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// An open source file, along with the program it assembles to if it does
+struct Document {
+    text: String,
+    program: Option<Program>,
+}
+
+struct Server {
+    out: io::Stdout,
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    /// Handle a request or notification from the client, returning whether the server should continue
+    fn handle(&mut self, message: &Value) -> Result<bool, TransportError> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // the whole document is sent on every change
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                    "codeActionProvider": true,
+                    "documentSymbolProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": ["declaration"] },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "ch8asm", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                self.update(document["uri"].as_str(), document["text"].as_str())?;
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                self.update(params["textDocument"]["uri"].as_str(), text)?;
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            "textDocument/codeAction" => Ok(code_actions(params)),
+            "textDocument/documentSymbol" => Ok(self.document_symbols(params)),
+            "textDocument/semanticTokens/full" => Ok(self.semantic_tokens(params)),
+            other => Err(format!("unsupported method: {other}")),
+        };
+
+        // notifications don't get a response
+        if let Some(id) = message.get("id") {
+            let mut response = json!({ "jsonrpc": "2.0", "id": id });
+            match result {
+                Ok(result) => response["result"] = result,
+                // MethodNotFound
+                Err(message) => response["error"] = json!({ "code": -32601, "message": message }),
+            }
+            transport::write_message(&mut self.out.lock(), &response)?;
+        }
+        Ok(true)
+    }
+
+    /// Reassemble a document after it changes and let the client know what's wrong with it
+    fn update(&mut self, uri: Option<&str>, text: Option<&str>) -> Result<(), TransportError> {
+        let (Some(uri), Some(text)) = (uri, text) else {
+            return Ok(());
+        };
+
+        let mut diagnostics = Vec::new();
+        let program = match super::assemble_program(text, &super::options_for(&path_of(uri))) {
+            Ok(program) => {
+                for warning in &program.warnings {
+                    diagnostics.push(diagnostic(text, warning.line(), WARNING_SEVERITY, warning));
+                }
+                Some(program)
+            }
+            Err(err) => {
+                // the line is already shown by the diagnostic's position
+                for (line, message, fix) in err.diagnostics(text) {
+                    let mut diagnostic = diagnostic(text, line, ERROR_SEVERITY, &message);
+                    // the fix rides along with the diagnostic, so it comes back in the context of a code action
+                    // request
+                    if let Some(fix) = fix {
+                        diagnostic["data"] = json!({ "fix": fix.to_json() });
+                    }
+                    diagnostics.push(diagnostic);
+                }
+                None
+            }
+        };
+
+        self.documents.insert(
+            uri.to_string(),
+            Document {
+                text: text.to_string(),
+                program,
+            },
+        );
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        transport::write_message(&mut self.out.lock(), &notification)
+    }
+
+    /// The document and the zero-indexed line and character a request is about
+    fn position<'s>(&'s self, params: &Value) -> Option<(&'s str, &'s Document, usize, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let (uri, document) = self.documents.get_key_value(uri)?;
+        let position = &params["position"];
+        Some((
+            uri,
+            document,
+            position["line"].as_u64()? as usize,
+            position["character"].as_u64()? as usize,
+        ))
+    }
+
+    /// Where the symbol under the cursor was declared
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, document, line, character)) = self.position(params) else {
+            return Value::Null;
+        };
+        let Some(token) = token_at(&document.text, line, character) else {
+            return Value::Null;
+        };
+        let declarations = outline::declarations(&document.text);
+        match outline::find_declaration(&declarations, token) {
+            Some(declaration) => json!({
+                "uri": uri,
+                "range": range(declaration.line, declaration.start, declaration.end),
+            }),
+            None => Value::Null,
+        }
+    }
+
+    /// What the mnemonic under the cursor does and what the line assembled to
+    fn hover(&self, params: &Value) -> Value {
+        let Some((_, document, line, character)) = self.position(params) else {
+            return Value::Null;
+        };
+        let Some(token) = token_at(&document.text, line, character) else {
+            return Value::Null;
+        };
+
+        let mut sections = Vec::new();
+        if let Some((_, docs)) = MNEMONIC_DOCS
+            .iter()
+            .find(|(mnemonic, _)| mnemonic.eq_ignore_ascii_case(token))
+        {
+            sections.push(docs.to_string());
+        }
+        if let Some(program) = &document.program {
+            if let Some(&addr) = program.labels.get(token.trim_end_matches(':')) {
+                sections.push(format!("label at `{addr:#05X}`"));
+            }
+            if let Some(&value) = program.constants.get(token) {
+                sections.push(format!("constant `{value}` (`{value:#X}`)"));
+            }
+            if let Some(encoding) = encoding_of(program, line + 1) {
+                sections.push(encoding);
+            }
+        }
+
+        if sections.is_empty() {
+            return Value::Null;
+        }
+        json!({ "contents": { "kind": "markdown", "value": sections.join("\n\n---\n\n") } })
+    }
+
+    /// Every symbol declared in the document for the editor's outline, along with its address or value if the document
+    /// assembled
+    fn document_symbols(&self, params: &Value) -> Value {
+        let Some(document) = params["textDocument"]["uri"]
+            .as_str()
+            .and_then(|uri| self.documents.get(uri))
+        else {
+            return Value::Null;
+        };
+        let symbols: Vec<Value> = outline::declarations(&document.text)
+            .iter()
+            .map(|declaration| {
+                let kind = SYMBOL_KINDS
+                    .iter()
+                    .find(|(kind, _)| *kind == declaration.kind)
+                    .map_or(VARIABLE_SYMBOL_KIND, |&(_, number)| number);
+                let program = document.program.as_ref();
+                let detail = match (
+                    program.and_then(|program| program.labels.get(declaration.name)),
+                    program.and_then(|program| program.constants.get(declaration.name)),
+                ) {
+                    (Some(addr), _) => format!("{} at {addr:#05X}", declaration.kind),
+                    (None, Some(value)) => format!("{} = {value}", declaration.kind),
+                    (None, None) => declaration.kind.to_string(),
+                };
+                let width = document
+                    .text
+                    .lines()
+                    .nth(declaration.line)
+                    .map_or(0, str::len);
+                json!({
+                    "name": declaration.name,
+                    "detail": detail,
+                    "kind": kind,
+                    "range": range(declaration.line, 0, width),
+                    "selectionRange": range(declaration.line, declaration.start, declaration.end),
+                })
+            })
+            .collect();
+        json!(symbols)
+    }
+
+    /// Every token in the document classified for highlighting, each encoded relative to the one before as LSP expects
+    fn semantic_tokens(&self, params: &Value) -> Value {
+        let Some(document) = params["textDocument"]["uri"]
+            .as_str()
+            .and_then(|uri| self.documents.get(uri))
+        else {
+            return Value::Null;
+        };
+        let mut data = Vec::new();
+        let (mut line, mut start) = (0, 0);
+        for token in outline::semantic_tokens(&document.text) {
+            let Some(&(_, kind)) = TOKEN_CLASSES.iter().find(|(class, _)| *class == token.kind)
+            else {
+                continue;
+            };
+            if token.line != line {
+                start = 0;
+            }
+            let modifiers = match token.kind {
+                "label-def" => DECLARATION_MODIFIER,
+                _ => 0,
+            };
+            data.extend([
+                (token.line - line) as u64,
+                (token.start - start) as u64,
+                token.length as u64,
+                kind,
+                modifiers,
+            ]);
+            (line, start) = (token.line, token.start);
+        }
+        json!({ "data": data })
+    }
+
+    /// Every mnemonic and every symbol declared in the document
+    fn completion(&self, params: &Value) -> Value {
+        let mnemonics = MNEMONIC_DOCS.iter().map(|&(mnemonic, docs)| {
+            json!({
+                "label": mnemonic,
+                "kind": KEYWORD_KIND,
+                "documentation": { "kind": "markdown", "value": docs },
+            })
+        });
+        let symbols: Vec<String> = params["textDocument"]["uri"]
+            .as_str()
+            .and_then(|uri| self.documents.get(uri))
+            .map(|document| {
+                outline::declarations(&document.text)
+                    .iter()
+                    .map(|declaration| declaration.name.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let symbols = symbols
+            .into_iter()
+            .map(|symbol| json!({ "label": symbol, "kind": VARIABLE_KIND }));
+        Value::Array(mnemonics.chain(symbols).collect())
+    }
+}
+
+/// A quick fix for each diagnostic in a code action request that has a suggested fix
+fn code_actions(params: &Value) -> Value {
+    let uri = &params["textDocument"]["uri"];
+    let actions: Vec<Value> = params["context"]["diagnostics"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|diagnostic| {
+            let fix = &diagnostic["data"]["fix"];
+            let line = diagnostic["range"]["start"]["line"].as_u64()? as usize;
+            let replacement = fix["replacement"].as_str()?;
+            let range = range(
+                line,
+                fix["start"].as_u64()? as usize,
+                fix["end"].as_u64()? as usize,
+            );
+            let mut changes = serde_json::Map::new();
+            changes.insert(
+                uri.as_str()?.to_string(),
+                json!([{ "range": range, "newText": replacement }]),
+            );
+            Some(json!({
+                "title": format!("Replace with `{replacement}`"),
+                "kind": "quickfix",
+                "diagnostics": [diagnostic],
+                "isPreferred": true,
+                "edit": { "changes": changes },
+            }))
+        })
+        .collect();
+    json!(actions)
+}
+
+/// The local path of a file:// uri
+fn path_of(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    // undo percent encoding, which is mostly spaces in practice
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        let escaped = (byte == b'%')
+            .then(|| {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()
+            })
+            .flatten();
+        decoded.push(escaped.unwrap_or(byte));
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// An LSP range within a single line
+fn range(line: usize, start: usize, end: usize) -> Value {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+/// A diagnostic covering the whole of a (1-indexed) line
+fn diagnostic(text: &str, line: usize, severity: u64, message: &impl ToString) -> Value {
+    let line = line.saturating_sub(1);
+    let width = text.lines().nth(line).map_or(0, str::len);
+    json!({
+        "range": range(line, 0, width),
+        "severity": severity,
+        "source": "ch8asm",
+        "message": message.to_string(),
+    })
+}
+
+/// The text of the token at a zero-indexed line and character, if there is one
+fn token_at(text: &str, line: usize, character: usize) -> Option<&str> {
+    let line = tokenize::tokenize_line(text.lines().nth(line)?);
+    line.tokens
+        .iter()
+        // token columns are 1-indexed, and the cursor can sit just after the last character
+        .find(|t| t.column - 1 <= character && character <= t.column - 1 + t.text.len())
+        .map(|t| t.text)
+}
+
+/// The address and bytes a (1-indexed) line of the source assembled to, if any
+fn encoding_of(program: &Program, line: usize) -> Option<String> {
+    let start = program.lines.iter().position(|&(_, l)| l == line)?;
+    let end = program.lines[start..]
+        .iter()
+        .position(|&(_, l)| l != line)
+        .map_or(program.lines.len(), |offset| start + offset);
+    let (first, _) = program.lines[start];
+    let last = program
+        .lines
+        .get(end)
+        .map_or(program.base + program.rom.len(), |&(addr, _)| addr);
+
+    let offset = program.base;
+    let bytes = program.rom.get(first - offset..last - offset)?;
+    // long blocks of data would swamp the hover
+    let shown: Vec<String> = bytes.iter().take(16).map(|b| format!("{b:02X}")).collect();
+    let more = if bytes.len() > 16 { " ..." } else { "" };
+    Some(format!("`{first:#05X}`: `{}{more}`", shown.join(" ")))
+}
@@ -2,9 +2,11 @@ use ch8asm::Config;
 use std::process;
 
 fn main() {
-    if let Err(err) = ch8asm::run(Config::make()) {
-        eprintln!("ERROR: {err}");
-        process::exit(1);
+    let config = Config::make();
+    let reporter = config.reporter();
+    if let Err(err) = ch8asm::run(config) {
+        reporter.report_all(&err.to_diagnostics(reporter.source().as_deref()));
+        process::exit(err.exit_code());
     }
     process::exit(0);
 }
@@ -0,0 +1,56 @@
+use std::io::{self, BufWriter, Write};
+
+use super::Program;
+
+/// Write every label, sprite, constant, and alias of a program, each section sorted by address or value and then by
+/// name, so where everything ended up can be looked up while debugging
+pub fn write(program: &Program, out: impl Write) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "; map written by ch8asm {}", env!("CARGO_PKG_VERSION"))?;
+
+    let mut labels: Vec<(usize, &str)> = program
+        .labels
+        .iter()
+        .map(|(name, &addr)| (addr, name.as_str()))
+        .collect();
+    labels.sort_unstable();
+    writeln!(out, "\n; labels")?;
+    for (addr, name) in labels {
+        writeln!(out, "{addr:#05X}  {name}")?;
+    }
+
+    let mut sprites: Vec<_> = program.sprites.iter().collect();
+    sprites.sort_by(|a, b| a.addr.cmp(&b.addr).then(a.name.cmp(&b.name)));
+    writeln!(out, "\n; sprites")?;
+    for placed in sprites {
+        let sprite = &placed.sprite;
+        let size = sprite.bytes().len();
+        writeln!(
+            out,
+            "{:#05X}  {}  {}x{}, {size} bytes",
+            placed.addr,
+            placed.name,
+            sprite.width,
+            sprite.rows.len() as u32 / sprite.planes
+        )?;
+    }
+
+    let mut constants: Vec<(usize, &str)> = program
+        .constants
+        .iter()
+        .map(|(name, &value)| (value, name.as_str()))
+        .collect();
+    constants.sort_unstable();
+    writeln!(out, "\n; constants")?;
+    for (value, name) in constants {
+        writeln!(out, "{value:#06X}  {name} = {value}")?;
+    }
+
+    let mut aliases: Vec<&(String, String)> = program.aliases.iter().collect();
+    aliases.sort_unstable_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+    writeln!(out, "\n; aliases")?;
+    for (name, value) in aliases {
+        writeln!(out, "{name} -> {value}")?;
+    }
+    out.flush()
+}
@@ -0,0 +1,606 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::assemble::parse::{self, AsmArgument};
+use super::assemble::{expr, ByteOrder};
+use super::emulator::{MEMORY_SIZE, PROGRAM_START};
+use super::preprocess::{self, InstructionText, Preprocessed};
+use super::symbols::SymbolTable;
+use super::{encode_instructions, RunError};
+
+/// What object files say they are, so anything else is rejected instead of linked into garbage
+const FORMAT: &str = "ch8asm-object";
+const VERSION: u64 = 1;
+
+/// A problem with separately assembled object files, either making one or linking them
+#[derive(Debug, Error)]
+pub enum ObjectError {
+    #[error("line {line}: `{symbol}` is an address that moves when the program is linked, so it can only be used as the address of JP, CALL, SYS, or LD I, or in dw")]
+    UnrelocatableSymbol { symbol: String, line: usize },
+    #[error("line {line}: `{expression}` can only be linked if it's a single label or symbol of another object plus or minus a number")]
+    UnrelocatableExpression { expression: String, line: usize },
+    #[error("{0} isn't a ch8asm object file")]
+    InvalidObject(PathBuf),
+    #[error("`{symbol}` is defined in both {first} and {second}")]
+    DuplicateSymbol {
+        symbol: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("`{symbol}` is used in {object}, but no object defines it")]
+    UndefinedSymbol { symbol: String, object: PathBuf },
+    #[error("address {value:#X} used in {object} is past the 0xFFF an instruction can reach")]
+    AddressOutOfRange { value: usize, object: PathBuf },
+    #[error("the linked program and its variables take up {0} bytes, more than fits in memory")]
+    TooBig(usize),
+}
+
+/// Which part of the linked program a symbol or address is relative to
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    /// The object's code and data
+    Code,
+    /// The object's variables, which are placed after the code of every object
+    Vars,
+    /// Free memory, which starts after the variables of every object
+    Free,
+    /// A fixed value that doesn't move
+    Absolute,
+    /// A symbol defined by some other object
+    Symbol(String),
+}
+
+/// The bits of the program an address is written into
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    /// The low 12 bits of an instruction
+    Address,
+    /// A whole 16 bit `dw` word
+    Word(ByteOrder),
+}
+
+/// A place in an object's code that holds an address, which has to be filled in once the object is linked
+#[derive(Debug)]
+struct Relocation {
+    offset: usize,
+    field: Field,
+    target: Target,
+    /// How far past the start of the target the address is
+    addend: usize,
+}
+
+/// A program assembled on its own, with its addresses left to be filled in when it's linked with others
+#[derive(Debug)]
+pub struct Object {
+    code: Vec<u8>,
+    /// How many bytes of variables the object declares
+    vars: usize,
+    /// Every symbol the object defines, which other objects can use
+    symbols: Vec<(String, Target, usize)>,
+    relocations: Vec<Relocation>,
+}
+
+/// Assemble source into an object. Labels are recorded relative to the object, and anything the source uses but
+/// doesn't define is left for the linker to find in another object
+pub fn compile(source: &str, options: &preprocess::Options) -> Result<Object, RunError> {
+    let arena = preprocess::Arena::default();
+    let Preprocessed {
+        instructions,
+        mut symbols,
+        size,
+        free_memory,
+        fixed_jumps,
+        ..
+    } = preprocess::preprocess(source, options, &arena)?;
+    let start = PROGRAM_START as usize;
+    let code_end = start + size;
+
+    // a token that isn't a number, register, or symbol is taken to be a symbol of another object, assembled as 0
+    // for now so encoding doesn't fail on it
+    let mut externals = HashSet::new();
+    for instruction in &instructions {
+        let line = match instruction.text() {
+            InstructionText::Source(inst) => &inst.line,
+            InstructionText::Words(data, _) | InstructionText::Bytes(data) => &data.line,
+            InstructionText::Data(_) | InstructionText::Code(_) => continue,
+        };
+        for token in &line.tokens[1..] {
+            let token = symbols.substitute(token.text);
+            if expr::is_expression(token) {
+                for name in expr::names(token) {
+                    let name = symbols.substitute(name);
+                    if symbols.value_of(name).is_none() && parse::parse_asm_arg(name).is_err() {
+                        externals.insert(name);
+                    }
+                }
+            } else if symbols.value_of(token).is_none() && parse::parse_asm_arg(token).is_err() {
+                externals.insert(token);
+            }
+        }
+    }
+    for &external in &externals {
+        symbols.define_constant(external, 0);
+    }
+    let code = encode_instructions(&instructions, &symbols, size)?;
+
+    let mut relocations = Vec::new();
+    for instruction in &instructions {
+        let (line, order) = match instruction.text() {
+            InstructionText::Source(inst) => (&inst.line, None),
+            InstructionText::Bytes(data) => (&data.line, None),
+            InstructionText::Words(data, order) => (&data.line, Some(*order)),
+            InstructionText::Data(_) | InstructionText::Code(_) => continue,
+        };
+        for (i, token) in line.tokens.iter().enumerate().skip(1) {
+            let token = symbols.substitute(token.text);
+            let relocatable = |name: &str| relocatable(name, &symbols, &externals, code_end);
+            let relocation = if expr::is_expression(token) {
+                relocatable_expression(token, &symbols, relocatable).ok_or_else(|| {
+                    ObjectError::UnrelocatableExpression {
+                        expression: token.to_string(),
+                        line: instruction.line(),
+                    }
+                })?
+            } else {
+                relocatable(token)
+            };
+            let Some((target, addend)) = relocation else {
+                continue;
+            };
+
+            let offset = instruction.addr() - start;
+            let (offset, field) = match order {
+                Some(order) => (offset + (i - 1) * 2, Field::Word(order)),
+                None if takes_address(line) => (offset, Field::Address),
+                None => {
+                    return Err(ObjectError::UnrelocatableSymbol {
+                        symbol: token.to_string(),
+                        line: instruction.line(),
+                    }
+                    .into())
+                }
+            };
+            relocations.push(Relocation {
+                offset,
+                field,
+                target,
+                addend,
+            });
+        }
+    }
+    for addr in fixed_jumps {
+        let offset = addr - start;
+        let jump = u16::from_be_bytes([code[offset], code[offset + 1]]) as usize & 0xFFF;
+        relocations.push(Relocation {
+            offset,
+            field: Field::Address,
+            target: Target::Code,
+            addend: jump - start,
+        });
+    }
+
+    let mut defined: Vec<(String, Target, usize)> = symbols
+        .labels()
+        .map(|(name, addr)| match addr {
+            addr if addr < code_end => (name.to_string(), Target::Code, addr - start),
+            addr => (name.to_string(), Target::Vars, addr - code_end),
+        })
+        .chain(
+            symbols
+                .constants()
+                .filter(|(name, _)| !externals.contains(name))
+                .map(|(name, value)| (name.to_string(), Target::Absolute, value)),
+        )
+        .collect();
+    // symbol tables iterate in any order, so sort them to keep objects the same from build to build
+    defined.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(Object {
+        code,
+        vars: free_memory - code_end,
+        symbols: defined,
+        relocations,
+    })
+}
+
+/// What part of the linked program a name moves with and how far into it the name is, or None if it doesn't move
+fn relocatable(
+    name: &str,
+    symbols: &SymbolTable,
+    externals: &HashSet<&str>,
+    code_end: usize,
+) -> Option<(Target, usize)> {
+    let start = PROGRAM_START as usize;
+    if externals.contains(name) {
+        Some((Target::Symbol(name.to_string()), 0))
+    } else if let Some(offset) = name.strip_prefix('#').filter(|_| !name.starts_with("#$")) {
+        // the preprocessor already made sure it's a number
+        Some((Target::Free, offset.parse().unwrap_or_default()))
+    } else if symbols.is_label(name) {
+        match symbols.value_of(name).unwrap_or_default() {
+            addr if addr < code_end => Some((Target::Code, addr - start)),
+            addr => Some((Target::Vars, addr - code_end)),
+        }
+    } else {
+        None
+    }
+}
+
+/// Where an expression using at most one name that moves, like `table + 2`, points once linked, Some(None) if it
+/// doesn't use any, or None if it uses more than one or does more than add a number to the one it uses
+fn relocatable_expression(
+    expression: &str,
+    symbols: &SymbolTable,
+    relocatable: impl Fn(&str) -> Option<(Target, usize)>,
+) -> Option<Option<(Target, usize)>> {
+    let mut moving = expr::names(expression)
+        .map(|name| symbols.substitute(name))
+        .filter_map(|name| Some((name, relocatable(name)?)));
+    let Some((name, (target, offset))) = moving.next() else {
+        return Some(None);
+    };
+    if moving.next().is_some() {
+        return None;
+    }
+
+    // see how the expression changes when the name moves, which has to be by exactly as much for it to be linked
+    let evaluate = |shift: usize| {
+        expr::evaluate(expression, |other| {
+            let other = symbols.substitute(other);
+            let value = symbols
+                .value_of(other)
+                .or_else(|| match parse::parse_asm_arg(other) {
+                    Ok(AsmArgument::Numeric(value)) => Some(value as usize),
+                    _ => None,
+                })?;
+            Some(if other == name { value + shift } else { value })
+        })
+        .ok()
+    };
+    let value = evaluate(0)? as usize;
+    if evaluate(1)? as usize != value + 1 {
+        return None;
+    }
+    let addr = symbols.value_of(name).unwrap_or_default();
+    Some(Some((target, (value + offset).checked_sub(addr)?)))
+}
+
+/// Whether a line's last argument is the 12 bit address of the instruction
+fn takes_address(line: &super::tokenize::Line) -> bool {
+    let tokens: Vec<&str> = line.tokens.iter().map(|token| token.text).collect();
+    match tokens.as_slice() {
+        [op, ..]
+            if ["JP", "CALL", "SYS"]
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(op)) =>
+        {
+            true
+        }
+        [op, i, _] => op.eq_ignore_ascii_case("LD") && i.eq_ignore_ascii_case("I"),
+        _ => false,
+    }
+}
+
+impl Object {
+    /// The object as it's written to an object file
+    pub fn to_json(&self) -> Value {
+        let symbols: Vec<Value> = self
+            .symbols
+            .iter()
+            .map(|(name, target, value)| {
+                json!({ "name": name, "section": target.name(), "value": value })
+            })
+            .collect();
+        let relocations: Vec<Value> = self
+            .relocations
+            .iter()
+            .map(|relocation| {
+                let field = match relocation.field {
+                    Field::Address => "address",
+                    Field::Word(ByteOrder::Big) => "word.be",
+                    Field::Word(ByteOrder::Little) => "word.le",
+                };
+                let mut value = json!({
+                    "offset": relocation.offset,
+                    "field": field,
+                    "target": relocation.target.name(),
+                    "addend": relocation.addend,
+                });
+                if let Target::Symbol(symbol) = &relocation.target {
+                    value["symbol"] = json!(symbol);
+                }
+                value
+            })
+            .collect();
+        json!({
+            "format": FORMAT,
+            "version": VERSION,
+            "code": self.code,
+            "vars": self.vars,
+            "symbols": symbols,
+            "relocations": relocations,
+        })
+    }
+
+    /// Read an object back from an object file, or None if it isn't one
+    fn from_json(value: &Value) -> Option<Object> {
+        if value["format"] != FORMAT || value["version"] != VERSION {
+            return None;
+        }
+        let code = value["code"]
+            .as_array()?
+            .iter()
+            .map(|byte| u8::try_from(byte.as_u64()?).ok())
+            .collect::<Option<_>>()?;
+        let symbols = value["symbols"]
+            .as_array()?
+            .iter()
+            .map(|symbol| {
+                let name = symbol["name"].as_str()?.to_string();
+                let target = Target::from_name(symbol["section"].as_str()?, None)?;
+                Some((name, target, symbol["value"].as_u64()? as usize))
+            })
+            .collect::<Option<_>>()?;
+        let relocations = value["relocations"]
+            .as_array()?
+            .iter()
+            .map(|relocation| {
+                let field = match relocation["field"].as_str()? {
+                    "address" => Field::Address,
+                    "word.be" => Field::Word(ByteOrder::Big),
+                    "word.le" => Field::Word(ByteOrder::Little),
+                    _ => return None,
+                };
+                let target = Target::from_name(
+                    relocation["target"].as_str()?,
+                    relocation["symbol"].as_str(),
+                )?;
+                Some(Relocation {
+                    offset: relocation["offset"].as_u64()? as usize,
+                    field,
+                    target,
+                    addend: relocation["addend"].as_u64()? as usize,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Object {
+            code,
+            vars: value["vars"].as_u64()? as usize,
+            symbols,
+            relocations,
+        })
+    }
+}
+
+impl Target {
+    fn name(&self) -> &'static str {
+        match self {
+            Target::Code => "code",
+            Target::Vars => "vars",
+            Target::Free => "free",
+            Target::Absolute => "absolute",
+            Target::Symbol(_) => "symbol",
+        }
+    }
+
+    fn from_name(name: &str, symbol: Option<&str>) -> Option<Target> {
+        match (name, symbol) {
+            ("code", _) => Some(Target::Code),
+            ("vars", _) => Some(Target::Vars),
+            ("free", _) => Some(Target::Free),
+            ("absolute", _) => Some(Target::Absolute),
+            ("symbol", Some(symbol)) => Some(Target::Symbol(symbol.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Where each part of an object ended up in the linked program
+struct Placement {
+    code: usize,
+    vars: usize,
+}
+
+/// Combine object files into a rom, one after the other in the order given, followed by all of their variables
+pub fn link(paths: &[PathBuf], output: Option<&Path>) -> Result<(), RunError> {
+    let mut objects = Vec::new();
+    for path in paths {
+        let text = fs::read_to_string(path)?;
+        let object = serde_json::from_str(&text)
+            .ok()
+            .and_then(|value| Object::from_json(&value))
+            .ok_or_else(|| ObjectError::InvalidObject(path.clone()))?;
+        objects.push(object);
+    }
+
+    let rom = link_objects(&objects, paths)?;
+    match output {
+        Some(path) => write_rom(&rom, fs::File::create(path)?)?,
+        None => write_rom(&rom, io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+/// Link objects read from the given paths into the bytes of a rom
+fn link_objects(objects: &[Object], paths: &[PathBuf]) -> Result<Vec<u8>, ObjectError> {
+    // code goes first, each object starting on an even address so its instructions stay aligned
+    let mut placements = Vec::new();
+    let mut addr = PROGRAM_START as usize;
+    for object in objects {
+        placements.push(Placement {
+            code: addr,
+            vars: 0,
+        });
+        addr += object.code.len() + object.code.len() % 2;
+    }
+    let code_end = addr - objects.last().map_or(0, |object| object.code.len() % 2);
+    for (object, placement) in objects.iter().zip(&mut placements) {
+        placement.vars = addr;
+        addr += object.vars;
+    }
+    let free = addr;
+    if free > MEMORY_SIZE {
+        return Err(ObjectError::TooBig(free - PROGRAM_START as usize));
+    }
+
+    let mut defined: HashMap<&str, (usize, &Path)> = HashMap::new();
+    for ((object, placement), path) in objects.iter().zip(&placements).zip(paths) {
+        for (name, target, value) in &object.symbols {
+            let addr = match target {
+                Target::Code => placement.code + value,
+                Target::Vars => placement.vars + value,
+                _ => *value,
+            };
+            // constants shared through an include are defined by every object including them, which is fine as long
+            // as they agree
+            match defined.insert(name, (addr, path)) {
+                Some((previous, _)) if *target == Target::Absolute && previous == addr => (),
+                Some((_, first)) => {
+                    return Err(ObjectError::DuplicateSymbol {
+                        symbol: name.clone(),
+                        first: first.to_path_buf(),
+                        second: path.clone(),
+                    });
+                }
+                None => (),
+            }
+        }
+    }
+
+    let mut rom = Vec::with_capacity(code_end - PROGRAM_START as usize);
+    for ((object, placement), path) in objects.iter().zip(&placements).zip(paths) {
+        let start = placement.code - PROGRAM_START as usize;
+        rom.resize(start, 0);
+        rom.extend(&object.code);
+        for relocation in &object.relocations {
+            let value = relocation.addend
+                + match &relocation.target {
+                    Target::Code => placement.code,
+                    Target::Vars => placement.vars,
+                    Target::Free => free,
+                    Target::Absolute => 0,
+                    Target::Symbol(symbol) => {
+                        defined
+                            .get(symbol.as_str())
+                            .ok_or_else(|| ObjectError::UndefinedSymbol {
+                                symbol: symbol.clone(),
+                                object: path.clone(),
+                            })?
+                            .0
+                    }
+                };
+            let at = start + relocation.offset;
+            let bytes = match relocation.field {
+                Field::Address => {
+                    if value > 0xFFF {
+                        return Err(ObjectError::AddressOutOfRange {
+                            value,
+                            object: path.clone(),
+                        });
+                    }
+                    let op = u16::from_be_bytes([rom[at], rom[at + 1]]);
+                    (op & 0xF000 | value as u16).to_be_bytes()
+                }
+                Field::Word(ByteOrder::Big) => (value as u16).to_be_bytes(),
+                Field::Word(ByteOrder::Little) => (value as u16).to_le_bytes(),
+            };
+            rom[at..at + 2].copy_from_slice(&bytes);
+        }
+    }
+    Ok(rom)
+}
+
+fn write_rom(rom: &[u8], out: impl Write) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    out.write_all(rom)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compile sources on their own, then link them in order as if they came from files named after their index
+    fn build(sources: &[&str]) -> Result<Vec<u8>, ObjectError> {
+        let objects: Vec<Object> = sources
+            .iter()
+            .map(|source| compile(source, &preprocess::Options::default()).unwrap())
+            .collect();
+        let paths: Vec<PathBuf> = (0..sources.len())
+            .map(|i| PathBuf::from(format!("{i}.o")))
+            .collect();
+        link_objects(&objects, &paths)
+    }
+
+    #[test]
+    fn symbols_of_other_objects() {
+        let rom = build(&["CALL helper\nHALT", "helper:\nRET"]).unwrap();
+        assert_eq!(rom, [0x22, 0x04, 0x12, 0x02, 0x00, 0xEE]);
+        // the same objects the other way around
+        let rom = build(&["helper:\nRET", "CALL helper\nHALT"]).unwrap();
+        assert_eq!(rom, [0x00, 0xEE, 0x22, 0x00, 0x12, 0x04]);
+    }
+
+    #[test]
+    fn odd_objects_are_padded() {
+        let rom = build(&["db 1", "target:\nJP target"]).unwrap();
+        assert_eq!(rom, [0x01, 0x00, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn expressions_and_words() {
+        let rom = build(&["LD I, table + 2\ndw table", "table:\ndb 1, 2, 3"]).unwrap();
+        assert_eq!(rom[..4], [0xA2, 0x06, 0x02, 0x04]);
+    }
+
+    #[test]
+    fn vars_go_after_every_objects_code() {
+        let rom = build(&["var counter 1\nLD I, counter", "var total 2\nLD I, total"]).unwrap();
+        assert_eq!(rom, [0xA2, 0x04, 0xA2, 0x05]);
+    }
+
+    #[test]
+    fn objects_round_trip_through_json() {
+        let object = compile("CALL helper\nhelper:\nRET", &preprocess::Options::default()).unwrap();
+        let read = Object::from_json(&object.to_json()).unwrap();
+        assert_eq!(read.to_json(), object.to_json());
+        assert!(Object::from_json(&json!({ "format": "something-else" })).is_none());
+    }
+
+    #[test]
+    fn link_errors() {
+        assert!(matches!(
+            build(&["CALL missing"]),
+            Err(ObjectError::UndefinedSymbol { symbol, .. }) if symbol == "missing"
+        ));
+        assert!(matches!(
+            build(&["twice:\nRET", "twice:\nRET"]),
+            Err(ObjectError::DuplicateSymbol { symbol, .. }) if symbol == "twice"
+        ));
+        // constants can be defined by every object as long as they agree
+        assert!(build(&["const N 1\nRET", "const N 1\nRET"]).is_ok());
+    }
+
+    #[test]
+    fn only_addresses_move() {
+        assert!(matches!(
+            compile("LD V0, elsewhere", &preprocess::Options::default()),
+            Err(RunError::Object(ObjectError::UnrelocatableSymbol {
+                line: 1,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            compile("LD I, first + second", &preprocess::Options::default()),
+            Err(RunError::Object(ObjectError::UnrelocatableExpression {
+                line: 1,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use clap::ValueEnum;
+
+use super::assemble;
+use super::preprocess::Options;
+use super::tokenize;
+use super::{assemble_program, Program, RunError};
+
+/// Opt-in changes to the program that make the rom smaller
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Optimization {
+    /// Drop instructions no path of execution from the start of the rom can reach
+    RemoveUnreachable,
+    /// Rewrite short runs of instructions into fewer that do the same thing
+    Peephole,
+}
+
+/// A pattern of neighbouring instructions the peephole optimizer rewrites
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Rule {
+    /// `LD Vx, 0` followed by `ADD Vx, k` becomes `LD Vx, k`
+    MergeLoadAdd,
+    /// `LD I, addr` straight after the same `LD I, addr` is dropped
+    DuplicateLoadI,
+    /// `JP` to the instruction right after it is dropped
+    JumpToNext,
+}
+
+/// New text for (1-indexed) lines of the source, where empty text drops the line
+type Edits = BTreeMap<usize, String>;
+
+/// An assembled instruction or piece of data, in address order
+struct Placed {
+    addr: usize,
+    line: usize,
+    /// The opcode, or None if this is data rather than an instruction
+    op: Option<u16>,
+}
+
+/// Apply the chosen optimizations in turn, reporting how much each saved. Each one moves code around, so the source
+/// is assembled again after it to resolve every label against the new layout
+pub fn optimize(
+    source: &str,
+    mut program: Program,
+    options: &Options,
+    optimizations: &[Optimization],
+    disabled: &[Rule],
+) -> Result<Program, RunError> {
+    let mut source = source.to_string();
+    for &optimization in Optimization::value_variants() {
+        if !optimizations.contains(&optimization) {
+            continue;
+        }
+        let (edits, description) = match optimization {
+            Optimization::RemoveUnreachable => (
+                unreachable_lines(&source, &program)
+                    .into_iter()
+                    .map(|line| (line, String::new()))
+                    .collect(),
+                "removing unreachable code",
+            ),
+            Optimization::Peephole => (
+                peephole(&source, &program, disabled),
+                "peephole optimization",
+            ),
+        };
+        let rewritten = rewrite(&source, &edits);
+        let optimized = assemble_program(&rewritten, options)?;
+        eprintln!(
+            "{description} saved {} bytes",
+            program.rom.len() - optimized.rom.len()
+        );
+        source = rewritten;
+        program = optimized;
+    }
+    Ok(program)
+}
+
+/// The (1-indexed) lines of instructions that can't be reached from the start of the rom, in order.
+/// Only lines of instructions are ever included, never sprites or other data, even if nothing reads them
+fn unreachable_lines(source: &str, program: &Program) -> Vec<usize> {
+    let reached: HashSet<usize> = reachable(&program.rom, program.base as u16)
+        .into_iter()
+        .filter_map(|addr| program.line_of(addr))
+        .collect();
+    let placed = placed(source, program);
+
+    let mut unreachable: Vec<usize> = placed
+        .iter()
+        .filter(|placed| placed.op.is_some() && !reached.contains(&placed.line))
+        .map(|placed| placed.line)
+        .collect();
+    unreachable.dedup();
+    unreachable
+}
+
+/// Rewrite every match of the enabled rules, printing each rewrite
+fn peephole(source: &str, program: &Program, disabled: &[Rule]) -> Edits {
+    let lines: Vec<&str> = source.lines().collect();
+    let placed = placed(source, program);
+    // code can jump to a label, so a label in the middle of a pattern means it isn't always run as a whole
+    let labelled: HashSet<usize> = program.labels.values().copied().collect();
+    // a line placed more than once, like the body of a `rept` or an included file, can't be rewritten for one copy of
+    // it without rewriting every other copy too
+    let mut copies: HashMap<usize, usize> = HashMap::new();
+    for placed in &placed {
+        *copies.entry(placed.line).or_default() += 1;
+    }
+    let expanded = |placed: &Placed| copies[&placed.line] > 1;
+    let rules: Vec<Rule> = Rule::value_variants()
+        .iter()
+        .copied()
+        .filter(|rule| !disabled.contains(rule))
+        .collect();
+
+    let mut edits = Edits::new();
+    let mut i = 0;
+    while i < placed.len() {
+        let current = &placed[i];
+        let next = placed.get(i + 1).filter(|next| {
+            next.addr == current.addr + 2 && !labelled.contains(&next.addr) && !expanded(next)
+        });
+        // a skip only skips the first instruction of a pattern, which means something else once it's rewritten
+        let after_skip = i
+            .checked_sub(1)
+            .and_then(|previous| placed[previous].op)
+            .is_some_and(is_skip);
+        let found = match current.op {
+            Some(op) if !after_skip && !expanded(current) => rules
+                .iter()
+                .find_map(|rule| rule.apply(current, op, next).map(|found| (rule, found))),
+            _ => None,
+        };
+
+        match found {
+            Some((rule, (rewrites, consumed))) => {
+                let rewritten: Edits = rewrites.into_iter().collect();
+                let (mut before, mut after) = (Vec::new(), Vec::new());
+                for placed in &placed[i..i + consumed] {
+                    let text = lines[placed.line - 1].trim();
+                    before.push(text);
+                    let text = rewritten.get(&placed.line).map_or(text, String::as_str);
+                    if !text.is_empty() {
+                        after.push(text);
+                    }
+                }
+                let rule = rule.to_possible_value().expect("no rule is hidden");
+                match after.as_slice() {
+                    [] => eprintln!(
+                        "line {}: {}: `{}` was dropped",
+                        current.line,
+                        rule.get_name(),
+                        before.join("`, `")
+                    ),
+                    after => eprintln!(
+                        "line {}: {}: `{}` became `{}`",
+                        current.line,
+                        rule.get_name(),
+                        before.join("`, `"),
+                        after.join("`, `")
+                    ),
+                }
+                edits.extend(rewritten);
+                i += consumed;
+            }
+            None => i += 1,
+        }
+    }
+    edits
+}
+
+impl Rule {
+    /// The edits to make if the instructions starting at one match this rule, along with how many instructions the
+    /// match covers
+    fn apply(
+        self,
+        current: &Placed,
+        op: u16,
+        next: Option<&Placed>,
+    ) -> Option<(Vec<(usize, String)>, usize)> {
+        let next_op = next.and_then(|next| next.op);
+        match self {
+            Rule::MergeLoadAdd => {
+                let x = op >> 8 & 0xF;
+                let add = next_op.filter(|next| op & 0xF0FF == 0x6000 && next >> 8 == 0x70 | x)?;
+                let merged = format!("LD V{x:X}, {:#04X}", add & 0xFF);
+                Some((vec![(current.line, merged), (next?.line, String::new())], 2))
+            }
+            Rule::DuplicateLoadI => {
+                next_op.filter(|&next| op & 0xF000 == 0xA000 && next == op)?;
+                Some((vec![(next?.line, String::new())], 2))
+            }
+            Rule::JumpToNext => (op == 0x1000 | (current.addr as u16 + 2))
+                .then(|| (vec![(current.line, String::new())], 1)),
+        }
+    }
+}
+
+/// Every instruction and piece of data in the program, telling apart instructions by their source line starting
+/// with a mnemonic
+fn placed(source: &str, program: &Program) -> Vec<Placed> {
+    let lines: Vec<&str> = source.lines().collect();
+    program
+        .lines
+        .iter()
+        .map(|&(addr, line)| {
+            let offset = addr - program.base;
+            let op = tokenize::tokenize_line(lines[line - 1])
+                .head()
+                .is_some_and(assemble::is_mnemonic)
+                .then(|| u16::from_be_bytes([program.rom[offset], program.rom[offset + 1]]));
+            Placed { addr, line, op }
+        })
+        .collect()
+}
+
+/// The source with some (1-indexed) lines replaced, so every other line keeps its number
+fn rewrite(source: &str, edits: &Edits) -> String {
+    let mut text = String::with_capacity(source.len());
+    for (number, line) in source.lines().enumerate() {
+        text.push_str(edits.get(&(number + 1)).map_or(line, String::as_str));
+        text.push('\n');
+    }
+    text
+}
+
+/// Whether an instruction might skip the one after it
+pub fn is_skip(op: u16) -> bool {
+    match op >> 12 {
+        0x3 | 0x4 | 0x5 | 0x9 => true,
+        0xE => matches!(op & 0xFF, 0x9E | 0xA1),
+        _ => false,
+    }
+}
+
+/// Every address execution can reach by following jumps, calls, skips, and returns from the start of the rom.
+/// This errs on the side of reaching too much: anything that isn't provably unreachable counts as reached
+fn reachable(rom: &[u8], start: u16) -> HashSet<u16> {
+    let opcode_at = |addr: u16| {
+        let offset = addr.checked_sub(start)? as usize;
+        Some(u16::from_be_bytes([
+            *rom.get(offset)?,
+            *rom.get(offset + 1)?,
+        ]))
+    };
+
+    let mut reached = HashSet::new();
+    let mut pending = vec![start];
+    while let Some(addr) = pending.pop() {
+        let Some(op) = opcode_at(addr) else {
+            continue;
+        };
+        if !reached.insert(addr) {
+            continue;
+        }
+
+        let next = addr + 2;
+        let nnn = op & 0x0FFF;
+        match (op & 0xF000) >> 12 {
+            _ if op == 0x00EE => (),
+            0x1 => pending.push(nnn),
+            // a routine comes back to the instruction after the call, unless it never returns, which we can't tell
+            0x2 => pending.extend([nnn, next]),
+            // the target of a computed jump isn't known, but it's almost always into a table of jumps, so everything
+            // in the run of jumps at the base address is reachable
+            0xB => {
+                let mut entry = nnn;
+                pending.push(entry);
+                while opcode_at(entry).is_some_and(|op| op & 0xF000 == 0x1000) {
+                    pending.push(entry);
+                    entry += 2;
+                }
+            }
+            _ if is_skip(op) => pending.extend([next, next + 2]),
+            _ => pending.push(next),
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rom of a source after the given optimizations
+    fn optimized(source: &str, optimizations: &[Optimization], disabled: &[Rule]) -> Vec<u8> {
+        let options = Options::default();
+        let program = assemble_program(source, &options).unwrap();
+        optimize(source, program, &options, optimizations, disabled)
+            .unwrap()
+            .rom
+    }
+
+    fn with_peephole(source: &str) -> Vec<u8> {
+        optimized(source, &[Optimization::Peephole], &[])
+    }
+
+    #[test]
+    fn remove_unreachable() {
+        let removed = |source| optimized(source, &[Optimization::RemoveUnreachable], &[]);
+        // labels after what's removed move back to match
+        assert_eq!(
+            removed("CLS\nJP end\nLD V0, 1\nADD V0, 2\nend:\nHALT"),
+            [0x00, 0xE0, 0x12, 0x04, 0x12, 0x04]
+        );
+        // as do routines nothing calls
+        assert_eq!(removed("HALT\nunused:\nCLS\nRET"), [0x12, 0x00]);
+    }
+
+    #[test]
+    fn everything_reachable_is_kept() {
+        let removed = |source| optimized(source, &[Optimization::RemoveUnreachable], &[]);
+        // data is never removed, even if nothing reads it
+        assert_eq!(
+            removed("JP start\ndb 0xFF, 0x81\nstart:\nHALT"),
+            [0x12, 0x04, 0xFF, 0x81, 0x12, 0x04]
+        );
+        // nor the instruction after a call or one that may be skipped
+        assert_eq!(
+            removed("CALL routine\nHALT\nroutine:\nSE V0, 1\nRET\nLD V1, 2\nRET"),
+            [0x22, 0x04, 0x12, 0x02, 0x30, 0x01, 0x00, 0xEE, 0x61, 0x02, 0x00, 0xEE]
+        );
+        // nor the table a computed jump lands in
+        assert_eq!(
+            removed("JP V0, table\ntable:\nJP left\nJP right\nleft:\nHALT\nright:\nHALT"),
+            [0xB2, 0x02, 0x12, 0x06, 0x12, 0x08, 0x12, 0x06, 0x12, 0x08]
+        );
+    }
+
+    #[test]
+    fn merge_load_add() {
+        assert_eq!(
+            with_peephole("LD V3, 0\nADD V3, 5\nHALT"),
+            [0x63, 0x05, 0x12, 0x02]
+        );
+        // the ADD is run on its own when something jumps to it
+        assert_eq!(
+            with_peephole("LD V3, 0\nagain:\nADD V3, 5\nJP again"),
+            [0x63, 0x00, 0x73, 0x05, 0x12, 0x02]
+        );
+        // or when the LD may be skipped
+        assert_eq!(
+            with_peephole("SE V0, 1\nLD V3, 0\nADD V3, 5\nHALT"),
+            [0x30, 0x01, 0x63, 0x00, 0x73, 0x05, 0x12, 0x06]
+        );
+    }
+
+    #[test]
+    fn duplicate_load_i() {
+        assert_eq!(
+            with_peephole("LD I, 0x300\nLD I, 0x300\nHALT"),
+            [0xA3, 0x00, 0x12, 0x02]
+        );
+        assert_eq!(
+            with_peephole("LD I, 0x300\nLD I, 0x301\nHALT"),
+            [0xA3, 0x00, 0xA3, 0x01, 0x12, 0x04]
+        );
+    }
+
+    #[test]
+    fn jump_to_next() {
+        assert_eq!(with_peephole("JP next\nnext:\nHALT"), [0x12, 0x00]);
+    }
+
+    #[test]
+    fn disabled_rules() {
+        assert_eq!(
+            optimized(
+                "JP next\nnext:\nHALT",
+                &[Optimization::Peephole],
+                &[Rule::JumpToNext]
+            ),
+            [0x12, 0x02, 0x12, 0x02]
+        );
+    }
+
+    #[test]
+    fn repeated_lines_are_left_alone() {
+        // each copy is the same line, so rewriting it for one would rewrite both
+        assert_eq!(
+            with_peephole("rept 2\nLD I, 0x300\nendr\nHALT"),
+            [0xA3, 0x00, 0xA3, 0x00, 0x12, 0x04]
+        );
+        assert_eq!(
+            with_peephole("LD V0, 0\nrept 2\nADD V0, 1\nLD V0, 0\nendr\nHALT"),
+            [0x60, 0x00, 0x70, 0x01, 0x60, 0x00, 0x70, 0x01, 0x60, 0x00, 0x12, 0x0A]
+        );
+        // a single copy is just a line
+        assert_eq!(
+            with_peephole("rept 1\nLD V0, 0\nADD V0, 1\nendr\nHALT"),
+            [0x60, 0x01, 0x12, 0x02]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use serde_json::{json, Value};
+
+use super::assemble::{self, parse};
+use super::preprocess;
+use super::tokenize;
+use super::{Program, RunError};
+
+/// Directives whose second token is the name of what they declare, and what kind of symbol that is
+const DECLARATIONS: [(&str, &str); 12] = [
+    ("alias", "alias"),
+    ("const", "constant"),
+    ("reg", "register"),
+    ("sprite", "sprite"),
+    ("sprite16", "sprite"),
+    ("spritesheet", "spritesheet"),
+    ("data", "data"),
+    ("text", "text"),
+    ("bcdtable", "table"),
+    ("jumptable", "jumptable"),
+    ("struct", "struct"),
+    ("var", "variable"),
+];
+
+/// Preprocessor keywords that don't declare anything themselves
+const DIRECTIVES: [&str; 45] = [
+    "include",
+    "incbin",
+    "equ",
+    ".if",
+    ".elif",
+    ".else",
+    ".endif",
+    ".ifdef",
+    ".ifndef",
+    "if",
+    "elif",
+    "else",
+    "endif",
+    "ifdef",
+    "ifndef",
+    "pixels",
+    "font",
+    "padsprite",
+    "byteorder",
+    "dw",
+    "dw.be",
+    "dw.le",
+    ".dw",
+    ".dw.be",
+    ".dw.le",
+    "db",
+    ".db",
+    "ds",
+    "align",
+    "rept",
+    ".rept",
+    "repeat",
+    "endr",
+    ".endr",
+    ".align",
+    "fill",
+    ".fill",
+    "org",
+    ".org",
+    "module",
+    "endmodule",
+    "export",
+    "endsprite",
+    "enddata",
+    "plane2",
+];
+
+/// Arguments that name a part of the machine rather than a value
+const KEYWORDS: [&str; 7] = ["I", "[I]", "DT", "ST", "K", "F", "B"];
+
+/// Where a symbol is declared in the source. Lines and characters are zero-indexed, as editors expect
+#[derive(Debug)]
+pub struct Declaration<'a> {
+    pub name: &'a str,
+    pub kind: &'static str,
+    pub line: usize,
+    /// The range of characters the name covers
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Every symbol declared in the source, in order
+pub fn declarations(text: &str) -> Vec<Declaration<'_>> {
+    let mut declarations = Vec::new();
+    for (number, source) in text.lines().enumerate() {
+        let line = tokenize::tokenize_line(source);
+        let (name, kind) = match line.tokens.first() {
+            Some(first) if line.text.ends_with(':') && line.tokens.len() == 1 => (first, "label"),
+            Some(first) if line.tokens.get(1).is_some_and(|t| t.text == "equ") => {
+                (first, "constant")
+            }
+            Some(first) => match DECLARATIONS.iter().find(|(head, _)| *head == first.text) {
+                Some(&(_, kind)) => match line.tokens.get(1) {
+                    Some(name) => (name, kind),
+                    None => continue,
+                },
+                None => continue,
+            },
+            None => continue,
+        };
+        let text = name.text.trim_end_matches(':');
+        let start = name.column - 1;
+        declarations.push(Declaration {
+            name: text,
+            kind,
+            line: number,
+            start,
+            end: start + text.len(),
+        });
+    }
+    declarations
+}
+
+/// The declaration of a symbol, or of what a generated symbol like `NAME_HEIGHT` or `Struct.field` was generated from
+pub fn find_declaration<'d, 'a>(
+    declarations: &'d [Declaration<'a>],
+    token: &str,
+) -> Option<&'d Declaration<'a>> {
+    let token = token.trim_end_matches(':');
+    declarations
+        .iter()
+        .find(|declaration| declaration.name == token)
+        .or_else(|| {
+            declarations
+                .iter()
+                .filter(|declaration| {
+                    token
+                        .strip_prefix(declaration.name)
+                        .is_some_and(|rest| rest.starts_with(['_', '.']))
+                })
+                .max_by_key(|declaration| declaration.name.len())
+        })
+}
+
+/// A classified span of the source for highlighting, on a zero-indexed line
+#[derive(Debug)]
+pub struct SemanticToken {
+    pub line: usize,
+    pub start: usize,
+    pub length: usize,
+    pub kind: &'static str,
+}
+
+/// Classify every token and comment in the source the way the assembler sees them, as a `mnemonic`, `directive`,
+/// `keyword` like `DT`, `register`, `literal` number or string, `label-def`, `label-ref`, other `symbol` like a
+/// constant or sprite, or `comment`
+pub fn semantic_tokens(text: &str) -> Vec<SemanticToken> {
+    let labels: Vec<&str> = declarations(text)
+        .into_iter()
+        .filter(|declaration| declaration.kind == "label")
+        .map(|declaration| declaration.name)
+        .collect();
+    let mut tokens = Vec::new();
+    for (number, source) in text.lines().enumerate() {
+        let line = tokenize::tokenize_line(source);
+        for (i, token) in line.tokens.iter().enumerate() {
+            let kind = match token.text {
+                t if i == 0 && t.ends_with(':') && line.tokens.len() == 1 => "label-def",
+                t if i == 0 && (assemble::is_mnemonic(t) || preprocess::is_pseudo_op(t)) => {
+                    "mnemonic"
+                }
+                t if i == 0
+                    && (DIRECTIVES.contains(&t)
+                        || DECLARATIONS.iter().any(|(head, _)| *head == t)) =>
+                {
+                    "directive"
+                }
+                t if KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(t)) => "keyword",
+                t if t.starts_with('"') || t.ends_with('"') => "literal",
+                t => match parse::parse_asm_arg(t) {
+                    Ok(parse::AsmArgument::Register(_)) => "register",
+                    Ok(_) => "literal",
+                    Err(_) if labels.contains(&t) => "label-ref",
+                    Err(_) => "symbol",
+                },
+            };
+            tokens.push(SemanticToken {
+                line: number,
+                start: token.column - 1,
+                length: token.text.len(),
+                kind,
+            });
+        }
+        if let Some(start) = tokenize::comment_start(source) {
+            tokens.push(SemanticToken {
+                line: number,
+                start,
+                length: source.len() - start,
+                kind: "comment",
+            });
+        }
+    }
+    tokens
+}
+
+/// Everything an editor plugin needs to build an outline and highlighting: every declaration, with its address or value if
+/// the source assembled, every classified token, and anything wrong with the source
+pub fn export(text: &str, program: &Result<Program, RunError>) -> Value {
+    let symbols: Vec<Value> = declarations(text)
+        .iter()
+        .map(|declaration| {
+            let program = program.as_ref().ok();
+            let addr = program.and_then(|program| program.labels.get(declaration.name));
+            let value = program.and_then(|program| program.constants.get(declaration.name));
+            json!({
+                "name": declaration.name,
+                "kind": declaration.kind,
+                "line": declaration.line,
+                "start": declaration.start,
+                "end": declaration.end,
+                "address": addr,
+                "value": value,
+            })
+        })
+        .collect();
+
+    let tokens = tokens(text);
+
+    let diagnostics: Vec<Value> = match program {
+        Ok(program) => program
+            .warnings
+            .iter()
+            .map(|warning| diagnostic(warning.line(), "warning", warning.to_string()))
+            .collect(),
+        Err(err) => err
+            .diagnostics(text)
+            .into_iter()
+            .map(|(line, message, fix)| {
+                let mut diagnostic = diagnostic(line, "error", message);
+                if let Some(fix) = fix {
+                    diagnostic["fix"] = fix.to_json();
+                }
+                diagnostic
+            })
+            .collect(),
+    };
+
+    json!({ "symbols": symbols, "tokens": tokens, "diagnostics": diagnostics })
+}
+
+/// Every classified token and comment in the source, as JSON objects with the zero-indexed line, start, length, and
+/// type of each
+pub fn tokens(text: &str) -> Value {
+    let tokens: Vec<Value> = semantic_tokens(text)
+        .iter()
+        .map(|token| {
+            json!({
+                "line": token.line,
+                "start": token.start,
+                "length": token.length,
+                "type": token.kind,
+            })
+        })
+        .collect();
+    json!(tokens)
+}
+
+/// A problem on a (1-indexed) line of the source, reported on its zero-indexed line
+fn diagnostic(line: usize, severity: &str, message: String) -> Value {
+    json!({ "line": line.saturating_sub(1), "severity": severity, "message": message })
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use thiserror::Error;
+
+use super::project::{self, ProjectError};
+
+/// A problem reading the directives out of the project file
+#[derive(Debug, Error)]
+pub enum PluginsError {
+    #[error("{0}")]
+    Project(
+        #[from]
+        #[source]
+        ProjectError,
+    ),
+    #[error(
+        "the [directives] table of {} should map names to commands, like `NAME = \"COMMAND\"`",
+        project::PROJECT_FILE
+    )]
+    Invalid,
+}
+
+/// The command run for each external directive declared in the `[directives]` table of the project file next to the
+/// source, if there is one
+pub fn load(dir: &Path) -> Result<HashMap<String, String>, PluginsError> {
+    let Some(manifest) = project::read(dir)? else {
+        return Ok(HashMap::new());
+    };
+    let Some(directives) = manifest.get("directives") else {
+        return Ok(HashMap::new());
+    };
+    directives
+        .as_table()
+        .ok_or(PluginsError::Invalid)?
+        .iter()
+        .map(|(name, command)| match command.as_str() {
+            Some(command) if !command.trim().is_empty() => Ok((name.clone(), command.to_string())),
+            _ => Err(PluginsError::Invalid),
+        })
+        .collect()
+}
+
+/// Run a directive's command on a block, returning the lines to put in its place or a description of what went wrong.
+/// The arguments on the directive's line are passed after the command's own, and the lines of the block are written
+/// to its stdin
+pub fn run(command: &str, args: &[&str], block: &str) -> Result<String, String> {
+    // we don't go through a shell, so arguments are simply split on whitespace, the same as --run-with
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or("the command is empty")?;
+    let mut child = Command::new(program)
+        .args(words)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("couldn't run `{command}`: {e}"))?;
+
+    // the block is written from a thread of its own while the output is read, so a command that writes as it reads
+    // can't fill both pipes and leave us each waiting on the other
+    let stdin = child.stdin.take();
+    let (written, output) = thread::scope(|scope| {
+        let writer = scope.spawn(move || match stdin {
+            Some(mut stdin) => stdin.write_all(block.as_bytes()),
+            None => Ok(()),
+        });
+        let output = child.wait_with_output();
+        (writer.join(), output)
+    });
+    let output = output.map_err(|e| format!("couldn't run `{command}`: {e}"))?;
+    match written {
+        // a command that doesn't read its input closes the pipe, which isn't an error as long as it succeeds
+        Ok(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => {
+            return Err(format!("couldn't write to `{command}`: {e}"))
+        }
+        Err(_) => return Err(format!("couldn't write to `{command}`")),
+        _ => (),
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => format!("`{command}` exited with {}", output.status),
+            stderr => format!("`{command}` exited with {}: {stderr}", output.status),
+        });
+    }
+    String::from_utf8(output.stdout).map_err(|_| format!("`{command}` didn't output utf-8 text"))
+}
@@ -1,42 +1,288 @@
-use std::cmp::Ordering;
+use alloc::borrow::Cow;
+#[cfg(feature = "std")]
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+// without std there's no random state to hash with, so the maps are ordered instead
+#[cfg(not(feature = "std"))]
+use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+use core::cmp::Ordering;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::collections::{HashMap, HashSet};
-use std::ops::Deref;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
 // the module path could be cleaned up a bit to make this nicer
-use super::assemble::parse::{self, AsmArgParseError};
+use super::assemble::ir::{Data, Instruction};
+use super::assemble::parse::{self, AsmArgParseError, AsmArgument};
+use super::assemble::{self, ByteOrder, MEMORY_SIZE, PROGRAM_START};
+#[cfg(feature = "std")]
+use super::bitmap::{self, BitmapError};
+#[cfg(feature = "std")]
+use super::plugins;
+use super::symbols::SymbolTable;
+use super::tokenize::{self, Line, Token};
 
-/// strings that shouldn't be used as aliases or labels because they have other meanings
-const RESERVED_WORDS: [&str; 21] = [
-    "CLS", "RET", "SYS", "JP", "CALL", "SE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SHL",
-    "SUBN", "SNE", "RND", "DRW", "SKP", "SKNP", "alias",
+mod arena;
+mod conditional;
+mod fill;
+#[cfg(feature = "std")]
+mod include;
+#[cfg(feature = "std")]
+mod library;
+mod local;
+mod pseudo;
+mod registers;
+mod repeat;
+mod sprite;
+#[cfg(feature = "std")]
+mod warning;
+pub use arena::Arena;
+pub use conditional::Target;
+pub use fill::Fill;
+#[cfg(feature = "std")]
+pub use pseudo::is_pseudo_op;
+pub use sprite::Sprite;
+#[cfg(feature = "std")]
+pub use warning::{WarningFlag, Warnings};
+
+/// Every directive that starts a line or ends a block of them, apart from the conditional and repeat ones, which their
+/// own modules list
+const DIRECTIVES: [&str; 39] = [
+    "include",
+    "incbin",
+    "alias",
+    "const",
+    "equ",
+    "reg",
+    "pixels",
+    "font",
+    "padsprite",
+    "byteorder",
+    "dw",
+    "dw.be",
+    "dw.le",
+    ".dw",
+    ".dw.be",
+    ".dw.le",
+    "db",
+    ".db",
+    "ds",
+    "align",
+    ".align",
+    "fill",
+    ".fill",
+    "org",
+    ".org",
+    "text",
+    "bcdtable",
+    "jumptable",
+    "sprite",
+    "sprite16",
+    "spritesheet",
+    "endsprite",
+    "struct",
+    "module",
+    "endmodule",
+    "export",
+    "var",
+    "data",
+    "enddata",
 ];
 
-/// To save allocations, we represent the instructions as an enum after processing so some can use the original string views while others are new strings
-#[derive(Debug)]
-pub enum PreprocessedInstruction<'a> {
-    Unchanged(&'a str),
-    Changed(String),
+/// Whether a word can't be the name of a label, alias, or constant because it has another meaning: a directive, the
+/// uppercase mnemonic of an operation or pseudo-instruction, or a register, keyword like `DT`, or number. Registers,
+/// keywords, and numbers are typed as soon as an instruction is placed, before any name is known
+fn is_reserved(name: &str) -> bool {
+    DIRECTIVES.contains(&name)
+        || conditional::DIRECTIVES.contains(&name)
+        || repeat::STARTS.contains(&name)
+        || repeat::ENDS.contains(&name)
+        || assemble::mnemonics().any(|mnemonic| mnemonic == name)
+        || pseudo::mnemonics().any(|mnemonic| mnemonic == name)
+        || parse::parse_asm_arg(name).is_ok()
+}
+
+/// Whether a word can't be the name of an alias. Aliases are substituted for the mnemonic of a line as well as its
+/// arguments, so on top of the reserved words, no mnemonic of an operation or pseudo-instruction can be one in any case
+fn is_reserved_alias(name: &str) -> bool {
+    is_reserved(name) || assemble::is_mnemonic(name) || pseudo::is_pseudo_op(name)
 }
 
-/// To seamlessly call functions on collections of instructions, we implement deref str
-impl<'a> Deref for PreprocessedInstruction<'a> {
-    type Target = str;
-    fn deref(&self) -> &str {
+/// The characters for lit and unlit pixels in pixel art sprite rows, until changed with `pixels`
+const DEFAULT_PIXELS: (char, char) = ('X', '.');
+
+/// The characters of the built in hex font in glyph order, until changed with `font`
+const DEFAULT_GLYPHS: &str = "0123456789ABCDEF";
+
+/// To save allocations, instructions keep borrowing the source after processing. Only what the preprocessor itself generates is stored some other way
+#[derive(Debug, Clone)]
+pub enum InstructionText<'a> {
+    /// A line of source to be assembled, typed here and with any symbols resolved at encode time
+    Source(Instruction<'a>),
+    /// A block of data generated by the preprocessor, copied into the rom as is
+    Data(Vec<u8>),
+    /// Instructions generated by the preprocessor, already encoded, copied into the rom as is
+    Code(Vec<u8>),
+    /// A line of 16 bit values from `dw`, with any symbols resolved at encode time
+    Words(Data<'a>, ByteOrder),
+    /// A line of bytes from `db`, with any symbols resolved at encode time
+    Bytes(Data<'a>),
+}
+
+impl InstructionText<'_> {
+    /// How many bytes the instruction takes up once encoded
+    pub fn size(&self) -> usize {
         match self {
-            Self::Changed(s) => s.deref(),
-            Self::Unchanged(s) => s,
+            InstructionText::Source(_) => 2,
+            InstructionText::Data(bytes) | InstructionText::Code(bytes) => bytes.len(),
+            // every token but the directive itself is a word
+            InstructionText::Words(data, _) => (data.line.tokens.len() - 1) * 2,
+            InstructionText::Bytes(data) => data.line.tokens.len() - 1,
         }
     }
 }
 
-impl<'a> From<&'a str> for PreprocessedInstruction<'a> {
-    fn from(s: &'a str) -> PreprocessedInstruction<'a> {
-        Self::Unchanged(s)
+/// An instruction after the first pass, along with where it will be placed and the line of the source it originated from
+#[derive(Debug)]
+pub struct PreprocessedInstruction<'a> {
+    text: InstructionText<'a>,
+    #[cfg(feature = "std")]
+    addr: usize,
+    line: usize,
+    /// Where in an included file or library it came from, if it did
+    origin: Option<Origin>,
+}
+
+/// Where an instruction from an included file or library came from: the file, named the way errors in it are, and the
+/// (1-indexed) line in it
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub file: Arc<str>,
+    pub line: usize,
+}
+
+impl<'a> PreprocessedInstruction<'a> {
+    /// What the instruction is made of
+    pub fn text(&self) -> &InstructionText<'a> {
+        &self.text
+    }
+
+    /// The tokenized source of the instruction, if it came straight from the source
+    fn source(&self) -> Option<&Line<'a>> {
+        match &self.text {
+            InstructionText::Source(inst) => Some(&inst.line),
+            InstructionText::Data(_)
+            | InstructionText::Code(_)
+            | InstructionText::Words(..)
+            | InstructionText::Bytes(_) => None,
+        }
+    }
+
+    /// The address the instruction will be placed at
+    #[cfg(feature = "std")]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// The (1-indexed) line of the source this instruction originated from
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Where in an included file or library this instruction came from, or None if it came from the source itself
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+}
+
+/// Choices that change how the source is preprocessed
+#[derive(Debug)]
+pub struct Options {
+    /// Where files the source refers to are looked up
+    #[cfg(feature = "std")]
+    pub dir: PathBuf,
+    /// Place only one copy of sprites with identical bytes, pointing every name at it
+    pub dedup_sprites: bool,
+    /// Symbols from another build, like the routines of a base rom an overlay calls into
+    pub imports: Vec<(String, usize)>,
+    /// The command run for each external directive declared in the project file, by name
+    #[cfg(feature = "std")]
+    pub directives: HashMap<String, String>,
+    /// The system being built for, which decides the branch of `.if TARGET == ...` blocks that's assembled
+    pub target: Target,
+    /// The address the program is loaded at, which is 0x200 unless it's for a platform that loads it somewhere else
+    pub base: usize,
+    /// The kinds of warnings that are reported
+    #[cfg(feature = "std")]
+    pub warnings: Warnings,
+    /// Where libraries included with `include <NAME>` are looked for, before the bundled standard library
+    #[cfg(feature = "std")]
+    pub include_paths: Vec<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            #[cfg(feature = "std")]
+            dir: PathBuf::new(),
+            dedup_sprites: false,
+            imports: Vec::new(),
+            #[cfg(feature = "std")]
+            directives: HashMap::new(),
+            target: Target::default(),
+            base: PROGRAM_START as usize,
+            #[cfg(feature = "std")]
+            warnings: Warnings::default(),
+            #[cfg(feature = "std")]
+            include_paths: Vec::new(),
+        }
     }
 }
 
+/// The output of the first pass: sized and placed instructions ready to be encoded and the symbols to resolve while encoding them
+#[derive(Debug)]
+pub struct Preprocessed<'a> {
+    pub instructions: Vec<PreprocessedInstruction<'a>>,
+    pub symbols: SymbolTable<'a>,
+    /// How many bytes the encoded program takes up
+    pub size: usize,
+    /// How many bytes of sprites were left out by deduplication
+    #[cfg(feature = "std")]
+    pub sprite_bytes_saved: usize,
+    #[cfg(feature = "std")]
+    pub sprites: Vec<PlacedSprite>,
+    #[cfg(feature = "std")]
+    pub warnings: Vec<PreprocessingWarning>,
+    /// Where free memory starts, after the program and its variables
+    #[cfg(feature = "std")]
+    pub free_memory: usize,
+    /// The address of every instruction the preprocessor generated with an address inside the program already filled
+    /// in, which has to be moved along with the program when it's linked somewhere else
+    #[cfg(feature = "std")]
+    pub fixed_jumps: Vec<usize>,
+    /// Every file pulled in with `include`, so they can be watched along with the source
+    #[cfg(feature = "std")]
+    pub included: Vec<PathBuf>,
+}
+
+/// A sprite along with the name it was declared with and the address its bytes start at
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct PlacedSprite {
+    pub name: String,
+    pub addr: usize,
+    pub sprite: Sprite,
+}
+
 #[derive(Debug, Error)]
 pub enum PreprocessingError {
     #[error("Too many arguments for `alias` preprocessor instruction: {0}")]
@@ -47,6 +293,82 @@ pub enum PreprocessingError {
     ReservedAlias(String),
     #[error("Reused alias in alias declaration: {0}")]
     ReusedAlias(String),
+    #[error("Invalid condition (expected `TARGET == NAME` or `TARGET != NAME`, where NAME is chip8, schip, or xochip, an expression, or the single NAME of `ifdef` or `ifndef`): {0}")]
+    InvalidCondition(String),
+    #[error("Couldn't evaluate the condition of {line}: {error}")]
+    UnevaluableCondition {
+        line: String,
+        error: AsmArgParseError,
+    },
+    #[error("`elif`, `else`, or `endif` without an open `if`, or after the `else`: {0}")]
+    UnexpectedConditional(String),
+    #[error("Missing `endif` for block: {0}")]
+    UnclosedIf(String),
+    #[error(
+        "Invalid `rept` (expected `rept COUNT`, optionally followed by the NAME of the index): {0}"
+    )]
+    InvalidRepeat(String),
+    #[error("Missing `endr` for block: {0}")]
+    UnclosedRepeat(String),
+    #[cfg(feature = "std")]
+    #[error(
+        "Invalid include (expected `include \"PATH\"`, optionally followed by `as NAMESPACE`): {0}"
+    )]
+    InvalidInclude(String),
+    #[cfg(feature = "std")]
+    #[error("Couldn't read included file {path}")]
+    UnreadableInclude {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[cfg(feature = "std")]
+    #[error(
+        "Invalid incbin (expected `incbin \"PATH\"`, optionally followed by an OFFSET and LENGTH): {0}"
+    )]
+    InvalidIncbin(String),
+    #[cfg(feature = "std")]
+    #[error("Couldn't read binary file {path}")]
+    UnreadableIncbin {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[cfg(feature = "std")]
+    #[error("{path} is only {size} bytes, so it doesn't have the bytes asked for by {line}")]
+    IncbinOutOfRange {
+        path: String,
+        size: usize,
+        line: String,
+    },
+    #[cfg(feature = "std")]
+    #[error("External directive `{name}` failed: {reason}")]
+    ExternalDirective { name: String, reason: String },
+    #[cfg(feature = "std")]
+    #[error("Missing `end` line for external directive: {0}")]
+    UnclosedExternalDirective(String),
+    #[cfg(feature = "std")]
+    #[error("No library `{0}` in the include paths or the standard library")]
+    UnknownLibrary(String),
+    #[cfg(feature = "std")]
+    #[error("{0} includes itself")]
+    RecursiveInclude(String),
+    #[cfg(feature = "std")]
+    #[error("{path} line {line}: {error}")]
+    Included {
+        path: String,
+        line: usize,
+        #[source]
+        error: Box<PreprocessingError>,
+    },
+    #[error("Invalid constant (expected `const NAME VALUE` or `NAME equ VALUE`, where VALUE is a number or an already defined symbol): {0}")]
+    InvalidConstant(String),
+    #[error("Reused name in constant declaration: {0}")]
+    ReusedConstant(String),
+    #[error("Invalid virtual register (expected `reg NAME`): {0}")]
+    InvalidVirtualRegister(String),
+    #[error("No register left for virtual register `{name}`: every one of V0-VE is either used by name or holds a live virtual register ({live})")]
+    OutOfRegisters { name: String, live: String },
     #[error("Too many arguments for `sprite` preprocessor instruction: {0}")]
     TooManySpriteArgs(String),
     #[error("Too few arguments for `sprite` preprocessor instruction: {0}")]
@@ -55,6 +377,12 @@ pub enum PreprocessingError {
     UnclosedSprite(String),
     #[error("Sprite of over 15 bytes delcared with {0}")]
     OversizedSprite(String),
+    #[error("16 pixel wide sprite of over 16 rows (32 bytes) declared with {0}")]
+    OversizedSprite16(String),
+    #[error("Sprite planes must be the same height, with a single `plane2` between them: {0}")]
+    MismatchedPlanes(String),
+    #[error("Sprite row isn't a number or pixel art: {0}")]
+    InvalidSpriteRow(String),
     #[error("unable to parse byte in sprite: {0}")]
     InvalidSpriteByte(#[from] AsmArgParseError),
     #[error("Use of reserved word in label: {0}")]
@@ -63,344 +391,2196 @@ pub enum PreprocessingError {
     InvalidLabel(String),
     #[error("Invalid memory offset (probably contains nonnumeric characters): {0}")]
     InvalidOffset(String),
+    #[error("Too many arguments for `data` preprocessor instruction: {0}")]
+    TooManyDataArgs(String),
+    #[error("Too few arguments for `data` preprocessor instruction: {0}")]
+    TooFewDataArgs(String),
+    #[error("Missing 'enddata' instruction for data declared with {0}")]
+    UnclosedData(String),
+    #[error("unable to parse byte in data: {0}")]
+    InvalidDataByte(AsmArgParseError),
+    #[error("Data block too big to fit in memory, declared with {0}")]
+    OversizedData(String),
+    #[error("Invalid `ds` (expected `ds COUNT`, optionally followed by a fill of `byte B`, `pattern B ...`, or `random SEED`): {0}")]
+    InvalidSpace(String),
+    #[error("Invalid `align` (expected `align N` with N above 0, optionally followed by a fill of `byte B`, `pattern B ...`, or `random SEED`): {0}")]
+    InvalidAlign(String),
+    #[error("Invalid `fill` (expected `fill COUNT, BYTE`, with BYTE from 0 to 0xFF): {0}")]
+    InvalidFill(String),
+    #[error("Invalid `org` (expected `org ADDR`, optionally followed by a fill of `byte B`, `pattern B ...`, or `random SEED`): {0}")]
+    InvalidOrigin(String),
+    #[error("The program is already at {addr:#05X}, past where {line} would start it")]
+    OriginBehind { addr: usize, line: String },
+    #[error("`font` preprocessor instruction takes a string with one character per glyph, each used once: {0}")]
+    InvalidFont(String),
+    #[error("Invalid text table (expected `text NAME \"STRING\"`): {0}")]
+    InvalidText(String),
+    #[error("Character `{glyph}` isn't in the current font: {line}")]
+    UnknownGlyph { glyph: char, line: String },
+    #[error("Generated constant `{0}` clashes with an existing label or constant")]
+    ConstantClash(String),
+    #[error("Invalid struct (expected `struct NAME {{ FIELD: SIZE, ... }}`): {0}")]
+    InvalidStruct(String),
+    #[error("Missing `}}` for struct declared with {0}")]
+    UnclosedStruct(String),
+    #[error("Invalid variable (expected `var NAME TYPE` or `var NAME TYPE[COUNT]`, where TYPE is a struct or size): {0}")]
+    InvalidVar(String),
+    #[error("Variable doesn't fit in memory after the program: {0}")]
+    OversizedVar(String),
+    #[error("Invalid jump table (expected `jumptable NAME: LABEL, ...` with 1 to 128 labels, inside addressable memory): {0}")]
+    InvalidJumpTable(String),
+    #[error("Invalid pseudo-instruction (expected `NOP`, `HALT`, `MOV Vx, Vy`, `CLR Vx`, `INC Vx`, or `DEC Vx`, with `HALT` inside addressable memory): {0}")]
+    InvalidPseudoOp(String),
+    #[error("Jump table entry `{entry}` isn't a label: {header}")]
+    UnknownJumpTableEntry { entry: String, header: String },
+    #[error("Invalid BCD table (expected `bcdtable NAME MAX` with MAX up to 255): {0}")]
+    InvalidBcdTable(String),
+    #[error("`padsprite` preprocessor instruction takes `off` or `byte` followed by a byte: {0}")]
+    InvalidPadSprite(String),
+    #[error("`byteorder` preprocessor instruction takes `be` or `le`: {0}")]
+    InvalidByteOrder(String),
+    #[error("Too few arguments for `db` or `dw`, which need at least one value: {0}")]
+    TooFewWordArgs(String),
     #[error("Reused label in label declaration: {0}")]
     ReusedLabel(String),
+    #[cfg(feature = "std")]
+    #[error("Invalid module (expected `module NAME`, running until `endmodule`): {0}")]
+    InvalidModule(String),
+    #[cfg(feature = "std")]
+    #[error("Missing `endmodule` for module declared with {0}")]
+    UnclosedModule(String),
+    #[cfg(feature = "std")]
+    #[error("`{name}` is exported from module `{module}` but isn't declared in it")]
+    UnknownExport { name: String, module: String },
+    #[cfg(feature = "std")]
+    #[error("`{name}` isn't exported from module `{module}`, so it can only be used inside it")]
+    UnexportedSymbol { name: String, module: String },
+    #[error("Local label declared before any global label it could belong to: {0}")]
+    UnscopedLocalLabel(String),
+    #[error("`pixels` preprocessor instruction takes two different single characters: {0}")]
+    InvalidPixels(String),
+    #[error("Pixel art sprite row wider than {width} pixels: {row}")]
+    WidePixelRow { width: u32, row: String },
+    #[cfg(feature = "std")]
+    #[error("Unclosed quote in sprite image path: {0}")]
+    InvalidSpritePath(String),
+    #[cfg(feature = "std")]
+    #[error("Unable to load image for sprite declared with {header}: {source}")]
+    SpriteImage {
+        header: String,
+        #[source]
+        source: BitmapError,
+    },
+    #[error("Sprite constant `{0}` clashes with an existing label or constant")]
+    SpriteConstantClash(String),
+    #[error("Transformation of a sprite that hasn't been declared yet: {0}")]
+    UnknownSprite(String),
+    #[error("Invalid sprite transformation (expected `mirror`, `flip`, `invert`, or `shift` with a number of pixels): {0}")]
+    InvalidSpriteTransform(String),
+    #[cfg(feature = "std")]
+    #[error("Invalid sprite sheet (expected `spritesheet NAME from PATH tile WIDTH, HEIGHT` dividing the image evenly): {0}")]
+    InvalidSpriteSheet(String),
+    #[cfg(feature = "std")]
+    #[error("Invalid region of sprite image (expected `rect X, Y, WIDTH, HEIGHT` inside the image): {0}")]
+    InvalidSpriteRect(String),
+    #[cfg(feature = "std")]
+    #[error("Sprite images must be 8 pixels wide and up to 15 tall, or 16 pixels wide and up to 16 tall: {0}")]
+    SpriteImageSize(String),
 }
 
-pub fn preprocess(unprocessed: &str) -> Result<Vec<PreprocessedInstruction>, PreprocessingError> {
-    // clean up the input before starting preprocessing
-    let mut lines = unprocessed
-        .lines()
-        .map(|l| l.trim()) // remove leading and trailing whitespace
-        .filter(|l| !l.is_empty()) // remove empty lines
-        .filter(|l| !l.starts_with(';')) // remove comment lines
-        // remove comments at the ends of lines
-        .map(|l| match l.find(';') {
-            None => l,
-            Some(i) => &l[..i],
-        })
-        // convert into preprocessedinstruction enums
-        .fold(Vec::new(), |mut acc, l| {
-            acc.push(PreprocessedInstruction::from(l));
-            acc
+impl PreprocessingError {
+    #[cfg(feature = "std")]
+    /// A short name for the kind of error that stays the same as the message is reworded, for tools that act on
+    /// particular errors. Errors from an included file are the kind of the error inside it
+    pub fn code(&self) -> &'static str {
+        match self {
+            PreprocessingError::TooManyAliasArgs(_) => "too-many-alias-args",
+            PreprocessingError::TooFewAliasArgs(_) => "too-few-alias-args",
+            PreprocessingError::ReservedAlias(_) => "reserved-alias",
+            PreprocessingError::ReusedAlias(_) => "reused-alias",
+            PreprocessingError::InvalidCondition(_) => "invalid-condition",
+            PreprocessingError::UnevaluableCondition { .. } => "unevaluable-condition",
+            PreprocessingError::UnexpectedConditional(_) => "unexpected-conditional",
+            PreprocessingError::UnclosedIf(_) => "unclosed-if",
+            PreprocessingError::InvalidRepeat(_) => "invalid-repeat",
+            PreprocessingError::UnclosedRepeat(_) => "unclosed-repeat",
+            #[cfg(feature = "std")]
+            PreprocessingError::InvalidInclude(_) => "invalid-include",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnreadableInclude { .. } => "unreadable-include",
+            #[cfg(feature = "std")]
+            PreprocessingError::InvalidIncbin(_) => "invalid-incbin",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnreadableIncbin { .. } => "unreadable-incbin",
+            #[cfg(feature = "std")]
+            PreprocessingError::IncbinOutOfRange { .. } => "incbin-out-of-range",
+            #[cfg(feature = "std")]
+            PreprocessingError::ExternalDirective { .. } => "external-directive",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnclosedExternalDirective(_) => "unclosed-external-directive",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnknownLibrary(_) => "unknown-library",
+            #[cfg(feature = "std")]
+            PreprocessingError::RecursiveInclude(_) => "recursive-include",
+            #[cfg(feature = "std")]
+            PreprocessingError::Included { error, .. } => error.code(),
+            PreprocessingError::InvalidConstant(_) => "invalid-constant",
+            PreprocessingError::ReusedConstant(_) => "reused-constant",
+            PreprocessingError::InvalidVirtualRegister(_) => "invalid-virtual-register",
+            PreprocessingError::OutOfRegisters { .. } => "out-of-registers",
+            PreprocessingError::TooManySpriteArgs(_) => "too-many-sprite-args",
+            PreprocessingError::TooFewSpriteArgs(_) => "too-few-sprite-args",
+            PreprocessingError::UnclosedSprite(_) => "unclosed-sprite",
+            PreprocessingError::OversizedSprite(_) => "oversized-sprite",
+            PreprocessingError::OversizedSprite16(_) => "oversized-sprite-16",
+            PreprocessingError::MismatchedPlanes(_) => "mismatched-planes",
+            PreprocessingError::InvalidSpriteRow(_) => "invalid-sprite-row",
+            PreprocessingError::InvalidSpriteByte(_) => "invalid-sprite-byte",
+            PreprocessingError::ReservedLabel(_) => "reserved-label",
+            PreprocessingError::InvalidLabel(_) => "invalid-label",
+            PreprocessingError::InvalidOffset(_) => "invalid-offset",
+            PreprocessingError::TooManyDataArgs(_) => "too-many-data-args",
+            PreprocessingError::TooFewDataArgs(_) => "too-few-data-args",
+            PreprocessingError::UnclosedData(_) => "unclosed-data",
+            PreprocessingError::InvalidDataByte(_) => "invalid-data-byte",
+            PreprocessingError::OversizedData(_) => "oversized-data",
+            PreprocessingError::InvalidSpace(_) => "invalid-space",
+            PreprocessingError::InvalidAlign(_) => "invalid-align",
+            PreprocessingError::InvalidFill(_) => "invalid-fill",
+            PreprocessingError::InvalidOrigin(_) => "invalid-origin",
+            PreprocessingError::OriginBehind { .. } => "origin-behind",
+            PreprocessingError::InvalidFont(_) => "invalid-font",
+            PreprocessingError::InvalidText(_) => "invalid-text",
+            PreprocessingError::UnknownGlyph { .. } => "unknown-glyph",
+            PreprocessingError::ConstantClash(_) => "constant-clash",
+            PreprocessingError::InvalidStruct(_) => "invalid-struct",
+            PreprocessingError::UnclosedStruct(_) => "unclosed-struct",
+            PreprocessingError::InvalidVar(_) => "invalid-var",
+            PreprocessingError::OversizedVar(_) => "oversized-var",
+            PreprocessingError::InvalidJumpTable(_) => "invalid-jump-table",
+            PreprocessingError::InvalidPseudoOp(_) => "invalid-pseudo-op",
+            PreprocessingError::UnknownJumpTableEntry { .. } => "unknown-jump-table-entry",
+            PreprocessingError::InvalidBcdTable(_) => "invalid-bcd-table",
+            PreprocessingError::InvalidPadSprite(_) => "invalid-pad-sprite",
+            PreprocessingError::InvalidByteOrder(_) => "invalid-byte-order",
+            PreprocessingError::TooFewWordArgs(_) => "too-few-word-args",
+            PreprocessingError::ReusedLabel(_) => "reused-label",
+            #[cfg(feature = "std")]
+            PreprocessingError::InvalidModule(_) => "invalid-module",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnclosedModule(_) => "unclosed-module",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnknownExport { .. } => "unknown-export",
+            #[cfg(feature = "std")]
+            PreprocessingError::UnexportedSymbol { .. } => "unexported-symbol",
+            PreprocessingError::UnscopedLocalLabel(_) => "unscoped-local-label",
+            PreprocessingError::InvalidPixels(_) => "invalid-pixels",
+            PreprocessingError::WidePixelRow { .. } => "wide-pixel-row",
+            #[cfg(feature = "std")]
+            PreprocessingError::InvalidSpritePath(_) => "invalid-sprite-path",
+            #[cfg(feature = "std")]
+            PreprocessingError::SpriteImage { .. } => "sprite-image",
+            PreprocessingError::SpriteConstantClash(_) => "sprite-constant-clash",
+            PreprocessingError::UnknownSprite(_) => "unknown-sprite",
+            PreprocessingError::InvalidSpriteTransform(_) => "invalid-sprite-transform",
+            #[cfg(feature = "std")]
+            PreprocessingError::InvalidSpriteSheet(_) => "invalid-sprite-sheet",
+            #[cfg(feature = "std")]
+            PreprocessingError::InvalidSpriteRect(_) => "invalid-sprite-rect",
+            #[cfg(feature = "std")]
+            PreprocessingError::SpriteImageSize(_) => "sprite-image-size",
+        }
+    }
+
+    /// The error inside any number of included files, along with where in the innermost one it was found
+    fn innermost(self) -> (Option<Origin>, PreprocessingError) {
+        #[cfg(feature = "std")]
+        if let PreprocessingError::Included { path, line, error } = self {
+            let (origin, error) = error.innermost();
+            let origin = origin.or_else(|| {
+                Some(Origin {
+                    file: Arc::from(path),
+                    line,
+                })
+            });
+            return (origin, error);
+        }
+        (None, self)
+    }
+}
+
+/// An error along with the (1-indexed) line of the source it was found on, and the (1-indexed) column when it's about
+/// one part of the line. Errors in an included file or library are found on the line it's included on, and also know
+/// where in it they are, with the column being on that line instead
+#[derive(Debug, Error)]
+pub struct Located<E: core::error::Error + 'static> {
+    pub line: usize,
+    pub column: Option<usize>,
+    pub origin: Option<Origin>,
+    #[source]
+    pub error: E,
+}
+
+impl<E: core::error::Error> Located<E> {
+    /// The error along with where in an included file or library it was found, if it was, to show on the line it's
+    /// included on
+    pub fn message(&self) -> String {
+        match (&self.origin, self.column) {
+            (Some(origin), Some(column)) => format!(
+                "{} line {}, column {column}: {}",
+                origin.file, origin.line, self.error
+            ),
+            (Some(origin), None) => format!("{} line {}: {}", origin.file, origin.line, self.error),
+            (None, _) => self.error.to_string(),
+        }
+    }
+}
+
+impl<E: core::error::Error> fmt::Display for Located<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.origin, self.column) {
+            (None, Some(column)) => {
+                write!(f, "line {}, column {column}: {}", self.line, self.error)
+            }
+            _ => write!(f, "line {}: {}", self.line, self.message()),
+        }
+    }
+}
+
+/// Something suspicious in the source that doesn't stop it from being assembled
+#[derive(Debug, Error)]
+pub enum PreprocessingWarning {
+    #[error("sprite `{name}` has an odd number of bytes, so a 0x00 byte was placed after it to keep the following instructions aligned; choose with `padsprite off` or `padsprite byte 0xNN`")]
+    PaddedSprite { name: String, line: usize },
+    #[cfg(feature = "std")]
+    #[error("`{name}` is held in {register}, which `{routine}` clobbers, but it's still used after this call")]
+    ClobberedRegister {
+        name: String,
+        register: &'static str,
+        routine: String,
+        line: usize,
+    },
+    #[cfg(feature = "std")]
+    #[error("{register} is read here, but `{routine}` clobbers it and was called on line {call}")]
+    ReadsClobberedRegister {
+        register: &'static str,
+        routine: String,
+        call: usize,
+        line: usize,
+    },
+    #[cfg(feature = "std")]
+    #[error("label `{name}` is never used")]
+    UnusedLabel { name: String, line: usize },
+    #[cfg(feature = "std")]
+    #[error("`ret` can be reached here without a call, so it returns to whatever happens to be on the stack")]
+    ReturnWithoutCall { line: usize },
+    #[cfg(feature = "std")]
+    #[error("`{name}` is called here but never returns, so every call leaves its return address on the stack")]
+    RoutineNeverReturns { name: String, line: usize },
+    #[cfg(feature = "std")]
+    #[error("execution runs past the end of this line into data at {addr:#05X}")]
+    FallsIntoData { addr: usize, line: usize },
+    #[cfg(feature = "std")]
+    #[error("this jumps into data at {addr:#05X}")]
+    JumpsIntoData { addr: usize, line: usize },
+}
+
+impl PreprocessingWarning {
+    /// The (1-indexed) line of the source the warning is about
+    #[cfg(feature = "std")]
+    pub fn line(&self) -> usize {
+        match self {
+            PreprocessingWarning::PaddedSprite { line, .. }
+            | PreprocessingWarning::UnusedLabel { line, .. }
+            | PreprocessingWarning::ClobberedRegister { line, .. }
+            | PreprocessingWarning::ReadsClobberedRegister { line, .. }
+            | PreprocessingWarning::ReturnWithoutCall { line }
+            | PreprocessingWarning::RoutineNeverReturns { line, .. }
+            | PreprocessingWarning::FallsIntoData { line, .. }
+            | PreprocessingWarning::JumpsIntoData { line, .. } => *line,
+        }
+    }
+
+    /// A short name for the kind of warning, for counting them up
+    #[cfg(feature = "std")]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PreprocessingWarning::PaddedSprite { .. } => "padded-sprite",
+            PreprocessingWarning::ClobberedRegister { .. } => "clobbered-register",
+            PreprocessingWarning::ReadsClobberedRegister { .. } => "reads-clobbered-register",
+            PreprocessingWarning::UnusedLabel { .. } => "unused-label",
+            PreprocessingWarning::ReturnWithoutCall { .. }
+            | PreprocessingWarning::RoutineNeverReturns { .. } => "unbalanced-call",
+            PreprocessingWarning::FallsIntoData { .. } => "falls-into-data",
+            PreprocessingWarning::JumpsIntoData { .. } => "jumps-into-data",
+        }
+    }
+}
+
+/// The first pass over the source. It sizes and places every instruction and collects every symbol in a single sweep,
+/// leaving the encoding, and so the resolving of those symbols, to the second pass. Text the preprocessor generates,
+/// like included files and renamed arguments, is kept in the arena, so the result can borrow it like the source.
+/// The sweep carries on past errors in lines that only declare something, since they don't move anything after them,
+/// so every one of those is returned along with the error that stopped it, if any
+pub fn preprocess<'a>(
+    unprocessed: &'a str,
+    options: &Options,
+    arena: &'a Arena,
+) -> Result<Preprocessed<'a>, Vec<Located<PreprocessingError>>> {
+    let allocation = registers::allocate(unprocessed).map_err(|error| vec![error])?;
+    let mut pass = FirstPass::new(options, arena);
+    pass.virtual_registers = allocation.registers;
+    pass.warnings = allocation.warnings;
+    for (name, value) in &options.imports {
+        pass.symbols.define_constant(name.clone(), *value);
+    }
+    let result = pass.sweep(unprocessed);
+    #[cfg(feature = "std")]
+    let result = result.and_then(|()| pass.place_libraries());
+    if let Err(error) = result {
+        let (origin, error) = error.innermost();
+        pass.errors.push(Located {
+            line: pass.line,
+            column: None,
+            origin,
+            error,
         });
+        return Err(pass.errors);
+    }
+
+    let free_memory = pass.place_vars();
+    let FirstPass {
+        instructions,
+        mut symbols,
+        addr,
+        #[cfg(feature = "std")]
+        sprite_bytes_saved,
+        #[cfg(feature = "std")]
+        placed,
+        #[cfg(feature = "std")]
+        mut warnings,
+        jump_table_entries,
+        #[cfg(feature = "std")]
+        fixed_jumps,
+        #[cfg(feature = "std")]
+        included,
+        #[cfg(feature = "std")]
+        labels_declared,
+        #[cfg(feature = "std")]
+        used,
+        #[cfg(feature = "std")]
+        exports,
+        #[cfg(feature = "std")]
+        qualified,
+        mut errors,
+        ..
+    } = pass;
+    #[cfg(feature = "std")]
+    {
+        // the first label at the start of the program names where it's run from, so it's used even if nothing refers
+        // to it. Any other label there isn't
+        let entry = labels_declared
+            .iter()
+            .position(|&(_, _, addr)| addr == options.base);
+        warnings.extend(
+            labels_declared
+                .into_iter()
+                .enumerate()
+                .filter(|(i, (name, _, _))| Some(*i) != entry && !used.contains(name.as_str()))
+                .map(|(_, (name, line, _))| PreprocessingWarning::UnusedLabel { name, line }),
+        );
+        warnings.retain(|warning| options.warnings.contains(warning.kind()));
+        warnings.sort_by_key(PreprocessingWarning::line);
+    }
+    // free memory starts right after the last instruction and any variables
+    evaluate_memory_offsets(&instructions, &mut symbols, free_memory, &mut errors);
+    // every module is known by now, so uses of what they don't export can be found
+    #[cfg(feature = "std")]
+    errors.extend(
+        qualified
+            .into_iter()
+            .filter_map(|(name, used_in, line, origin)| {
+                let (module, symbol) = name.split_once('.')?;
+                let exported = exports.get(module)?;
+                // names generated from an exported one, like a variable's `NAME.length`, are exported with it
+                let is_exported = exported.iter().any(|&export| {
+                    symbol
+                        .strip_prefix(export)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '_']))
+                });
+                (used_in != Some(module) && !is_exported).then(|| Located {
+                    line,
+                    column: None,
+                    origin,
+                    error: PreprocessingError::UnexportedSymbol {
+                        name: name.to_string(),
+                        module: module.to_string(),
+                    },
+                })
+            }),
+    );
+    // every label is known by now, so jump tables can be checked
+    errors.extend(
+        jump_table_entries
+            .iter()
+            .filter(|(entry, ..)| !symbols.is_label(entry))
+            .map(|&(entry, header, line)| Located {
+                line,
+                column: None,
+                origin: None,
+                error: PreprocessingError::UnknownJumpTableEntry {
+                    entry: entry.to_string(),
+                    header: header.to_string(),
+                },
+            }),
+    );
+    if !errors.is_empty() {
+        errors.sort_by_key(|located| located.line);
+        return Err(errors);
+    }
+
+    Ok(Preprocessed {
+        instructions,
+        symbols,
+        size: addr - options.base,
+        #[cfg(feature = "std")]
+        sprite_bytes_saved,
+        #[cfg(feature = "std")]
+        sprites: placed,
+        #[cfg(feature = "std")]
+        warnings,
+        #[cfg(feature = "std")]
+        free_memory,
+        #[cfg(feature = "std")]
+        fixed_jumps,
+        #[cfg(feature = "std")]
+        included,
+    })
+}
+
+/// What goes after a sprite with an odd number of bytes, set with `padsprite`
+#[derive(Debug, Clone, Copy)]
+enum SpritePadding {
+    /// Pad with 0x00, warning that it happened, since the user hasn't said what they want
+    Warn,
+    /// Leave the sprite as is, so whatever follows starts at an odd address
+    Off,
+    /// Pad with the given byte
+    Byte(u8),
+}
 
-    lines = evaluate_aliases(lines)?;
-    lines = evaluate_sprites(lines)?;
-    lines = evaluate_memory_offsets(lines)?;
-    evaluate_labels(lines)
+/// The state of the first pass as it sweeps through the source
+struct FirstPass<'a> {
+    /// Where text generated along the way is kept
+    arena: &'a Arena,
+    instructions: Vec<PreprocessedInstruction<'a>>,
+    symbols: SymbolTable<'a>,
+    /// The register each virtual register declared with `reg` was given, worked out before the sweep
+    virtual_registers: HashMap<&'a str, &'static str>,
+    /// Where the next instruction will be placed
+    addr: usize,
+    /// The line of the source being processed
+    line: usize,
+    /// The last global label, which local labels like `.loop` belong to
+    scope: Option<&'a str>,
+    /// How many anonymous labels have been declared so far
+    anonymous: usize,
+    /// The characters for lit and unlit pixels in pixel art sprite rows
+    pixels: (char, char),
+    /// The characters of the current font, in the order of their glyphs
+    glyphs: &'a str,
+    /// The system being built for
+    target: Target,
+    /// The command run for each external directive, by name
+    #[cfg(feature = "std")]
+    directives: HashMap<String, String>,
+    /// Where files the source refers to are looked up
+    #[cfg(feature = "std")]
+    dir: PathBuf,
+    /// Every file being included, innermost last, to catch files that include themselves
+    #[cfg(feature = "std")]
+    including: Vec<PathBuf>,
+    /// Every file included so far
+    #[cfg(feature = "std")]
+    included: Vec<PathBuf>,
+    /// Where libraries are looked for
+    #[cfg(feature = "std")]
+    include_paths: Vec<PathBuf>,
+    /// Every library included, to place the routines of once the rest of the program has been swept
+    #[cfg(feature = "std")]
+    libraries: Vec<library::Library<'a>>,
+    /// The line of the original source the file being swept was included from, which everything in it is reported on
+    included_from: Option<usize>,
+    /// The included file or library being swept, or None for the source itself
+    file: Option<Arc<str>>,
+    /// Every sprite declared so far, by name
+    sprites: HashMap<Cow<'a, str>, Sprite>,
+    /// Where each distinct block of sprite bytes was placed, when deduplicating sprites
+    placed_sprites: Option<HashMap<Vec<u8>, usize>>,
+    sprite_bytes_saved: usize,
+    /// Every sprite in the order they were declared, along with where it ended up
+    #[cfg(feature = "std")]
+    placed: Vec<PlacedSprite>,
+    sprite_padding: SpritePadding,
+    /// How the values of a plain `dw` are packed
+    byte_order: ByteOrder,
+    /// The size of every struct declared so far, by name
+    structs: HashMap<&'a str, usize>,
+    /// Every variable's name, size, declaration, and line, to be placed in free memory once the program's size is known
+    vars: Vec<(&'a str, usize, &'a str, usize)>,
+    /// Every label a jump table jumps to, along with the table's header and line, to check once every label is known
+    jump_table_entries: Vec<(&'a str, &'a str, usize)>,
+    /// Generated instructions that jump into the program, see [`Preprocessed::fixed_jumps`]
+    fixed_jumps: Vec<usize>,
+    /// Every label written in the source itself, rather than an included file, along with its line and address
+    labels_declared: Vec<(String, usize, usize)>,
+    /// Every name used in the arguments of a line, to find the labels that are never used
+    used: HashSet<&'a str>,
+    /// The module being swept, if any
+    #[cfg(feature = "std")]
+    module: Option<&'a str>,
+    /// What every module declared so far exports, by the module's name
+    #[cfg(feature = "std")]
+    exports: HashMap<&'a str, Vec<&'a str>>,
+    /// Every qualified name used in the arguments of a line, like `NAME.name`, along with the module it was used in
+    /// and where, to check once every module is known that it's only used outside its module if it's exported
+    #[cfg(feature = "std")]
+    qualified: Vec<(&'a str, Option<&'a str>, usize, Option<Origin>)>,
+    warnings: Vec<PreprocessingWarning>,
+    /// Every error the sweep carried on past, see [`FirstPass::recoverable`]
+    errors: Vec<Located<PreprocessingError>>,
 }
 
-fn evaluate_aliases(
-    mut lines: Vec<PreprocessedInstruction>,
-) -> Result<Vec<PreprocessedInstruction>, PreprocessingError> {
-    let reserved: HashSet<&str> = HashSet::from(RESERVED_WORDS);
-    let mut alias_map: HashMap<String, String> = HashMap::new();
-
-    // find aliases
-    let mut to_remove = Vec::new();
-    for (i, line) in lines.iter_mut().enumerate() {
-        if line.starts_with("alias") {
-            // check for a valid alias
-            let tokens = line.split_whitespace().collect::<Vec<&str>>();
-            match tokens.len().cmp(&3) {
-                Ordering::Greater => {
-                    return Err(PreprocessingError::TooManyAliasArgs(line.to_string()))
+impl<'a> FirstPass<'a> {
+    fn new(options: &Options, arena: &'a Arena) -> FirstPass<'a> {
+        FirstPass {
+            arena,
+            instructions: Vec::new(),
+            symbols: SymbolTable::default(),
+            virtual_registers: HashMap::new(),
+            addr: options.base,
+            line: 0,
+            scope: None,
+            anonymous: 0,
+            pixels: DEFAULT_PIXELS,
+            glyphs: DEFAULT_GLYPHS,
+            target: options.target,
+            #[cfg(feature = "std")]
+            directives: options.directives.clone(),
+            #[cfg(feature = "std")]
+            dir: options.dir.clone(),
+            #[cfg(feature = "std")]
+            including: Vec::new(),
+            #[cfg(feature = "std")]
+            included: Vec::new(),
+            #[cfg(feature = "std")]
+            include_paths: options.include_paths.clone(),
+            #[cfg(feature = "std")]
+            libraries: Vec::new(),
+            included_from: None,
+            file: None,
+            sprites: HashMap::new(),
+            placed_sprites: options.dedup_sprites.then(HashMap::new),
+            sprite_bytes_saved: 0,
+            #[cfg(feature = "std")]
+            placed: Vec::new(),
+            sprite_padding: SpritePadding::Warn,
+            byte_order: ByteOrder::Big,
+            structs: HashMap::new(),
+            vars: Vec::new(),
+            jump_table_entries: Vec::new(),
+            fixed_jumps: Vec::new(),
+            labels_declared: Vec::new(),
+            used: HashSet::new(),
+            #[cfg(feature = "std")]
+            module: None,
+            #[cfg(feature = "std")]
+            exports: HashMap::new(),
+            #[cfg(feature = "std")]
+            qualified: Vec::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Sweep through the source, keeping track of the line being processed so errors can point at it
+    fn sweep(&mut self, unprocessed: &'a str) -> Result<(), PreprocessingError> {
+        let lines = unprocessed
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, tokenize::tokenize_line(l))) // split lines into tokens, number lines from 1
+            .filter(|(_, l)| !l.tokens.is_empty()); // remove empty and comment lines
+        self.sweep_lines(lines)
+    }
+
+    /// Sweep through lines that have already been tokenized, along with the line each came from
+    fn sweep_lines(
+        &mut self,
+        mut lines: impl Iterator<Item = (usize, Line<'a>)>,
+    ) -> Result<(), PreprocessingError> {
+        let mut conditions = conditional::Conditions::default();
+        while let Some((number, mut line)) = lines.next() {
+            self.line = number;
+            let number = self.included_from.unwrap_or(number);
+            if conditions.directive(&line, self.target, &self.symbols)? || !conditions.active() {
+                continue;
+            }
+            if let Some(scope) = self.scope {
+                local::localize(&mut line, scope, self.arena);
+            }
+            local::anonymize(&mut line, self.anonymous, self.arena);
+            for token in line.tokens.iter().skip(1) {
+                self.used.extend(
+                    token
+                        .text
+                        .split(|c: char| !(local::is_name_char(c) || c == '.'))
+                        .filter(|name| !name.is_empty()),
+                );
+                #[cfg(feature = "std")]
+                for name in token
+                    .text
+                    .split(|c: char| !(local::is_name_char(c) || c == '.'))
+                    .filter(|name| name.contains('.'))
+                {
+                    let origin = self.file.clone().map(|file| Origin {
+                        file,
+                        line: self.line,
+                    });
+                    self.qualified.push((name, self.module, number, origin));
                 }
-                Ordering::Less => {
-                    return Err(PreprocessingError::TooFewAliasArgs(line.to_string()))
+            }
+            match line.head() {
+                #[cfg(feature = "std")]
+                Some("include") => self.include(&line, number)?,
+                #[cfg(feature = "std")]
+                Some("incbin") => self.incbin(&line, number)?,
+                Some("alias") => self.recoverable(|pass| pass.alias(&line)),
+                Some("const") => self.recoverable(|pass| pass.declare_constant(&line)),
+                _ if line.tokens.get(1).map(|t| t.text) == Some("equ") => {
+                    self.recoverable(|pass| pass.declare_constant(&line))
                 }
-
-                Ordering::Equal => {
-                    let key = tokens[1].trim_end_matches(',').to_string(); // remove comma
-                                                                           // check if the alias is a reserved word
-                    if reserved.contains(&*key) {
-                        return Err(PreprocessingError::ReservedAlias(line.to_string()));
-                    }
-                    // check if the alias has already been declared
-                    if alias_map.insert(key, tokens[2].to_string()).is_some() {
-                        return Err(PreprocessingError::ReusedAlias(line.to_string()));
-                    } else {
-                        to_remove.push(i);
-                    }
+                Some("reg") => self.recoverable(|pass| pass.virtual_register(&line)),
+                Some("pixels") => self.pixels(&line)?,
+                Some("font") => self.font(&line)?,
+                Some("padsprite") => self.pad_sprite(&line)?,
+                Some("byteorder") => self.recoverable(|pass| pass.byte_order(&line)),
+                Some("dw" | "dw.be" | "dw.le" | ".dw" | ".dw.be" | ".dw.le") => {
+                    self.words(line, number)?
+                }
+                Some("db" | ".db") => self.bytes(line, number)?,
+                Some("ds") => self.space(&line, number)?,
+                Some("align" | ".align") => self.align(&line, number)?,
+                Some("fill" | ".fill") => self.fill(&line, number)?,
+                Some("org" | ".org") => self.origin(&line, number)?,
+                Some("text") => self.text(&line, number)?,
+                Some("bcdtable") => self.bcd_table(&line, number)?,
+                Some("jumptable") => self.jump_table(&line, number)?,
+                #[cfg(feature = "std")]
+                Some("sprite") if line.tokens.get(2).map(|t| t.text) == Some("from") => {
+                    self.image_sprite(&line, number)?
+                }
+                #[cfg(feature = "std")]
+                Some("spritesheet") => self.sprite_sheet(&line, number)?,
+                Some("sprite") if line.tokens.len() > 3 => {
+                    self.transformed_sprite(&line, number)?
+                }
+                Some(keyword @ ("sprite" | "sprite16")) => {
+                    self.check_sprite_header(&line)?;
+                    let rows = take_block(&mut lines, "endsprite")
+                        .ok_or_else(|| PreprocessingError::UnclosedSprite(line.text.to_string()))?;
+                    let width = if keyword == "sprite16" { 16 } else { 8 };
+                    self.sprite(&line, &rows, width)?;
+                }
+                _ if repeat::starts(&line) => {
+                    let rows = repeat::take(&mut lines)
+                        .ok_or_else(|| PreprocessingError::UnclosedRepeat(line.text.to_string()))?;
+                    self.repeat(&line, rows)?;
                 }
+                Some("struct") => {
+                    // a struct is either on one line or runs until a line starting with `}`
+                    let rows = match line.tokens.last() {
+                        Some(last) if last.text == "}" => Vec::new(),
+                        _ => take_block(&mut lines, "}").ok_or_else(|| {
+                            PreprocessingError::UnclosedStruct(line.text.to_string())
+                        })?,
+                    };
+                    self.structure(&line, &rows)?;
+                }
+                #[cfg(feature = "std")]
+                Some("module") => {
+                    let rows = take_block(&mut lines, "endmodule")
+                        .ok_or_else(|| PreprocessingError::UnclosedModule(line.text.to_string()))?;
+                    self.module(&line, rows)?;
+                }
+                Some("var") => self.var(&line, number)?,
+                Some("data") => {
+                    self.check_data_header(&line)?;
+                    let rows = take_block(&mut lines, "enddata")
+                        .ok_or_else(|| PreprocessingError::UnclosedData(line.text.to_string()))?;
+                    self.data(&line, &rows)?;
+                }
+                #[cfg(feature = "std")]
+                Some(name) if self.directives.contains_key(name) => {
+                    let rows = take_block(&mut lines, &format!("end{name}")).ok_or_else(|| {
+                        PreprocessingError::UnclosedExternalDirective(line.text.to_string())
+                    })?;
+                    self.external_directive(&line, &rows, number)?;
+                }
+                _ if line.text.ends_with(':') => {
+                    self.recoverable(|pass| pass.label_line(line.text))
+                }
+                Some(head) if pseudo::is_pseudo_op(head) => self.pseudo_op(&line, number)?,
+                _ => self.emit(InstructionText::Source(Instruction::new(line)), number),
             }
         }
+        conditions.finish()
     }
 
-    if alias_map.is_empty() {
-        return Ok(lines);
+    /// Declare something without placing anything, keeping any error to report later and carrying on, since nothing
+    /// after it moves. Errors from lines that place something stop the sweep, since everything after them would be
+    /// misplaced, and so report errors that aren't there
+    fn recoverable(&mut self, declare: impl FnOnce(&mut Self) -> Result<(), PreprocessingError>) {
+        if let Err(error) = declare(self) {
+            self.errors.push(Located {
+                line: self.line,
+                column: None,
+                origin: None,
+                error,
+            });
+        }
     }
 
-    // remove alias declarations from instructions
-    for (i, index) in to_remove.into_iter().enumerate() {
-        lines.remove(index - i);
+    /// Sweep through another file as if it were pasted in place of the include, with everything it declares renamed to
+    /// `NAMESPACE.name` if it's given one
+    /// Include syntax is `include "PATH"` or `include "PATH" as NAMESPACE`, with PATH relative to the including file
+    #[cfg(feature = "std")]
+    fn include(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidInclude(line.text.to_string());
+        if line.tokens.len() < 2 {
+            return Err(invalid());
+        }
+        let (path, rest) = split_path(line.rest(1)).ok_or_else(invalid)?;
+        let namespace = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => None,
+            ["as", namespace] => Some(namespace),
+            _ => return Err(invalid()),
+        };
+
+        if let Some(name) = path
+            .strip_prefix('<')
+            .and_then(|path| path.strip_suffix('>'))
+        {
+            return self.include_library(name, namespace, number);
+        }
+
+        let path = self.dir.join(path);
+        let display = path.display().to_string();
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.including.contains(&canonical) {
+            return Err(PreprocessingError::RecursiveInclude(display));
+        }
+        self.included.push(path.clone());
+        let text = include::load(&path, namespace).map_err(|source| {
+            PreprocessingError::UnreadableInclude {
+                path: display.clone(),
+                source,
+            }
+        })?;
+        let text = self.arena.keep(text);
+
+        let dir = self.dir.clone();
+        self.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.including.push(canonical);
+        // local labels in the included file belong to its own global labels
+        let scope = self.scope.take();
+        let result = self.sweep_in_place(text, display, number);
+        self.scope = scope;
+        self.dir = dir;
+        self.including.pop();
+        result
     }
 
-    // replace aliases
-    let mut to_replace: Vec<(usize, String)> = Vec::new();
-    let mut curr_inst_as_string = String::new();
-    for (i, line) in lines.iter().enumerate() {
-        let mut replace_this_line = false;
+    /// Sweep through a block with everything it declares renamed to `NAME.name`, the same way as a file included with a
+    /// namespace, so the names in one part of a big program can't clash with another's. Unlike a namespace, only what
+    /// the module exports can be used outside it, by either name
+    ///
+    /// Module syntax is `module NAME`, running until `endmodule`. `export SYMBOL, ...` inside it makes symbols usable
+    /// outside it, by their own names as well as `NAME.SYMBOL`
+    #[cfg(feature = "std")]
+    fn module(
+        &mut self,
+        header: &Line<'a>,
+        rows: Vec<(usize, Line<'a>)>,
+    ) -> Result<(), PreprocessingError> {
+        let name = match header.tokens[1..] {
+            [name]
+                if local::starts_name(name.text) && name.text.chars().all(local::is_name_char) =>
+            {
+                name.text
+            }
+            _ => return Err(PreprocessingError::InvalidModule(header.text.to_string())),
+        };
+        let (exports, rows): (Vec<_>, Vec<_>) = rows
+            .into_iter()
+            .partition(|(_, row)| row.head() == Some("export"));
 
-        for token in line.split_whitespace().map(|s| s.trim_end_matches(',')) {
-            curr_inst_as_string.push(' ');
-            if let Some(alias_val) = alias_map.get(token) {
-                curr_inst_as_string.push_str(alias_val);
-                replace_this_line = true;
-            } else {
-                curr_inst_as_string.push_str(token);
+        // the renamed text has a line for every row, so each one still reports the line it came from
+        let text: Vec<&str> = rows.iter().map(|(_, row)| row.text).collect();
+        let renamed = self.arena.keep(include::namespaced(&text.join("\n"), name));
+        let lines = rows
+            .iter()
+            .map(|&(number, _)| number)
+            .zip(renamed.lines().map(tokenize::tokenize_line));
+        // local labels in the module belong to its own global labels
+        let scope = self.scope.take();
+        let module = self.module.replace(name);
+        let result = self.sweep_lines(lines);
+        self.scope = scope;
+        self.module = module;
+        result?;
+
+        let mut exported = Vec::new();
+        for (number, export) in &exports {
+            self.line = *number;
+            for token in &export.tokens[1..] {
+                self.recoverable(|pass| pass.export(name, token.text, export.text));
+                exported.push(token.text);
             }
         }
+        self.exports.insert(name, exported);
+        Ok(())
+    }
 
-        if replace_this_line {
-            to_replace.push((i, String::from(curr_inst_as_string.trim())));
-            curr_inst_as_string = String::new();
-        } else {
-            curr_inst_as_string.clear();
+    /// Define a symbol declared in a module by its own name too
+    #[cfg(feature = "std")]
+    fn export(
+        &mut self,
+        module: &str,
+        name: &'a str,
+        line: &str,
+    ) -> Result<(), PreprocessingError> {
+        if is_reserved(name) {
+            return Err(PreprocessingError::ReservedLabel(line.to_string()));
         }
+        let declared = self.arena.keep(format!("{module}.{name}"));
+        // uses outside the module go by the exported name, so the declared one counts as used
+        self.used.insert(declared);
+        let value =
+            self.symbols
+                .value_of(declared)
+                .ok_or_else(|| PreprocessingError::UnknownExport {
+                    name: name.to_string(),
+                    module: module.to_string(),
+                })?;
+        if self.symbols.is_label(declared) {
+            if !self.symbols.define_label(name, value) {
+                return Err(PreprocessingError::ReusedLabel(line.to_string()));
+            }
+        } else if !self.symbols.define_constant(name, value) {
+            return Err(PreprocessingError::ReusedConstant(line.to_string()));
+        }
+        Ok(())
     }
 
-    for (i, replacement) in to_replace.into_iter() {
-        lines[i] = PreprocessedInstruction::Changed(replacement);
+    /// Find a library included with `include <NAME>`, leaving it to be placed once the rest of the program has been
+    /// swept. Only the routines that something uses are placed, after everything else
+    #[cfg(feature = "std")]
+    fn include_library(
+        &mut self,
+        name: &str,
+        namespace: Option<&str>,
+        number: usize,
+    ) -> Result<(), PreprocessingError> {
+        let found = library::find(name, &self.include_paths)
+            .ok_or_else(|| PreprocessingError::UnknownLibrary(name.to_string()))?;
+        let (text, display, dir) = match found {
+            library::Found::File(path) => {
+                let display = path.display().to_string();
+                let text = fs::read_to_string(&path).map_err(|source| {
+                    PreprocessingError::UnreadableInclude {
+                        path: display.clone(),
+                        source,
+                    }
+                })?;
+                let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                self.included.push(path);
+                (text, display, dir)
+            }
+            library::Found::Bundled(text) => {
+                (text.to_string(), format!("<{name}>"), self.dir.clone())
+            }
+        };
+        let text = match namespace {
+            Some(namespace) => include::namespaced(&text, namespace),
+            None => text,
+        };
+        self.libraries.push(library::Library::new(
+            self.arena.keep(text),
+            display,
+            dir,
+            number,
+        ));
+        Ok(())
     }
 
-    Ok(lines)
-}
+    /// Place the routines of every library that the program uses, along with the ones those routines use in turn
+    #[cfg(feature = "std")]
+    fn place_libraries(&mut self) -> Result<(), PreprocessingError> {
+        loop {
+            let mut placed = false;
+            // placing routines can include more libraries, which are placed in the same way
+            for i in 0..self.libraries.len() {
+                let Some(text) = self.libraries[i].take_used(&self.used) else {
+                    continue;
+                };
+                let library = &self.libraries[i];
+                let (name, line) = (library.name.clone(), library.line);
+                let dir = std::mem::replace(&mut self.dir, library.dir.clone());
+                // errors in the library are reported on the line that included it
+                self.line = line;
+                let scope = self.scope.take();
+                let result = self.sweep_in_place(self.arena.keep(text), name, line);
+                self.scope = scope;
+                self.dir = dir;
+                result?;
+                placed = true;
+            }
+            if !placed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Place the bytes of a binary file as they are
+    /// Incbin syntax is `incbin "PATH"`, with the path relative to the source file, optionally followed by `OFFSET` or
+    /// `OFFSET, LENGTH` to place only part of the file
+    #[cfg(feature = "std")]
+    fn incbin(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidIncbin(line.text.to_string());
+        if line.tokens.len() < 2 {
+            return Err(invalid());
+        }
+        let (path, rest) = split_path(line.rest(1)).ok_or_else(invalid)?;
+        let range = parse_numbers(rest, "").ok_or_else(invalid)?;
 
-/// Find sprite blocks, condense the bytes into raw hex strings and replace the sprite declaration with a label
-/// sprite syntax is `sprite NAME` (with an optional colon), any number of bytes beginning with 0b then `endsprite`
-fn evaluate_sprites(
-    mut lines: Vec<PreprocessedInstruction>,
-) -> Result<Vec<PreprocessedInstruction>, PreprocessingError> {
-    let mut to_change: Vec<(usize, PreprocessedInstruction)> = Vec::new();
-    let mut to_remove: Vec<usize> = Vec::new();
-    // iterate over the lines, looking for sprite instructions
-    let mut i = 0;
-    while i < lines.len() {
-        let cur_line = &lines[i];
-
-        if cur_line.starts_with("sprite") {
-            // once we have a sprite instruction, make sure it's valid
-            let tokens = cur_line.split_whitespace().collect::<Vec<_>>();
-            match tokens.len().cmp(&2) {
-                Ordering::Less => {
-                    return Err(PreprocessingError::TooFewSpriteArgs(cur_line.to_string()))
+        let path = self.dir.join(path);
+        let display = path.display().to_string();
+        self.included.push(path.clone());
+        let bytes = fs::read(&path).map_err(|source| PreprocessingError::UnreadableIncbin {
+            path: display.clone(),
+            source,
+        })?;
+        let (offset, length) = match range[..] {
+            [] => (0, bytes.len()),
+            [offset] => (offset, bytes.len().saturating_sub(offset)),
+            [offset, length] => (offset, length),
+            _ => return Err(invalid()),
+        };
+        let bytes = bytes
+            .get(offset..offset.saturating_add(length))
+            .ok_or_else(|| PreprocessingError::IncbinOutOfRange {
+                path: display,
+                size: bytes.len(),
+                line: line.text.to_string(),
+            })?;
+        if self.addr + bytes.len() > MEMORY_SIZE {
+            return Err(PreprocessingError::OversizedData(line.text.to_string()));
+        }
+        if !bytes.is_empty() {
+            self.emit(InstructionText::Data(bytes.to_vec()), number);
+        }
+        Ok(())
+    }
+
+    /// Run a block through the command of an external directive declared in the project file, and sweep through the
+    /// lines it outputs in place of the block
+    /// An external directive's block runs from its line to `endNAME`, and anything after NAME on its line is passed
+    /// to the command as arguments
+    #[cfg(feature = "std")]
+    fn external_directive(
+        &mut self,
+        line: &Line<'a>,
+        rows: &[(usize, Line<'a>)],
+        number: usize,
+    ) -> Result<(), PreprocessingError> {
+        let name = line.tokens[0].text;
+        let args: Vec<&str> = line.tokens[1..].iter().map(|token| token.text).collect();
+        let mut block = String::new();
+        for (_, row) in rows {
+            block.push_str(row.text);
+            block.push('\n');
+        }
+        let output = plugins::run(&self.directives[name], &args, &block).map_err(|reason| {
+            PreprocessingError::ExternalDirective {
+                name: name.to_string(),
+                reason,
+            }
+        })?;
+        self.sweep_in_place(
+            self.arena.keep(output),
+            format!("output of `{name}`"),
+            number,
+        )
+    }
+
+    /// Sweep through the lines of a block again and again
+    /// Repeat syntax is `rept COUNT`, or `rept COUNT, NAME` to replace NAME in the block with the index of each
+    /// repetition, counting from 0, and the block runs until `endr`. `.rept` and `repeat` work too. Labels in the
+    /// block would be declared again on every repetition, so it can only declare anonymous labels
+    fn repeat(
+        &mut self,
+        header: &Line<'a>,
+        rows: Vec<(usize, Line<'a>)>,
+    ) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidRepeat(header.text.to_string());
+        let (count, name) = match header.tokens[1..] {
+            [count] => (count.text, None),
+            [count, name] => (count.text, Some(name.text)),
+            _ => return Err(invalid()),
+        };
+        let count = self.value(count).ok_or_else(invalid)?;
+        for i in 0..count {
+            let mut rows = rows.clone();
+            if let Some(name) = name {
+                for (_, row) in &mut rows {
+                    repeat::index(row, name, i, self.arena);
                 }
-                Ordering::Greater => {
-                    return Err(PreprocessingError::TooManySpriteArgs(cur_line.to_string()))
+            }
+            self.sweep_lines(rows.into_iter())?;
+        }
+        Ok(())
+    }
+
+    /// Sweep through generated or included text as if it were pasted in at a line, reporting everything in it on that
+    /// line and wrapping any error with where in the text it was found
+    #[cfg(feature = "std")]
+    fn sweep_in_place(
+        &mut self,
+        text: &'a str,
+        name: String,
+        number: usize,
+    ) -> Result<(), PreprocessingError> {
+        let included_from = self.included_from.replace(number);
+        let file = self.file.replace(Arc::from(name.as_str()));
+        let outer = self.line;
+        let recovered = self.errors.len();
+        let result = self.sweep(text);
+        let line = self.line;
+        self.included_from = included_from;
+        self.file = file;
+        self.line = outer;
+        // errors carried on past in the text are reported on the line it's in, like the one that stopped it, keeping
+        // where in the innermost file they were found
+        let inner = self.errors.split_off(recovered);
+        let file: Arc<str> = Arc::from(name.as_str());
+        self.errors.extend(inner.into_iter().map(|located| Located {
+            line: outer,
+            origin: located.origin.or_else(|| {
+                Some(Origin {
+                    file: file.clone(),
+                    line: located.line,
+                })
+            }),
+            ..located
+        }));
+        result.map_err(|error| PreprocessingError::Included {
+            path: name,
+            line,
+            error: Box::new(error),
+        })
+    }
+
+    /// Place an instruction at the current address and move past it
+    fn emit(&mut self, text: InstructionText<'a>, line: usize) {
+        let size = text.size();
+        self.instructions.push(PreprocessedInstruction {
+            text,
+            #[cfg(feature = "std")]
+            addr: self.addr,
+            line,
+            origin: self.file.clone().map(|file| Origin {
+                file,
+                line: self.line,
+            }),
+        });
+        self.addr += size;
+    }
+
+    /// Record what an alias stands for in the symbol table
+    /// Alias syntax is `alias NAME, VALUE`, and every token matching NAME is replaced with VALUE when assembled
+    fn alias(&mut self, line: &Line<'a>) -> Result<(), PreprocessingError> {
+        match line.tokens.len().cmp(&3) {
+            Ordering::Greater => Err(PreprocessingError::TooManyAliasArgs(line.text.to_string())),
+            Ordering::Less => Err(PreprocessingError::TooFewAliasArgs(line.text.to_string())),
+
+            Ordering::Equal => {
+                let key = line.tokens[1].text;
+                // check if the alias is a reserved word
+                if is_reserved_alias(key) {
+                    return Err(PreprocessingError::ReservedAlias(line.text.to_string()));
+                }
+                // check if the alias has already been declared
+                if !self.symbols.define_alias(key, line.tokens[2].text) {
+                    return Err(PreprocessingError::ReusedAlias(line.text.to_string()));
                 }
+                Ok(())
+            }
+        }
+    }
+
+    /// Name a number, which unlike an alias is checked to be one when it's declared
+    /// Constant syntax is `const NAME VALUE` or `NAME equ VALUE`, where VALUE is a number or a constant or label declared
+    /// earlier
+    fn declare_constant(&mut self, line: &Line<'a>) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidConstant(line.text.to_string());
+        let (name, value) = match line.tokens[..] {
+            [head, name, value] if head.text == "const" => (name.text, value.text),
+            [name, equ, value] if equ.text == "equ" => (name.text, value.text),
+            _ => return Err(invalid()),
+        };
+        let value = self.value(value).ok_or_else(invalid)?;
+        if is_reserved(name) {
+            return Err(PreprocessingError::ReservedLabel(line.text.to_string()));
+        }
+        if !self.symbols.define_constant(name, value) {
+            return Err(PreprocessingError::ReusedConstant(line.text.to_string()));
+        }
+        Ok(())
+    }
 
-                // find the end of the sprite, pair up bytes, and convert to raws
-                Ordering::Equal => {
-                    let sprite_start = i;
-                    while &*lines[i] != "endsprite" {
-                        // this is cursed
-                        i += 1;
-                        if i == lines.len() {
-                            return Err(PreprocessingError::UnclosedSprite(cur_line.to_string()));
-                        }
+    /// The value of a number, symbol, or expression, as far as it's known at this point of the source
+    fn value(&self, text: &str) -> Option<usize> {
+        let text = self.symbols.substitute(text);
+        match self.symbols.value_of(text) {
+            Some(value) => Some(value),
+            None if assemble::expr::is_expression(text) => assemble::evaluate(text, &self.symbols)
+                .ok()
+                .map(usize::from),
+            None => match parse::parse_asm_arg(text) {
+                Ok(AsmArgument::Numeric(value)) => Some(value as usize),
+                _ => None,
+            },
+        }
+    }
+
+    /// Alias a virtual register to the register it was given
+    /// Virtual register syntax is `reg NAME`, and every token matching NAME is replaced with that register when assembled
+    fn virtual_register(&mut self, line: &Line<'a>) -> Result<(), PreprocessingError> {
+        let [_, name] = line.tokens[..] else {
+            return Err(PreprocessingError::InvalidVirtualRegister(
+                line.text.to_string(),
+            ));
+        };
+        if is_reserved_alias(name.text) {
+            return Err(PreprocessingError::ReservedAlias(line.text.to_string()));
+        }
+        let register = self.virtual_registers[name.text];
+        if !self.symbols.define_alias(name.text, register) {
+            return Err(PreprocessingError::ReusedAlias(line.text.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Change the characters used for pixel art in the sprites that follow
+    /// Pixels syntax is `pixels ON OFF`, where both are single characters, such as `pixels # _`
+    fn pixels(&mut self, line: &Line) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidPixels(line.text.to_string());
+        let [_, on, off] = line.tokens[..] else {
+            return Err(invalid());
+        };
+        let single = |text: &str| {
+            let mut chars = text.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        };
+        match (single(on.text), single(off.text)) {
+            (Some(on), Some(off)) if on != off => {
+                self.pixels = (on, off);
+                Ok(())
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Change the characters of the font that the text tables that follow index into
+    /// Font syntax is `font "CHARACTERS"`, listing a character for each glyph in the order the glyphs are laid out, such
+    /// as `font "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ "`
+    fn font(&mut self, line: &Line<'a>) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidFont(line.text.to_string());
+        let glyphs = match split_path(line.tokens.get(1).map_or("", |_| line.rest(1))) {
+            Some((glyphs, "")) if !glyphs.is_empty() => glyphs,
+            _ => return Err(invalid()),
+        };
+        // a character can only stand for one glyph
+        let mut seen = HashSet::new();
+        if !glyphs.chars().all(|c| seen.insert(c)) {
+            return Err(invalid());
+        }
+        self.glyphs = glyphs;
+        Ok(())
+    }
+
+    /// Choose what goes after the sprites that follow when they have an odd number of bytes
+    /// Pad sprite syntax is `padsprite off`, leaving the following instruction at an odd address, or `padsprite byte
+    /// VALUE`, padding with VALUE. Until either is given, sprites are padded with 0x00 and a warning
+    fn pad_sprite(&mut self, line: &Line) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidPadSprite(line.text.to_string());
+        self.sprite_padding = match line.tokens[1..] {
+            [mode] if mode.text == "off" => SpritePadding::Off,
+            [mode, value] if mode.text == "byte" => {
+                let arg = parse::parse_asm_arg(self.symbols.substitute(value.text))
+                    .map_err(|_| invalid())?;
+                SpritePadding::Byte(parse::parse_valid_byte(&arg).map_err(|_| invalid())?)
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(())
+    }
+
+    /// Choose how the values of the plain `dw` lines that follow are packed
+    /// Byte order syntax is `byteorder be` for the high byte first, as instructions are, or `byteorder le` for the low
+    /// byte first. `dw.be` and `dw.le` ignore it and always use their own order
+    fn byte_order(&mut self, line: &Line) -> Result<(), PreprocessingError> {
+        self.byte_order = match line.tokens[1..] {
+            [order] if order.text == "be" => ByteOrder::Big,
+            [order] if order.text == "le" => ByteOrder::Little,
+            _ => return Err(PreprocessingError::InvalidByteOrder(line.text.to_string())),
+        };
+        Ok(())
+    }
+
+    /// Reserve a number of bytes of padding
+    /// Space syntax is `ds COUNT`, optionally followed by what to fill it with: `byte B`, `pattern B ...` repeated from
+    /// its start, or `random SEED`. Without one, it's filled with 0x00
+    fn space(&mut self, line: &Line, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidSpace(line.text.to_string());
+        let words = self.substituted(&line.tokens[1..]);
+        let (count, fill) = words.split_first().ok_or_else(invalid)?;
+        let count = match parse::parse_asm_arg(count) {
+            Ok(AsmArgument::Numeric(count)) => count as usize,
+            _ => return Err(invalid()),
+        };
+        let fill = fill_of(fill).ok_or_else(invalid)?;
+        self.pad(count, &fill, line.text, number)
+    }
+
+    /// Pad until the next instruction starts at a multiple of some number of bytes
+    /// Align syntax is `align N` or `.align N`, optionally followed by a fill the same way as `ds`
+    fn align(&mut self, line: &Line, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidAlign(line.text.to_string());
+        let words = self.substituted(&line.tokens[1..]);
+        let (multiple, fill) = words.split_first().ok_or_else(invalid)?;
+        let multiple = match parse::parse_asm_arg(multiple) {
+            Ok(AsmArgument::Numeric(multiple)) if multiple > 0 => multiple as usize,
+            _ => return Err(invalid()),
+        };
+        let fill = fill_of(fill).ok_or_else(invalid)?;
+        let count = (multiple - self.addr % multiple) % multiple;
+        self.pad(count, &fill, line.text, number)
+    }
+
+    /// Place a block of padding at the current address
+    fn pad(
+        &mut self,
+        count: usize,
+        fill: &Fill,
+        header: &str,
+        number: usize,
+    ) -> Result<(), PreprocessingError> {
+        if self.addr + count > MEMORY_SIZE {
+            return Err(PreprocessingError::OversizedData(header.to_string()));
+        }
+        if count > 0 {
+            self.emit(InstructionText::Data(fill.bytes(count)), number);
+        }
+        Ok(())
+    }
+
+    /// Place a run of the same byte
+    /// Fill syntax is `fill COUNT, BYTE` or `.fill COUNT, BYTE`, where the byte is 0 if it's left out. Both can use
+    /// constants and labels declared above it
+    fn fill(&mut self, line: &Line, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidFill(line.text.to_string());
+        let (count, byte) = match line.tokens[1..] {
+            [count] => (count.text, None),
+            [count, byte] => (count.text, Some(byte.text)),
+            _ => return Err(invalid()),
+        };
+        let count = self.value(count).ok_or_else(invalid)?;
+        let byte = match byte {
+            Some(byte) => self.value(byte).and_then(|byte| u8::try_from(byte).ok()),
+            None => Some(0),
+        }
+        .ok_or_else(invalid)?;
+        self.pad(count, &Fill::Byte(byte), line.text, number)
+    }
+
+    /// Pad until the next instruction starts at an address
+    /// Org syntax is `org ADDR` or `.org ADDR`, optionally followed by a fill the same way as `ds`. The address can't be
+    /// before where the program already is, and can use constants and labels declared above it
+    fn origin(&mut self, line: &Line, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidOrigin(line.text.to_string());
+        let words = self.substituted(&line.tokens[1..]);
+        let (addr, fill) = words.split_first().ok_or_else(invalid)?;
+        let addr = self.value(addr).ok_or_else(invalid)?;
+        let fill = fill_of(fill).ok_or_else(invalid)?;
+        let count =
+            addr.checked_sub(self.addr)
+                .ok_or_else(|| PreprocessingError::OriginBehind {
+                    addr: self.addr,
+                    line: line.text.to_string(),
+                })?;
+        self.pad(count, &fill, line.text, number)
+    }
+
+    /// The text of tokens as the assembler will see them, with any aliases substituted
+    fn substituted(&self, tokens: &[Token<'a>]) -> Vec<&'a str> {
+        tokens
+            .iter()
+            .map(|token| self.symbols.substitute(token.text))
+            .collect()
+    }
+
+    /// Place a line of 16 bit values, which are encoded in the second pass so they can be labels declared later
+    /// Word syntax is `dw VALUE, ...`, packed in the order set by `byteorder`, or `dw.be` and `dw.le` for a fixed order.
+    /// Each can also be written with a leading `.`
+    fn words(&mut self, line: Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let order = match line.head().map(|head| head.trim_start_matches('.')) {
+            Some("dw.be") => ByteOrder::Big,
+            Some("dw.le") => ByteOrder::Little,
+            _ => self.byte_order,
+        };
+        if line.tokens.len() < 2 {
+            return Err(PreprocessingError::TooFewWordArgs(line.text.to_string()));
+        }
+        self.emit(InstructionText::Words(Data::new(line), order), number);
+        Ok(())
+    }
+
+    /// Place a line of byte values, which are encoded in the second pass so they can be labels declared later
+    /// Byte syntax is `db VALUE, ...` or `.db VALUE, ...`
+    fn bytes(&mut self, line: Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        if line.tokens.len() < 2 {
+            return Err(PreprocessingError::TooFewWordArgs(line.text.to_string()));
+        }
+        self.emit(InstructionText::Bytes(Data::new(line)), number);
+        Ok(())
+    }
+
+    /// Record the label a line declares. A local label, like `.loop:`, is declared as `global.loop` under the last
+    /// global label, which is what `.loop` refers to until the next one, so the same local name can be used again
+    /// under each global label
+    /// An anonymous label, `@@:`, is only referred to as `@b` after it or `@f` before it, so it can be used as often as
+    /// needed
+    fn label_line(&mut self, line: &'a str) -> Result<(), PreprocessingError> {
+        let label = line.trim_end_matches(':');
+        if label == local::ANONYMOUS {
+            self.anonymous += 1;
+            return self.label_at(format!("{label}{}", self.anonymous - 1), line, self.addr);
+        }
+        let label = match local::is_local(label) {
+            true => {
+                let scope = self
+                    .scope
+                    .ok_or_else(|| PreprocessingError::UnscopedLocalLabel(line.to_string()))?;
+                format!("{scope}{label}")
+            }
+            false => {
+                self.scope = Some(label);
+                label.to_string()
+            }
+        };
+        if self.included_from.is_none() {
+            self.labels_declared
+                .push((label.clone(), self.line, self.addr));
+        }
+        self.label_at(label, line, self.addr)
+    }
+
+    /// Record the address a label points to, which is wherever the next instruction will be placed
+    /// Label syntax is `label:\n`
+    fn label(&mut self, label: &'a str, line: &str) -> Result<(), PreprocessingError> {
+        self.label_at(label, line, self.addr)
+    }
+
+    /// Record the address a label points to
+    fn label_at(
+        &mut self,
+        label: impl Into<Cow<'a, str>>,
+        line: &str,
+        addr: usize,
+    ) -> Result<(), PreprocessingError> {
+        let label = label.into();
+        // labels can't contain spaces because that's how we separate tokens
+        if label.contains(char::is_whitespace) {
+            Err(PreprocessingError::InvalidLabel(line.to_string()))
+        // check if the label is a reserved word
+        } else if is_reserved(&label) {
+            Err(PreprocessingError::ReservedLabel(line.to_string()))
+        } else if !self.symbols.define_label(label, addr) {
+            Err(PreprocessingError::ReusedLabel(line.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Make sure a sprite declaration is valid before looking for the rest of the sprite
+    fn check_sprite_header(&self, header: &Line) -> Result<(), PreprocessingError> {
+        match header.tokens.len().cmp(&2) {
+            Ordering::Less => Err(PreprocessingError::TooFewSpriteArgs(
+                header.text.to_string(),
+            )),
+            Ordering::Greater => Err(PreprocessingError::TooManySpriteArgs(
+                header.text.to_string(),
+            )),
+            Ordering::Equal => Ok(()),
+        }
+    }
+
+    /// Condense the rows of a sprite into a block of data, labelled with the name of the sprite
+    /// Sprite syntax is `sprite NAME` (with an optional colon), any number of rows then `endsprite`. Rows are either bytes
+    /// beginning with 0b or pixel art such as `..XX..X.`, left aligned if shorter than 8 pixels.
+    /// `sprite16` declares a 16 pixel wide Super-CHIP sprite of up to 16 rows the same way, with 16 bit rows.
+    /// A `plane2` line splits the rows into the two planes of an XO-CHIP sprite, which need to be the same height.
+    /// Rows are parsed as soon as the sprite is reached, so any aliases they use must be declared before it
+    fn sprite(
+        &mut self,
+        header: &Line<'a>,
+        rows: &[(usize, Line<'a>)],
+        width: u32,
+    ) -> Result<(), PreprocessingError> {
+        let planes: Vec<&[(usize, Line<'a>)]> = rows
+            .split(|(_, row)| row.head() == Some("plane2"))
+            .collect();
+        match planes[..] {
+            [_] => (),
+            [first, second] if first.len() == second.len() => (),
+            _ => {
+                return Err(PreprocessingError::MismatchedPlanes(
+                    header.text.to_string(),
+                ))
+            }
+        }
+        match width {
+            8 if planes[0].len() > 15 => {
+                return Err(PreprocessingError::OversizedSprite(header.text.to_string()))
+            }
+            16 if planes[0].len() > 16 => {
+                return Err(PreprocessingError::OversizedSprite16(
+                    header.text.to_string(),
+                ))
+            }
+            _ => (),
+        }
+
+        let mut sprite = Sprite {
+            width,
+            planes: planes.len() as u32,
+            rows: Vec::with_capacity(rows.len()),
+        };
+        for row in planes
+            .iter()
+            .flat_map(|plane| plane.iter().map(|(_, row)| row))
+        {
+            let value = match self.pixel_row(row.text, width)? {
+                Some(value) => value,
+                None => match parse::parse_asm_arg(self.symbols.substitute(row.text))? {
+                    arg @ AsmArgument::Numeric(_) if width == 8 => {
+                        parse::parse_valid_byte(&arg)? as u16
                     }
-                    let sprite_end = i;
-                    if sprite_end - sprite_start > 16 {
-                        return Err(PreprocessingError::OversizedSprite(cur_line.to_string()));
-                    };
-                    process_sprite(
-                        &mut lines,
-                        sprite_start,
-                        sprite_end,
-                        &mut to_change,
-                        &mut to_remove,
-                    )?;
-                }
+                    AsmArgument::Numeric(value) => value,
+                    _ => return Err(PreprocessingError::InvalidSpriteRow(row.text.to_string())),
+                },
+            };
+            sprite.rows.push(value);
+        }
+
+        let line = rows.first().map_or(0, |&(line, _)| line);
+        self.emit_sprite(header, sprite, line)
+    }
+
+    /// Make sure a data declaration is valid before looking for the rest of the block
+    fn check_data_header(&self, header: &Line) -> Result<(), PreprocessingError> {
+        match header.tokens.len().cmp(&2) {
+            Ordering::Less => Err(PreprocessingError::TooFewDataArgs(header.text.to_string())),
+            Ordering::Greater => Err(PreprocessingError::TooManyDataArgs(header.text.to_string())),
+            Ordering::Equal => Ok(()),
+        }
+    }
+
+    /// Place a block of raw bytes, labelled with its name
+    /// Data syntax is `data NAME` (with an optional colon), any number of rows of bytes then `enddata`. Unlike sprites
+    /// there's no limit on the size, and each row can hold as many bytes as you like, such as `1, 2, 0xFF, 0b1010`
+    fn data(
+        &mut self,
+        header: &Line<'a>,
+        rows: &[(usize, Line<'a>)],
+    ) -> Result<(), PreprocessingError> {
+        let mut bytes = Vec::new();
+        for (_, row) in rows {
+            for token in &row.tokens {
+                let arg = parse::parse_asm_arg(self.symbols.substitute(token.text))
+                    .map_err(PreprocessingError::InvalidDataByte)?;
+                bytes.push(
+                    parse::parse_valid_byte(&arg).map_err(PreprocessingError::InvalidDataByte)?,
+                );
             }
         }
-        i += 1;
+        if self.addr + bytes.len() > MEMORY_SIZE {
+            return Err(PreprocessingError::OversizedData(header.text.to_string()));
+        }
+
+        let name = header.tokens[1].text.trim_end_matches(':');
+        let line = rows.first().map_or(0, |&(line, _)| line);
+        self.emit_table(name, header.text, bytes, line)
     }
 
-    for (i, change_to) in to_change.into_iter() {
-        lines[i] = change_to;
+    /// Place a table of the glyph index of each character of a string, labelled with its name
+    /// Text syntax is `text NAME "STRING"`, indexing into the font declared by the last `font`, or the built in hex font.
+    /// `NAME_LENGTH` is defined as the number of characters, so rendering loops don't need to hard code it
+    fn text(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidText(line.text.to_string());
+        if line.tokens.len() < 3 {
+            return Err(invalid());
+        }
+        let text = match split_path(line.rest(2)) {
+            Some((text, "")) => text,
+            _ => return Err(invalid()),
+        };
+        let bytes = text
+            .chars()
+            .map(|c| {
+                self.glyphs
+                    .chars()
+                    .position(|glyph| glyph == c)
+                    .and_then(|index| u8::try_from(index).ok())
+                    .ok_or_else(|| PreprocessingError::UnknownGlyph {
+                        glyph: c,
+                        line: line.text.to_string(),
+                    })
+            })
+            .collect::<Result<Vec<u8>, PreprocessingError>>()?;
+
+        let name = line.tokens[1].text.trim_end_matches(':');
+        self.constant(format!("{name}_LENGTH"), bytes.len())?;
+        self.emit_table(name, line.text, bytes, number)
     }
 
-    for (i, index) in to_remove.into_iter().enumerate() {
-        lines.remove(index - i);
+    /// Place a table of the decimal digits of every number up to a maximum, labelled with its name
+    /// BCD table syntax is `bcdtable NAME MAX`, where MAX is up to 255. Each entry is as many bytes as MAX has digits,
+    /// most significant first, like Fx33 would store them. `NAME_STRIDE` is defined as the size of an entry and
+    /// `NAME_LENGTH` as the number of entries, so the digits of n start at `NAME + n * NAME_STRIDE`
+    fn bcd_table(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidBcdTable(line.text.to_string());
+        let [_, name, max] = line.tokens[..] else {
+            return Err(invalid());
+        };
+        let max = parse::parse_asm_arg(self.symbols.substitute(max.text))
+            .ok()
+            .and_then(|arg| parse::parse_valid_byte(&arg).ok())
+            .ok_or_else(invalid)?;
+
+        let stride = max.to_string().len();
+        let bytes = (0..=max)
+            .flat_map(|n| [n / 100, n / 10 % 10, n % 10][3 - stride..].to_vec())
+            .collect();
+
+        let name = name.text.trim_end_matches(':');
+        self.constant(format!("{name}_STRIDE"), stride)?;
+        self.constant(format!("{name}_LENGTH"), max as usize + 1)?;
+        self.emit_table(name, line.text, bytes, number)
     }
 
-    Ok(lines)
-}
+    /// Place a dispatcher that jumps to one of a list of labels by the index in V0, labelled with its name
+    /// Jump table syntax is `jumptable NAME: LABEL, ...` with up to 128 labels. Calling or jumping to NAME doubles V0
+    /// (clobbering VF) and jumps through a table of `JP LABEL` instructions with `JP V0`. The table itself is labelled
+    /// `NAME_TABLE`, and `NAME_LENGTH` is defined as the number of labels
+    fn jump_table(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let entries = line.tokens.get(2..).unwrap_or_default();
+        if entries.is_empty() || entries.len() > 128 {
+            return Err(PreprocessingError::InvalidJumpTable(line.text.to_string()));
+        }
+
+        let name = line.tokens[1].text.trim_end_matches(':');
+        self.constant(format!("{name}_LENGTH"), entries.len())?;
+        self.label(name, line.text)?;
+        // the table follows the two instructions of the dispatcher, so its address is already known
+        let table = self.addr + 4;
+        if table > 0xFFF {
+            return Err(PreprocessingError::InvalidJumpTable(line.text.to_string()));
+        }
+        // ADD V0, V0 then JP V0, table
+        self.fixed_jumps.push(self.addr + 2);
+        self.emit(
+            InstructionText::Code(vec![0x80, 0x04, 0xB0 | (table >> 8) as u8, table as u8]),
+            number,
+        );
+
+        self.label_at(format!("{name}_TABLE"), line.text, table)?;
+        for entry in entries {
+            self.jump_table_entries
+                .push((entry.text, line.text, number));
+            // the jumps are assembled like any other line, so the labels can be declared after the table
+            let jump = Line {
+                text: line.text,
+                tokens: vec![
+                    Token {
+                        text: "JP",
+                        column: entry.column,
+                    },
+                    *entry,
+                ],
+            };
+            self.emit(InstructionText::Source(Instruction::new(jump)), number);
+        }
+        Ok(())
+    }
+
+    /// Place the instruction a pseudo-instruction stands for, like `ADD Vx, 1` for `INC Vx`
+    fn pseudo_op(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        match pseudo::expand(line) {
+            Some(pseudo::Expansion::Line(expanded)) => {
+                self.emit(InstructionText::Source(Instruction::new(expanded)), number)
+            }
+            Some(pseudo::Expansion::JumpToSelf) if self.addr <= 0xFFF => {
+                let addr = self.addr;
+                self.fixed_jumps.push(addr);
+                self.emit(
+                    InstructionText::Code(vec![0x10 | (addr >> 8) as u8, addr as u8]),
+                    number,
+                );
+            }
+            _ => return Err(PreprocessingError::InvalidPseudoOp(line.text.to_string())),
+        }
+        Ok(())
+    }
 
-/// Given the bounds of a sprite declared in lines, record the necessary changes to process it, or error if it can't be parsed
-fn process_sprite(
-    lines: &mut [PreprocessedInstruction],
-    start: usize,
-    end: usize,
-    change_list: &mut Vec<(usize, PreprocessedInstruction)>,
-    remove_list: &mut Vec<usize>,
-) -> Result<(), PreprocessingError> {
-    // I beg your forgiveness for this unholy abomination
-    let sprite_bytes = parse::parse_asm_args(
-        // convert our preprocessed instructions into string slices in order to use our parse module
-        &(lines[start + 1..end]
+    /// Record the layout of a struct as constants for the offset of each field and its total size
+    /// Struct syntax is `struct NAME { FIELD: SIZE, ... }`, on one line or with the fields on the lines before a closing
+    /// `}`. `NAME.FIELD` is defined as the offset of each field in bytes, and `NAME.size` as the size of the whole struct
+    fn structure(
+        &mut self,
+        header: &Line<'a>,
+        rows: &[(usize, Line<'a>)],
+    ) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidStruct(header.text.to_string());
+        if header.tokens.get(2).map(|t| t.text) != Some("{") {
+            return Err(invalid());
+        }
+        let name = header.tokens[1].text;
+        let tokens: Vec<&str> = header.tokens[3..]
             .iter()
-            .map(|l| &**l)
-            .collect::<Vec<_>>()),
-    )?
-    .into_iter()
-    .map(|arg| parse::parse_valid_byte(&arg).map_err(PreprocessingError::from))
-    .collect::<Result<Vec<u8>, PreprocessingError>>()?;
-
-    // pair up bytes and convert to u16
-    let raws = sprite_bytes
-        .chunks(2)
-        .map(|chunk| ((chunk[0] as u16) << 8) + if chunk.len() == 2 { chunk[1] as u16 } else { 0 });
-
-    // we're going to convert the sprite block into a label and raws, so let's start with the label
-    let mut new_label = lines[start]
-        .strip_prefix("sprite")
-        .expect("We check that this starts with sprite in the calling context")
-        .trim()
-        .to_string();
-    if !new_label.ends_with(':') {
-        new_label.push(':')
-    };
-    change_list.push((start, PreprocessedInstruction::Changed(new_label)));
-
-    let remove_threshold = start + raws.len() + 1;
-
-    // record changes from bytes to raws
-    for (i, raw) in raws.into_iter().enumerate() {
-        change_list.push((
-            start + 1 + i,
-            PreprocessedInstruction::Changed(format!("{raw:#X}")),
-        ));
+            .chain(rows.iter().flat_map(|(_, row)| &row.tokens))
+            .map(|t| t.text)
+            .filter(|&t| t != "}")
+            .collect();
+        if tokens.len() % 2 == 1 {
+            return Err(invalid());
+        }
+
+        let mut offset = 0;
+        for field in tokens.chunks(2) {
+            let size = self.size_of(field[1]).ok_or_else(invalid)?;
+            self.constant(format!("{name}.{}", field[0].trim_end_matches(':')), offset)?;
+            offset += size;
+        }
+        self.constant(format!("{name}.size"), offset)?;
+        self.structs.insert(name, offset);
+        Ok(())
     }
 
-    // record deletions for extra bytes
-    for i in remove_threshold..=end {
-        remove_list.push(i);
+    /// Reserve space for a variable in free memory, after the program
+    /// Var syntax is `var NAME TYPE`, where TYPE is a number of bytes or the name of a struct, optionally followed by
+    /// `[COUNT]` for an array, such as `var enemies Enemy[8]`. NAME is a label for the first byte, and `NAME.length` is
+    /// defined as COUNT. Variables are placed in the order they're declared, and `#n` offsets start after them
+    fn var(&mut self, line: &Line<'a>, number: usize) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidVar(line.text.to_string());
+        let [_, name, ty] = line.tokens[..] else {
+            return Err(invalid());
+        };
+        let (ty, count) = match ty.text.split_once('[') {
+            Some((ty, count)) => {
+                let count = count.strip_suffix(']').ok_or_else(invalid)?;
+                (ty, self.size_of(count).ok_or_else(invalid)?)
+            }
+            None => (ty.text, 1),
+        };
+        let size = self.size_of(ty).ok_or_else(invalid)?;
+
+        self.constant(format!("{}.length", name.text), count)?;
+        self.vars.push((name.text, size * count, line.text, number));
+        Ok(())
     }
 
-    Ok(())
-}
+    /// The number of bytes a struct or numeric size stands for
+    fn size_of(&self, text: &str) -> Option<usize> {
+        if let Some(&size) = self.structs.get(text) {
+            return Some(size);
+        }
+        match parse::parse_asm_arg(self.symbols.substitute(text)).ok()? {
+            AsmArgument::Numeric(size) => Some(size as usize),
+            _ => None,
+        }
+    }
 
-/// Find label declarations in instructions, remove them, and replace references to them with corresponding memory addresses
-/// Label syntax is `label:\n`
-fn evaluate_labels(
-    mut lines: Vec<PreprocessedInstruction>,
-) -> Result<Vec<PreprocessedInstruction>, PreprocessingError> {
-    let reserved = HashSet::from(RESERVED_WORDS);
-    let mut label_map: HashMap<String, usize> = HashMap::new();
-    let mut to_remove = Vec::new();
-
-    // find labels, record where the point to, and remove them
-    for (i, line) in lines.iter().enumerate() {
-        if line.ends_with(':') {
-            let label = line.trim_end_matches(':');
-            // labels can't contain spaces because that's how we separate tokens
-            if label.contains(char::is_whitespace) {
-                return Err(PreprocessingError::InvalidLabel(line.to_string()));
-            // check if the label is a reserved word
-            } else if reserved.contains(label) {
-                return Err(PreprocessingError::ReservedLabel(line.to_string()));
-
-            // the program starts at 0x200 and each instruction is 2 bytes so our label address is 0x200 + 2 times the number of instructions before
-            } else if label_map
-                .insert(label.to_string(), (i - to_remove.len()) * 2 + 0x200)
-                .is_some()
-            {
-                return Err(PreprocessingError::ReusedLabel(line.to_string()));
-            } else {
-                to_remove.push(i);
+    /// Label every variable in turn from the end of the program, returning where free memory starts after them. The
+    /// program is already placed, so errors are kept to report with the rest
+    fn place_vars(&mut self) -> usize {
+        let mut addr = self.addr;
+        for (name, size, text, line) in core::mem::take(&mut self.vars) {
+            self.line = line;
+            self.recoverable(|pass| pass.label_at(name, text, addr));
+            addr += size;
+            if addr > MEMORY_SIZE {
+                self.errors.push(Located {
+                    line,
+                    column: None,
+                    origin: None,
+                    error: PreprocessingError::OversizedVar(text.to_string()),
+                });
+                break;
             }
         }
+        addr
     }
 
-    for (i, index) in to_remove.into_iter().enumerate() {
-        lines.remove(index - i);
+    /// Define a constant generated from the source
+    fn constant(&mut self, name: String, value: usize) -> Result<(), PreprocessingError> {
+        if !self.symbols.define_constant(name.clone(), value) {
+            return Err(PreprocessingError::ConstantClash(name));
+        }
+        Ok(())
     }
 
-    // find where labels are used and replace them with their addresses
-    let mut to_replace: Vec<(usize, String)> = Vec::new();
-    let mut curr_inst_as_string = String::new();
-    for (i, line) in lines.iter().enumerate() {
-        let mut replace_this_line = false;
+    /// Label a generated table of bytes with its name and place it
+    fn emit_table(
+        &mut self,
+        name: &'a str,
+        header: &str,
+        mut bytes: Vec<u8>,
+        line: usize,
+    ) -> Result<(), PreprocessingError> {
+        // pad odd tables out to a whole word so the following instructions stay aligned
+        if bytes.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        // the table's name points at its first byte
+        self.label(name, header)?;
+        if !bytes.is_empty() {
+            self.emit(InstructionText::Data(bytes), line);
+        }
+        Ok(())
+    }
 
-        for token in line.split_whitespace() {
-            if let Some(addr) = label_map.get(token.trim_end_matches(',')) {
-                curr_inst_as_string.push_str(&format!(" 0x{:x}", addr));
-                replace_this_line = true;
-            } else {
-                curr_inst_as_string.push(' ');
-                curr_inst_as_string.push_str(token);
+    /// Declare a sprite as a transformation of one declared before it
+    /// Transformed sprite syntax is `sprite NAME TRANSFORM SOURCE`, where the transform is `mirror` (left to right), `flip`
+    /// (top to bottom), `invert`, or `shift` followed by a number of pixels to move right, or left if negative
+    fn transformed_sprite(
+        &mut self,
+        header: &Line<'a>,
+        line: usize,
+    ) -> Result<(), PreprocessingError> {
+        let invalid = || PreprocessingError::InvalidSpriteTransform(header.text.to_string());
+        // anything else is just a regular sprite header with too many arguments
+        if !matches!(
+            header.tokens[2].text,
+            "mirror" | "flip" | "invert" | "shift"
+        ) {
+            return Err(PreprocessingError::TooManySpriteArgs(
+                header.text.to_string(),
+            ));
+        }
+        let source = self
+            .sprites
+            .get(header.tokens[3].text)
+            .ok_or_else(|| PreprocessingError::UnknownSprite(header.text.to_string()))?;
+        let sprite = match (header.tokens[2].text, &header.tokens[4..]) {
+            ("mirror", []) => source.mirror(),
+            ("flip", []) => source.flip(),
+            ("invert", []) => source.invert(),
+            ("shift", [by]) => source.shift(by.text.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        self.emit_sprite(header, sprite, line)
+    }
+
+    /// Load a sprite from an image, labelled with the name of the sprite
+    /// Image sprite syntax is `sprite NAME from "PATH"`, with the path relative to the source file, optionally followed by
+    /// `rect X, Y, WIDTH, HEIGHT` to use only part of the image. Images are png, bmp, or pbm, and are thresholded so bright
+    /// pixels are lit. The sprite must be 8 pixels wide and up to 15 tall, or 16 wide and up to 16 tall
+    #[cfg(feature = "std")]
+    fn image_sprite(&mut self, header: &Line<'a>, line: usize) -> Result<(), PreprocessingError> {
+        if header.tokens.len() < 4 {
+            return Err(PreprocessingError::TooFewSpriteArgs(
+                header.text.to_string(),
+            ));
+        }
+        let (path, rest) = split_path(header.rest(3))
+            .ok_or_else(|| PreprocessingError::InvalidSpritePath(header.text.to_string()))?;
+
+        let image = bitmap::load(&self.dir.join(path)).map_err(|source| {
+            PreprocessingError::SpriteImage {
+                header: header.text.to_string(),
+                source,
             }
+        })?;
+        let image = match rest {
+            "" => image,
+            rect => parse_numbers(rect, "rect")
+                .and_then(|rect| match rect[..] {
+                    [x, y, width, height] => image.crop(x, y, width, height),
+                    _ => None,
+                })
+                .ok_or_else(|| PreprocessingError::InvalidSpriteRect(header.text.to_string()))?,
+        };
+        let sprite = Sprite::from_image(&image)
+            .ok_or_else(|| PreprocessingError::SpriteImageSize(header.text.to_string()))?;
+        self.emit_sprite(header, sprite, line)
+    }
+
+    /// Split an image into a grid of tiles, placing each as a sprite named after its column and row
+    /// Sprite sheet syntax is `spritesheet NAME from "PATH" tile WIDTH, HEIGHT`, with the path relative to the source file.
+    /// Tiles are named `NAME_X_Y`, counting from 0 at the top left, and are placed row by row. Each tile follows the same
+    /// rules as a sprite imported from an image
+    #[cfg(feature = "std")]
+    fn sprite_sheet(&mut self, header: &Line<'a>, line: usize) -> Result<(), PreprocessingError> {
+        if header.tokens.len() < 4 || header.tokens[2].text != "from" {
+            return Err(PreprocessingError::InvalidSpriteSheet(
+                header.text.to_string(),
+            ));
         }
+        let (path, rest) = split_path(header.rest(3))
+            .ok_or_else(|| PreprocessingError::InvalidSpritePath(header.text.to_string()))?;
+        let [width, height] = parse_numbers(rest, "tile")
+            .and_then(|tile| <[usize; 2]>::try_from(tile).ok())
+            .filter(|&[width, height]| width > 0 && height > 0)
+            .ok_or_else(|| PreprocessingError::InvalidSpriteSheet(header.text.to_string()))?;
 
-        if replace_this_line {
-            to_replace.push((i, curr_inst_as_string));
-            curr_inst_as_string = String::new();
-        } else {
-            curr_inst_as_string.clear();
+        let image = bitmap::load(&self.dir.join(path)).map_err(|source| {
+            PreprocessingError::SpriteImage {
+                header: header.text.to_string(),
+                source,
+            }
+        })?;
+        if image.width % width != 0 || image.height % height != 0 {
+            return Err(PreprocessingError::InvalidSpriteSheet(
+                header.text.to_string(),
+            ));
+        }
+
+        let name = header.tokens[1].text.trim_end_matches(':');
+        for y in 0..image.height / height {
+            for x in 0..image.width / width {
+                let tile = image
+                    .crop(x * width, y * height, width, height)
+                    .and_then(|tile| Sprite::from_image(&tile))
+                    .ok_or_else(|| PreprocessingError::SpriteImageSize(header.text.to_string()))?;
+                self.emit_named_sprite(format!("{name}_{x}_{y}").into(), header.text, tile, line)?;
+            }
         }
+        Ok(())
     }
 
-    for (i, replacement) in to_replace.into_iter() {
-        lines[i] = PreprocessedInstruction::Changed(replacement);
+    /// Label the bytes of a sprite with the name from its header and place them
+    fn emit_sprite(
+        &mut self,
+        header: &Line<'a>,
+        sprite: Sprite,
+        line: usize,
+    ) -> Result<(), PreprocessingError> {
+        let name = header.tokens[1].text.trim_end_matches(':');
+        self.emit_named_sprite(name.into(), header.text, sprite, line)
     }
 
-    Ok(lines)
-}
+    /// Label the bytes of a sprite with its name and place them
+    fn emit_named_sprite(
+        &mut self,
+        name: Cow<'a, str>,
+        header: &str,
+        sprite: Sprite,
+        line: usize,
+    ) -> Result<(), PreprocessingError> {
+        self.sprite_constants(&name, &sprite)?;
+        let mut sprite_bytes = sprite.bytes();
+        self.sprites.insert(name.clone(), sprite.clone());
 
-/// Find instances of the #n free memory offset syntax and replace them with
-/// correct addresses based on the length of the program
-fn evaluate_memory_offsets(
-    mut lines: Vec<PreprocessedInstruction>,
-) -> Result<Vec<PreprocessedInstruction>, PreprocessingError> {
-    // determine where offset #0 is
-    let used_memory = 2 * lines.len();
-
-    // iterate over the instructions until we stop finding offsets
-    let mut found_changes = true;
-    while found_changes {
-        found_changes = false;
-
-        // keep track of instructions to swap out with evaluated alternatives
-        let mut to_replace = Vec::new();
-
-        for (i, line) in lines.iter().enumerate() {
-            if let Some(index) = line.find('#') {
-                found_changes = true;
-
-                // equivalent regex would be \#.+ but we throw an error if it's not numeric
-                let start = index + 1;
-                let mut end = index + 1;
-                // i hate that we need copy and an allocation
-                let chars = line.chars().collect::<Vec<_>>();
-                while end < chars.len() && !chars[end].is_whitespace() {
-                    end += 1;
+        // pad odd sprites out to a whole word so the following instructions stay aligned, unless told otherwise
+        if sprite_bytes.len() % 2 == 1 {
+            match self.sprite_padding {
+                SpritePadding::Warn => {
+                    self.warnings.push(PreprocessingWarning::PaddedSprite {
+                        name: name.to_string(),
+                        line,
+                    });
+                    sprite_bytes.push(0);
                 }
+                SpritePadding::Off => (),
+                SpritePadding::Byte(byte) => sprite_bytes.push(byte),
+            }
+        }
 
-                if start >= chars.len() {
-                    return Err(PreprocessingError::InvalidOffset(line.to_string()));
-                }
+        // point at an identical copy if one has already been placed
+        if let Some(placed) = &mut self.placed_sprites {
+            if let Some(&addr) = placed.get(&sprite_bytes) {
+                self.sprite_bytes_saved += sprite_bytes.len();
+                #[cfg(feature = "std")]
+                self.placed.push(PlacedSprite {
+                    name: name.to_string(),
+                    addr,
+                    sprite,
+                });
+                return self.label_at(name, header, addr);
+            }
+            placed.insert(sprite_bytes.clone(), self.addr);
+        }
+
+        // the sprite's name points at its first byte
+        #[cfg(feature = "std")]
+        self.placed.push(PlacedSprite {
+            name: name.to_string(),
+            addr: self.addr,
+            sprite,
+        });
+        self.label_at(name, header, self.addr)?;
+        if !sprite_bytes.is_empty() {
+            self.emit(InstructionText::Data(sprite_bytes), line);
+        }
+
+        Ok(())
+    }
+
+    /// Define `NAME_HEIGHT` for a sprite, and `NAME_WIDTH` if it's 16 pixels wide, so draw instructions can keep up
+    /// with changes to the art
+    fn sprite_constants(&mut self, name: &str, sprite: &Sprite) -> Result<(), PreprocessingError> {
+        let mut constants = vec![(format!("{name}_HEIGHT"), sprite.height())];
+        if sprite.width == 16 {
+            constants.push((format!("{name}_WIDTH"), 16));
+        }
+        for (constant, value) in constants {
+            if !self.symbols.define_constant(constant.clone(), value) {
+                return Err(PreprocessingError::SpriteConstantClash(constant));
+            }
+        }
+        Ok(())
+    }
 
-                let offset: usize = str::parse(&line[start..end])
-                    .map_err(|_| PreprocessingError::InvalidOffset(line.to_string()))?;
+    /// Convert a row of pixel art for a sprite of the given width into its bits, or None if the row isn't pixel art
+    fn pixel_row(&self, row: &str, width: u32) -> Result<Option<u16>, PreprocessingError> {
+        let (on, off) = self.pixels;
+        if !row.chars().all(|c| c == on || c == off) {
+            return Ok(None);
+        }
+        if row.chars().count() > width as usize {
+            return Err(PreprocessingError::WidePixelRow {
+                width,
+                row: row.to_string(),
+            });
+        }
+        // the leftmost pixel is the most significant bit
+        Ok(Some(row.chars().enumerate().fold(0, |bits, (i, c)| {
+            if c == on {
+                bits | 1 << (width as usize - 1 - i)
+            } else {
+                bits
+            }
+        })))
+    }
+}
+
+/// Collect the rows of a block up to the line starting with its end keyword, or None if the source ends first
+fn take_block<'a>(
+    lines: &mut impl Iterator<Item = (usize, Line<'a>)>,
+    end: &str,
+) -> Option<Vec<(usize, Line<'a>)>> {
+    let mut rows = Vec::new();
+    loop {
+        match lines.next()? {
+            (_, row) if row.head() == Some(end) => return Some(rows),
+            row => rows.push(row),
+        }
+    }
+}
+
+/// Split a path or string, which is quoted if it contains whitespace, from whatever follows it, or None if the quote isn't closed
+fn split_path(text: &str) -> Option<(&str, &str)> {
+    match text.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"')?;
+            Some((&quoted[..end], quoted[end + 1..].trim_start()))
+        }
+        None => {
+            let end = text.find(char::is_whitespace).unwrap_or(text.len());
+            Some((&text[..end], text[end..].trim_start()))
+        }
+    }
+}
+
+/// The fill described by the words after a padding directive, or 0x00 bytes if there aren't any
+fn fill_of(words: &[&str]) -> Option<Fill> {
+    match words {
+        [] => Some(Fill::default()),
+        words => Fill::parse(words),
+    }
+}
 
-                // replace the instruction with one that uses a raw decimal number instead
-                let mut replacement = line[0..index].to_string();
-                replacement.push_str(&(used_memory + offset).to_string());
-                if end < line.len() - 1 {
-                    replacement.push_str(&line[(end + 1)..]);
+#[cfg(feature = "std")]
+/// Parse a keyword followed by a list of numbers, such as `rect 8, 0, 8, 8`
+fn parse_numbers(text: &str, keyword: &str) -> Option<Vec<usize>> {
+    text.strip_prefix(keyword)?
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse().ok())
+        .collect()
+}
+
+/// Find instances of the #n free memory offset syntax and record the correct addresses for them based on where free memory starts
+fn evaluate_memory_offsets<'a>(
+    lines: &[PreprocessedInstruction<'a>],
+    symbols: &mut SymbolTable<'a>,
+    free_memory: usize,
+    errors: &mut Vec<Located<PreprocessingError>>,
+) {
+    for line in lines {
+        let Some(source) = line.source() else {
+            continue;
+        };
+        // offsets can show up through aliases too, so look at tokens as the assembler will see them
+        for token in &source.tokens {
+            let column = token.column;
+            let token = symbols.substitute(token.text);
+            // an expression can use any number of offsets
+            let in_expression: Vec<&str>;
+            let names = if assemble::expr::is_expression(token) {
+                in_expression = assemble::expr::names(token).collect();
+                &in_expression[..]
+            } else {
+                core::slice::from_ref(&token)
+            };
+            for &name in names {
+                let name = symbols.substitute(name);
+                // `#$1F` is a number in hex rather than an offset
+                if let Some(offset) = name.strip_prefix('#').filter(|_| !name.starts_with("#$")) {
+                    let Ok(offset) = str::parse::<usize>(offset) else {
+                        errors.push(Located {
+                            line: line.line(),
+                            column: Some(column),
+                            origin: line.origin().cloned(),
+                            error: PreprocessingError::InvalidOffset(source.text.to_string()),
+                        });
+                        continue;
+                    };
+                    symbols.define_offset(name, free_memory + offset);
                 }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// The names of the labels reported as unused with -Wall
+    fn unused_labels(source: &str) -> Vec<String> {
+        let options = Options {
+            warnings: Warnings::from_flags(&[WarningFlag::parse("all").unwrap()]),
+            ..Options::default()
+        };
+        let arena = Arena::default();
+        let preprocessed = preprocess(source, &options, &arena).unwrap();
+        preprocessed
+            .warnings
+            .into_iter()
+            .filter_map(|warning| match warning {
+                PreprocessingWarning::UnusedLabel { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
 
-                to_replace.push((i, replacement))
+    #[test]
+    fn directives_and_operations_are_reserved() {
+        let arena = Arena::default();
+        for word in [
+            "db", "dw", "const", "org", "include", "rept", "endr", "module", "ifdef",
+        ] {
+            for source in [format!("{word}:\nCLS"), format!("alias {word} V1")] {
+                let errors = preprocess(&source, &Options::default(), &arena).unwrap_err();
+                assert!(
+                    matches!(
+                        errors[0].error,
+                        PreprocessingError::ReservedLabel(_) | PreprocessingError::ReservedAlias(_)
+                    ),
+                    "`{source}`: {}",
+                    errors[0].error
+                );
             }
         }
+        for word in ["NOP", "HALT", "LD", "CLS"] {
+            let source = format!("const {word} 1");
+            let errors = preprocess(&source, &Options::default(), &arena).unwrap_err();
+            assert!(matches!(
+                errors[0].error,
+                PreprocessingError::ReservedLabel(_)
+            ));
+        }
+        // only aliases are substituted for mnemonics, so a label can be one in another case
+        assert!(preprocess("sub:\nJP sub", &Options::default(), &arena).is_ok());
+        let errors = preprocess("alias nop V1", &Options::default(), &arena).unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            PreprocessingError::ReservedAlias(_)
+        ));
+    }
 
-        for (index, new_str) in to_replace.into_iter() {
-            lines[index] = PreprocessedInstruction::Changed(new_str)
+    #[test]
+    fn only_exports_are_used_outside_modules() {
+        let module = "module m\nexport draw\ndraw:\nCALL hidden\nRET\nhidden:\nRET\nendmodule";
+        let arena = Arena::default();
+        for source in ["CALL draw", "CALL m.draw"] {
+            let source = format!("{source}\n{module}");
+            assert!(preprocess(&source, &Options::default(), &arena).is_ok());
         }
+        // a use before the module is declared is caught too
+        let source = format!("CLS\nCALL m.hidden\n{module}");
+        let errors = preprocess(&source, &Options::default(), &arena).unwrap_err();
+        assert_eq!(errors[0].line, 2);
+        assert!(matches!(
+            &errors[0].error,
+            PreprocessingError::UnexportedSymbol { name, module } if name == "m.hidden" && module == "m"
+        ));
     }
 
-    Ok(lines)
+    #[test]
+    fn sprite_sheets_are_split_into_tiles() {
+        let sheet = "P1\n16 4\n\
+                     1111000000001111\n1000000000000001\n0000000011111111\n1111111100000000\n";
+        let dir = crate::test_files("sprite-sheets", &[("sheet.pbm", sheet)]);
+        let options = Options {
+            dir: dir.clone(),
+            ..Options::default()
+        };
+        let source = "LD I, tiles_1_0\nLD V0, tiles_1_0_HEIGHT\nHALT\nspritesheet tiles from \"sheet.pbm\" tile 8, 2";
+        assert_eq!(
+            crate::assemble_program(source, &options).unwrap().rom,
+            [0xA2, 0x08, 0x60, 0x02, 0x12, 0x04, 0xF0, 0x80, 0x0F, 0x01, 0x00, 0xFF, 0xFF, 0x00]
+        );
+
+        // tiles have to divide the image evenly
+        let arena = Arena::default();
+        let source = "spritesheet tiles from \"sheet.pbm\" tile 5, 2";
+        let errors = preprocess(source, &options, &arena).unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            PreprocessingError::InvalidSpriteSheet(_)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_entry_label_is_used_without_references() {
+        assert_eq!(unused_labels("start:\nCLS\nJP start"), Vec::<String>::new());
+        assert_eq!(unused_labels("main:\nCLS\nRET"), Vec::<String>::new());
+        assert_eq!(unused_labels("start:\nunused:\nJP start"), ["unused"]);
+        assert_eq!(unused_labels("start:\nCLS\nlater:\nJP start"), ["later"]);
+    }
 }
@@ -0,0 +1,22 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// Text generated while preprocessing, like included files and arguments with local labels renamed. Everything the
+/// preprocessor produces borrows from the source, so generated text is kept here for as long as the result is used,
+/// and is all freed together when the arena is dropped
+#[derive(Default)]
+pub struct Arena {
+    texts: RefCell<Vec<String>>,
+}
+
+impl Arena {
+    /// Keep a piece of text, borrowing it for as long as the arena lives
+    pub fn keep(&self, text: String) -> &str {
+        let kept: *const str = text.as_str();
+        self.texts.borrow_mut().push(text);
+        // SAFETY: the text's bytes stay where they are when the String is moved into the Vec, or when the Vec grows
+        // and moves it again, and nothing is taken out of the arena until it's dropped, which the borrow outlives
+        unsafe { &*kept }
+    }
+}
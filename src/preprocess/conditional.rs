@@ -0,0 +1,164 @@
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use clap::ValueEnum;
+
+use super::super::assemble;
+use super::super::symbols::SymbolTable;
+use super::super::tokenize::Line;
+use super::PreprocessingError;
+
+/// The conditional directives, each of which can also be written with a leading `.`
+pub const DIRECTIVES: [&str; 6] = ["if", "ifdef", "ifndef", "elif", "else", "endif"];
+
+/// The system a program is built for, which `.if TARGET == ...` blocks pick code by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(ValueEnum))]
+pub enum Target {
+    #[default]
+    Chip8,
+    Schip,
+    #[cfg_attr(feature = "std", value(name = "xochip"))]
+    XoChip,
+}
+
+impl Target {
+    /// The target with a name, in any case, as it's written on the command line
+    fn named(name: &str) -> Option<Target> {
+        [
+            ("chip8", Target::Chip8),
+            ("schip", Target::Schip),
+            ("xochip", Target::XoChip),
+        ]
+        .into_iter()
+        .find(|(target, _)| target.eq_ignore_ascii_case(name))
+        .map(|(_, target)| target)
+    }
+}
+
+/// One `.if` block that's still open
+struct Branch<'a> {
+    header: &'a str,
+    /// Whether the lines of the current branch are assembled, ignoring any blocks this one is inside
+    active: bool,
+    /// Whether one of the branches so far was picked, so any later ones aren't
+    taken: bool,
+    /// Whether the block is past its `.else`
+    otherwise: bool,
+}
+
+/// Which lines are assembled, going by the `.if`, `.elif`, `.else`, and `.endif` lines seen so far
+#[derive(Default)]
+pub struct Conditions<'a> {
+    open: Vec<Branch<'a>>,
+}
+
+impl<'a> Conditions<'a> {
+    /// Whether lines at this point are assembled
+    pub fn active(&self) -> bool {
+        self.open.iter().all(|branch| branch.active)
+    }
+
+    /// Handle a line if it's one of the [`DIRECTIVES`], returning whether it was
+    /// Condition syntax is `.if TARGET == NAME`, `.if TARGET != NAME`, or `.if EXPRESSION`, which holds if it isn't 0,
+    /// with `.elif` taking the same condition. `.ifdef NAME` and `.ifndef NAME` check whether a symbol was declared
+    /// above them. Each directive can also be written without its leading `.`
+    pub fn directive(
+        &mut self,
+        line: &Line<'a>,
+        target: Target,
+        symbols: &SymbolTable,
+    ) -> Result<bool, PreprocessingError> {
+        match line
+            .head()
+            .map(|head| head.strip_prefix('.').unwrap_or(head))
+        {
+            Some(kind @ ("if" | "ifdef" | "ifndef")) => {
+                // conditions in blocks that aren't assembled aren't checked, so they can use symbols that aren't declared
+                let active = self.active() && condition(kind, line, target, symbols)?;
+                self.open.push(Branch {
+                    header: line.text,
+                    active,
+                    taken: active,
+                    otherwise: false,
+                });
+            }
+            Some("elif") => {
+                let enclosing = self.open.iter().rev().skip(1).all(|branch| branch.active);
+                let taken = self.innermost(line)?.taken;
+                let condition = !taken && enclosing && condition("if", line, target, symbols)?;
+                let branch = self.innermost(line)?;
+                branch.active = condition;
+                branch.taken |= condition;
+            }
+            Some("else") => {
+                let branch = self.innermost(line)?;
+                branch.active = !branch.taken;
+                branch.taken = true;
+                branch.otherwise = true;
+            }
+            Some("endif") => {
+                self.innermost(line)?;
+                self.open.pop();
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Make sure every block was closed by the end of the source
+    pub fn finish(&self) -> Result<(), PreprocessingError> {
+        match self.open.last() {
+            Some(branch) => Err(PreprocessingError::UnclosedIf(branch.header.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// The block a `.elif`, `.else`, or `.endif` belongs to, which can't come after the block's `.else`
+    fn innermost(&mut self, line: &Line) -> Result<&mut Branch<'a>, PreprocessingError> {
+        let unexpected = || PreprocessingError::UnexpectedConditional(line.text.to_string());
+        let branch = self.open.last_mut().ok_or_else(unexpected)?;
+        if branch.otherwise && !matches!(line.head(), Some(".endif" | "endif")) {
+            return Err(unexpected());
+        }
+        Ok(branch)
+    }
+}
+
+/// Whether the condition on an `.if`, `.elif`, `.ifdef`, or `.ifndef` line holds
+fn condition(
+    kind: &str,
+    line: &Line,
+    target: Target,
+    symbols: &SymbolTable,
+) -> Result<bool, PreprocessingError> {
+    let invalid = || PreprocessingError::InvalidCondition(line.text.to_string());
+    if line.tokens.len() < 2 {
+        return Err(invalid());
+    }
+    if kind != "if" {
+        let [_, name] = line.tokens[..] else {
+            return Err(invalid());
+        };
+        return Ok(symbols.is_defined(name.text) == (kind == "ifdef"));
+    }
+
+    // the tokens rather than the text, since a repeated block may have replaced its index in them
+    let condition: Vec<&str> = line.tokens[1..].iter().map(|token| token.text).collect();
+    let condition = condition.join(" ");
+    if let ["TARGET", comparison, name] = condition.split_whitespace().collect::<Vec<_>>()[..] {
+        let equal = Target::named(name).ok_or_else(invalid)? == target;
+        return match comparison {
+            "==" => Ok(equal),
+            "!=" => Ok(!equal),
+            _ => Err(invalid()),
+        };
+    }
+    assemble::evaluate(&condition, symbols)
+        .map(|value| value != 0)
+        .map_err(|error| PreprocessingError::UnevaluableCondition {
+            line: line.text.to_string(),
+            error,
+        })
+}
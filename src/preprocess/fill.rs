@@ -0,0 +1,72 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::super::assemble::parse::{self, AsmArgument};
+
+/// What padding is filled with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fill {
+    /// The same byte over and over
+    Byte(u8),
+    /// A run of bytes repeated from the start of the padding
+    Pattern(Vec<u8>),
+    /// Bytes from a random number generator started from a seed, so the same seed always gives the same bytes
+    Random(u64),
+}
+
+impl Default for Fill {
+    fn default() -> Fill {
+        Fill::Byte(0)
+    }
+}
+
+impl Fill {
+    /// Parse a fill from the words describing it: `byte B`, `pattern B ...`, or `random SEED`, or None if they don't
+    /// describe one
+    pub fn parse(words: &[&str]) -> Option<Fill> {
+        match words {
+            ["byte", byte] => Some(Fill::Byte(parse_byte(byte)?)),
+            ["pattern", bytes @ ..] if !bytes.is_empty() => bytes
+                .iter()
+                .map(|byte| parse_byte(byte))
+                .collect::<Option<_>>()
+                .map(Fill::Pattern),
+            ["random", seed] => {
+                let seed = match seed.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                    None => seed.parse().ok()?,
+                };
+                Some(Fill::Random(seed))
+            }
+            _ => None,
+        }
+    }
+
+    /// A block of padding of the given length
+    pub fn bytes(&self, count: usize) -> Vec<u8> {
+        match self {
+            Fill::Byte(byte) => vec![*byte; count],
+            Fill::Pattern(pattern) => pattern.iter().copied().cycle().take(count).collect(),
+            Fill::Random(seed) => {
+                // splitmix64, which is tiny and good enough for filler
+                let mut state = *seed;
+                (0..count)
+                    .map(|_| {
+                        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                        let mut z = state;
+                        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                        (z ^ (z >> 31)) as u8
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn parse_byte(text: &str) -> Option<u8> {
+    match parse::parse_asm_arg(text) {
+        Ok(AsmArgument::Numeric(byte)) => u8::try_from(byte).ok(),
+        _ => None,
+    }
+}
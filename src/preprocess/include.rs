@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::super::outline;
+use super::super::tokenize;
+
+/// Read an included file, with everything it declares renamed to `NAMESPACE.name` if it's included with a namespace
+pub fn load(path: &Path, namespace: Option<&str>) -> io::Result<String> {
+    let text = fs::read_to_string(path)?;
+    Ok(match namespace {
+        Some(namespace) => namespaced(&text, namespace),
+        None => text,
+    })
+}
+
+/// Prefix every use of a symbol the text declares with the namespace, so the file keeps referring to its own symbols
+/// the same way while everything else sees them as `NAMESPACE.name`. Names generated from a declaration, like a jump
+/// table's `NAME_LENGTH` or a variable's `NAME.length`, get the prefix too
+pub fn namespaced(text: &str, namespace: &str) -> String {
+    let declared: HashSet<&str> = outline::declarations(text)
+        .into_iter()
+        // virtual registers aren't symbols, they're swapped for a register before anything is declared
+        .filter(|declaration| declaration.kind != "register")
+        // local labels are renamed with the global label they're under, which gets the prefix, and anonymous labels
+        // are never referred to by name
+        .filter(|declaration| !declaration.name.starts_with(['.', '@']))
+        .map(|declaration| declaration.name)
+        .collect();
+    let is_declared = |token: &str| {
+        let name = token.trim_end_matches(':');
+        declared.contains(name)
+            || name
+                .match_indices(['.', '_'])
+                .any(|(i, _)| declared.contains(&name[..i]))
+    };
+
+    let mut namespaced = String::with_capacity(text.len());
+    for source in text.lines() {
+        let mut line = source.to_string();
+        // go backwards so inserting a prefix doesn't move the tokens that are still to come
+        for token in tokenize::tokenize_line(source).tokens.iter().rev() {
+            if is_declared(token.text) {
+                line.insert_str(token.column - 1, &format!("{namespace}."));
+            }
+        }
+        namespaced.push_str(&line);
+        namespaced.push('\n');
+    }
+    namespaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declared_names_get_the_namespace() {
+        assert_eq!(
+            namespaced(
+                "const length 4\nplay_beep:\n.loop:\nLD V0, length\nJP .loop\nJP play_beep\nJP elsewhere",
+                "sfx"
+            ),
+            "const sfx.length 4\nsfx.play_beep:\n.loop:\nLD V0, sfx.length\nJP .loop\nJP sfx.play_beep\nJP elsewhere\n"
+        );
+        // along with names generated from them
+        assert_eq!(
+            namespaced("table:\nLD V0, table_length", "sfx"),
+            "sfx.table:\nLD V0, sfx.table_length\n"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use super::super::tokenize;
+use super::local;
+
+/// The standard library bundled with the assembler, by the name it's included with
+const BUNDLED: [(&str, &str); 4] = [
+    ("std/delay", include_str!("../../std/delay.asm")),
+    ("std/keypad", include_str!("../../std/keypad.asm")),
+    ("std/print", include_str!("../../std/print.asm")),
+    ("std/random", include_str!("../../std/random.asm")),
+];
+
+/// Where a library was found
+pub enum Found {
+    File(PathBuf),
+    Bundled(&'static str),
+}
+
+/// Find a library included with `include <NAME>`: NAME or NAME.asm in each of the include paths in order, then the
+/// bundled standard library, or None if it's nowhere
+pub fn find(name: &str, paths: &[PathBuf]) -> Option<Found> {
+    for dir in paths {
+        for path in [dir.join(name), dir.join(format!("{name}.asm"))] {
+            if path.is_file() {
+                return Some(Found::File(path));
+            }
+        }
+    }
+    BUNDLED
+        .iter()
+        .find(|(bundled, _)| *bundled == name)
+        .map(|(_, text)| Found::Bundled(text))
+}
+
+/// A library waiting for the rest of the program to be swept, so only the routines it uses are placed
+pub struct Library<'a> {
+    pub text: &'a str,
+    /// What errors in it are reported as coming from
+    pub name: String,
+    /// Where files it includes are looked up
+    pub dir: PathBuf,
+    /// The line it was included on
+    pub line: usize,
+    /// The (0-indexed) line each routine starts on and the label starting it, or None for the lines before the first
+    /// label, along with whether it's been placed yet
+    routines: Vec<(usize, Option<&'a str>, bool)>,
+    /// The (0-indexed) line of every include, which goes with the lines before the first label wherever it is
+    includes: Vec<usize>,
+}
+
+impl<'a> Library<'a> {
+    /// A library split into routines, each running from a global label up to the next one
+    pub fn new(text: &'a str, name: String, dir: PathBuf, line: usize) -> Library<'a> {
+        let mut routines = vec![(0, None, false)];
+        let mut includes = Vec::new();
+        for (i, source) in text.lines().enumerate() {
+            let line = tokenize::tokenize_line(source);
+            if line.head() == Some("include") {
+                includes.push(i);
+                continue;
+            }
+            let label = match line.tokens.as_slice() {
+                [token] if line.text.ends_with(':') => token.text.trim_end_matches(':'),
+                _ => continue,
+            };
+            if local::starts_name(label) {
+                routines.push((i, Some(label), false));
+            }
+        }
+        Library {
+            text,
+            name,
+            dir,
+            line,
+            routines,
+            includes,
+        }
+    }
+
+    /// The text of the routines that have been used but not placed yet, marking them as placed, or None if there
+    /// aren't any. The lines before the first label, which declare things like constants, are always used. Every
+    /// other line is left blank, so errors still point at the line they're on
+    pub fn take_used(&mut self, used: &HashSet<&str>) -> Option<String> {
+        let mut taken = Vec::new();
+        for (i, (_, label, placed)) in self.routines.iter_mut().enumerate() {
+            if !*placed && label.is_none_or(|label| used.contains(label)) {
+                *placed = true;
+                taken.push(i);
+            }
+        }
+        if taken.is_empty() {
+            return None;
+        }
+        let ends: Vec<usize> = self
+            .routines
+            .iter()
+            .skip(1)
+            .map(|&(start, ..)| start)
+            .chain([usize::MAX])
+            .collect();
+        let kept = |line: usize| match self.includes.contains(&line) {
+            true => taken.first() == Some(&0),
+            false => taken
+                .iter()
+                .any(|&i| (self.routines[i].0..ends[i]).contains(&line)),
+        };
+        let text: Vec<&str> = self
+            .text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| if kept(i) { line } else { "" })
+            .collect();
+        Some(text.join("\n"))
+    }
+}
@@ -0,0 +1,158 @@
+use alloc::format;
+use alloc::string::String;
+
+use super::super::tokenize::Line;
+use super::Arena;
+
+/// Whether a label is local to the global label before it, like `.loop`
+pub fn is_local(label: &str) -> bool {
+    label.strip_prefix('.').is_some_and(starts_name)
+}
+
+/// The label an anonymous label line, `@@:`, declares
+pub const ANONYMOUS: &str = "@@";
+
+/// Rename every local label used in the arguments of a line, including inside expressions, to the name it was
+/// declared as under the global label the line is in, like `.loop` to `main.loop`
+pub fn localize<'a>(line: &mut Line<'a>, scope: &str, arena: &'a Arena) {
+    rewrite(line, arena, |text| scoped(text, scope));
+}
+
+/// Rename every `@b` used in the arguments of a line to the last anonymous label declared before it, and every `@f`
+/// to the next one after it, given how many have been declared so far. The nth anonymous label is declared as `@@n`
+pub fn anonymize<'a>(line: &mut Line<'a>, declared: usize, arena: &'a Arena) {
+    rewrite(line, arena, |text| anonymous(text, declared));
+}
+
+/// Replace the text of every argument of a line that's rewritten. Directives that read the rest of their line as it's
+/// written, like a path, still see what was written. The rewritten text is kept in the arena the source's other
+/// generated text is
+pub fn rewrite<'a>(
+    line: &mut Line<'a>,
+    arena: &'a Arena,
+    rewritten: impl Fn(&str) -> Option<String>,
+) {
+    for token in line.tokens.iter_mut().skip(1) {
+        if let Some(text) = rewritten(token.text) {
+            // the columns stay the same, so errors still point at what was written
+            token.text = arena.keep(text);
+        }
+    }
+}
+
+/// An argument with each `@b` and `@f` in it renamed, or None if it doesn't use any
+fn anonymous(text: &str, declared: usize) -> Option<String> {
+    let mut renamed = String::new();
+    let mut copied = 0;
+    for (i, _) in text.match_indices('@') {
+        let name = match text.get(i..i + 2) {
+            // there's no anonymous label before the first one, so that's left to fail as an unknown symbol
+            Some("@b") if declared > 0 => format!("{ANONYMOUS}{}", declared - 1),
+            Some("@f") => format!("{ANONYMOUS}{declared}"),
+            _ => continue,
+        };
+        let within_name = text[..i].chars().next_back().is_some_and(is_name_char)
+            || text[i + 2..].starts_with(is_name_char);
+        if within_name {
+            continue;
+        }
+        renamed.push_str(&text[copied..i]);
+        renamed.push_str(&name);
+        copied = i + 2;
+    }
+    if copied == 0 {
+        return None;
+    }
+    renamed.push_str(&text[copied..]);
+    Some(renamed)
+}
+
+/// An argument with each local label in it prefixed with the scope, or None if it doesn't use any
+fn scoped(text: &str, scope: &str) -> Option<String> {
+    let mut scoped = String::new();
+    let mut copied = 0;
+    let mut found = false;
+    for (i, _) in text.match_indices('.') {
+        // a dot in the middle of a name, like `NAME.length`, is part of that name
+        let after_name = text[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| is_name_char(c) || c == '.');
+        if after_name || !starts_name(&text[i + 1..]) {
+            continue;
+        }
+        scoped.push_str(&text[copied..i]);
+        scoped.push_str(scope);
+        copied = i;
+        found = true;
+    }
+    if !found {
+        return None;
+    }
+    scoped.push_str(&text[copied..]);
+    Some(scoped)
+}
+
+/// Whether text starts with something that can begin a name
+pub fn starts_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+}
+
+/// Whether a character can be part of a name
+pub fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::preprocess::{preprocess, Options, PreprocessingError};
+
+    /// The rom of a source
+    fn rom(source: &str) -> Vec<u8> {
+        crate::assemble_program(source, &Options::default())
+            .unwrap()
+            .rom
+    }
+
+    #[test]
+    fn local_labels_are_scoped_to_the_global_label_before_them() {
+        assert_eq!(
+            rom("first:\n.loop:\nJP .loop\nsecond:\n.loop:\nJP .loop\nJP first.loop"),
+            [0x12, 0x00, 0x12, 0x02, 0x12, 0x00]
+        );
+        // including inside expressions
+        assert_eq!(
+            rom("main:\nLD I, .table + 1\n.table:\ndb 1, 2"),
+            [0xA2, 0x03, 0x01, 0x02]
+        );
+        assert_eq!(
+            scoped(".row + .column", "main").as_deref(),
+            Some("main.row + main.column")
+        );
+        assert_eq!(scoped("NAME.length", "main"), None);
+    }
+
+    #[test]
+    fn local_labels_need_a_global_label() {
+        let errors =
+            preprocess(".loop:\nJP .loop", &Options::default(), &Default::default()).unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            PreprocessingError::UnscopedLocalLabel(_)
+        ));
+    }
+
+    #[test]
+    fn anonymous_labels_are_the_nearest_one_either_way() {
+        assert_eq!(
+            rom("@@:\nADD V0, 1\nSE V0, 8\nJP @b\nJP @f\nCLS\n@@:\nHALT"),
+            [0x70, 0x01, 0x30, 0x08, 0x12, 0x00, 0x12, 0x0A, 0x00, 0xE0, 0x12, 0x0A]
+        );
+        // they can be declared in every repetition of a block
+        assert_eq!(rom("rept 2\n@@:\nJP @b\nendr"), [0x12, 0x00, 0x12, 0x02]);
+        assert_eq!(anonymous("@f + 2", 3).as_deref(), Some("@@3 + 2"));
+        // there's nothing before the first
+        assert_eq!(anonymous("@b", 0), None);
+    }
+}
@@ -0,0 +1,74 @@
+use alloc::vec;
+
+use super::super::tokenize::{Line, Token};
+
+/// Every pseudo-instruction by its uppercase mnemonic, with the mnemonic it expands to and the number of registers it
+/// takes. HALT isn't expanded to a line, since it jumps to its own address
+const PSEUDO_OPS: [(&str, &str, usize); 6] = [
+    // LD V0, V0 - 8000
+    ("NOP", "LD", 0),
+    // JP to itself - 1nnn
+    ("HALT", "JP", 0),
+    // LD Vx, Vy - 8xy0
+    ("MOV", "LD", 2),
+    // LD Vx, 0 - 6x00
+    ("CLR", "LD", 1),
+    // ADD Vx, 1 - 7x01
+    ("INC", "ADD", 1),
+    // ADD Vx, 0xFF - 7xFF
+    ("DEC", "ADD", 1),
+];
+
+/// What a pseudo-instruction expands to
+pub enum Expansion<'a> {
+    /// A line of a real instruction, assembled like any other line
+    Line(Line<'a>),
+    /// A jump to the address the pseudo-instruction is placed at
+    JumpToSelf,
+}
+
+/// Every mnemonic of a pseudo-instruction, in uppercase
+pub fn mnemonics() -> impl Iterator<Item = &'static str> {
+    PSEUDO_OPS.iter().map(|&(mnemonic, ..)| mnemonic)
+}
+
+/// Whether a token is the mnemonic of a pseudo-instruction, in any case
+pub fn is_pseudo_op(token: &str) -> bool {
+    PSEUDO_OPS
+        .iter()
+        .any(|(mnemonic, ..)| mnemonic.eq_ignore_ascii_case(token))
+}
+
+/// Expand a line of a pseudo-instruction into the instruction it stands for, or None if it doesn't have the number of
+/// arguments it takes. The expansion keeps the text and columns of the line, so errors about it still point at what
+/// was written
+pub fn expand<'a>(line: &Line<'a>) -> Option<Expansion<'a>> {
+    let (mnemonic, args) = line.tokens.split_first()?;
+    let &(pseudo, op, count) = PSEUDO_OPS
+        .iter()
+        .find(|(pseudo, ..)| pseudo.eq_ignore_ascii_case(mnemonic.text))?;
+    if args.len() != count {
+        return None;
+    }
+
+    // generated arguments point at the end of the line
+    let column = line.tokens.last()?.column;
+    let generated = |text| Token { text, column };
+    let mut tokens = vec![Token {
+        text: op,
+        column: mnemonic.column,
+    }];
+    match pseudo {
+        "HALT" => return Some(Expansion::JumpToSelf),
+        "NOP" => tokens.extend([generated("V0"), generated("V0")]),
+        "MOV" => tokens.extend_from_slice(args),
+        "CLR" => tokens.extend([args[0], generated("0")]),
+        "INC" => tokens.extend([args[0], generated("1")]),
+        "DEC" => tokens.extend([args[0], generated("0xFF")]),
+        _ => unreachable!("every pseudo-instruction has an expansion"),
+    }
+    Some(Expansion::Line(Line {
+        text: line.text,
+        tokens,
+    }))
+}
@@ -0,0 +1,408 @@
+use alloc::collections::BTreeSet;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+// without std there's no random state to hash with, so the maps are ordered instead
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+#[cfg(feature = "std")]
+use super::super::assemble;
+use super::super::assemble::parse::{self, AsmArgument};
+#[cfg(feature = "std")]
+use super::super::doc;
+use super::super::tokenize::{self, Line};
+use super::{Located, PreprocessingError, PreprocessingWarning};
+
+/// The name of each register, so virtual registers can be aliased to one without allocating
+const REGISTERS: [&str; 16] = [
+    "V0", "V1", "V2", "V3", "V4", "V5", "V6", "V7", "V8", "V9", "VA", "VB", "VC", "VD", "VE", "VF",
+];
+
+/// A name that stands for a register, either picked by the user with `alias` or handed out to a `reg`
+struct Named<'a> {
+    name: &'a str,
+    /// Whether the register was handed out rather than picked
+    virtual_register: bool,
+    register: Option<usize>,
+    /// The (zero-indexed) lines the register has to hold the name's value from and to
+    start: usize,
+    end: usize,
+}
+
+/// Which register each virtual register declared with `reg` is held in, along with warnings about registers
+/// clobbered by the routines called while they're in use
+pub struct Allocation<'a> {
+    pub registers: HashMap<&'a str, &'static str>,
+    pub warnings: Vec<PreprocessingWarning>,
+}
+
+/// Hand out a register to every virtual register from the ones the source doesn't use by name. A virtual register is
+/// live from its `reg` line to the last line using it, stretched over any loop it's live at the start of, and two
+/// live at once never share a register
+pub fn allocate(source: &str) -> Result<Allocation<'_>, Located<PreprocessingError>> {
+    let text: Vec<&str> = source.lines().collect();
+    let lines: Vec<Line> = text
+        .iter()
+        .map(|line| tokenize::tokenize_line(line))
+        .collect();
+
+    // registers used by name are off limits, and so is the flag register
+    let mut free: BTreeSet<usize> = (0..0xF).collect();
+    for line in &lines {
+        let range = line
+            .tokens
+            .iter()
+            .any(|token| token.text.eq_ignore_ascii_case("[I]"));
+        for register in line.tokens.iter().filter_map(|token| register(token.text)) {
+            // `LD [I], Vx` and `LD Vx, [I]` use every register up to x
+            if range {
+                free.retain(|&r| r > register);
+            } else {
+                free.remove(&register);
+            }
+        }
+    }
+
+    let mut named: Vec<Named> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(number, line)| match line.tokens.as_slice() {
+            [head, name] if head.text == "reg" => Some(Named {
+                name: name.text,
+                virtual_register: true,
+                register: None,
+                start: number,
+                end: number,
+            }),
+            [head, name, value] if head.text == "alias" => Some(Named {
+                name: name.text,
+                virtual_register: false,
+                register: Some(register(value.text)?),
+                start: number,
+                end: number,
+            }),
+            _ => None,
+        })
+        .collect();
+    for named in &mut named {
+        named.end = (named.start..lines.len())
+            .rev()
+            .find(|&number| lines[number].tokens.iter().any(|t| t.text == named.name))
+            .unwrap_or(named.start);
+    }
+    stretch_over_loops(&lines, &mut named);
+
+    // hand out registers in the order they become live, taking them back once they're not
+    let mut order: Vec<usize> = (0..named.len())
+        .filter(|&i| named[i].virtual_register)
+        .collect();
+    order.sort_by_key(|&i| named[i].start);
+    let mut live: Vec<usize> = Vec::new();
+    for i in order {
+        let start = named[i].start;
+        live.retain(|&other| {
+            let done = named[other].end < start;
+            if done {
+                free.insert(named[other].register.expect("live registers are allocated"));
+            }
+            !done
+        });
+        let Some(register) = free.pop_first() else {
+            let live: Vec<&str> = live.iter().map(|&other| named[other].name).collect();
+            return Err(Located {
+                line: start + 1,
+                column: None,
+                origin: None,
+                error: PreprocessingError::OutOfRegisters {
+                    name: named[i].name.to_string(),
+                    live: live.join(", "),
+                },
+            });
+        };
+        named[i].register = Some(register);
+        live.push(i);
+    }
+
+    Ok(Allocation {
+        registers: named
+            .iter()
+            .filter(|named| named.virtual_register)
+            .map(|named| {
+                (
+                    named.name,
+                    REGISTERS[named.register.expect("just allocated")],
+                )
+            })
+            .collect(),
+        // what routines clobber is read from their comments the way `doc` reads them, which needs std
+        #[cfg(feature = "std")]
+        warnings: clobbered(&text, &lines, &named),
+        #[cfg(not(feature = "std"))]
+        warnings: Vec::new(),
+    })
+}
+
+/// A value that's live at the start of a loop has to survive until the jump back to the start, even if it isn't used
+/// that late
+fn stretch_over_loops(lines: &[Line], named: &mut [Named]) {
+    let labels: HashMap<&str, usize> = label_lines(lines);
+    let loops: Vec<(usize, usize)> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(number, line)| match line.tokens.as_slice() {
+            [op, target] if op.text.eq_ignore_ascii_case("JP") => {
+                let start = *labels.get(target.text)?;
+                (start < number).then_some((start, number))
+            }
+            _ => None,
+        })
+        .collect();
+
+    // stretching over one loop can make a value live at the start of an enclosing one
+    let mut stretched = true;
+    while stretched {
+        stretched = false;
+        for named in named.iter_mut() {
+            for &(start, end) in &loops {
+                if named.start <= start && named.end >= start && named.end < end {
+                    named.end = end;
+                    stretched = true;
+                }
+            }
+        }
+    }
+}
+
+/// Warnings for every call to a routine annotated as clobbering a register that holds a value still in use after
+/// the call, either as a named register that's still live or by the code after the call reading the register before
+/// setting it again
+#[cfg(feature = "std")]
+fn clobbered(text: &[&str], lines: &[Line], named: &[Named]) -> Vec<PreprocessingWarning> {
+    let clobbers: HashMap<&str, Vec<usize>> = label_lines(lines)
+        .into_iter()
+        .filter_map(|(label, number)| {
+            let registers = register_list(doc::annotation(text, number, doc::CLOBBERS)?);
+            Some((label, registers))
+        })
+        .collect();
+    let names: HashMap<&str, usize> = named
+        .iter()
+        .filter_map(|named| Some((named.name, named.register?)))
+        .collect();
+    let register_of = |token: &str| register(token).or_else(|| names.get(token).copied());
+
+    let mut warnings = Vec::new();
+    for (number, line) in lines.iter().enumerate() {
+        let (routine, registers) = match line.tokens.as_slice() {
+            [op, target] if op.text.eq_ignore_ascii_case("CALL") => {
+                match clobbers.get(target.text) {
+                    Some(registers) => (target.text, registers),
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        for named in named {
+            match named.register {
+                Some(register)
+                    if registers.contains(&register)
+                        && named.start < number
+                        && number < named.end =>
+                {
+                    warnings.push(PreprocessingWarning::ClobberedRegister {
+                        name: named.name.to_string(),
+                        register: REGISTERS[register],
+                        routine: routine.to_string(),
+                        line: number + 1,
+                    })
+                }
+                _ => (),
+            }
+        }
+
+        // follow the straight line of code after the call, until every clobbered register is set again
+        let mut pending = registers.clone();
+        for (after, line) in lines.iter().enumerate().skip(number + 1) {
+            let Some(head) = line.head() else {
+                continue;
+            };
+            if pending.is_empty() || !assemble::is_mnemonic(head) {
+                break;
+            }
+            let (reads, writes) = accesses(line, &register_of);
+            for read in reads {
+                if let Some(i) = pending.iter().position(|&r| r == read) {
+                    pending.remove(i);
+                    warnings.push(PreprocessingWarning::ReadsClobberedRegister {
+                        register: REGISTERS[read],
+                        routine: routine.to_string(),
+                        call: number + 1,
+                        line: after + 1,
+                    });
+                }
+            }
+            pending.retain(|r| !writes.contains(r));
+            // anything after a jump, return, call, or skip might not run straight after this call
+            if matches!(
+                head.to_ascii_uppercase().as_str(),
+                "JP" | "RET" | "CALL" | "SE" | "SNE" | "SKP" | "SKNP"
+            ) {
+                break;
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(feature = "std")]
+/// The registers an instruction reads, and then the ones it writes
+fn accesses(line: &Line, register_of: &impl Fn(&str) -> Option<usize>) -> (Vec<usize>, Vec<usize>) {
+    let (mut reads, mut writes) = (Vec::new(), Vec::new());
+    let Some((op, args)) = line.tokens.split_first() else {
+        return (reads, writes);
+    };
+    let registers: Vec<Option<usize>> = args.iter().map(|arg| register_of(arg.text)).collect();
+    let range = args.iter().any(|arg| arg.text.eq_ignore_ascii_case("[I]"));
+    match (op.text.to_ascii_uppercase().as_str(), registers.as_slice()) {
+        // `LD Vx, [I]` and `LD [I], Vx` go through every register up to x
+        ("LD", [Some(x), None]) if range => writes.extend(0..=*x),
+        ("LD", [None, Some(x)]) if range => reads.extend(0..=*x),
+        ("LD" | "RND", [Some(x), rest @ ..]) => {
+            reads.extend(rest.iter().flatten());
+            writes.push(*x);
+        }
+        // adding a number is the only arithmetic that leaves the flag alone
+        ("ADD", [Some(x), None]) => {
+            reads.push(*x);
+            writes.push(*x);
+        }
+        ("ADD" | "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "SHR" | "SHL", [Some(x), rest @ ..]) => {
+            reads.push(*x);
+            reads.extend(rest.iter().flatten());
+            writes.extend([*x, 0xF]);
+        }
+        ("DRW", _) => {
+            reads.extend(registers.iter().flatten());
+            writes.push(0xF);
+        }
+        _ => reads.extend(registers.iter().flatten()),
+    }
+    (reads, writes)
+}
+
+#[cfg(feature = "std")]
+/// The registers in a list like `V0-V2, VF`
+fn register_list(text: &str) -> Vec<usize> {
+    text.split([',', ' ', '\t'])
+        .filter(|item| !item.is_empty())
+        .flat_map(|item| match item.split_once('-') {
+            Some((first, last)) => match (register(first), register(last)) {
+                (Some(first), Some(last)) => (first..=last).collect(),
+                _ => Vec::new(),
+            },
+            None => register(item).into_iter().collect(),
+        })
+        .collect()
+}
+
+/// The (zero-indexed) line each label is declared on
+fn label_lines<'a>(lines: &[Line<'a>]) -> HashMap<&'a str, usize> {
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.tokens.len() == 1 && line.text.ends_with(':'))
+        .map(|(number, line)| (line.text.trim_end_matches(':'), number))
+        .collect()
+}
+
+/// The number of the register a token names, if it names one
+fn register(token: &str) -> Option<usize> {
+    match parse::parse_asm_arg(token) {
+        Ok(AsmArgument::Register(register)) => Some(register as usize),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::preprocess::Options;
+
+    /// The register each virtual register is held in
+    fn allocated(source: &str) -> Vec<(&str, &'static str)> {
+        let mut registers: Vec<_> = allocate(source).unwrap().registers.into_iter().collect();
+        registers.sort();
+        registers
+    }
+
+    #[test]
+    fn registers_used_by_name_are_not_handed_out() {
+        assert_eq!(
+            allocated("reg score\nreg lives\nLD score, 1\nLD lives, 3\nADD score, lives"),
+            [("lives", "V1"), ("score", "V0")]
+        );
+        assert_eq!(
+            allocated("LD V0, 1\nreg score\nLD score, V0"),
+            [("score", "V1")]
+        );
+        // nor the ones a range load reaches
+        assert_eq!(
+            allocated("LD V2, [I]\nreg score\nLD score, 1"),
+            [("score", "V3")]
+        );
+        assert_eq!(
+            crate::assemble_program("reg score\nLD score, 5\nADD score, 1", &Options::default())
+                .unwrap()
+                .rom,
+            [0x60, 0x05, 0x70, 0x01]
+        );
+    }
+
+    #[test]
+    fn registers_are_shared_once_they_are_not_live() {
+        assert_eq!(
+            allocated("reg first\nLD first, 1\nreg second\nLD second, 2"),
+            [("first", "V0"), ("second", "V0")]
+        );
+        // unless the first is still needed by the next time round a loop
+        assert_eq!(
+            allocated(
+                "reg first\nLD first, 1\nloop:\nADD first, 1\nreg second\nLD second, 2\nJP loop"
+            ),
+            [("first", "V0"), ("second", "V1")]
+        );
+    }
+
+    #[test]
+    fn more_live_registers_than_there_are_is_an_error() {
+        let mut source: String = (0..16).map(|i| format!("reg r{i}\n")).collect();
+        source += &(0..16)
+            .map(|i| format!("LD r{i}, {i}\n"))
+            .collect::<String>();
+        let error = allocate(&source).err().unwrap();
+        assert_eq!(error.line, 16);
+        assert!(matches!(
+            error.error,
+            PreprocessingError::OutOfRegisters { ref name, .. } if name == "r15"
+        ));
+    }
+
+    #[test]
+    fn calls_that_clobber_live_registers_are_warned_about() {
+        let source =
+            "reg score\nLD score, 1\nCALL draw\nADD score, 1\nHALT\n; @clobbers V0\ndraw:\nRET";
+        let warnings = allocate(source).unwrap().warnings;
+        assert!(matches!(
+            &warnings[..],
+            [
+                PreprocessingWarning::ClobberedRegister { name, register: "V0", line: 3, .. },
+                PreprocessingWarning::ReadsClobberedRegister { register: "V0", call: 3, line: 4, .. },
+            ] if name == "score"
+        ));
+        // setting the register again after the call is fine
+        let source = "LD V1, 1\nCALL draw\nLD V1, 2\nADD V1, 1\nHALT\n; @clobbers V1\ndraw:\nRET";
+        assert!(allocate(source).unwrap().warnings.is_empty());
+    }
+}
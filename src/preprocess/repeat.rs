@@ -0,0 +1,125 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::super::tokenize::Line;
+use super::{local, Arena};
+
+/// What starts and ends a repeated block
+pub const STARTS: [&str; 3] = ["rept", ".rept", "repeat"];
+pub const ENDS: [&str; 2] = ["endr", ".endr"];
+
+/// Whether a line starts a repeated block
+pub fn starts(line: &Line) -> bool {
+    line.head().is_some_and(|head| STARTS.contains(&head))
+}
+
+/// Take the lines of a repeated block up to its `endr`, skipping over the `endr` of any block repeated inside it, or
+/// None if it's never closed
+pub fn take<'a>(
+    lines: &mut impl Iterator<Item = (usize, Line<'a>)>,
+) -> Option<Vec<(usize, Line<'a>)>> {
+    let mut rows = Vec::new();
+    let mut depth = 0;
+    loop {
+        let (number, row) = lines.next()?;
+        if starts(&row) {
+            depth += 1;
+        } else if row.head().is_some_and(|head| ENDS.contains(&head)) {
+            if depth == 0 {
+                return Some(rows);
+            }
+            depth -= 1;
+        }
+        rows.push((number, row));
+    }
+}
+
+/// Replace every use of the name of a repetition's index in the arguments of a line with the index
+pub fn index<'a>(line: &mut Line<'a>, name: &str, index: usize, arena: &'a Arena) {
+    local::rewrite(line, arena, |text| replaced(text, name, index));
+}
+
+/// An argument with every use of a name in it replaced with a number, or None if it doesn't use it
+fn replaced(text: &str, name: &str, value: usize) -> Option<String> {
+    let mut replaced = String::new();
+    let mut copied = 0;
+    for (i, _) in text.match_indices(name) {
+        let end = i + name.len();
+        // only whole names count, not part of a longer one like `NAME.length`
+        let within_name = text[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| local::is_name_char(c) || c == '.')
+            || text[end..].starts_with(|c| local::is_name_char(c) || c == '.');
+        if within_name {
+            continue;
+        }
+        replaced.push_str(&text[copied..i]);
+        replaced.push_str(&value.to_string());
+        copied = end;
+    }
+    if copied == 0 {
+        return None;
+    }
+    replaced.push_str(&text[copied..]);
+    Some(replaced)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::preprocess::{preprocess, Options, PreprocessingError};
+    use crate::tokenize;
+
+    /// The rom of a source
+    fn rom(source: &str) -> Vec<u8> {
+        crate::assemble_program(source, &Options::default())
+            .unwrap()
+            .rom
+    }
+
+    #[test]
+    fn blocks_are_repeated() {
+        assert_eq!(
+            rom("rept 3\nCLS\nendr\nHALT"),
+            [0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0, 0x12, 0x06]
+        );
+        assert_eq!(
+            rom(".rept 2\ndb 0xFF\n.endr\nrepeat 0\nCLS\nendr"),
+            [0xFF, 0xFF]
+        );
+        // the count can be a constant
+        assert_eq!(rom("const rows 2\nrept rows\ndb 0x81\nendr"), [0x81, 0x81]);
+    }
+
+    #[test]
+    fn the_index_is_replaced_in_each_repetition() {
+        assert_eq!(
+            rom("rept 3, i\nLD V0, i * 2\nendr"),
+            [0x60, 0x00, 0x60, 0x02, 0x60, 0x04]
+        );
+        assert_eq!(
+            rom("rept 2, row\nrept 2, column\ndb row * 2 + column\nendr\nendr"),
+            [0x00, 0x01, 0x02, 0x03]
+        );
+        // only where it's the whole name
+        assert_eq!(replaced("i + width", "i", 3).as_deref(), Some("3 + width"));
+        assert_eq!(replaced("i.length", "i", 3), None);
+    }
+
+    #[test]
+    fn blocks_run_until_their_own_end() {
+        let text = "CLS\nrept 2\nRET\nendr\nendr\nHALT";
+        let mut lines = text.lines().map(tokenize::tokenize_line).enumerate();
+        let rows = take(&mut lines).unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(lines.next().map(|(number, _)| number), Some(5));
+
+        let errors =
+            preprocess("rept 2\nCLS", &Options::default(), &Default::default()).unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            PreprocessingError::UnclosedRepeat(_)
+        ));
+    }
+}
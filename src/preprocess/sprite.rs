@@ -0,0 +1,191 @@
+#[cfg(feature = "std")]
+use alloc::format;
+#[cfg(feature = "std")]
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use super::super::bitmap::Bitmap;
+
+/// The rows of a sprite, kept around so later sprites can be declared as transformations of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sprite {
+    /// 8 for regular sprites or 16 for Super-CHIP sprites
+    pub width: u32,
+    /// 1 for monochrome sprites or 2 for XO-CHIP sprites drawn to both planes at once
+    pub planes: u32,
+    /// Each row's pixels, with the leftmost pixel in the most significant of the low `width` bits.
+    /// Every row of the first plane comes before every row of the second, the way XO-CHIP reads them
+    pub rows: Vec<u16>,
+}
+
+impl Sprite {
+    /// Convert an image into a sprite, or None if it's the wrong size for one
+    #[cfg(feature = "std")]
+    pub fn from_image(image: &Bitmap) -> Option<Sprite> {
+        let max_height = match image.width {
+            8 => 15,
+            16 => 16,
+            _ => return None,
+        };
+        if image.height > max_height {
+            return None;
+        }
+
+        let rows = (0..image.height)
+            .map(|y| {
+                // the leftmost pixel is the most significant bit
+                (0..image.width).fold(0, |bits, x| {
+                    if image.get(x, y) {
+                        bits | 1 << (image.width - 1 - x)
+                    } else {
+                        bits
+                    }
+                })
+            })
+            .collect();
+        Some(Sprite {
+            width: image.width as u32,
+            planes: 1,
+            rows,
+        })
+    }
+
+    /// How many rows tall each plane of the sprite is
+    pub fn height(&self) -> usize {
+        self.rows.len() / self.planes as usize
+    }
+
+    /// The rows of each plane in turn
+    pub fn planes(&self) -> impl Iterator<Item = &[u16]> {
+        // chunks panics on 0, and an empty sprite has no rows in any plane anyway
+        self.rows.chunks(self.height().max(1))
+    }
+
+    /// The bytes making up the sprite in memory
+    pub fn bytes(&self) -> Vec<u8> {
+        match self.width {
+            8 => self.rows.iter().map(|&row| row as u8).collect(),
+            _ => self.rows.iter().flat_map(|row| row.to_be_bytes()).collect(),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    /// A row as its value alongside pixel art of it, for reviewing the art without running the rom
+    pub fn preview(&self, row: u16) -> String {
+        let art: String = (0..self.width)
+            .rev()
+            .map(|bit| if row >> bit & 1 == 1 { 'X' } else { '.' })
+            .collect();
+        format!(
+            "{row:#0width$X}  {art}",
+            width = self.width as usize / 4 + 2
+        )
+    }
+
+    /// Mask for the bits of a row that are pixels
+    fn mask(&self) -> u16 {
+        (u32::MAX >> (32 - self.width)) as u16
+    }
+
+    /// The same sprite with different rows
+    fn with_rows(&self, rows: Vec<u16>) -> Sprite {
+        Sprite {
+            width: self.width,
+            planes: self.planes,
+            rows,
+        }
+    }
+
+    /// The sprite reflected left to right
+    pub fn mirror(&self) -> Sprite {
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| row.reverse_bits() >> (16 - self.width))
+            .collect();
+        self.with_rows(rows)
+    }
+
+    /// The sprite reflected top to bottom, each plane on its own
+    pub fn flip(&self) -> Sprite {
+        let rows = self
+            .planes()
+            .flat_map(|plane| plane.iter().rev().copied())
+            .collect();
+        self.with_rows(rows)
+    }
+
+    /// The sprite with every pixel toggled
+    pub fn invert(&self) -> Sprite {
+        self.with_rows(self.rows.iter().map(|row| !row & self.mask()).collect())
+    }
+
+    /// The sprite moved right by a number of pixels, or left if negative. Pixels moved off the
+    /// edge are lost
+    pub fn shift(&self, by: i32) -> Sprite {
+        let rows = self
+            .rows
+            .iter()
+            .map(|&row| match by {
+                by if by.unsigned_abs() >= self.width => 0,
+                by if by >= 0 => row >> by,
+                by => (row << -by) & self.mask(),
+            })
+            .collect();
+        self.with_rows(rows)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::preprocess::{preprocess, Options, PreprocessingError};
+
+    #[test]
+    fn sprites_are_declared_as_transformations() {
+        let source = "sprite right\nXX......\nX.......\nendsprite\nsprite left mirror right\nsprite down flip right\n\
+                      sprite dark invert right\nsprite moved shift right 2\nsprite back shift right -1";
+        assert_eq!(
+            crate::assemble_program(source, &Options::default())
+                .unwrap()
+                .rom,
+            [0xC0, 0x80, 0x03, 0x01, 0x80, 0xC0, 0x3F, 0x7F, 0x30, 0x20, 0x80, 0x00]
+        );
+
+        let arena = Default::default();
+        for (source, unknown) in [
+            ("sprite left mirror right", true),
+            ("sprite right\nX\nendsprite\nsprite left shift right", false),
+        ] {
+            let errors = preprocess(source, &Options::default(), &arena).unwrap_err();
+            assert_eq!(
+                matches!(errors[0].error, PreprocessingError::UnknownSprite(_)),
+                unknown,
+                "`{source}`: {}",
+                errors[0].error
+            );
+        }
+    }
+
+    #[test]
+    fn wide_sprites_and_planes_are_transformed_whole() {
+        let wide = Sprite {
+            width: 16,
+            planes: 1,
+            rows: vec![0x8001, 0xF000],
+        };
+        assert_eq!(wide.mirror().rows, [0x8001, 0x000F]);
+        assert_eq!(wide.invert().rows, [0x7FFE, 0x0FFF]);
+        assert_eq!(wide.shift(-4).rows, [0x0010, 0x0000]);
+        assert_eq!(wide.shift(16).rows, [0, 0]);
+
+        // each plane is flipped on its own
+        let planes = Sprite {
+            width: 8,
+            planes: 2,
+            rows: vec![1, 2, 3, 4],
+        };
+        assert_eq!(planes.flip().rows, [2, 1, 4, 3]);
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Every kind of warning, and whether it's reported without asking for it with -W
+const KINDS: [(&str, bool); 7] = [
+    ("padded-sprite", true),
+    ("clobbered-register", true),
+    ("reads-clobbered-register", true),
+    ("unused-label", false),
+    ("unbalanced-call", true),
+    ("falls-into-data", true),
+    ("jumps-into-data", true),
+];
+
+/// One -W flag: `all`, the kind of warning to report, or `no-` and the kind to leave out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarningFlag {
+    /// None for `all`
+    kind: Option<&'static str>,
+    enabled: bool,
+}
+
+impl WarningFlag {
+    /// Parse a flag as given after -W, like `all` or `no-unused-label`, listing the kinds of warnings there are if it
+    /// doesn't name one
+    pub fn parse(text: &str) -> Result<WarningFlag, String> {
+        let (name, enabled) = match text.strip_prefix("no-") {
+            Some(name) => (name, false),
+            None => (text, true),
+        };
+        let kind = match name {
+            "all" => None,
+            _ => match KINDS.iter().find(|(kind, _)| *kind == name) {
+                Some((kind, _)) => Some(*kind),
+                None => {
+                    let kinds: Vec<&str> = KINDS.iter().map(|(kind, _)| *kind).collect();
+                    return Err(format!(
+                        "expected `all` or one of {}, optionally after `no-`",
+                        kinds.join(", ")
+                    ));
+                }
+            },
+        };
+        Ok(WarningFlag { kind, enabled })
+    }
+}
+
+/// Which kinds of warnings are reported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warnings(Vec<&'static str>);
+
+impl Default for Warnings {
+    fn default() -> Warnings {
+        Warnings(
+            KINDS
+                .iter()
+                .filter(|(_, default)| *default)
+                .map(|(kind, _)| *kind)
+                .collect(),
+        )
+    }
+}
+
+impl Warnings {
+    /// The kinds reported by default with the flags applied in order, so a later flag wins over an earlier one
+    pub fn from_flags(flags: &[WarningFlag]) -> Warnings {
+        let mut warnings = Warnings::default();
+        for flag in flags {
+            let kinds = KINDS
+                .iter()
+                .map(|(kind, _)| *kind)
+                .filter(|kind| flag.kind.is_none_or(|flagged| flagged == *kind));
+            for kind in kinds {
+                warnings.0.retain(|enabled| *enabled != kind);
+                if flag.enabled {
+                    warnings.0.push(kind);
+                }
+            }
+        }
+        warnings
+    }
+
+    pub fn contains(&self, kind: &str) -> bool {
+        self.0.contains(&kind)
+    }
+}
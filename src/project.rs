@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use thiserror::Error;
+use toml::{Table, Value};
+
+use super::optimize::{self, Optimization, Rule};
+use super::preprocess::{self, Target};
+use super::{input, plugins, plural, RunError};
+
+/// The project file, looked up next to the source or in the directory `build` is run from
+pub const PROJECT_FILE: &str = "ch8asm.toml";
+
+/// Where roms are written unless the project says otherwise, with a directory for each profile inside
+const DEFAULT_OUT: &str = "out";
+
+/// The profile built unless another is asked for
+const DEFAULT_PROFILE: &str = "debug";
+
+/// A problem with the project file, or with building what it describes
+#[derive(Debug, Error)]
+pub enum ProjectError {
+    #[error("there's no {PROJECT_FILE} in the current directory to build")]
+    NoProject,
+    #[error("couldn't read {}", .0.display())]
+    Unreadable(PathBuf, #[source] io::Error),
+    #[error("{} isn't valid TOML: {}", .0.display(), .1)]
+    InvalidToml(PathBuf, #[source] toml::de::Error),
+    #[error("{}: `{key}` {expected}", path.display())]
+    InvalidKey {
+        path: PathBuf,
+        key: String,
+        expected: &'static str,
+    },
+    #[error("{} doesn't list any sources to build under [build]", .0.display())]
+    NoSources(PathBuf),
+    #[error("{} doesn't have a [profile.{profile}] table", path.display())]
+    UnknownProfile { path: PathBuf, profile: String },
+    #[error("{}: {error}", source_path.display())]
+    Source {
+        source_path: PathBuf,
+        #[source]
+        error: Box<RunError>,
+    },
+    #[error("{}: {} denied by [lints]", source_path.display(), plural(*count, "warning"))]
+    Denied { source_path: PathBuf, count: usize },
+}
+
+/// What to do about a kind of warning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// Everything that decides how the sources of a project are built, after the profile is applied
+struct Settings {
+    sources: Vec<PathBuf>,
+    out: PathBuf,
+    target: Target,
+    optimizations: Vec<Optimization>,
+    disabled_rules: Vec<Rule>,
+    dedup_sprites: bool,
+    defines: Vec<(String, usize)>,
+    lints: HashMap<String, Level>,
+}
+
+/// Read the project file in a directory, or None if there isn't one
+pub fn read(dir: &Path) -> Result<Option<Table>, ProjectError> {
+    let path = dir.join(PROJECT_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ProjectError::Unreadable(path, e)),
+    };
+    text.parse()
+        .map(Some)
+        .map_err(|e| ProjectError::InvalidToml(path, e))
+}
+
+/// Build every source listed in the project file in the current directory with a profile, writing a rom for each to
+/// `OUT/PROFILE/NAME.ch8`
+pub fn build(profile: Option<&str>) -> Result<(), RunError> {
+    let dir = PathBuf::new();
+    let path = dir.join(PROJECT_FILE);
+    let manifest = read(&dir)?.ok_or(ProjectError::NoProject)?;
+    let profile = profile.unwrap_or(DEFAULT_PROFILE);
+    let settings = Settings::from_manifest(&manifest, &path, profile)?;
+    let directives = plugins::load(&dir)?;
+
+    let out = settings.out.join(profile);
+    fs::create_dir_all(&out)?;
+    for source_path in &settings.sources {
+        let within = |error: RunError| ProjectError::Source {
+            source_path: source_path.clone(),
+            error: Box::new(error),
+        };
+        let source = input::read_source(source_path).map_err(|e| within(e.into()))?;
+        let options = preprocess::Options {
+            directives: directives.clone(),
+            imports: settings.defines.clone(),
+            target: settings.target,
+            dedup_sprites: settings.dedup_sprites,
+            ..super::options_for(source_path)
+        };
+        let mut program = super::assemble_program(&source, &options).map_err(within)?;
+        if !settings.optimizations.is_empty() {
+            program = optimize::optimize(
+                &source,
+                program,
+                &options,
+                &settings.optimizations,
+                &settings.disabled_rules,
+            )
+            .map_err(within)?;
+        }
+
+        let mut denied = 0;
+        for warning in &program.warnings {
+            match settings.lints.get(warning.kind()).copied() {
+                Some(Level::Allow) => (),
+                Some(Level::Deny) => {
+                    denied += 1;
+                    eprintln!(
+                        "ERROR: {}: line {}: {warning}",
+                        source_path.display(),
+                        warning.line()
+                    );
+                }
+                Some(Level::Warn) | None => eprintln!(
+                    "WARNING: {}: line {}: {warning}",
+                    source_path.display(),
+                    warning.line()
+                ),
+            }
+        }
+        if denied > 0 {
+            return Err(ProjectError::Denied {
+                source_path: source_path.clone(),
+                count: denied,
+            }
+            .into());
+        }
+
+        let stem = source_path.file_stem().unwrap_or_default();
+        let rom = out.join(stem).with_extension("ch8");
+        program.write_rom(fs::File::create(&rom)?, Default::default())?;
+        eprintln!("built {} ({} bytes)", rom.display(), program.rom.len());
+    }
+    Ok(())
+}
+
+impl Settings {
+    /// Read the `[build]` table, with the keys of the `[profile.NAME]` table laid over it. `defines` and `lints` are
+    /// merged rather than replaced, so a profile only needs to list what it changes
+    fn from_manifest(
+        manifest: &Table,
+        path: &Path,
+        profile: &str,
+    ) -> Result<Settings, ProjectError> {
+        let invalid = |key: &str, expected| ProjectError::InvalidKey {
+            path: path.to_path_buf(),
+            key: key.to_string(),
+            expected,
+        };
+        let empty = Table::new();
+        let table =
+            |value, key| table_or(value, &empty).ok_or_else(|| invalid(key, "should be a table"));
+        let build = table(manifest.get("build"), "build")?;
+        let profiles = table(manifest.get("profile"), "profile")?;
+        let overrides = match profiles.get(profile) {
+            Some(_) => table(profiles.get(profile), "profile")?,
+            // the default profile doesn't need a table of its own
+            None if profile == DEFAULT_PROFILE => &empty,
+            None => {
+                return Err(ProjectError::UnknownProfile {
+                    path: path.to_path_buf(),
+                    profile: profile.to_string(),
+                })
+            }
+        };
+        let get = |key: &str| overrides.get(key).or_else(|| build.get(key));
+
+        let strings = |key: &str| -> Result<Vec<&str>, ProjectError> {
+            match get(key) {
+                Some(Value::Array(values)) => values
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .ok_or_else(|| invalid(key, "should be a list of strings"))
+                    })
+                    .collect(),
+                Some(_) => Err(invalid(key, "should be a list of strings")),
+                None => Ok(Vec::new()),
+            }
+        };
+
+        let sources: Vec<PathBuf> = strings("sources")?.into_iter().map(PathBuf::from).collect();
+        if sources.is_empty() {
+            return Err(ProjectError::NoSources(path.to_path_buf()));
+        }
+        let out = match get("out") {
+            Some(Value::String(out)) => PathBuf::from(out),
+            Some(_) => return Err(invalid("out", "should be a path")),
+            None => PathBuf::from(DEFAULT_OUT),
+        };
+        let target = match get("target") {
+            Some(Value::String(target)) => Target::from_str(target, true)
+                .map_err(|_| invalid("target", "should be chip8, schip, or xochip"))?,
+            Some(_) => return Err(invalid("target", "should be chip8, schip, or xochip")),
+            None => Target::default(),
+        };
+        let optimizations = strings("optimize")?
+            .into_iter()
+            .map(|name| Optimization::from_str(name, true))
+            .collect::<Result<_, _>>()
+            .map_err(|_| {
+                invalid(
+                    "optimize",
+                    "should list optimizations, like remove-unreachable",
+                )
+            })?;
+        let disabled_rules = strings("disable-rules")?
+            .into_iter()
+            .map(|name| Rule::from_str(name, true))
+            .collect::<Result<_, _>>()
+            .map_err(|_| {
+                invalid(
+                    "disable-rules",
+                    "should list peephole rules, like jump-to-next",
+                )
+            })?;
+        let dedup_sprites = match get("dedup-sprites") {
+            Some(Value::Boolean(dedup)) => *dedup,
+            Some(_) => return Err(invalid("dedup-sprites", "should be true or false")),
+            None => false,
+        };
+
+        let mut defines = HashMap::new();
+        for key_table in [build.get("defines"), overrides.get("defines")] {
+            for (name, value) in table(key_table, "defines")? {
+                let value = value
+                    .as_integer()
+                    .and_then(|value| usize::try_from(value).ok())
+                    .ok_or_else(|| {
+                        invalid("defines", "should map names to non-negative numbers")
+                    })?;
+                defines.insert(name.clone(), value);
+            }
+        }
+        let mut lints = HashMap::new();
+        for key_table in [manifest.get("lints"), overrides.get("lints")] {
+            for (kind, level) in table(key_table, "lints")? {
+                let level = level
+                    .as_str()
+                    .and_then(|level| Level::from_str(level, true).ok())
+                    .ok_or_else(|| {
+                        invalid("lints", "should map warnings to allow, warn, or deny")
+                    })?;
+                lints.insert(kind.clone(), level);
+            }
+        }
+
+        Ok(Settings {
+            sources,
+            out,
+            target,
+            optimizations,
+            disabled_rules,
+            dedup_sprites,
+            defines: defines.into_iter().collect(),
+            lints,
+        })
+    }
+}
+
+/// A value that should be a table, with a missing one taken to be empty, or None if it's something else
+fn table_or<'t>(value: Option<&'t Value>, empty: &'t Table) -> Option<&'t Table> {
+    match value {
+        Some(Value::Table(table)) => Some(table),
+        Some(_) => None,
+        None => Some(empty),
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+/// An error encountered while writing the contents of the display to an image
+#[derive(Debug, Error)]
+pub enum ScreenDumpError {
+    #[error("failed to write screen dump")]
+    Io(
+        #[from]
+        #[source]
+        io::Error,
+    ),
+    #[error("unsupported screen dump format `{0}`; use .pbm or .png")]
+    UnsupportedFormat(String),
+    #[cfg(not(feature = "images"))]
+    #[error("png screen dumps require ch8asm to be built with the `images` feature")]
+    PngUnavailable,
+    #[cfg(feature = "images")]
+    #[error("failed to encode png screen dump")]
+    Png(
+        #[from]
+        #[source]
+        png::EncodingError,
+    ),
+}
+
+/// Write the display to an image, picking the format from the file extension
+/// PBM is always available, while PNG needs the `images` feature
+pub fn dump<R: AsRef<[bool]>>(display: &[R], path: &Path) -> Result<(), ScreenDumpError> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "pbm" => Ok(fs::write(path, to_pbm(display))?),
+        "png" => write_png(display, path),
+        other => Err(ScreenDumpError::UnsupportedFormat(other.to_string())),
+    }
+}
+
+/// Render the display as a plain (ascii) PBM, which diffs nicely when checked into a repository
+fn to_pbm<R: AsRef<[bool]>>(display: &[R]) -> String {
+    let width = display.first().map(|row| row.as_ref().len()).unwrap_or(0);
+    let mut out = format!("P1\n{width} {}\n", display.len());
+    for row in display {
+        let row = row
+            .as_ref()
+            .iter()
+            .map(|&on| if on { "1" } else { "0" })
+            .collect::<Vec<&str>>();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(feature = "images")]
+fn write_png<R: AsRef<[bool]>>(display: &[R], path: &Path) -> Result<(), ScreenDumpError> {
+    let width = display.first().map(|row| row.as_ref().len()).unwrap_or(0);
+    let mut encoder = png::Encoder::new(
+        io::BufWriter::new(fs::File::create(path)?),
+        width as u32,
+        display.len() as u32,
+    );
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let pixels = display
+        .iter()
+        .flat_map(|row| row.as_ref().iter().map(|&on| if on { 0xFF } else { 0x00 }))
+        .collect::<Vec<u8>>();
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "images"))]
+fn write_png<R: AsRef<[bool]>>(_display: &[R], _path: &Path) -> Result<(), ScreenDumpError> {
+    Err(ScreenDumpError::PngUnavailable)
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::emulator::MEMORY_SIZE;
+use super::input;
+use super::outline;
+use super::preprocess::{self, Preprocessed};
+use super::RunError;
+
+/// What the code before the first label is called
+const START: &str = "<start>";
+
+/// A stretch of the program or its variables that's counted as one
+struct Region<'a> {
+    name: String,
+    kind: &'a str,
+    size: usize,
+}
+
+/// Break down the bytes a program takes up by the label, sprite, or other block each one belongs to, along with each
+/// variable, biggest first, to show what's worth shrinking when the rom doesn't fit
+pub fn run(input: &Path, target: preprocess::Target) -> Result<(), RunError> {
+    let source = input::read_source(input)?;
+    let options = preprocess::Options {
+        target,
+        ..super::options_for(input)
+    };
+    let arena = preprocess::Arena::default();
+    let Preprocessed {
+        instructions,
+        symbols,
+        size,
+        free_memory,
+        ..
+    } = preprocess::preprocess(&source, &options, &arena)?;
+    let start = options.base;
+    let code_end = start + size;
+
+    let declarations = outline::declarations(&source);
+    let kinds: HashMap<&str, &str> = declarations
+        .iter()
+        .map(|declaration| (declaration.name, declaration.kind))
+        .collect();
+    let mut labels: Vec<(usize, &str)> = symbols
+        .labels()
+        .map(|(name, addr)| (addr, name))
+        // a jump table's table of jumps is counted as part of the jump table
+        .filter(|(_, name)| {
+            name.strip_suffix("_TABLE")
+                .is_none_or(|table| kinds.get(table) != Some(&"jumptable"))
+        })
+        .collect();
+    labels.sort();
+
+    let mut regions = Vec::new();
+    let code: Vec<(usize, &str)> = labels
+        .iter()
+        .copied()
+        .filter(|&(addr, _)| addr < code_end)
+        .collect();
+    if code.first().is_none_or(|&(addr, _)| addr > start) {
+        let end = code.first().map_or(code_end, |&(addr, _)| addr);
+        regions.push(Region {
+            name: START.to_string(),
+            kind: "code",
+            size: end - start,
+        });
+    }
+    for (i, &(addr, name)) in code.iter().enumerate() {
+        let end = code.get(i + 1).map_or(code_end, |&(next, _)| next);
+        let kind = match kinds.get(name) {
+            Some(&"label") | None => "code",
+            Some(kind) => kind,
+        };
+        // a sprite or other block can be followed by code without a label of its own, which is counted separately
+        // so it isn't mistaken for part of the block
+        let block_end = match kind {
+            "code" => end,
+            _ => {
+                let line = instructions
+                    .iter()
+                    .find(|instruction| instruction.addr() == addr)
+                    .map(|instruction| instruction.line());
+                instructions
+                    .iter()
+                    .find(|instruction| {
+                        (addr..end).contains(&instruction.addr())
+                            && Some(instruction.line()) != line
+                    })
+                    .map_or(end, |instruction| instruction.addr())
+            }
+        };
+        regions.push(Region {
+            name: name.to_string(),
+            kind,
+            size: block_end - addr,
+        });
+        regions.push(Region {
+            name: format!("<after {name}>"),
+            kind: "code",
+            size: end - block_end,
+        });
+    }
+    let vars: Vec<(usize, &str)> = labels
+        .iter()
+        .copied()
+        .filter(|&(addr, _)| addr >= code_end)
+        .collect();
+    for (i, &(addr, name)) in vars.iter().enumerate() {
+        let end = vars.get(i + 1).map_or(free_memory, |&(next, _)| next);
+        regions.push(Region {
+            name: name.to_string(),
+            kind: "variable",
+            size: end - addr,
+        });
+    }
+    regions.retain(|region| region.size > 0);
+    regions.sort_by(|a, b| b.size.cmp(&a.size).then(a.name.cmp(&b.name)));
+
+    let mut out = io::stdout().lock();
+    let available = MEMORY_SIZE - start;
+    let used = free_memory - start;
+    writeln!(
+        out,
+        "rom: {size} bytes, variables: {} bytes, free: {} of {available} bytes",
+        free_memory - code_end,
+        available - used
+    )?;
+    let width = regions
+        .first()
+        .map_or(1, |region| region.size.to_string().len());
+    for region in &regions {
+        writeln!(
+            out,
+            "{:>width$}  {:>5.1}%  {} ({})",
+            region.size,
+            region.size as f64 * 100.0 / used as f64,
+            region.name,
+            region.kind
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use thiserror::Error;
+
+use super::assemble::Buffers;
+use super::preprocess::{self, Arena, Located, Options};
+
+/// Why a snippet couldn't be assembled by [`assemble_snippet`]
+#[derive(Error, Debug)]
+#[error("line {line}: {message}")]
+pub struct SnippetError {
+    /// The (1-indexed) line of the snippet the error was found on
+    pub line: usize,
+    pub message: String,
+}
+
+impl<E: core::error::Error> From<&Located<E>> for SnippetError {
+    fn from(located: &Located<E>) -> SnippetError {
+        SnippetError {
+            line: located.line,
+            message: located.error.to_string(),
+        }
+    }
+}
+
+/// Assemble a snippet into the bytes of a rom loaded at `base`, with the same preprocessor and encoder as
+/// [`assemble`](crate::assemble). Unlike it, this doesn't need `std`, so it works on the device running the rom, but
+/// the directives that read files or run commands, like `include`, `incbin`, `module`, sprites from images, and
+/// external directives, aren't understood
+pub fn assemble_snippet(source: &str, base: usize) -> Result<Vec<u8>, SnippetError> {
+    let options = Options {
+        base,
+        ..Options::default()
+    };
+    let arena = Arena::default();
+    // errors are in the order of their lines, so the first is the first in the snippet
+    let preprocessed = preprocess::preprocess(source, &options, &arena)
+        .map_err(|errors| SnippetError::from(&errors[0]))?;
+
+    let mut rom = Vec::with_capacity(preprocessed.size);
+    let mut buffers = Buffers::default();
+    for instruction in &preprocessed.instructions {
+        super::encode_instruction(instruction, &preprocessed.symbols, &mut buffers, &mut rom)
+            .map_err(|located| SnippetError::from(&located))?;
+    }
+    Ok(rom)
+}
@@ -0,0 +1,51 @@
+use std::io::{self, Write};
+
+use serde_json::json;
+
+use super::preprocess::Origin;
+use super::Program;
+
+/// Where the bytes at an address came from
+#[derive(Debug)]
+pub struct Mapping {
+    pub addr: usize,
+    pub size: usize,
+    /// Whether it's instructions, rather than sprites or data
+    pub code: bool,
+    /// The (1-indexed) line of the source, which for anything included is the line it was included on
+    pub line: usize,
+    /// Where in an included file or library it came from, if it did
+    pub origin: Option<Origin>,
+}
+
+/// Write a JSON source map of a program: the address and size of everything in the rom, along with the file and line
+/// it came from after includes were followed but before aliases were substituted, so a debugger can step through the
+/// source. `source` is the name of the main file, which anything not included comes from
+pub fn write(program: &Program, source: &str, mut out: impl Write) -> io::Result<()> {
+    let mappings: Vec<_> = program
+        .mappings
+        .iter()
+        .map(|mapping| {
+            let (file, line) = match &mapping.origin {
+                Some(origin) => (&*origin.file, origin.line),
+                None => (source, mapping.line),
+            };
+            json!({
+                "address": mapping.addr,
+                "size": mapping.size,
+                "kind": if mapping.code { "code" } else { "data" },
+                "file": file,
+                "line": line,
+                "included_from": mapping.origin.as_ref().map(|_| mapping.line),
+            })
+        })
+        .collect();
+    let map = json!({
+        "version": 1,
+        "source": source,
+        "base": program.base,
+        "mappings": mappings,
+    });
+    let json = serde_json::to_string_pretty(&map).map_err(io::Error::from)?;
+    writeln!(out, "{json}")
+}
@@ -0,0 +1,101 @@
+use std::io::Write;
+
+use super::assemble::{self, expr};
+use super::preprocess::{self, InstructionText, Preprocessed};
+use super::symbols::SymbolTable;
+use super::tokenize::Line;
+use super::RunError;
+
+/// How many bytes of a data block are written per line
+const BYTES_PER_LINE: usize = 8;
+
+/// A point in the pipeline the instruction list can be dumped at
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// As the preprocessor leaves it, with every instruction placed and sprites, text, and tables turned into bytes
+    Preprocessed,
+    /// With aliases and virtual registers swapped for what they stand for
+    AfterAliases,
+    /// With every label, constant, and `#n` offset swapped for its value and every expression evaluated, which is what
+    /// gets encoded
+    AfterLabels,
+}
+
+/// Write the address, source line, and text of every instruction as it stands after a stage
+pub fn write(
+    source: &str,
+    options: &preprocess::Options,
+    stage: Stage,
+    mut out: impl Write,
+) -> Result<(), RunError> {
+    let arena = preprocess::Arena::default();
+    let Preprocessed {
+        instructions,
+        symbols,
+        ..
+    } = preprocess::preprocess(source, options, &arena)?;
+    for instruction in &instructions {
+        let (addr, line) = (instruction.addr(), instruction.line());
+        match instruction.text() {
+            InstructionText::Source(inst) => writeln!(
+                out,
+                "{addr:#05X}  {line:>4}  {}",
+                text(&inst.line, &symbols, stage)
+            )?,
+            InstructionText::Words(data, _) | InstructionText::Bytes(data) => writeln!(
+                out,
+                "{addr:#05X}  {line:>4}  {}",
+                text(&data.line, &symbols, stage)
+            )?,
+            InstructionText::Data(bytes) | InstructionText::Code(bytes) => {
+                for (i, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+                    let bytes: Vec<String> =
+                        chunk.iter().map(|byte| format!("{byte:#04X}")).collect();
+                    writeln!(
+                        out,
+                        "{:#05X}  {line:>4}  db {}",
+                        addr + i * BYTES_PER_LINE,
+                        bytes.join(", ")
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A line of source rewritten the way it stands after a stage. Aliases are swapped in for the mnemonic too, since an
+/// alias can stand for one
+fn text(line: &Line, symbols: &SymbolTable, stage: Stage) -> String {
+    let mut tokens = line.tokens.iter().map(|token| match stage {
+        Stage::Preprocessed => token.text,
+        Stage::AfterAliases | Stage::AfterLabels => symbols.substitute(token.text),
+    });
+    let head = tokens.next().unwrap_or_default();
+    let args: Vec<String> = tokens
+        .map(|token| match stage {
+            Stage::Preprocessed | Stage::AfterAliases => token.to_string(),
+            Stage::AfterLabels => value(token, symbols).unwrap_or_else(|| token.to_string()),
+        })
+        .collect();
+    if args.is_empty() {
+        head.to_string()
+    } else {
+        format!("{head} {}", args.join(", "))
+    }
+}
+
+/// The value of an argument that's a symbol or an expression, the way it's written after the labels stage, or None if
+/// it's neither or can't be evaluated, so it's shown as written
+fn value(token: &str, symbols: &SymbolTable) -> Option<String> {
+    if let Some(value) = symbols.value_of(token) {
+        return Some(format!("{value:#X}"));
+    }
+    if !expr::is_expression(token) {
+        return None;
+    }
+    match assemble::evaluate_signed(token, symbols).ok()? {
+        value @ 0.. => Some(format!("{value:#X}")),
+        value => Some(value.to_string()),
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+// without std there's no random state to hash with, so the maps are ordered instead
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+/// A handle to a string in an [`Interner`], so comparing and hashing symbols is comparing and
+/// hashing integers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+/// Hands out one symbol per distinct string. Strings from the source are borrowed, so interning
+/// them never allocates a copy; only names the preprocessor generates are owned
+#[derive(Debug, Default)]
+pub struct Interner<'a> {
+    ids: HashMap<Cow<'a, str>, Symbol>,
+    names: Vec<Cow<'a, str>>,
+}
+
+impl<'a> Interner<'a> {
+    /// The symbol for a string, creating one if it hasn't been seen before
+    pub fn intern(&mut self, name: impl Into<Cow<'a, str>>) -> Symbol {
+        let name = name.into();
+        if let Some(&symbol) = self.ids.get(&name) {
+            return symbol;
+        }
+        let symbol = Symbol(self.names.len() as u32);
+        self.names.push(name.clone());
+        self.ids.insert(name, symbol);
+        symbol
+    }
+
+    /// The symbol for a string, if it has been interned
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).copied()
+    }
+
+    #[cfg(feature = "std")]
+    /// The string a symbol was made from
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        &self.names[symbol.0 as usize]
+    }
+}
+
+/// The substitutions collected by the preprocessor, which the assembler resolves as it encodes
+/// each instruction instead of the preprocessor rewriting the source
+#[derive(Debug, Default)]
+pub struct SymbolTable<'a> {
+    interner: Interner<'a>,
+    /// Tokens to be replaced with other tokens from the source
+    aliases: HashMap<Symbol, &'a str>,
+    /// The address each label points to
+    labels: HashMap<Symbol, usize>,
+    /// The address each `#n` free memory offset points to
+    offsets: HashMap<Symbol, usize>,
+    /// Named values generated by the preprocessor
+    constants: HashMap<Symbol, usize>,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Record that a token stands for another, returning false if it was already aliased
+    pub fn define_alias(&mut self, name: &'a str, value: &'a str) -> bool {
+        let name = self.interner.intern(name);
+        self.aliases.insert(name, value).is_none()
+    }
+
+    /// Record the address of a label, returning false if it was already declared
+    pub fn define_label(&mut self, name: impl Into<Cow<'a, str>>, addr: usize) -> bool {
+        let name = self.interner.intern(name);
+        if self.constants.contains_key(&name) {
+            return false;
+        }
+        self.labels.insert(name, addr).is_none()
+    }
+
+    /// Record a generated constant, returning false if the name is already a label or constant
+    pub fn define_constant(&mut self, name: impl Into<Cow<'a, str>>, value: usize) -> bool {
+        let name = self.interner.intern(name);
+        if self.labels.contains_key(&name) {
+            return false;
+        }
+        self.constants.insert(name, value).is_none()
+    }
+
+    /// Record the address of a `#n` free memory offset
+    pub fn define_offset(&mut self, name: &'a str, addr: usize) {
+        let name = self.interner.intern(name);
+        self.offsets.insert(name, addr);
+    }
+
+    /// Replace a token with what it's aliased to, if anything
+    pub fn substitute<'t>(&self, token: &'t str) -> &'t str
+    where
+        'a: 't,
+    {
+        self.interner
+            .get(token)
+            .and_then(|symbol| self.aliases.get(&symbol))
+            .map_or(token, |&value| value)
+    }
+
+    /// The value a label, memory offset, or constant token stands for, if it is one
+    pub fn value_of(&self, token: &str) -> Option<usize> {
+        let symbol = self.interner.get(token)?;
+        self.labels
+            .get(&symbol)
+            .or_else(|| self.offsets.get(&symbol))
+            .or_else(|| self.constants.get(&symbol))
+            .copied()
+    }
+
+    /// Whether a name was declared as anything: an alias, label, memory offset, or constant
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.interner.get(name).is_some_and(|symbol| {
+            self.aliases.contains_key(&symbol)
+                || self.labels.contains_key(&symbol)
+                || self.offsets.contains_key(&symbol)
+                || self.constants.contains_key(&symbol)
+        })
+    }
+
+    /// Whether a token is a declared label, after substituting any alias
+    pub fn is_label(&self, token: &str) -> bool {
+        self.interner
+            .get(self.substitute(token))
+            .is_some_and(|symbol| self.labels.contains_key(&symbol))
+    }
+
+    #[cfg(feature = "std")]
+    /// Every generated constant and its value
+    pub fn constants(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.constants
+            .iter()
+            .map(|(&symbol, &value)| (self.interner.resolve(symbol), value))
+    }
+
+    #[cfg(feature = "std")]
+    /// Every alias and the token it stands for
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &'a str)> + '_ {
+        self.aliases
+            .iter()
+            .map(|(&symbol, &value)| (self.interner.resolve(symbol), value))
+    }
+
+    #[cfg(feature = "std")]
+    /// Every label and the address it points to
+    pub fn labels(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.labels
+            .iter()
+            .map(|(&symbol, &addr)| (self.interner.resolve(symbol), addr))
+    }
+}
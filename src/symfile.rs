@@ -0,0 +1,60 @@
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// A problem reading a symbol file
+#[derive(Debug, Error)]
+pub enum SymbolFileError {
+    #[error("couldn't read symbol file {0}")]
+    Unreadable(PathBuf, #[source] io::Error),
+    #[error("{path} line {line}: expected `NAME ADDRESS`, like `draw_player 0x24A`")]
+    Invalid { path: PathBuf, line: usize },
+}
+
+/// Write the address of every label, one `NAME 0xADDR` per line in address order, so another build can refer to them
+/// by name
+pub fn write(path: &Path, labels: &[(&str, usize)]) -> io::Result<()> {
+    let mut labels = labels.to_vec();
+    labels.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    writeln!(
+        out,
+        "; symbols exported by ch8asm {}",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    for (name, addr) in labels {
+        writeln!(out, "{name} {addr:#05X}")?;
+    }
+    out.flush()
+}
+
+/// Read the symbols from a file written by [`write`]. Blank lines and `;` comments are skipped, and addresses can be
+/// decimal or hex with a leading 0x
+pub fn read(path: &Path) -> Result<Vec<(String, usize)>, SymbolFileError> {
+    let text =
+        fs::read_to_string(path).map_err(|e| SymbolFileError::Unreadable(path.to_path_buf(), e))?;
+    let mut symbols = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || SymbolFileError::Invalid {
+            path: path.to_path_buf(),
+            line: number + 1,
+        };
+        let [name, addr] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+        let addr = match addr.strip_prefix("0x").or_else(|| addr.strip_prefix("0X")) {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => addr.parse(),
+        }
+        .map_err(|_| invalid())?;
+        symbols.push((name.to_string(), addr));
+    }
+    Ok(symbols)
+}
@@ -0,0 +1,181 @@
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::diagnostic::Reporter;
+use super::emulator::{Chip8, CYCLES_PER_FRAME, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::headless;
+use super::input;
+use super::screen;
+use super::RunError;
+
+/// How long a frame lasts, at 60 frames a second
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// How many frames a key counts as held after it's pressed. Terminals only say when a key is pressed, not when it's
+/// let go, and this is long enough to cover the gap before a held key starts repeating
+const HOLD_FRAMES: u64 = 30;
+
+/// The keyboard keys for each key of the keypad, laid out the same way:
+///
+/// ```text
+/// 1 2 3 4      1 2 3 C
+/// q w e r      4 5 6 D
+/// a s d f  ->  7 8 9 E
+/// z x c v      A 0 B F
+/// ```
+const KEYMAP: [u8; 16] = [
+    b'x', b'1', b'2', b'3', b'q', b'w', b'e', b'a', b's', b'd', b'z', b'c', b'4', b'r', b'f', b'v',
+];
+
+/// The bytes that stop the program: Escape and Ctrl-C
+const QUIT: [u8; 2] = [0x1B, 0x03];
+
+/// Puts the terminal into raw mode, so key presses arrive as they happen without being echoed, and puts it back the
+/// way it was when dropped, however the run ends
+struct RawMode {
+    /// The settings from before, as `stty -g` gives them, or None if they couldn't be changed
+    saved: Option<String>,
+}
+
+impl RawMode {
+    fn enable() -> RawMode {
+        // stty changes the terminal on its stdin, so it's handed ours
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        let raw = saved.is_some() && stty(&["raw", "-echo"]);
+        // clear the screen and hide the cursor
+        print!("\x1b[2J\x1b[?25l");
+        RawMode {
+            saved: saved.filter(|_| raw),
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?25h");
+        let _ = io::stdout().flush();
+        if let Some(saved) = &self.saved {
+            stty(&[saved]);
+        }
+    }
+}
+
+/// Run stty with some arguments on our terminal, returning whether it worked
+fn stty(args: &[&str]) -> bool {
+    Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Send every byte typed to the returned channel from a thread of its own, so the emulator never waits on the keyboard
+fn keyboard() -> Receiver<u8> {
+    let (send, receive) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            let Ok(byte) = byte else { break };
+            if send.send(byte).is_err() {
+                break;
+            }
+        }
+    });
+    receive
+}
+
+/// Assemble a program and run it in the terminal in real time, drawing the display with half block characters and
+/// reading the keypad from the keyboard, until Escape or Ctrl-C is pressed. Then print the state of the machine and
+/// optionally dump the display to an image, the same as a headless run
+pub fn run(input: &Path, dump_screen: Option<&Path>, reporter: &Reporter) -> Result<(), RunError> {
+    let source = input::read_source(input)?;
+    let program = super::assemble_program(&source, &super::options_for(input))?;
+    program.print_warnings(reporter);
+    let mut chip8 = Chip8::new(&program.rom)?;
+
+    let interactive = io::stdin().is_terminal();
+    let keys = keyboard();
+    let mut held_until = [0; 16];
+    let mut frame: u64 = 0;
+    let result = {
+        let _raw = interactive.then(RawMode::enable);
+        let mut next = Instant::now();
+        'run: loop {
+            for byte in keys.try_iter() {
+                if QUIT.contains(&byte) {
+                    break 'run Ok(());
+                }
+                let typed = byte.to_ascii_lowercase();
+                if let Some(key) = KEYMAP.iter().position(|&key| key == typed) {
+                    held_until[key] = frame + HOLD_FRAMES;
+                }
+            }
+            for (key, &until) in held_until.iter().enumerate() {
+                chip8.keys[key] = frame < until;
+            }
+
+            let sounding = chip8.sound_timer > 0;
+            for _ in 0..CYCLES_PER_FRAME {
+                if let Err(error) = chip8.step() {
+                    break 'run Err(error);
+                }
+            }
+            chip8.tick_timers();
+            frame += 1;
+            // ring the terminal's bell as a sound starts
+            let bell = !sounding && chip8.sound_timer > 0;
+            draw(&chip8, bell)?;
+
+            next += FRAME;
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    };
+    // start below the display, whether or not it finished on a line of its own
+    println!();
+    result?;
+
+    print!("stopped at {:#05X}", chip8.pc);
+    if let Some(line) = program.line_of(chip8.pc) {
+        print!(" (line {line})");
+    }
+    println!(" after {frame} frames");
+    headless::print_state(&chip8);
+    if let Some(path) = dump_screen {
+        screen::dump(&chip8.display, path)?;
+    }
+    Ok(())
+}
+
+/// Draw the display from the top left of the terminal, two rows of pixels to a line of text
+fn draw(chip8: &Chip8, bell: bool) -> io::Result<()> {
+    let mut frame = String::with_capacity((DISPLAY_WIDTH * 3 + 2) * DISPLAY_HEIGHT / 2 + 8);
+    frame.push_str("\x1b[H");
+    for rows in chip8.display.chunks(2) {
+        for x in 0..DISPLAY_WIDTH {
+            frame.push(match (rows[0][x], rows.get(1).is_some_and(|row| row[x])) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        // raw mode doesn't go back to the start of the line on a newline
+        frame.push_str("\r\n");
+    }
+    frame.push_str("Esc or Ctrl-C to quit");
+    if bell {
+        frame.push('\x07');
+    }
+    let mut out = io::stdout().lock();
+    out.write_all(frame.as_bytes())?;
+    out.flush()
+}
@@ -0,0 +1,191 @@
+use alloc::vec::Vec;
+
+/// A whitespace separated piece of a line of source, with its optional trailing comma removed. An arithmetic
+/// expression written with spaces, like `table + 5`, is kept together as one token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    /// The (1-indexed) byte column of the line the token starts at
+    pub column: usize,
+}
+
+/// A line of source with its comment removed, split into tokens
+#[derive(Debug, Clone)]
+pub struct Line<'a> {
+    /// The text of the line without surrounding whitespace, for reporting back to the user
+    pub text: &'a str,
+    pub tokens: Vec<Token<'a>>,
+}
+
+impl<'a> Line<'a> {
+    /// The text of the first token, which decides what the line is
+    pub fn head(&self) -> Option<&'a str> {
+        self.tokens.first().map(|t| t.text)
+    }
+
+    /// The rest of the line as it's written, starting from the token at the given index, for
+    /// arguments that can contain whitespace, such as quoted paths
+    pub fn rest(&self, index: usize) -> &'a str {
+        // the text starts at the first token, so columns give offsets into it even for tokens
+        // that were renamed after tokenizing
+        let start = self.tokens[index].column - self.tokens[0].column;
+        &self.text[start..]
+    }
+}
+
+/// Operators that can stand on their own between the parts of an expression, like the `+` of `table + 5`
+const OPERATORS: [&str; 18] = [
+    "+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "==", "!=", "<", "<=", ">", ">=", "&&",
+    "||",
+];
+
+/// Where the comment on a line starts: the first semicolon that isn't inside a string
+pub fn comment_start(line: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Split a line of source into tokens. This is the only place whitespace, commas, and comments are
+/// handled, so every later stage sees lines exactly the same way
+pub fn tokenize_line(line: &str) -> Line<'_> {
+    // everything after a semicolon is a comment
+    let line = match comment_start(line) {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    // the start and end of each piece, split at whitespace and commas outside of strings, and whether a comma came
+    // after it. Commas are optional, so they're dropped entirely, and `LD V0,5` is the same as `LD V0, 5`
+    let mut pieces: Vec<(usize, usize, bool)> = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    // a trailing space makes sure the last token gets closed off
+    for (i, c) in line
+        .char_indices()
+        .chain(core::iter::once((line.len(), ' ')))
+    {
+        if c == '"' && i < line.len() {
+            quoted = !quoted;
+        }
+        let splits = (c.is_whitespace() || c == ',') && (!quoted || i == line.len());
+        match (start, splits) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                pieces.push((s, i, c == ','));
+                start = None;
+            }
+            // a comma after whitespace still separates the piece before it from the next
+            (None, true) if c == ',' => {
+                if let Some(piece) = pieces.last_mut() {
+                    piece.2 = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut tokens: Vec<Token> = Vec::new();
+    // the piece before this one, and whether it ended with a comma
+    let mut previous: Option<(&str, bool)> = None;
+    for (s, e, comma) in pieces {
+        let text = &line[s..e];
+        // an expression written with spaces, like `table + 5` or `(WIDTH / 2) - 1`, is still a single argument. The
+        // first token decides what the line is, so it's never part of one, and strings are left alone
+        let joined = match previous {
+            Some((previous, false)) => {
+                tokens.len() > 1
+                    && !line.contains('"')
+                    && (OPERATORS.contains(&previous)
+                        || previous.ends_with(['(', '+', '-', '*', '/', '%', '&', '|', '^'])
+                        || OPERATORS.contains(&text)
+                        || text.starts_with([')', '+', '*', '/', '%', '&', '|', '^']))
+            }
+            _ => false,
+        };
+        match tokens.last_mut() {
+            Some(token) if joined => token.text = &line[token.column - 1..e],
+            _ => tokens.push(Token {
+                text,
+                column: s + 1,
+            }),
+        }
+        previous = Some((text, comma));
+    }
+
+    Line {
+        text: line.trim(),
+        tokens,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// The text of every token of a line
+    fn texts(line: &str) -> Vec<&str> {
+        tokenize_line(line)
+            .tokens
+            .iter()
+            .map(|token| token.text)
+            .collect()
+    }
+
+    #[test]
+    fn commas_are_optional() {
+        assert_eq!(texts("LD V0, 5"), ["LD", "V0", "5"]);
+        assert_eq!(texts("LD V0,5"), ["LD", "V0", "5"]);
+        assert_eq!(texts("LD V0 5"), ["LD", "V0", "5"]);
+        assert_eq!(texts("  LD  V0 ,5  "), ["LD", "V0", "5"]);
+    }
+
+    #[test]
+    fn comments_are_dropped() {
+        let line = tokenize_line("  CLS ; clear the screen");
+        assert_eq!(line.text, "CLS");
+        assert_eq!(line.tokens.len(), 1);
+        assert!(tokenize_line("; only a comment").tokens.is_empty());
+        assert_eq!(comment_start(r#"text t "a;b" ; c"#), Some(13));
+    }
+
+    #[test]
+    fn columns_start_at_1() {
+        let columns: Vec<usize> = tokenize_line("  LD V0,5")
+            .tokens
+            .iter()
+            .map(|token| token.column)
+            .collect();
+        assert_eq!(columns, [3, 6, 9]);
+    }
+
+    #[test]
+    fn expressions_with_spaces_are_one_token() {
+        assert_eq!(texts("LD I, table + 5"), ["LD", "I", "table + 5"]);
+        assert_eq!(
+            texts("LD V0, (WIDTH / 2) - 1"),
+            ["LD", "V0", "(WIDTH / 2) - 1"]
+        );
+        assert_eq!(texts("db 1 + 2, 3"), ["db", "1 + 2", "3"]);
+        // a comma still ends an expression
+        assert_eq!(texts("db 1, -1"), ["db", "1", "-1"]);
+    }
+
+    #[test]
+    fn strings_are_kept_together() {
+        assert_eq!(
+            texts(r#"text greeting "HI, THERE""#),
+            ["text", "greeting", r#""HI, THERE""#]
+        );
+        let line = tokenize_line(r#"include "my file.asm" as lib"#);
+        assert_eq!(line.rest(1), r#""my file.asm" as lib"#);
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+use thiserror::Error;
+
+/// An error in the transport shared by the debug adapter and language server protocols, as
+/// opposed to a failed request
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("failed to communicate with the client")]
+    Io(
+        #[from]
+        #[source]
+        io::Error,
+    ),
+    #[error("received malformed message from the client: {0}")]
+    Json(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    #[error("received message with a missing or invalid Content-Length header")]
+    MissingContentLength,
+}
+
+/// Read a single message framed by a Content-Length header, or None at the end of input
+pub fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>, TransportError> {
+    let mut content_length = None;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(len) = header.strip_prefix("Content-Length:") {
+            content_length = Some(
+                len.trim()
+                    .parse::<usize>()
+                    .map_err(|_| TransportError::MissingContentLength)?,
+            );
+        }
+    }
+
+    let mut body = vec![0; content_length.ok_or(TransportError::MissingContentLength)?];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Write a message to the client framed by a Content-Length header
+pub fn write_message(out: &mut impl Write, message: &Value) -> Result<(), TransportError> {
+    let body = serde_json::to_string(message)?;
+    write!(out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::preprocess;
+use super::project;
+use super::RunError;
+
+/// How often the watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait on an emulator to accept a notification before giving up on it
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The key sent in the websocket handshake. It's only there to prove the server speaks websockets, so it doesn't need
+/// to be random
+const WEBSOCKET_KEY: &str = "Y2g4YXNtIGhvdHJlbG9hZA==";
+
+/// A way of telling a running emulator the rom was rebuilt
+#[derive(Debug, Clone)]
+pub enum Notify {
+    /// Send a signal to a process with `kill`
+    Signal { pid: u32, signal: String },
+    /// POST the path of the rom to a url
+    Http { host: String, path: String },
+    /// Send the path of the rom as a text message over a websocket
+    WebSocket { host: String, path: String },
+}
+
+impl Notify {
+    /// Parse `signal:PID`, `signal:PID:SIGNAL`, `http://HOST[:PORT]/PATH`, or `ws://HOST[:PORT]/PATH`
+    pub fn parse(text: &str) -> Result<Notify, String> {
+        if let Some(signal) = text.strip_prefix("signal:") {
+            let (pid, signal) = signal.split_once(':').unwrap_or((signal, "USR1"));
+            let pid = pid
+                .parse()
+                .map_err(|_| format!("`{pid}` isn't a process id"))?;
+            return Ok(Notify::Signal {
+                pid,
+                signal: signal.trim_start_matches("SIG").to_string(),
+            });
+        }
+        if let Some(url) = text.strip_prefix("http://") {
+            let (host, path) = split_url(url, 80);
+            return Ok(Notify::Http { host, path });
+        }
+        if let Some(url) = text.strip_prefix("ws://") {
+            let (host, path) = split_url(url, 80);
+            return Ok(Notify::WebSocket { host, path });
+        }
+        Err(
+            "expected `signal:PID[:SIGNAL]`, `http://HOST[:PORT]/PATH`, or `ws://HOST[:PORT]/PATH`"
+                .to_string(),
+        )
+    }
+
+    /// Tell the emulator the rom at a path was rebuilt
+    pub fn send(&self, rom: &Path) -> io::Result<()> {
+        let rom = rom.to_string_lossy();
+        match self {
+            Notify::Signal { pid, signal } => {
+                let status = Command::new("kill")
+                    .args(["-s", signal, &pid.to_string()])
+                    .status()?;
+                if !status.success() {
+                    return Err(io::Error::other(format!("`kill` exited with {status}")));
+                }
+            }
+            Notify::Http { host, path } => {
+                let mut stream = connect(host)?;
+                write!(
+                    stream,
+                    "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{rom}",
+                    rom.len()
+                )?;
+                expect_status(&stream, "2")?;
+            }
+            Notify::WebSocket { host, path } => {
+                let mut stream = connect(host)?;
+                write!(
+                    stream,
+                    "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {WEBSOCKET_KEY}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+                )?;
+                expect_status(&stream, "101")?;
+                // a text message, then a close, both masked as everything a client sends has to be
+                stream.write_all(&frame(0x1, rom.as_bytes()))?;
+                stream.write_all(&frame(0x8, &[]))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notify::Signal { pid, signal } => write!(f, "signal:{pid}:{signal}"),
+            Notify::Http { host, path } => write!(f, "http://{host}{path}"),
+            Notify::WebSocket { host, path } => write!(f, "ws://{host}{path}"),
+        }
+    }
+}
+
+/// Rebuild whenever the contents of the source, a file it includes, or any of the other given files change, until
+/// interrupted. Each rebuild puts the files the source included in the list it's handed, so newly included files are
+/// watched too, and leaves it as it was if it fails before finding out
+pub fn watch(
+    input: &Path,
+    options: &preprocess::Options,
+    others: &[PathBuf],
+    mut rebuild: impl FnMut(&mut Vec<PathBuf>),
+) -> Result<(), RunError> {
+    let mut files = watched(input, options, others, &[]);
+    let mut included = Vec::new();
+    loop {
+        let mut since = SystemTime::now();
+        // hashed before the build, so a file saved while it was running counts as changed
+        let hashes = hash_files(&files);
+        rebuild(&mut included);
+        files = watched(input, options, others, &included);
+        loop {
+            while !changed_since(&files, since) {
+                thread::sleep(POLL_INTERVAL);
+            }
+            // editors often save files that haven't changed, which there's no need to rebuild for
+            let checked = SystemTime::now();
+            if contents_changed(&files, &hashes) {
+                break;
+            }
+            since = checked;
+        }
+        eprintln!("[watch] rebuilding {}", input.display());
+    }
+}
+
+/// Replace a file with new contents all at once, by writing them next to it and renaming them over it, so an emulator
+/// that's reloading the file never sees half of it
+pub fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+/// The source, the files it includes, the project file next to it, and the other files
+fn watched(
+    input: &Path,
+    options: &preprocess::Options,
+    others: &[PathBuf],
+    included: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut files = vec![input.to_path_buf(), options.dir.join(project::PROJECT_FILE)];
+    files.extend_from_slice(included);
+    files.extend_from_slice(others);
+    files
+}
+
+/// Whether any of the files were modified after a time. Files that don't exist (yet) haven't changed
+fn changed_since(files: &[PathBuf], time: SystemTime) -> bool {
+    files.iter().any(|file| {
+        fs::metadata(file)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified > time)
+    })
+}
+
+/// The hash of the contents of every file that can be read
+fn hash_files(files: &[PathBuf]) -> HashMap<PathBuf, u64> {
+    files
+        .iter()
+        .filter_map(|file| Some((file.clone(), hash_file(file)?)))
+        .collect()
+}
+
+/// The hash of the contents of a file, or None if it can't be read
+fn hash_file(file: &Path) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    fs::read(file).ok()?.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Whether any of the files have different contents than they were hashed with. Files that weren't hashed have
+/// changed if they can be read now
+fn contents_changed(files: &[PathBuf], hashes: &HashMap<PathBuf, u64>) -> bool {
+    files
+        .iter()
+        .any(|file| hashes.get(file).copied() != hash_file(file))
+}
+
+/// Split the part of a url after the scheme into the host, with a port, and the path
+fn split_url(url: &str, port: u16) -> (String, String) {
+    let (host, path) = match url.find('/') {
+        Some(slash) => (&url[..slash], &url[slash..]),
+        None => (url, "/"),
+    };
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:{port}")
+    };
+    (host, path.to_string())
+}
+
+/// Connect to an emulator, without waiting forever on one that isn't listening
+fn connect(host: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(host)?;
+    stream.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+    stream.set_write_timeout(Some(NOTIFY_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Check the status of an http response starts with what's expected
+fn expect_status(stream: &TcpStream, expected: &str) -> io::Result<()> {
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with(expected) => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "the emulator responded with `{}`",
+            status.trim()
+        ))),
+    }
+}
+
+/// A masked websocket frame with an opcode and payload
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos())
+        .to_be_bytes();
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(mask);
+    frame.extend(
+        payload
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(byte, mask)| byte ^ mask),
+    );
+    frame
+}
@@ -0,0 +1,10 @@
+; Waiting on the delay timer
+
+;;; Wait for V0 frames of 1/60th of a second. Clobbers V0
+delay:
+  LD DT, V0
+.wait:
+  LD V0, DT
+  SE V0, 0
+  JP .wait
+  RET
@@ -0,0 +1,9 @@
+; Reading the keypad
+
+;;; Wait for a key to be pressed and released, and put it in V0
+wait_key:
+  LD V0, K
+.release:
+  SKNP V0
+  JP .release
+  RET
@@ -0,0 +1,23 @@
+; Printing numbers with the built in font
+
+;;; Draw V0 as three decimal digits at (VD, VE), 5 pixels apart. Clobbers V0-V2, VD, VF, and I
+print_bcd:
+  LD I, print_bcd_digits
+  LD B, V0
+  LD V2, [I]
+  LD F, V0
+  DRW VD, VE, 5
+  ADD VD, 5
+  LD F, V1
+  DRW VD, VE, 5
+  ADD VD, 5
+  LD F, V2
+  DRW VD, VE, 5
+  RET
+var print_bcd_digits 3
+
+;;; Draw the low nibble of V0 as a hex digit at (VD, VE). Clobbers VF and I
+print_hex:
+  LD F, V0
+  DRW VD, VE, 5
+  RET
@@ -0,0 +1,14 @@
+; Random numbers
+
+;;; Put a random number from V1 up to but not including V2 in V0. V1 must be less than V2. Clobbers VE and VF
+random_range:
+  LD VE, V2
+  SUB VE, V1
+  RND V0, 0xFF
+.reduce:
+  SUB V0, VE
+  SE VF, 0
+  JP .reduce
+  ADD V0, VE
+  ADD V0, V1
+  RET